@@ -35,18 +35,22 @@ mod create_info {{
     }
 
     println!("cargo::rerun-if-changed=build.rs");
-    sparko_graphql_builder::builder("graphql")
-        .with_type("Date", "sparko_graphql::types::Date")
-        .with_type("DateTime", "sparko_graphql::types::DateTime")
-        .with_type("Decimal", "crate::octopus::decimal::Decimal")
-        .with_schema("graphql/octopus/octopus-schema.graphql")
-        .with_query("graphql/octopus/Login.graphql", "login")
-        .with_query("graphql/octopus/account.graphql", "account")
-        .with_query("graphql/octopus/meter.graphql", "meter")
-        .with_query("graphql/octopus/bill.graphql", "bill")
-        // .with_query("force_error", "force_error")
-        // .with_print(true)
-        .build()?;
+
+    // The GraphQL client is only needed by the octopus module
+    if env::var_os("CARGO_FEATURE_OCTOPUS").is_some() {
+        sparko_graphql_builder::builder("graphql")
+            .with_type("Date", "sparko_graphql::types::Date")
+            .with_type("DateTime", "sparko_graphql::types::DateTime")
+            .with_type("Decimal", "crate::octopus::decimal::Decimal")
+            .with_schema("graphql/octopus/octopus-schema.graphql")
+            .with_query("graphql/octopus/Login.graphql", "login")
+            .with_query("graphql/octopus/account.graphql", "account")
+            .with_query("graphql/octopus/meter.graphql", "meter")
+            .with_query("graphql/octopus/bill.graphql", "bill")
+            // .with_query("force_error", "force_error")
+            // .with_print(true)
+            .build()?;
+    }
 
     // panic!("Panic test!"); 
     Ok(())
@@ -0,0 +1,152 @@
+use std::collections::BTreeMap;
+
+use rust_decimal::Decimal;
+use sparko_graphql::types::{DateRange, DateTime};
+use tracing::warn;
+
+use crate::output::{Document, Table, Value};
+use crate::system::{Commodity, EnergyDataSource, MeterType};
+
+/* ***************************************************************************************************************************************************************
+ * Commands for the home context which combine the data of all the active modules.
+ *
+ * Each module is queried through its EnergyDataSource, so nothing here knows which provider the data came from. A module or meter which can't be read is
+ * reported after the totals of the others rather than failing the whole command.
+ *************************************************************************************************************************************************************** */
+
+/// The energy data of one module, labelled with its module id
+pub type DataSources<'a> = Vec<(&'a str, &'a dyn EnergyDataSource)>;
+
+/// Total consumption and export for each commodity over the given period
+pub async fn consumption(sources: &DataSources<'_>, date_range: &DateRange) -> anyhow::Result<Document> {
+    let mut totals: BTreeMap<(String, String, &'static str), (usize, Decimal)> = BTreeMap::new();
+    let mut failures = Vec::new();
+
+    for (module_id, source) in sources {
+        let meter_points = match source.get_meter_points().await {
+            Ok(meter_points) => meter_points,
+            Err(error) => {
+                failed(&mut failures, format!("module {}", module_id), error);
+                continue;
+            },
+        };
+        for meter_point in meter_points {
+            let total: Decimal = match source.get_consumption(&meter_point.id, date_range).await {
+                Ok(consumption) => consumption.iter().map(|item| item.value).sum(),
+                Err(error) => {
+                    failed(&mut failures, format!("meter {} of module {}", meter_point.name, module_id), error);
+                    continue;
+                },
+            };
+
+            let key = (format!("{:?}", meter_point.commodity), format!("{:?}", meter_point.meter_type), meter_point.commodity.unit());
+            let entry = totals.entry(key).or_default();
+            entry.0 += 1;
+            entry.1 += total;
+        }
+    }
+
+    let mut table = Table::new(&["Commodity", "Type", "Meters", "Total", "Unit"]);
+    for ((commodity, meter_type, unit), (meters, total)) in totals {
+        table.push(vec!(Value::from(commodity), Value::from(meter_type), Value::number(meters), Value::number(format!("{:.3}", total)), Value::from(unit)));
+    }
+    Ok(with_failures(table, failures))
+}
+
+/// Total charges and credits of the bills of each module whose billing period ends in the given period
+pub async fn spend(sources: &DataSources<'_>, date_range: &DateRange) -> anyhow::Result<Document> {
+    let mut table = Table::new(&["Module", "Bills", "Charges", "Credits"]);
+    let mut total_charges = Decimal::ZERO;
+    let mut total_credits = Decimal::ZERO;
+    let mut failures = Vec::new();
+
+    for (module_id, source) in sources {
+        let bills = match source.get_bills().await {
+            Ok(bills) => bills,
+            Err(error) => {
+                failed(&mut failures, format!("module {}", module_id), error);
+                continue;
+            },
+        };
+        let bills: Vec<_> = bills.into_iter()
+            .filter(|bill| *bill.to_date >= *date_range.start && *bill.to_date <= *date_range.end)
+            .collect();
+
+        let charges: Decimal = bills.iter().filter_map(|bill| bill.charges).sum();
+        let credits: Decimal = bills.iter().filter_map(|bill| bill.credits).sum();
+
+        table.push(vec!(Value::from(*module_id), Value::number(bills.len()), Value::number(format!("{:.2}", charges)), Value::number(format!("{:.2}", credits))));
+        total_charges += charges;
+        total_credits += credits;
+    }
+
+    table.push(vec!(Value::from("Total"), Value::Empty, Value::number(format!("{:.2}", total_charges)), Value::number(format!("{:.2}", total_credits))));
+    Ok(with_failures(table, failures))
+}
+
+/// Electricity imported and exported in each interval of the given period, summed over all meters of all modules
+pub async fn series(sources: &DataSources<'_>, date_range: &DateRange) -> anyhow::Result<Document> {
+    // keyed by timestamp so that intervals from different sources line up regardless of their UTC offset
+    let mut intervals: BTreeMap<i64, (DateTime, Decimal, Decimal)> = BTreeMap::new();
+
+    let mut failures = Vec::new();
+
+    for (module_id, source) in sources {
+        let meter_points = match source.get_meter_points().await {
+            Ok(meter_points) => meter_points,
+            Err(error) => {
+                failed(&mut failures, format!("module {}", module_id), error);
+                continue;
+            },
+        };
+        for meter_point in meter_points {
+            if meter_point.commodity != Commodity::Electricity {
+                continue;
+            }
+
+            let consumption = match source.get_consumption(&meter_point.id, date_range).await {
+                Ok(consumption) => consumption,
+                Err(error) => {
+                    failed(&mut failures, format!("meter {} of module {}", meter_point.name, module_id), error);
+                    continue;
+                },
+            };
+            for item in consumption {
+                let entry = intervals.entry(item.start.unix_timestamp())
+                    .or_insert_with(|| (item.start.clone(), Decimal::ZERO, Decimal::ZERO));
+
+                match meter_point.meter_type {
+                    MeterType::Consumption => entry.1 += item.value,
+                    MeterType::Export => entry.2 += item.value,
+                }
+            }
+        }
+    }
+
+    let format = time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]")?;
+    let mut table = Table::new(&["Start", "Import (kWh)", "Export (kWh)", "Net (kWh)"]);
+    for (_timestamp, (start, import, export)) in intervals {
+        table.push(vec!(
+            Value::from(start.format(&format)?),
+            Value::number(format!("{:.3}", import)),
+            Value::number(format!("{:.3}", export)),
+            Value::number(format!("{:.3}", import - export)),
+        ));
+    }
+    Ok(with_failures(table, failures))
+}
+
+/// Note that what is described couldn't be read, so that it can be reported alongside the totals of everything else
+fn failed(failures: &mut Vec<String>, what: String, error: anyhow::Error) {
+    warn!("Unable to read {}: {}", what, error);
+    failures.push(format!("Unable to read {}: {}", what, error));
+}
+
+/// A document of the table followed by what couldn't be read, whose data the table leaves out
+fn with_failures(table: Table, failures: Vec<String>) -> Document {
+    let mut document = Document::from(table);
+    for failure in failures {
+        document.text(&failure);
+    }
+    document
+}
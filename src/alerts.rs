@@ -0,0 +1,444 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fs::{self, File, OpenOptions};
+use std::io::Write;
+use std::path::Path;
+use std::time::Duration;
+
+use fs4::fs_std::FileExt;
+use rust_decimal::Decimal;
+use serde::{Deserialize, Serialize};
+use sparko_graphql::types::{Date, DateRange, DateTime};
+use time::OffsetDateTime;
+use tracing::{info, warn};
+
+use crate::output::{Document, Table, Value};
+use crate::system::{Bill, Demand, EnergyDataSource, MeterPoint, MeterType, Tariff};
+use crate::{Cli, MarcoSparkoContext};
+
+/* ***************************************************************************************************************************************************************
+ * Alerts raised by rules, which are given in the profile, on the data of the active modules.
+ *
+ * The rules are checked after each sync, after each MQTT update and by the alerts command. Rules on a condition (e.g. demand above a threshold) raise an alert
+ * when the condition becomes true and not again until it has become false, rules on a change (e.g. a new bill) compare the data with what was seen by the
+ * previous check. Both need to remember what has been seen, which is saved in the cache directory so that it carries over between runs and processes.
+ *
+ * A check first fetches the data for all the rules, then, as with the cache, holds an exclusive lock on the saved state from reading it to writing it back,
+ * and writes it to a temporary file which is renamed over the original, so that checks by different processes (e.g. a sync and the MQTT loop) neither lose
+ * each other's updates nor leave a half written file. No API call is made while the lock is held, so dismissing alerts in the GUI only waits briefly.
+ *************************************************************************************************************************************************************** */
+
+/// The most recent alerts kept for display by the GUI
+const MAX_RECENT_ALERTS: usize = 20;
+/// How long a webhook has to accept an alert, so that a hung endpoint doesn't stall a sync or the MQTT loop
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AlertSettings {
+    pub rules: Vec<Rule>,
+    /// Where alerts are sent, the terminal and the GUI if none are given
+    pub sinks: Vec<AlertSink>,
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum Rule {
+    /// Demand from a smart meter has been above the given watts for at least the given minutes
+    Demand {
+        above: f64,
+        #[serde(default)]
+        minutes: u64,
+    },
+    /// Yesterday's cost in pounds, from consumption and the fixed unit rate and standing charge of each meter, is above the given amount
+    DailyCost {
+        above: f64,
+    },
+    /// A new bill or statement has been issued
+    NewBill,
+    /// The current tariff of a meter has changed
+    TariffChange,
+    /// The closing balance of the latest statement is below the given pounds
+    Balance {
+        below: f64,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum AlertSink {
+    /// Print alerts on the terminal
+    Terminal,
+    /// Show alerts in a banner on the home page of the desktop application
+    Gui,
+    /// POST each alert as JSON to the given URL
+    Webhook {
+        url: String,
+    },
+    /// Run the given shell command for each alert, with the alert in the environment variables MARCO_SPARKO_ALERT_MODULE and MARCO_SPARKO_ALERT_MESSAGE
+    Command {
+        command: String,
+    },
+}
+
+#[derive(Clone, Serialize, Deserialize, Debug)]
+#[serde(rename_all = "camelCase")]
+pub struct Alert {
+    pub raised_at: DateTime,
+    pub module_id: String,
+    pub message: String,
+}
+
+/// What previous checks have seen
+#[derive(Serialize, Deserialize, Debug, Default)]
+#[serde(rename_all = "camelCase", default)]
+pub struct AlertState {
+    /// The ids of the bills of each module
+    bills: BTreeMap<String, BTreeSet<String>>,
+    /// The current tariff code of each meter, keyed by module and meter point id
+    tariffs: BTreeMap<String, String>,
+    /// When the demand of each device went above the threshold, as a unix timestamp
+    demand_above_since: BTreeMap<String, i64>,
+    /// The conditions which have raised an alert and are still true, with the value (e.g. a date) for which they were raised
+    raised: BTreeMap<String, String>,
+    pub recent: Vec<Alert>,
+}
+
+impl AlertState {
+    pub fn read(context: &MarcoSparkoContext) -> AlertState {
+        context.get_alert_state_file_path().ok()
+            .and_then(|path| fs::File::open(path).ok())
+            .and_then(|file| serde_json::from_reader(file).ok())
+            .unwrap_or_default()
+    }
+
+    /// Take the exclusive lock on the saved state, which is released when the returned file is dropped
+    fn lock(context: &MarcoSparkoContext) -> anyhow::Result<File> {
+        let path = Self::sibling_path(&context.get_alert_state_file_path()?, "lock");
+
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+
+    /// Save the state, which the caller should have locked before reading it
+    fn write(&self, context: &MarcoSparkoContext) -> anyhow::Result<()> {
+        let path = context.get_alert_state_file_path()?;
+        let temp_path = Self::sibling_path(&path, "tmp");
+
+        let mut out = File::create(&temp_path)?;
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()?;
+        out.sync_all()?;
+        drop(out);
+
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// The path of a file next to the state file, with the given extension added to its name
+    fn sibling_path(path: &Path, extension: &str) -> std::path::PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", extension));
+        name.into()
+    }
+
+    /// Record whether a condition holds, returning true if it has newly become true. A condition which is raised for a value (e.g. the day for a
+    /// daily limit) is raised again if it holds for a different value.
+    fn condition(&mut self, key: String, holds: bool, value: &str) -> bool {
+        if !holds {
+            self.raised.remove(&key);
+            return false
+        }
+        self.raised.insert(key, value.to_string()).as_deref() != Some(value)
+    }
+}
+
+/// Check the rules in the active profile against the data of all the active modules, and send any alerts which are raised
+pub async fn check(cli: &Cli) -> Vec<Alert> {
+    let settings = match &cli.context.profile.active_profile.alerts {
+        Some(settings) if !settings.rules.is_empty() => settings,
+        _ => return Vec::new(),
+    };
+
+    // the data is fetched before the state is locked, so that the lock is only held for as long as reading and writing the state takes
+    let now = OffsetDateTime::now_utc();
+    let mut observations = Vec::new();
+    for (module_id, source) in cli.data_sources() {
+        for rule in &settings.rules {
+            match observe(rule, source, now).await {
+                Ok(observation) => observations.push((module_id, observation)),
+                Err(error) => warn!("Unable to check rule {:?} for module {}: {}", rule, module_id, error),
+            }
+        }
+    }
+
+    let lock = match AlertState::lock(&cli.context) {
+        Ok(lock) => lock,
+        Err(error) => {
+            warn!("Unable to lock alert state: {}", error);
+            return Vec::new()
+        },
+    };
+    let mut state = AlertState::read(&cli.context);
+    let mut alerts = Vec::new();
+
+    for (module_id, observation) in observations {
+        for message in evaluate(observation, module_id, &mut state, now) {
+            info!("Alert from {}: {}", module_id, message);
+            alerts.push(Alert {
+                raised_at: DateTime::now_utc(),
+                module_id: module_id.to_string(),
+                message,
+            });
+        }
+    }
+
+    let sinks = if settings.sinks.is_empty() { vec!(AlertSink::Terminal, AlertSink::Gui) } else { settings.sinks.clone() };
+
+    if sinks.contains(&AlertSink::Gui) {
+        state.recent.extend(alerts.iter().cloned());
+        let excess = state.recent.len().saturating_sub(MAX_RECENT_ALERTS);
+        state.recent.drain(..excess);
+    }
+    if let Err(error) = state.write(&cli.context) {
+        warn!("Unable to save alert state: {}", error);
+    }
+    drop(lock);
+
+    for sink in &sinks {
+        for alert in &alerts {
+            if let Err(error) = send(sink, alert).await {
+                warn!("Unable to send alert to {:?}: {}", sink, error);
+            }
+        }
+    }
+    alerts
+}
+
+/// The recent alerts shown by the GUI, as a table
+pub fn recent_alerts(context: &MarcoSparkoContext) -> Document {
+    let mut table = Table::new(&["Raised At", "Module", "Alert"]);
+    for alert in AlertState::read(context).recent.iter().rev() {
+        table.push(vec!(Value::from(alert.raised_at.to_string()), Value::from(alert.module_id.as_str()), Value::from(alert.message.as_str())));
+    }
+    table.into()
+}
+
+/// Clear the recent alerts shown by the GUI. This waits for the lock on the state, so a caller which mustn't block (e.g. the GUI) should run it on a
+/// blocking thread.
+pub fn dismiss_alerts(context: &MarcoSparkoContext) -> anyhow::Result<()> {
+    let _lock = AlertState::lock(context)?;
+    let mut state = AlertState::read(context);
+    state.recent.clear();
+    state.write(context)
+}
+
+async fn send(sink: &AlertSink, alert: &Alert) -> anyhow::Result<()> {
+    match sink {
+        AlertSink::Terminal => println!("ALERT {}: {}", alert.module_id, alert.message),
+        AlertSink::Gui => {},
+        AlertSink::Webhook { url } => {
+            let client = reqwest::Client::builder().timeout(WEBHOOK_TIMEOUT).build()?;
+            client.post(url).json(alert).send().await?.error_for_status()?;
+        },
+        AlertSink::Command { command } => {
+            #[cfg(windows)]
+            let mut process = tokio::process::Command::new("cmd");
+            #[cfg(windows)]
+            process.arg("/C");
+            #[cfg(not(windows))]
+            let mut process = tokio::process::Command::new("sh");
+            #[cfg(not(windows))]
+            process.arg("-c");
+
+            let status = process.arg(command)
+                .env("MARCO_SPARKO_ALERT_MODULE", &alert.module_id)
+                .env("MARCO_SPARKO_ALERT_MESSAGE", &alert.message)
+                .status().await?;
+            if !status.success() {
+                return Err(anyhow::anyhow!("Command exited with {}", status))
+            }
+        },
+    }
+    Ok(())
+}
+
+/// The data of a module which a rule is checked against
+enum Observation {
+    Demand {
+        above: Decimal,
+        minutes: u64,
+        readings: Vec<Demand>,
+    },
+    DailyCost {
+        above: Decimal,
+        date: time::Date,
+        cost: Decimal,
+    },
+    NewBill {
+        bills: Vec<Bill>,
+    },
+    TariffChange {
+        /// The current tariff of each meter point which has one
+        current: Vec<(MeterPoint, Tariff)>,
+    },
+    Balance {
+        below: Decimal,
+        balance: Option<Decimal>,
+    },
+}
+
+/// Fetch the data of a module which a rule needs
+async fn observe(rule: &Rule, source: &dyn EnergyDataSource, now: OffsetDateTime) -> anyhow::Result<Observation> {
+    Ok(match rule {
+        Rule::Demand { above, minutes } => Observation::Demand {
+            above: Decimal::try_from(*above)?,
+            minutes: *minutes,
+            readings: source.get_current_demand().await?,
+        },
+        Rule::DailyCost { above } => {
+            let date = now.date() - time::Duration::days(1);
+            Observation::DailyCost {
+                above: Decimal::try_from(*above)?,
+                date,
+                cost: daily_cost(source, date).await?,
+            }
+        },
+        Rule::NewBill => Observation::NewBill {
+            bills: source.get_bills().await?,
+        },
+        Rule::TariffChange => {
+            // the cached tariffs would otherwise never show a change
+            source.refresh_tariffs().await?;
+            let mut current = Vec::new();
+            for meter_point in source.get_meter_points().await? {
+                let tariff = source.get_tariffs(&meter_point.id).await?.into_iter()
+                    .find(|tariff| *tariff.valid_from <= now && tariff.valid_to.as_ref().map_or(true, |valid_to| **valid_to > now));
+                if let Some(tariff) = tariff {
+                    current.push((meter_point, tariff));
+                }
+            }
+            Observation::TariffChange { current }
+        },
+        Rule::Balance { below } => Observation::Balance {
+            below: Decimal::try_from(*below)?,
+            balance: source.get_bills().await?.into_iter()
+                .filter(|bill| bill.closing_balance.is_some())
+                .max_by_key(|bill| *bill.to_date)
+                .and_then(|bill| bill.closing_balance),
+        },
+    })
+}
+
+/// Compare what was observed of a module with what previous checks saw, returning the messages of any alerts which are raised
+fn evaluate(observation: Observation, module_id: &str, state: &mut AlertState, now: OffsetDateTime) -> Vec<String> {
+    let mut messages = Vec::new();
+
+    match observation {
+        Observation::Demand { above, minutes, readings } => {
+            for reading in readings {
+                let key = format!("{}/{}", module_id, reading.device_id);
+                let holds = if reading.demand > above {
+                    let since = *state.demand_above_since.entry(key.clone()).or_insert(now.unix_timestamp());
+                    now.unix_timestamp() - since >= (minutes * 60) as i64
+                }
+                else {
+                    state.demand_above_since.remove(&key);
+                    false
+                };
+                if state.condition(format!("demand/{}", key), holds, "") {
+                    messages.push(format!("Demand of {}W from {} has been above {}W for {} minutes", reading.demand, reading.device_id, above, minutes));
+                }
+            }
+        },
+        Observation::DailyCost { above, date, cost } => {
+            if state.condition(format!("daily-cost/{}", module_id), cost > above, &date.to_string()) {
+                messages.push(format!("Cost of £{:.2} on {} is above £{}", cost, date, above));
+            }
+        },
+        Observation::NewBill { bills } => {
+            let ids: BTreeSet<String> = bills.iter().map(|bill| bill.id.clone()).collect();
+            // The first check only records the bills there are
+            if let Some(known) = state.bills.get(module_id) {
+                for bill in bills.iter().filter(|bill| !known.contains(&bill.id)) {
+                    messages.push(format!("New {} {} issued on {} for {} to {}", bill.bill_type, bill.id, bill.issued_date, bill.from_date, bill.to_date));
+                }
+            }
+            state.bills.insert(module_id.to_string(), ids);
+        },
+        Observation::TariffChange { current } => {
+            for (meter_point, tariff) in current {
+                let key = format!("{}/{}", module_id, meter_point.id);
+                if let Some(previous) = state.tariffs.insert(key, tariff.code.clone()) {
+                    if previous != tariff.code {
+                        messages.push(format!("Tariff of meter {} changed from {} to {} ({})", meter_point.name, previous, tariff.code, tariff.name));
+                    }
+                }
+            }
+        },
+        Observation::Balance { below, balance } => {
+            if let Some(balance) = balance {
+                if state.condition(format!("balance/{}", module_id), balance < below, "") {
+                    messages.push(format!("Balance of £{:.2} is below £{}", balance, below));
+                }
+            }
+        },
+    }
+    messages
+}
+
+/// The cost in pounds of a day's consumption, for the meters whose tariff has a fixed unit rate
+async fn daily_cost(source: &dyn EnergyDataSource, date: time::Date) -> anyhow::Result<Decimal> {
+    let day = Date::from_calendar_date(date.year(), date.month(), date.day())?;
+    let date_range = DateRange { start: day.clone(), end: day };
+    let start_of_day = date.midnight().assume_utc();
+    let mut cost = Decimal::ZERO;
+
+    for meter_point in source.get_meter_points().await? {
+        if meter_point.meter_type != MeterType::Consumption {
+            continue;
+        }
+
+        let tariff = source.get_tariffs(&meter_point.id).await?.into_iter()
+            .find(|tariff| *tariff.valid_from <= start_of_day && tariff.valid_to.as_ref().map_or(true, |valid_to| **valid_to > start_of_day));
+
+        if let Some(tariff) = tariff {
+            if let Some(unit_rate) = tariff.unit_rate {
+                let units: Decimal = source.get_consumption(&meter_point.id, &date_range).await?.iter().map(|item| item.value).sum();
+                cost += units * Decimal::try_from(unit_rate)? / Decimal::ONE_HUNDRED;
+                cost += Decimal::try_from(tariff.standing_charge.unwrap_or(0.0))? / Decimal::ONE_HUNDRED;
+            }
+        }
+    }
+    Ok(cost)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_condition() {
+        let mut state = AlertState::default();
+
+        assert!(state.condition("balance".to_string(), true, ""));
+        assert!(!state.condition("balance".to_string(), true, ""));
+        assert!(!state.condition("balance".to_string(), false, ""));
+        assert!(state.condition("balance".to_string(), true, ""));
+
+        assert!(state.condition("cost".to_string(), true, "2025-01-01"));
+        assert!(!state.condition("cost".to_string(), true, "2025-01-01"));
+        assert!(state.condition("cost".to_string(), true, "2025-01-02"));
+    }
+
+    #[test]
+    fn test_rules_from_profile() {
+        let settings: AlertSettings = serde_json::from_str(r#"{
+            "rules": [{"type": "demand", "above": 3000, "minutes": 10}, {"type": "newBill"}],
+            "sinks": [{"type": "webhook", "url": "http://localhost/alert"}]
+        }"#).unwrap();
+
+        assert_eq!(settings.rules, vec!(Rule::Demand { above: 3000.0, minutes: 10 }, Rule::NewBill));
+        assert_eq!(settings.sinks, vec!(AlertSink::Webhook { url: "http://localhost/alert".to_string() }));
+    }
+}
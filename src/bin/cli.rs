@@ -1,16 +1,23 @@
-use marco_sparko::Cli;
+use std::process::ExitCode;
+
+use marco_sparko::{Cli, CliError, EXIT_INITIALIZATION_FAILED};
 
 #[tokio::main]
-async fn main() {
+async fn main() -> ExitCode {
 
     match Cli::new().await {
         Ok(ms) => {
             let mut cli = ms;
 
             if let Err(error) = cli.run().await {
-                println!("Execution failed: {}", error);
+                eprintln!("Execution failed: {}", error);
+                return ExitCode::from(CliError::exit_code_for(&error))
             }
+            ExitCode::SUCCESS
+        },
+        Err(error) => {
+            eprintln!("Initialization failed: {}", error);
+            ExitCode::from(EXIT_INITIALIZATION_FAILED)
         },
-        Err(error) => println!("Initialization failed: {}", error),
     }
-}
\ No newline at end of file
+}
@@ -0,0 +1,471 @@
+mod archive;
+
+use std::collections::BTreeMap;
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+use sparko_graphql::types::Date;
+
+use crate::cache_manager::{bucket_name, Bucket, CacheManager, CacheStore, DataSet, FsStore, Granularity};
+use crate::output::{Document, Table, Value};
+use crate::profile::CacheSettings;
+use crate::{util, CliError, MarcoSparkoContext};
+
+/* ***************************************************************************************************************************************************************
+ * The cache command, which inspects and maintains the cached data of a module in the active profile. Everything works through the module's CacheStore, so it
+ * works the same whichever store the profile selects.
+ *************************************************************************************************************************************************************** */
+
+#[derive(Parser, Debug)]
+pub struct CacheArgs {
+    #[command(subcommand)]
+    command: CacheCommand,
+}
+
+#[derive(Subcommand, Debug)]
+enum CacheCommand {
+    /// List the data sets, and the buckets of time series, with their record counts and sizes
+    List {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+    },
+    /// Show the records of a data set
+    Show {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+        hash_key: String,
+        /// Only show the time series bucket containing this date (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE")]
+        bucket: Option<String>,
+    },
+    /// Check that every record is readable, that no sort key appears twice, and that time series have no missing buckets
+    Verify {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+    },
+    /// Delete a data set, or the time series buckets which start in a range of dates
+    Purge {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+        /// The data set to delete, all data sets if only a range is given
+        hash_key: Option<String>,
+        /// Delete the buckets starting on or after this date (YYYY-MM-DD) or in a period such as last-year
+        #[arg(long, value_name = "DATE")]
+        from: Option<String>,
+        /// Delete the buckets starting on or before this date (YYYY-MM-DD)
+        #[arg(long, value_name = "DATE", requires = "from")]
+        to: Option<String>,
+    },
+    /// Totals for each data set
+    Stats {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+    },
+    /// Import a cache directory into the cache store selected by the profile
+    Migrate {
+        #[arg(value_name = "MODULE")]
+        module_id: String,
+        /// The directory to import, by default the module's cache directory for the profile
+        #[arg(long, value_name = "DIR")]
+        from: Option<PathBuf>,
+    },
+    /// Write the cached data of the profile's modules, and their tokens, to an archive file
+    Export {
+        file: PathBuf,
+        /// Only export this module, all the modules in the profile if not given
+        #[arg(long = "module", value_name = "MODULE")]
+        module_ids: Vec<String>,
+        /// Leave out the modules' cached access tokens
+        #[arg(long)]
+        exclude_tokens: bool,
+        /// Export a profile which encrypts its cache, writing its data decrypted since the archive isn't encrypted
+        #[arg(long)]
+        plaintext: bool,
+        /// With --plaintext, include the modules' cached access tokens, which are otherwise left out
+        #[arg(long, requires = "plaintext", conflicts_with = "exclude_tokens")]
+        include_tokens: bool,
+    },
+    /// Merge an archive written by export into the cache of the profile's modules
+    Import {
+        file: PathBuf,
+        /// Only show the contents of the archive
+        #[arg(long)]
+        list: bool,
+    },
+}
+
+pub async fn exec(context: &MarcoSparkoContext, args: CacheArgs) -> anyhow::Result<Document> {
+    match args.command {
+        CacheCommand::List { module_id } => list(&open(context, &module_id)?).await,
+        CacheCommand::Show { module_id, hash_key, bucket } => show(&open(context, &module_id)?, &hash_key, bucket.as_deref()).await,
+        CacheCommand::Verify { module_id } => verify(&open(context, &module_id)?).await,
+        CacheCommand::Purge { module_id, hash_key, from, to } => purge(&open(context, &module_id)?, hash_key.as_deref(), from.as_deref(), to.as_deref()).await,
+        CacheCommand::Stats { module_id } => stats(&open(context, &module_id)?).await,
+        CacheCommand::Migrate { module_id, from } => migrate(context, &module_id, from).await,
+        CacheCommand::Export { file, module_ids, exclude_tokens, plaintext, include_tokens } => {
+            export(context, module_ids, exclude_tokens, plaintext, include_tokens, &file).await
+        },
+        CacheCommand::Import { file, list } => import(context, &file, list).await,
+    }
+}
+
+fn open(context: &MarcoSparkoContext, module_id: &str) -> anyhow::Result<std::sync::Arc<CacheManager>> {
+    if !context.profile.active_profile.modules.contains_key(module_id) {
+        return Err(CliError::Usage(format!("Module '{}' is not in profile '{}'", module_id, context.profile.active_profile.name)).into())
+    }
+    context.create_cache_manager(module_id)
+}
+
+/// The data sets of a store, sorted by hash key and then bucket
+async fn sorted_data_sets(store: &dyn CacheStore) -> anyhow::Result<Vec<DataSet>> {
+    let mut data_sets = store.data_sets().await?;
+    data_sets.sort_by_key(|data_set| match data_set {
+        DataSet::One(hash_key) => (hash_key.clone(), String::new()),
+        DataSet::Records(hash_key, bucket) => (hash_key.clone(), bucket.as_ref().map(bucket_name).unwrap_or_default()),
+    });
+    Ok(data_sets)
+}
+
+/// The number of records and bytes in a data set
+async fn measure(store: &dyn CacheStore, data_set: &DataSet) -> anyhow::Result<(usize, usize)> {
+    Ok(match data_set {
+        DataSet::One(hash_key) => (1, store.read_one(hash_key).await?.map(|value| value.len()).unwrap_or(0)),
+        DataSet::Records(hash_key, bucket) => {
+            let records = store.read_records(hash_key, bucket.as_ref()).await?;
+            (records.len(), records.iter().map(|(key, value)| key.len() + value.len()).sum())
+        },
+    })
+}
+
+async fn list(cache_manager: &CacheManager) -> anyhow::Result<Document> {
+    let store = cache_manager.store().await?;
+    let mut table = Table::new(&["Hash Key", "Bucket", "Records", "Bytes"]);
+
+    for data_set in sorted_data_sets(store).await? {
+        let (records, bytes) = measure(store, &data_set).await?;
+        let (hash_key, bucket) = match &data_set {
+            DataSet::One(hash_key) => (hash_key, "(single record)".to_string()),
+            DataSet::Records(hash_key, bucket) => (hash_key, bucket.as_ref().map(bucket_name).unwrap_or_default()),
+        };
+        table.push(vec!(Value::from(hash_key.as_str()), Value::from(bucket), Value::number(records), Value::number(bytes)));
+    }
+    Ok(table.into())
+}
+
+async fn show(cache_manager: &CacheManager, hash_key: &str, bucket: Option<&str>) -> anyhow::Result<Document> {
+    let store = cache_manager.store().await?;
+    let date = match bucket {
+        Some(date) => Some(util::parse_date_range(Some(date), None).map_err(|error| CliError::Usage(error.to_string()))?.start),
+        None => None,
+    };
+
+    let mut document = Document::new();
+    for data_set in sorted_data_sets(store).await? {
+        match &data_set {
+            DataSet::One(key) if key == hash_key => {
+                if let Some(value) = store.read_one(key).await? {
+                    document.text(&pretty(&cache_manager.unseal(value.trim_end())?));
+                }
+            },
+            DataSet::Records(key, data_set_bucket) if key == hash_key && selects(data_set_bucket.as_ref(), date.as_ref()) => {
+                if let Some(data_set_bucket) = data_set_bucket {
+                    document.heading(&format!("Bucket {}", bucket_name(data_set_bucket)));
+                }
+                for (sort_key, value) in store.read_records(key, data_set_bucket.as_ref()).await? {
+                    document.heading(&sort_key);
+                    document.text(&pretty(&cache_manager.unseal(&value)?));
+                }
+            },
+            _ => {},
+        }
+    }
+
+    if document.is_empty() {
+        return Err(CliError::Usage(format!("There is no cached data set {}", hash_key)).into())
+    }
+    Ok(document)
+}
+
+/// Whether a data set in the given bucket is selected by show --bucket DATE, which selects the bucket containing the date whatever its size
+fn selects(bucket: Option<&Bucket>, date: Option<&Date>) -> bool {
+    match (bucket, date) {
+        (_, None) => true,
+        (Some(bucket), Some(date)) => Bucket::containing(bucket.granularity, date).is_ok_and(|containing| containing == *bucket),
+        (None, Some(_)) => false,
+    }
+}
+
+fn pretty(json: &str) -> String {
+    serde_json::from_str::<serde_json::Value>(json)
+        .and_then(|value| serde_json::to_string_pretty(&value))
+        .unwrap_or(json.to_string())
+}
+
+async fn verify(cache_manager: &CacheManager) -> anyhow::Result<Document> {
+    let store = cache_manager.store().await?;
+    let mut problems = Table::new(&["Hash Key", "Bucket", "Problem"]);
+    let mut buckets: BTreeMap<String, Vec<Bucket>> = BTreeMap::new();
+    let data_sets = sorted_data_sets(store).await?;
+
+    for data_set in &data_sets {
+        let (hash_key, bucket) = match data_set {
+            DataSet::One(hash_key) => {
+                if let Some(value) = store.read_one(hash_key).await? {
+                    let problem = match cache_manager.unseal(value.trim_end()) {
+                        Ok(json) => serde_json::from_str::<serde::de::IgnoredAny>(&json).err().map(|error| format!("Invalid JSON: {}", error)),
+                        Err(error) => Some(error.to_string()),
+                    };
+                    if let Some(problem) = problem {
+                        problems.push(vec!(Value::from(hash_key.as_str()), Value::from(""), Value::from(problem)));
+                    }
+                }
+                continue;
+            },
+            DataSet::Records(hash_key, bucket) => (hash_key, bucket),
+        };
+        let bucket_label = bucket.as_ref().map(bucket_name).unwrap_or_default();
+
+        for line in store.invalid_records(hash_key, bucket.as_ref()).await? {
+            problems.push(vec!(Value::from(hash_key.as_str()), Value::from(bucket_label.as_str()), Value::from(format!("Invalid record <{}>", line))));
+        }
+
+        let mut sort_keys = BTreeMap::new();
+        for (sort_key, _value) in store.read_records(hash_key, bucket.as_ref()).await? {
+            *sort_keys.entry(sort_key).or_insert(0) += 1;
+        }
+        for (sort_key, count) in sort_keys.into_iter().filter(|(_, count)| *count > 1) {
+            problems.push(vec!(Value::from(hash_key.as_str()), Value::from(bucket_label.as_str()), Value::from(format!("Sort key {} appears {} times", sort_key, count))));
+        }
+
+        if let Some(bucket) = bucket {
+            buckets.entry(hash_key.clone()).or_default().push(bucket.clone());
+        }
+    }
+
+    for (hash_key, buckets) in buckets {
+        for missing in missing_buckets(&buckets)? {
+            problems.push(vec!(Value::from(hash_key.as_str()), Value::from(bucket_name(&missing)), Value::from("Missing bucket")));
+        }
+    }
+
+    let mut document = Document::new();
+    document.text(&format!("Checked {} data sets", data_sets.len()));
+    document.table(problems);
+    Ok(document)
+}
+
+/// The buckets which are missing between the first and last of the given buckets of one time series, which must be in order. Buckets of different sizes,
+/// which are left behind when the bucket size of a data set is changed, are checked separately.
+fn missing_buckets(buckets: &[Bucket]) -> anyhow::Result<Vec<Bucket>> {
+    let mut missing = Vec::new();
+    for granularity in [Granularity::Day, Granularity::Week, Granularity::Month, Granularity::Year] {
+        let series: Vec<&Bucket> = buckets.iter().filter(|bucket| bucket.granularity == granularity).collect();
+        let (first, last) = match (series.first(), series.last()) {
+            (Some(first), Some(last)) => (first, last),
+            _ => continue,
+        };
+
+        let mut expected = (*first).clone();
+        while *expected.start < *last.start {
+            expected = Bucket { granularity, start: expected.end()? };
+            if !series.contains(&&expected) {
+                missing.push(expected.clone());
+            }
+        }
+    }
+    Ok(missing)
+}
+
+async fn purge(cache_manager: &CacheManager, hash_key: Option<&str>, from: Option<&str>, to: Option<&str>) -> anyhow::Result<Document> {
+    if hash_key.is_none() && from.is_none() {
+        return Err(CliError::Usage("usage: cache purge MODULE [HASH_KEY] [--from DATE [--to DATE]], give a hash key, a range or both".to_string()).into())
+    }
+    let range = match from {
+        Some(from) => Some(util::parse_date_range(Some(from), to).map_err(|error| CliError::Usage(error.to_string()))?),
+        None => None,
+    };
+
+    let store = cache_manager.store().await?;
+    let mut deleted = 0;
+
+    for data_set in sorted_data_sets(store).await? {
+        let (key, bucket) = match &data_set {
+            DataSet::One(key) => (key, None),
+            DataSet::Records(key, bucket) => (key, bucket.as_ref()),
+        };
+        if hash_key.is_some_and(|hash_key| hash_key != key) {
+            continue;
+        }
+        if let Some(range) = &range {
+            // A range only selects buckets of time series
+            match bucket {
+                Some(bucket) if *bucket.start >= *range.start && *bucket.start <= *range.end => {},
+                _ => continue,
+            }
+        }
+        store.delete(&data_set).await?;
+        if let Some(bucket) = bucket {
+            store.delete(&DataSet::One(CacheManager::bucket_info_key(bucket, key))).await?;
+        }
+        deleted += 1;
+    }
+
+    let mut document = Document::new();
+    document.text(&format!("Deleted {} data sets", deleted));
+    Ok(document)
+}
+
+async fn stats(cache_manager: &CacheManager) -> anyhow::Result<Document> {
+    let store = cache_manager.store().await?;
+    // Buckets, records, bytes, first bucket and last bucket of each hash key
+    let mut totals: BTreeMap<String, (usize, usize, usize, Option<Bucket>, Option<Bucket>)> = BTreeMap::new();
+
+    for data_set in sorted_data_sets(store).await? {
+        let (records, bytes) = measure(store, &data_set).await?;
+        let (hash_key, bucket) = match &data_set {
+            DataSet::One(hash_key) => (hash_key, None),
+            DataSet::Records(hash_key, bucket) => (hash_key, bucket.clone()),
+        };
+
+        let total = totals.entry(hash_key.clone()).or_insert((0, 0, 0, None, None));
+        total.0 += 1;
+        total.1 += records;
+        total.2 += bytes;
+        if let Some(bucket) = bucket {
+            if total.3.is_none() {
+                total.3 = Some(bucket.clone());
+            }
+            total.4 = Some(bucket);
+        }
+    }
+
+    let mut table = Table::new(&["Hash Key", "Buckets", "Records", "Bytes", "First Bucket", "Last Bucket"]);
+    let (mut all_records, mut all_bytes) = (0, 0);
+    for (hash_key, (buckets, records, bytes, first, last)) in &totals {
+        all_records += records;
+        all_bytes += bytes;
+        table.push(vec!(Value::from(hash_key.as_str()), Value::number(buckets), Value::number(records), Value::number(bytes),
+            Value::from(first.as_ref().map(bucket_name).unwrap_or_default()), Value::from(last.as_ref().map(bucket_name).unwrap_or_default())));
+    }
+
+    let mut document = Document::new();
+    document.fields(vec!(
+        ("Data Sets".to_string(), Value::number(totals.len())),
+        ("Records".to_string(), Value::number(all_records)),
+        ("Bytes".to_string(), Value::number(all_bytes)),
+    ));
+    document.table(table);
+    Ok(document)
+}
+
+async fn migrate(context: &MarcoSparkoContext, module_id: &str, from: Option<PathBuf>) -> anyhow::Result<Document> {
+    if matches!(context.profile.active_profile.cache, None | Some(CacheSettings::Files)) {
+        return Err(CliError::Usage(format!("Profile '{}' already keeps its cache in files, set \"cache\" in the profile to choose another store",
+            context.profile.active_profile.name)).into())
+    }
+
+    let dir_path = match from {
+        Some(dir_path) => dir_path,
+        None => context.get_cache_data_dir_path(module_id)?,
+    };
+    if !dir_path.is_dir() {
+        return Err(CliError::Usage(format!("There is no cache directory {:?}", dir_path)).into())
+    }
+
+    let cache_manager = context.create_cache_manager(module_id)?;
+    let count = cache_manager.import_from(&FsStore::new(dir_path.clone())?).await?;
+
+    let mut document = Document::new();
+    document.text(&format!("Imported {} data sets from {:?}", count, dir_path));
+    Ok(document)
+}
+
+async fn export(context: &MarcoSparkoContext, module_ids: Vec<String>, exclude_tokens: bool, plaintext: bool, include_tokens: bool, file: &PathBuf)
+    -> anyhow::Result<Document> {
+    // the cache of such a profile holds personal details, the account's API key and the tokens, none of which should end up in the clear by accident
+    let encrypted = context.profile.active_profile.encryption.is_some();
+    if encrypted && !plaintext {
+        return Err(CliError::Usage(format!("Profile '{}' encrypts its cache but archives are not encrypted, give --plaintext to export it decrypted",
+            context.profile.active_profile.name)).into())
+    }
+
+    let module_ids = if module_ids.is_empty() {
+        context.profile.active_profile.modules.keys().cloned().collect()
+    }
+    else {
+        for module_id in &module_ids {
+            open(context, module_id)?;
+        }
+        module_ids
+    };
+
+    let include_tokens = if encrypted { include_tokens } else { !exclude_tokens };
+    let manifest = archive::export(context, &module_ids, include_tokens, file).await?;
+
+    let mut document = manifest_document(&manifest);
+    document.text(&format!("Exported to {:?}", file));
+    if encrypted {
+        document.text("WARNING: the archive is not encrypted, keep it somewhere safe");
+    }
+    Ok(document)
+}
+
+fn manifest_document(manifest: &archive::Manifest) -> Document {
+    let mut document = Document::new();
+    document.fields(vec!(
+        ("Profile".to_string(), Value::from(manifest.profile.as_str())),
+        ("Created".to_string(), Value::from(manifest.created_at.as_str())),
+    ));
+
+    let mut table = Table::new(&["Module", "Hash Key", "Buckets", "Records", "From", "To"]);
+    for (module_id, module_manifest) in &manifest.modules {
+        if module_manifest.token {
+            table.push(vec!(Value::from(module_id.as_str()), Value::from("(token)"), Value::from(""), Value::from(""), Value::from(""), Value::from("")));
+        }
+        for (hash_key, data_set) in &module_manifest.data_sets {
+            table.push(vec!(Value::from(module_id.as_str()), Value::from(hash_key.as_str()), Value::number(data_set.buckets), Value::number(data_set.records),
+                Value::from(data_set.from.clone().unwrap_or_default()), Value::from(data_set.to.clone().unwrap_or_default())));
+        }
+    }
+    document.table(table);
+    document
+}
+
+async fn import(context: &MarcoSparkoContext, file: &PathBuf, list: bool) -> anyhow::Result<Document> {
+    if !file.is_file() {
+        return Err(CliError::Usage(format!("There is no archive {:?}", file)).into())
+    }
+    if list {
+        return Ok(manifest_document(&archive::read_manifest(file)?))
+    }
+
+    let summary = archive::import(context, file).await?;
+
+    let mut table = Table::new(&["Module", "Data Sets", "Records Added", "Skipped"]);
+    for (module_id, (data_sets, records, skipped)) in &summary.modules {
+        table.push(vec!(Value::from(module_id.as_str()), Value::number(data_sets), Value::number(records), Value::number(skipped)));
+    }
+    let mut document = Document::new();
+    document.table(table);
+    if summary.modules.keys().any(|module_id| !context.profile.active_profile.modules.contains_key(module_id)) {
+        document.text(&format!("Modules which are not in profile '{}' were skipped", context.profile.active_profile.name));
+    }
+    Ok(document)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    #[test]
+    fn test_missing_buckets() {
+        let bucket = |granularity, year, month, day| Bucket { granularity, start: Date::from_calendar_date(year, month, day).unwrap() };
+        let buckets = vec!(bucket(Granularity::Month, 2024, Month::November, 1), bucket(Granularity::Week, 2024, Month::December, 30),
+            bucket(Granularity::Month, 2025, Month::January, 1), bucket(Granularity::Week, 2025, Month::January, 13), bucket(Granularity::Month, 2025, Month::March, 1));
+
+        assert_eq!(missing_buckets(&buckets).unwrap(), vec!(bucket(Granularity::Week, 2025, Month::January, 6),
+            bucket(Granularity::Month, 2024, Month::December, 1), bucket(Granularity::Month, 2025, Month::February, 1)));
+        assert!(missing_buckets(&[]).unwrap().is_empty());
+    }
+}
@@ -0,0 +1,401 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use anyhow::anyhow;
+use indexmap::IndexMap;
+use serde::{Deserialize, Serialize};
+use time::format_description::well_known::Rfc3339;
+use time::OffsetDateTime;
+
+use crate::cache_manager::{bucket_from_name, bucket_name, date_name, Bucket, CacheManager, DataSet, BUCKET_INFO_SUFFIX, KEY_CHECK_HASH_KEY};
+use crate::MarcoSparkoContext;
+
+/* ***************************************************************************************************************************************************************
+ * Cache archives, written by cache export and read by cache import.
+ *
+ * An archive is JSON lines compressed with zstd. The first line is the manifest, which lists the data sets of each module with their record counts and the
+ * range of dates covered by time series, and each following line is one entry: a module's cached token, a single record data set, or a data set or time
+ * series bucket with its records. Values are written decrypted, so that an archive can be imported into a cache with a different key, and are encrypted again
+ * on import if the profile encrypts its cache. Because of that the cache command only exports a profile which encrypts its cache when asked to.
+ *
+ * Importing merges by sort key: the longer copy of a data set is kept in its order and any records of the shorter it doesn't have are added at the end. The
+ * sort keys of a time series bucket are cursors, which are only comparable between copies fetched by queries which started at the same time, as given by
+ * the bucket's info. When the archive's and the cache's copies started at different times the longer is kept whole, with its info. A bucket replaced by
+ * the archive's copy is replaced in one step so that a crash can't lose it.
+ *
+ * Export doesn't hold the cache in memory: each entry is written to a temporary file as it is read, because the manifest which goes before them isn't
+ * known until the end. The archive is then written to another temporary file next to the destination and renamed over it, so that an export which fails
+ * leaves neither a partial archive nor the temporary files behind.
+ *************************************************************************************************************************************************************** */
+
+/// The version of the archive format written by export()
+const ARCHIVE_VERSION: u32 = 1;
+const COMPRESSION_LEVEL: i32 = 9;
+/// The entries are only kept until the archive is written, so they are compressed quickly
+const ENTRIES_COMPRESSION_LEVEL: i32 = 1;
+const TEMP_FILE_EXTENSION: &str = "tmp";
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct Manifest {
+    pub version: u32,
+    pub profile: String,
+    pub created_at: String,
+    pub modules: BTreeMap<String, ModuleManifest>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct ModuleManifest {
+    /// Whether the archive holds the module's cached token
+    pub token: bool,
+    pub data_sets: BTreeMap<String, DataSetManifest>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct DataSetManifest {
+    pub records: usize,
+    /// The number of time series buckets, 0 if the data set isn't a time series
+    pub buckets: usize,
+    /// The start of the first bucket of a time series (YYYY-MM-DD)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    /// The end of the last bucket of a time series (YYYY-MM-DD), i.e. the day after the last day it can hold
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Entry {
+    Token {
+        module: String,
+        value: serde_json::Value,
+    },
+    One {
+        module: String,
+        #[serde(rename = "hashKey")]
+        hash_key: String,
+        value: serde_json::Value,
+    },
+    Records {
+        module: String,
+        #[serde(rename = "hashKey")]
+        hash_key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        bucket: Option<String>,
+        records: Vec<(String, serde_json::Value)>,
+    },
+}
+
+/// What import() did for each module: data sets imported, records added and entries skipped
+#[derive(Default)]
+pub struct ImportSummary {
+    pub modules: BTreeMap<String, (usize, usize, usize)>,
+}
+
+/// Write the cached data of the given modules to an archive at path, with their tokens if include_tokens is set
+pub async fn export(context: &MarcoSparkoContext, module_ids: &[String], include_tokens: bool, path: &Path) -> anyhow::Result<Manifest> {
+    let file_name = path.file_name().ok_or(anyhow!("Invalid archive path {:?}", path))?.to_string_lossy();
+    let entries_path = path.with_file_name(format!("{}.entries.{}", file_name, TEMP_FILE_EXTENSION));
+    let temp_path = path.with_file_name(format!("{}.{}", file_name, TEMP_FILE_EXTENSION));
+
+    let result = write_archive(context, module_ids, include_tokens, path, &entries_path, &temp_path).await;
+    let _ = fs::remove_file(&entries_path);
+    if result.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    result
+}
+
+/// Write the entries to a temporary file at entries_path, then the manifest and the entries to one at temp_path, which is renamed to path
+async fn write_archive(context: &MarcoSparkoContext, module_ids: &[String], include_tokens: bool, path: &Path, entries_path: &Path, temp_path: &Path)
+    -> anyhow::Result<Manifest> {
+    let mut manifest = Manifest {
+        version: ARCHIVE_VERSION,
+        profile: context.profile.active_profile.name.clone(),
+        created_at: OffsetDateTime::now_utc().format(&Rfc3339)?,
+        modules: BTreeMap::new(),
+    };
+    let mut entries = zstd::Encoder::new(BufWriter::new(File::create(entries_path)?), ENTRIES_COMPRESSION_LEVEL)?;
+
+    for module_id in module_ids {
+        let cache_manager = context.create_cache_manager(module_id)?;
+        let module_manifest = manifest.modules.entry(module_id.clone()).or_default();
+
+        if include_tokens {
+            if let Some(token) = context.read_cache::<serde_json::Value>(module_id) {
+                writeln!(entries, "{}", serde_json::to_string(&Entry::Token { module: module_id.clone(), value: token })?)?;
+                module_manifest.token = true;
+            }
+        }
+
+        for data_set in super::sorted_data_sets(cache_manager.store().await?).await? {
+            // the key check belongs to this cache's key, values are written decrypted
+            if data_set == DataSet::One(KEY_CHECK_HASH_KEY.to_string()) {
+                continue;
+            }
+            let entry = read_entry(&cache_manager, module_id, &data_set).await?;
+            let data_set_manifest = module_manifest.data_sets.entry(match &data_set {
+                DataSet::One(hash_key) | DataSet::Records(hash_key, _) => hash_key.clone(),
+            }).or_default();
+
+            match &entry {
+                Some(Entry::One { .. }) => data_set_manifest.records += 1,
+                Some(Entry::Records { records, .. }) => data_set_manifest.records += records.len(),
+                _ => {},
+            }
+            if let DataSet::Records(_, Some(bucket)) = &data_set {
+                data_set_manifest.buckets += 1;
+                if data_set_manifest.from.is_none() {
+                    data_set_manifest.from = Some(date_name(&bucket.start));
+                }
+                data_set_manifest.to = Some(date_name(&bucket.end()?));
+            }
+            if let Some(entry) = entry {
+                writeln!(entries, "{}", serde_json::to_string(&entry)?)?;
+            }
+        }
+    }
+
+    entries.finish()?.flush()?;
+
+    let mut out = zstd::Encoder::new(BufWriter::new(File::create(temp_path)?), COMPRESSION_LEVEL)?;
+    writeln!(out, "{}", serde_json::to_string(&manifest)?)?;
+    std::io::copy(&mut zstd::Decoder::new(File::open(entries_path)?)?, &mut out)?;
+    let mut writer = out.finish()?;
+    writer.flush()?;
+    writer.get_ref().sync_all()?;
+    drop(writer);
+
+    fs::rename(temp_path, path)?;
+
+    Ok(manifest)
+}
+
+async fn read_entry(cache_manager: &CacheManager, module_id: &str, data_set: &DataSet) -> anyhow::Result<Option<Entry>> {
+    let store = cache_manager.store().await?;
+
+    Ok(match data_set {
+        DataSet::One(hash_key) => match store.read_one(hash_key).await? {
+            Some(value) => Some(Entry::One {
+                module: module_id.to_string(),
+                hash_key: hash_key.clone(),
+                value: serde_json::from_str(&cache_manager.unseal(value.trim_end())?)?,
+            }),
+            None => None,
+        },
+        DataSet::Records(hash_key, bucket) => {
+            let mut records = Vec::new();
+            for (sort_key, value) in store.read_records(hash_key, bucket.as_ref()).await? {
+                records.push((sort_key, serde_json::from_str(&cache_manager.unseal(&value)?)?));
+            }
+            Some(Entry::Records {
+                module: module_id.to_string(),
+                hash_key: hash_key.clone(),
+                bucket: bucket.as_ref().map(bucket_name),
+                records,
+            })
+        },
+    })
+}
+
+/// The manifest of the archive at path
+pub fn read_manifest(path: &Path) -> anyhow::Result<Manifest> {
+    let mut lines = open(path)?.lines();
+    let manifest: Manifest = serde_json::from_str(&lines.next().ok_or(anyhow!("{:?} is empty", path))??)
+        .map_err(|error| anyhow!("{:?} is not a cache archive: {}", path, error))?;
+
+    if manifest.version > ARCHIVE_VERSION {
+        return Err(anyhow!("{:?} was written by a newer version (archive version {})", path, manifest.version))
+    }
+    Ok(manifest)
+}
+
+fn open(path: &Path) -> anyhow::Result<BufReader<zstd::Decoder<'static, BufReader<File>>>> {
+    Ok(BufReader::new(zstd::Decoder::new(File::open(path)?)?))
+}
+
+/// Merge the archive at path into the caches of the modules of the active profile, skipping modules which aren't in the profile
+pub async fn import(context: &MarcoSparkoContext, path: &Path) -> anyhow::Result<ImportSummary> {
+    read_manifest(path)?;
+    let mut summary = ImportSummary::default();
+    let mut cache_managers = BTreeMap::new();
+
+    // the info of each bucket follows its records in the archive, but is needed to import them
+    let mut bucket_infos = HashMap::new();
+    for line in open(path)?.lines().skip(1) {
+        let line = line?;
+        if line.contains(BUCKET_INFO_SUFFIX) {
+            if let Entry::One { module, hash_key, value } = serde_json::from_str(&line)? {
+                bucket_infos.insert((module, hash_key), value);
+            }
+        }
+    }
+
+    for line in open(path)?.lines().skip(1) {
+        let entry: Entry = serde_json::from_str(&line?)?;
+        let module_id = match &entry {
+            Entry::Token { module, .. } | Entry::One { module, .. } | Entry::Records { module, .. } => module.clone(),
+        };
+        let counts = summary.modules.entry(module_id.clone()).or_default();
+
+        if !context.profile.active_profile.modules.contains_key(&module_id) {
+            counts.2 += 1;
+            continue;
+        }
+        if !cache_managers.contains_key(&module_id) {
+            cache_managers.insert(module_id.clone(), context.create_cache_manager(&module_id)?);
+        }
+        let cache_manager = &cache_managers[&module_id];
+
+        match entry {
+            Entry::Token { value, .. } => {
+                // A working token is worth more than one from another machine
+                if context.read_cache::<serde_json::Value>(&module_id).is_none() {
+                    context.update_cache(&module_id, &value)?;
+                    counts.0 += 1;
+                }
+                else {
+                    counts.2 += 1;
+                }
+            },
+            Entry::One { hash_key, .. } if hash_key == KEY_CHECK_HASH_KEY => counts.2 += 1,
+            // imported with the records of its bucket
+            Entry::One { hash_key, .. } if hash_key.ends_with(BUCKET_INFO_SUFFIX) => {},
+            Entry::One { hash_key, value, .. } => {
+                if import_one(cache_manager, &hash_key, value).await? {
+                    counts.0 += 1;
+                    counts.1 += 1;
+                }
+                else {
+                    counts.2 += 1;
+                }
+            },
+            Entry::Records { hash_key, bucket, records, .. } => {
+                let bucket = bucket.as_deref().map(bucket_from_name).transpose()?;
+                let info = bucket.as_ref().and_then(|bucket| bucket_infos.remove(&(module_id.clone(), CacheManager::bucket_info_key(bucket, &hash_key))));
+                counts.0 += 1;
+                counts.1 += import_records(cache_manager, &hash_key, bucket.as_ref(), records, info).await?;
+            },
+        }
+    }
+    Ok(summary)
+}
+
+/// The time at which a single record was fetched, 0 if it doesn't say
+fn fetched_at(value: &serde_json::Value) -> i64 {
+    value.get("fetchedAt").and_then(serde_json::Value::as_i64).unwrap_or(0)
+}
+
+/// Import a single record unless the cache already has one which was fetched more recently, returning whether it was imported
+async fn import_one(cache_manager: &CacheManager, hash_key: &str, value: serde_json::Value) -> anyhow::Result<bool> {
+    let store = cache_manager.store().await?;
+
+    if let Some(existing) = store.read_one(hash_key).await? {
+        let existing: serde_json::Value = serde_json::from_str(&cache_manager.unseal(existing.trim_end())?)?;
+        if fetched_at(&existing) >= fetched_at(&value) {
+            return Ok(false)
+        }
+    }
+    store.write_one(hash_key, &cache_manager.seal(serde_json::to_string(&value)?)?).await?;
+    Ok(true)
+}
+
+/// Merge the records of a data set by sort key, or of a bucket along with the archive's info for it as CacheManager::merge_bucket(), returning the number
+/// of records added
+async fn import_records(cache_manager: &CacheManager, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, serde_json::Value)>,
+    info: Option<serde_json::Value>) -> anyhow::Result<usize> {
+    let mut imported = Vec::new();
+    for (sort_key, value) in records {
+        imported.push((sort_key, cache_manager.seal(serde_json::to_string(&value)?)?));
+    }
+
+    if let Some(bucket) = bucket {
+        let info = match info {
+            Some(info) => Some(serde_json::to_string(&info)?),
+            None => None,
+        };
+        return cache_manager.merge_bucket(hash_key, bucket, imported, info).await
+    }
+
+    let store = cache_manager.store().await?;
+    let existing = store.read_records(hash_key, None).await?;
+    let existing_cnt = existing.len();
+    if imported.len() <= existing_cnt {
+        // The cache has the longer history, so the archive can only add records at the end
+        let new_records: Vec<(String, String)> = {
+            let existing_keys: HashSet<&String> = existing.iter().map(|(sort_key, _)| sort_key).collect();
+            imported.into_iter().filter(|(sort_key, _)| !existing_keys.contains(sort_key)).collect()
+        };
+        let added = new_records.len();
+        if added > 0 {
+            store.merge_records(hash_key, None, new_records).await?;
+        }
+        return Ok(added)
+    }
+
+    // The archive has the longer history, so it goes first and the cache's records are merged into it
+    let mut merged: IndexMap<String, String> = imported.into_iter().collect();
+    for (sort_key, value) in existing {
+        merged.entry(sort_key).or_insert(value);
+    }
+    let added = merged.len() - existing_cnt;
+    store.replace_records(hash_key, None, merged.into_iter().collect()).await?;
+    Ok(added)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+    use sparko_graphql::types::Date;
+    use time::Month;
+    use crate::cache_manager::{CachePolicy, FsStore, Granularity};
+
+    fn archived(sort_keys: &[&str]) -> Vec<(String, serde_json::Value)> {
+        sort_keys.iter().map(|sort_key| (sort_key.to_string(), json!(sort_key))).collect()
+    }
+
+    fn stored(sort_keys: &[&str]) -> Vec<(String, String)> {
+        sort_keys.iter().map(|sort_key| (sort_key.to_string(), format!("\"{}\"", sort_key))).collect()
+    }
+
+    #[tokio::test]
+    async fn test_import_records() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache_manager = CacheManager::new(Box::new(FsStore::new(dir.path().to_path_buf()).unwrap()), CachePolicy::default(), None);
+        let store = cache_manager.store().await.unwrap();
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+        let info_key = CacheManager::bucket_info_key(&bucket, "A#Consumption");
+        let cache_info = json!({"fetchedAt": 1, "value": {"queryStartAt": "cache"}});
+        let archive_info = json!({"fetchedAt": 2, "value": {"queryStartAt": "archive"}});
+
+        // copies from the same origin are merged, the cache has the longer history so the archive's other records go at the end
+        store.merge_records("A#Consumption", Some(&bucket), stored(&["a", "b", "c"])).await.unwrap();
+        store.write_one(&info_key, &cache_info.to_string()).await.unwrap();
+
+        let added = import_records(&cache_manager, "A#Consumption", Some(&bucket), archived(&["a", "d"]), Some(cache_info.clone())).await.unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), stored(&["a", "b", "c", "d"]));
+
+        // a shorter copy from another origin is left out
+        let added = import_records(&cache_manager, "A#Consumption", Some(&bucket), archived(&["x", "y"]), Some(archive_info.clone())).await.unwrap();
+        assert_eq!(added, 0);
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), stored(&["a", "b", "c", "d"]));
+        assert_eq!(cache_manager.read_one::<serde_json::Value>(&info_key).await.unwrap(), Some(json!({"queryStartAt": "cache"})));
+
+        // a longer one replaces the cache's records and info
+        let added = import_records(&cache_manager, "A#Consumption", Some(&bucket), archived(&["t", "u", "v", "w", "x", "y"]), Some(archive_info)).await.unwrap();
+        assert_eq!(added, 2);
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), stored(&["t", "u", "v", "w", "x", "y"]));
+        assert_eq!(cache_manager.read_one::<serde_json::Value>(&info_key).await.unwrap(), Some(json!({"queryStartAt": "archive"})));
+
+        // a copy fetched from the start of the bucket has no info, so the cache's goes
+        import_records(&cache_manager, "A#Consumption", Some(&bucket), archived(&["a", "b", "c", "d", "e", "f", "g"]), None).await.unwrap();
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap().len(), 7);
+        assert_eq!(store.read_one(&info_key).await.unwrap(), None);
+    }
+}
@@ -1,297 +1,877 @@
-use std::fs::{File, OpenOptions};
-use std::io::{BufReader, Lines, Write};
-use std::io::BufRead;
-use std::path::Path;
-use std::{fs, path::PathBuf};
+use std::collections::{BTreeMap, HashSet};
+use std::future::Future;
+use std::path::PathBuf;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
 use anyhow::anyhow;
+use async_trait::async_trait;
 use indexmap::IndexMap;
 use serde::de::DeserializeOwned;
-use serde::Serialize;
-use fs4::fs_std::FileExt; // Import the trait for fs4 methods
+use serde::{Deserialize, Serialize};
+use time::OffsetDateTime;
+use tracing::{debug, trace, warn};
 
 use sparko_graphql::types::Date;
 use time::Month;
 
+use crate::encryption::Cipher;
+use crate::profile::CacheSettings;
+
+mod fs_store;
+#[cfg(feature = "sqlite")]
+mod sqlite_store;
+#[cfg(feature = "dynamodb")]
+mod dynamo_store;
+
+pub use fs_store::FsStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite_store::SqliteStore;
+#[cfg(feature = "dynamodb")]
+pub use dynamo_store::DynamoStore;
+
 /* ***************************************************************************************************************************************************************
- * Manager for local file system cache.
- * 
- * The current implementation uses advisory locking to prevent clashes by multiple concurrent processes, but this is imperfect because if a file has been updated
- * by another process we may end up appending records which have already been added by that other process.
+ * Manager for the local cache.
+ *
+ * The data is stored under a hash key, and each record of a multi record data set under a sort key, as described in docs/cachedData.md. Time series data sets
+ * are split into buckets of a day, a week (from Monday), a calendar month or a year, as given by the TimeSeries of the data set or its setting in the profile.
+ * Each bucket is identified by its granularity and the date on which it starts. Buckets written before the size was changed in the profile stay as they are,
+ * and read_monthly_vec_range() reads the monthly ones so that their history needn't be fetched again. CacheManager converts between typed values and the
+ * JSON which is kept by a CacheStore, which is where the data actually lives (files in a directory, an SQLite database or a DynamoDB table) as selected by the
+ * profile.
+ *
+ * Single record data sets are written with the time at which they were fetched, and read_one_or_fetch() fetches them again once they are older than the TTL
+ * of their Freshness. A stale record is still returned, so that the caller doesn't wait, while it is fetched again in the background, except by a one-shot
+ * command which would exit before the fetch finished.
  *************************************************************************************************************************************************************** */
 
-pub struct CacheManager {
-    pub dir_path: PathBuf,
-    pub verbose: bool,
+/// A data set held by a CacheStore
+#[derive(Debug, Clone, PartialEq)]
+pub enum DataSet {
+    /// A single record, written by write_one
+    One(String),
+    /// Records, in the given bucket for a time series
+    Records(String, Option<Bucket>),
 }
 
-pub type Indexer<T> = Box<dyn Fn(&T) -> String + Send + Sync>;
+/// The size of the buckets of a time series
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize, Deserialize, Default)]
+#[serde(rename_all = "camelCase")]
+pub enum Granularity {
+    Day,
+    /// Monday to Sunday
+    Week,
+    #[default]
+    Month,
+    Year,
+}
 
-impl CacheManager {
-    fn path_for_date(path: &mut PathBuf, date: &Date) {
-        path.push(date.year().to_string());
-        // path.push(date.month().to_string());
+impl Granularity {
+    /// The start and end of the bucket containing date
+    pub fn bounds(&self, date: &Date) -> anyhow::Result<(Date, Date)> {
+        let date: time::Date = **date;
+        let (start_date, end_date) = match self {
+            Granularity::Day => (date, date.next_day().ok_or(anyhow!("Date out of range"))?),
+            Granularity::Week => {
+                let start_date = date - time::Duration::days(date.weekday().number_days_from_monday() as i64);
+                (start_date, start_date + time::Duration::days(7))
+            },
+            Granularity::Month => {
+                let start_date = time::Date::from_calendar_date(date.year(), date.month(), 1)?;
+                if date.month() == Month::December {
+                    (start_date, time::Date::from_calendar_date(date.year() + 1, Month::January, 1)?)
+                }
+                else {
+                    (start_date, time::Date::from_calendar_date(date.year(), date.month().next(), 1)?)
+                }
+            },
+            Granularity::Year => (time::Date::from_calendar_date(date.year(), Month::January, 1)?, time::Date::from_calendar_date(date.year() + 1, Month::January, 1)?),
+        };
+        Ok((to_date(start_date)?, to_date(end_date)?))
     }
 
-    fn path_hash_key_for_date(path: &mut PathBuf, date: &Date, hash_key: &str) {
-        // path.push(format!("{}#{}", date.day(), hash_key));
-        path.push(format!("{}#{}", date.month(), hash_key));
+    /// The suffix of the names of buckets of this size, monthly buckets have none so that their names are the same as before the size could be chosen
+    fn suffix(&self) -> &'static str {
+        match self {
+            Granularity::Day => ".day",
+            Granularity::Week => ".week",
+            Granularity::Month => "",
+            Granularity::Year => ".year",
+        }
     }
+}
+
+fn to_date(date: time::Date) -> anyhow::Result<Date> {
+    Ok(Date::from_calendar_date(date.year(), date.month(), date.day())?)
+}
 
+/// A bucket of a time series
+#[derive(Debug, Clone, PartialEq)]
+pub struct Bucket {
+    pub granularity: Granularity,
+    pub start: Date,
+}
 
-    ////////////////
-    /// 
-    /// 
-    
-    pub fn write_vec<T: Serialize>(&self, hash_key: &str, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+impl Bucket {
+    /// The bucket of the given size containing date
+    pub fn containing(granularity: Granularity, date: &Date) -> anyhow::Result<Bucket> {
+        Ok(Bucket {
+            granularity,
+            start: granularity.bounds(date)?.0,
+        })
+    }
 
-        self.do_write_vec(path, vec, cached_cnt)
+    /// The day after the last day of the bucket, which is the start of the next one
+    pub fn end(&self) -> anyhow::Result<Date> {
+        Ok(self.granularity.bounds(&self.start)?.1)
     }
+}
 
-    pub fn write_vec_for_date<T: Serialize>(&self, date: &Date, hash_key: &str, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
+/// A data set which is split into buckets of time
+pub struct TimeSeries {
+    /// The name by which the bucket size of the data set is given in the profile
+    pub data_set: &'static str,
+    /// The bucket size, unless the profile gives another
+    pub granularity: Granularity,
+}
 
-        Self::path_for_date(&mut path, date);
+/// Storage for the cache, holding each record as its sort key and JSON
+#[async_trait]
+pub trait CacheStore: Send + Sync {
+    /// The records of a data set, or of one bucket of it, in the order they were written
+    async fn read_records(&self, hash_key: &str, bucket: Option<&Bucket>) -> anyhow::Result<Vec<(String, String)>>;
+
+    /// Add records to a data set, replacing any existing record with the same sort key
+    async fn merge_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()>;
+
+    /// Replace all the records of a data set, or of one bucket of it, with the given records in the given order. A crash part way through leaves either
+    /// the old records or the new ones, as far as the store allows.
+    async fn replace_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()>;
+
+    async fn read_one(&self, hash_key: &str) -> anyhow::Result<Option<String>>;
+    async fn write_one(&self, hash_key: &str, value: &str) -> anyhow::Result<()>;
+
+    /// All the data sets in the store
+    async fn data_sets(&self) -> anyhow::Result<Vec<DataSet>>;
+
+    /// Remove a data set, or one bucket of a time series
+    async fn delete(&self, data_set: &DataSet) -> anyhow::Result<()>;
+
+    /// The non-empty buckets of the given size of a time series, from the one containing from up to those which start on or after to, in time order with
+    /// the records of each in the order they were written. The default reads each bucket in turn, stores which index buckets by time read them in one query.
+    async fn read_records_range(&self, hash_key: &str, granularity: Granularity, from: &Date, to: &Date) -> anyhow::Result<Vec<(Bucket, Vec<(String, String)>)>> {
+        let mut buckets = Vec::new();
+        let mut bucket = Bucket::containing(granularity, from)?;
+        while *bucket.start < **to {
+            let records = self.read_records(hash_key, Some(&bucket)).await?;
+            let next = Bucket { granularity, start: bucket.end()? };
+            if !records.is_empty() {
+                buckets.push((bucket, records));
+            }
+            bucket = next;
+        }
+        Ok(buckets)
+    }
 
-        fs::create_dir_all(&path)?;
+    /// Merge a copy of a time series bucket into the store, along with its info: the single record info_key (as written by write_one) which says where the
+    /// query which issued the bucket's sort keys started. Sort keys from the same origin are comparable, so copies whose info same_origin says is the same
+    /// are merged by sort key, the longer copy first. Otherwise the longer copy is kept whole, with its info. Returns the number of records added. The default
+    /// reads and writes in separate steps, stores which can lock the bucket or use a transaction do it in one.
+    async fn merge_bucket(&self, hash_key: &str, bucket: &Bucket, info_key: &str, records: Vec<(String, String)>, info: Option<String>,
+        same_origin: SameOrigin) -> anyhow::Result<usize> {
+        let existing = self.read_records(hash_key, Some(bucket)).await?;
+        let existing_info = self.read_one(info_key).await?;
+        let merge = BucketMerge::new(existing, existing_info.as_deref().map(str::trim_end), records, info, &same_origin)?;
+
+        if let Some(records) = merge.records {
+            self.replace_records(hash_key, Some(bucket), records).await?;
+        }
+        match merge.info {
+            Some(Some(info)) => self.write_one(info_key, &info).await?,
+            Some(None) => self.delete(&DataSet::One(info_key.to_string())).await?,
+            None => {},
+        }
+        Ok(merge.added)
+    }
 
-        Self::path_hash_key_for_date(&mut path, date, hash_key);
+    /// Note that a time series bucket is complete, so that a store can keep it in a more compact form. Records can still be merged into a closed bucket.
+    async fn close_bucket(&self, _hash_key: &str, _bucket: &Bucket) -> anyhow::Result<()> {
+        Ok(())
+    }
 
-        self.do_write_vec(path, vec, cached_cnt)
+    /// Anything in a data set which is not a valid record, which is only possible for stores which keep records as text
+    async fn invalid_records(&self, _hash_key: &str, _bucket: Option<&Bucket>) -> anyhow::Result<Vec<String>> {
+        Ok(Vec::new())
     }
+}
 
-    fn do_write_vec<T: Serialize>(&self, path: PathBuf, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+/// Whether the info of a bucket in a store (as stored, None if it has none) has the same origin as that of a copy being merged into it
+pub type SameOrigin = Arc<dyn Fn(Option<&str>) -> anyhow::Result<bool> + Send + Sync>;
+
+/// What CacheStore::merge_bucket() writes to merge a copy of a bucket into the store
+struct BucketMerge {
+    /// The records to replace the bucket's with, if they change
+    records: Option<Vec<(String, String)>>,
+    /// The info to replace the bucket's with, if it changes, None to remove it
+    info: Option<Option<String>>,
+    /// The number of records the bucket gains
+    added: usize,
+}
 
-        if cached_cnt == 0 {
-            let mut out = fs::File::create(path)?;
-            for (key, value) in vec {
-                writeln!(out, "{}\t{}", key, serde_json::to_string(&value)?)?;
-                if self.verbose 
-                {
-                    println!("WRITE {}", key);
-                }
-            }
+impl BucketMerge {
+    fn new(existing: Vec<(String, String)>, existing_info: Option<&str>, records: Vec<(String, String)>, info: Option<String>,
+        same_origin: &SameOrigin) -> anyhow::Result<BucketMerge> {
+        let unchanged = BucketMerge { records: None, info: None, added: 0 };
+        let existing_cnt = existing.len();
+
+        if records.is_empty() {
+            return Ok(unchanged)
+        }
+        if existing.is_empty() {
+            // any info left without records is ignored
+            return Ok(BucketMerge { added: records.len(), records: Some(records), info: Some(info) })
         }
-        else {
-            let mut out = OpenOptions::new().append(true).open(path)?;
-
-            let mut i = cached_cnt;
-            while i < vec.len() {
-                let (key, value) = vec.get(i).unwrap();
-                writeln!(out, "{}\t{}", key, serde_json::to_string(&value)?)?;
-                i += 1;
+        if !same_origin(existing_info)? {
+            // sort keys issued by queries which started at different times can't be compared, so one copy is kept as it is
+            if records.len() <= existing_cnt {
+                return Ok(unchanged)
             }
+            return Ok(BucketMerge { added: records.len() - existing_cnt, records: Some(records), info: Some(info) })
         }
 
-        Ok(())
+        let (first, second) = if records.len() > existing_cnt { (records, existing) } else { (existing, records) };
+        let mut merged: IndexMap<String, String> = IndexMap::new();
+        for (sort_key, value) in first.into_iter().chain(second) {
+            merged.entry(sort_key).or_insert(value);
+        }
+        let added = merged.len().saturating_sub(existing_cnt);
+        if added == 0 {
+            return Ok(unchanged)
+        }
+        Ok(BucketMerge { records: Some(merged.into_iter().collect()), info: None, added })
     }
+}
+
+/// The name of a bucket, YYYY-MM-DD of its start so that buckets sort by time, followed by its size unless it is a month (e.g. 2025-03-10.week), as used by
+/// stores which key buckets by name
+pub fn bucket_name(bucket: &Bucket) -> String {
+    format!("{}{}", date_name(&bucket.start), bucket.granularity.suffix())
+}
 
-pub fn read_vec<T: DeserializeOwned>(&self, hash_key: &str, vec: &mut Vec<(String, T)>) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+/// A date as YYYY-MM-DD
+pub fn date_name(date: &Date) -> String {
+    format!("{:04}-{:02}-{:02}", date.year(), date.month() as u8, date.day())
+}
 
-        self.do_read_vec(path, vec)
+/// The bucket named by bucket_name()
+pub fn bucket_from_name(name: &str) -> anyhow::Result<Bucket> {
+    let (date, granularity) = match name.split_once('.') {
+        Some((date, "day")) => (date, Granularity::Day),
+        Some((date, "week")) => (date, Granularity::Week),
+        Some((date, "year")) => (date, Granularity::Year),
+        Some(_) => return Err(anyhow!("Invalid cache bucket {}", name)),
+        None => (name, Granularity::Month),
+    };
+    let mut parts = date.splitn(3, '-').map(|part| part.parse::<i32>());
+    match (parts.next(), parts.next(), parts.next()) {
+        (Some(Ok(year)), Some(Ok(month)), Some(Ok(day))) => Ok(Bucket {
+            granularity,
+            start: Date::from_calendar_date(year, Month::try_from(month as u8)?, day as u8)?,
+        }),
+        _ => Err(anyhow!("Invalid cache bucket {}", name)),
     }
+}
+
+/// The freshness policy of a single record data set
+pub struct Freshness {
+    /// The name by which the data set is refreshed (--refresh=NAME) or given a different TTL in the profile
+    pub data_set: &'static str,
+    /// How long a cached record is used before it is fetched again, unless the profile gives a different TTL
+    pub ttl: Duration,
+}
+
+/// What the command line and profile say about refreshing cached data and the size of buckets
+#[derive(Default, Clone)]
+pub struct CachePolicy {
+    /// TTLs in seconds, by data set name, which replace the defaults
+    pub ttl: BTreeMap<String, u64>,
+    /// Data sets to fetch again whatever their age, all of them if empty
+    pub refresh: Option<Vec<String>>,
+    /// Bucket sizes, by data set name, which replace the defaults
+    pub buckets: BTreeMap<String, Granularity>,
+    /// Fetch stale records again before returning them rather than in the background, for a process which exits before a background fetch would finish
+    pub wait_for_stale: bool,
+}
 
-    pub fn read_vec_for_date<T: DeserializeOwned>(&self, date: &Date, hash_key: &str, vec: &mut Vec<(String, T)>) -> anyhow::Result<(Date, Date)> {
-        let start_date = Date::from_calendar_date(date.year(), date.month(), 1)?;
-        let end_date = if date.month() == Month::December {
-            Date::from_calendar_date(date.year() + 1, Month::January, 1)?
+/// The end of the hash keys of the single records written by CacheManager::bucket_info_key()
+pub const BUCKET_INFO_SUFFIX: &str = "#BucketInfo";
+
+/// The hash key of the single record which holds the salt and check value of the key with which the store's data is encrypted, so that every machine
+/// sharing the store (or a copy of it) uses the same key
+pub const KEY_CHECK_HASH_KEY: &str = "#KeyCheck";
+
+const DIFFERENT_KEY: &str = "The cache is encrypted with a different key, made on another machine which shares it or from which it was copied. \
+    Copy the profile's -key.json file from the .marco-sparko-cache directory of that machine";
+
+/// The JSON value of a value stored with the given cipher, as CacheManager::unseal()
+fn unseal(cipher: Option<&Cipher>, stored: &str) -> anyhow::Result<String> {
+    if !stored.starts_with('"') {
+        return Ok(stored.to_string())
+    }
+    match serde_json::from_str::<String>(stored) {
+        Ok(sealed) if Cipher::is_encrypted(&sealed) => {
+            let cipher = cipher.ok_or(anyhow!("The cache holds encrypted data but the profile has no encryption settings"))?;
+            Ok(String::from_utf8(cipher.decrypt(&sealed)?)?)
+        },
+        _ => Ok(stored.to_string()),
+    }
+}
+
+/// The value of the JSON of a single record and the unix time at which it was fetched, which is unknown for records written before fetch times were kept
+fn open_one(json: &str) -> Result<(serde_json::Value, Option<i64>), serde_json::Error> {
+    let value: serde_json::Value = serde_json::from_str(json)?;
+    let is_envelope = value.as_object().is_some_and(|object| object.len() == 2 && object.contains_key("fetchedAt") && object.contains_key("value"));
+
+    if is_envelope {
+        let envelope: Envelope<serde_json::Value> = serde_json::from_value(value)?;
+        Ok((envelope.value, Some(envelope.fetched_at)))
+    }
+    else {
+        Ok((value, None))
+    }
+}
+
+/// A single record as written by write_one, with the unix time at which it was fetched
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct Envelope<T> {
+    fetched_at: i64,
+    value: T,
+}
+
+pub struct CacheManager {
+    store: Box<dyn CacheStore>,
+    policy: CachePolicy,
+    /// Hash keys which this process has fetched again because of CachePolicy::refresh, so that each is only refreshed once
+    refreshed: Mutex<HashSet<String>>,
+    /// Hash keys which are being fetched again in the background, so that a stale record isn't fetched by several tasks at once
+    revalidating: Arc<Mutex<HashSet<String>>>,
+    /// Seals each value before it is written when the profile encrypts the cache
+    cipher: Option<Arc<Cipher>>,
+    /// Set once the store has been checked against the key of cipher, by checked_store()
+    key_checked: tokio::sync::OnceCell<()>,
+}
+
+pub type Indexer<T> = Box<dyn Fn(&T) -> String + Send + Sync>;
+
+impl CacheManager {
+    pub fn new(store: Box<dyn CacheStore>, policy: CachePolicy, cipher: Option<Arc<Cipher>>) -> CacheManager {
+        CacheManager {
+            store,
+            policy,
+            refreshed: Mutex::new(HashSet::new()),
+            revalidating: Arc::new(Mutex::new(HashSet::new())),
+            cipher,
+            key_checked: tokio::sync::OnceCell::new(),
         }
-        else {
-            Date::from_calendar_date(date.year(), date.month().next(), 1)?
-        };
-        let mut path = self.dir_path.clone();
+    }
 
-        Self::path_for_date(&mut path, date);
-        Self::path_hash_key_for_date(&mut path, date, hash_key);
+    /// The store, once it has been checked that its data is encrypted with the profile's key
+    pub async fn store(&self) -> anyhow::Result<&dyn CacheStore> {
+        self.key_checked.get_or_try_init(|| self.check_key()).await?;
+        Ok(self.store.as_ref())
+    }
 
-        self.do_read_vec(path, vec)?;
+    /// Check that the store is encrypted with the key of cipher. The first time a store is used by a profile which encrypts it, anything in it which was
+    /// written before the cache was encrypted is sealed and then the key check is saved in it, so that another machine which shares the store (or has a copy
+    /// of it) can't start a second key alongside.
+    async fn check_key(&self) -> anyhow::Result<()> {
+        let cipher = match &self.cipher {
+            Some(cipher) => cipher,
+            None => return Ok(()),
+        };
 
-        Ok((start_date, end_date))
+        if let Some(stored) = self.store.read_one(KEY_CHECK_HASH_KEY).await? {
+            if !cipher.has_key_check(stored.trim_end())? {
+                return Err(anyhow!(DIFFERENT_KEY))
+            }
+            return Ok(())
+        }
+
+        self.seal_existing(cipher).await?;
+        self.store.write_one(KEY_CHECK_HASH_KEY, &cipher.key_check()?).await
     }
 
-    fn do_read_vec<T: DeserializeOwned>(&self, path: PathBuf, vec: &mut Vec<(String, T)>) -> anyhow::Result<()> {
-        match Self::read_lines(path) {
-            Ok(lines) => {
-                // Consumes the iterator, returns an (Optional) String
-                for line in lines.map_while(Result::ok) {
-                    if self.verbose 
-                    {
-                        println!("READ {}", line);
+    /// Seal every value in the store which isn't sealed yet, failing if a sealed one can't be opened with cipher's key
+    async fn seal_existing(&self, cipher: &Cipher) -> anyhow::Result<()> {
+        let mut verified = false;
+        let mut reseal = |stored: &str| -> anyhow::Result<Option<String>> {
+            match serde_json::from_str::<String>(stored) {
+                Ok(sealed) if Cipher::is_encrypted(&sealed) => {
+                    if !verified {
+                        cipher.decrypt(&sealed).map_err(|_| anyhow!(DIFFERENT_KEY))?;
+                        verified = true;
                     }
+                    Ok(None)
+                },
+                _ => Ok(Some(self.seal(stored.to_string())?)),
+            }
+        };
 
-                    match line.split_once('\t') {
-                        Some((key, value)) => vec.push((key.to_string(), serde_json::from_str(value)?)),
-                        None => return Err(anyhow!(format!("Invalid cached object <{}>", line))),
+        let mut sealed_cnt = 0;
+        for data_set in self.store.data_sets().await? {
+            match &data_set {
+                DataSet::One(hash_key) => {
+                    if let Some(stored) = self.store.read_one(hash_key).await? {
+                        if let Some(value) = reseal(stored.trim_end())? {
+                            self.store.write_one(hash_key, &value).await?;
+                            sealed_cnt += 1;
+                        }
                     }
-                }
-            },
+                },
+                DataSet::Records(hash_key, bucket) => {
+                    let mut records = Vec::new();
+                    for (sort_key, stored) in self.store.read_records(hash_key, bucket.as_ref()).await? {
+                        if let Some(value) = reseal(&stored)? {
+                            records.push((sort_key, value));
+                        }
+                    }
+                    sealed_cnt += records.len();
+                    if !records.is_empty() {
+                        // the sort keys are already there, so the records keep their places
+                        self.store.merge_records(hash_key, bucket.as_ref(), records).await?;
+                    }
+                },
+            }
+        }
+        if sealed_cnt > 0 {
+            debug!("Encrypted {} records cached before encryption was turned on", sealed_cnt);
+        }
+        Ok(())
+    }
 
-            Err(error) => {
-                if error.kind() != std::io::ErrorKind::NotFound {
-                    println!("ERROR {:?}", error);
-                    return Err(anyhow!(error))
-                }
-                
+    /// Open the store selected by the given profile settings. dir_path is the directory used by the files store, other stores are kept alongside it.
+    pub fn open(settings: Option<&CacheSettings>, dir_path: PathBuf, policy: CachePolicy, cipher: Option<Arc<Cipher>>) -> anyhow::Result<CacheManager> {
+        let store: Box<dyn CacheStore> = match settings {
+            None | Some(CacheSettings::Files) => Box::new(FsStore::new(dir_path)?),
+            #[cfg(feature = "sqlite")]
+            Some(CacheSettings::Sqlite { path }) => {
+                let path = path.as_ref().map(PathBuf::from).unwrap_or_else(|| dir_path.with_extension("sqlite"));
+                Box::new(SqliteStore::open(&path)?)
             },
+            #[cfg(not(feature = "sqlite"))]
+            Some(CacheSettings::Sqlite { .. }) => return Err(anyhow!("This build does not include the sqlite cache")),
+            #[cfg(feature = "dynamodb")]
+            Some(CacheSettings::Dynamodb { table, region, endpoint }) => Box::new(DynamoStore::new(table.clone(), region.clone(), endpoint.clone())),
+            #[cfg(not(feature = "dynamodb"))]
+            Some(CacheSettings::Dynamodb { .. }) => return Err(anyhow!("This build does not include the dynamodb cache")),
+        };
+        Ok(CacheManager::new(store, policy, cipher))
+    }
+
+    /// The JSON to store for the given JSON value, which is a JSON string holding the value encrypted if the cache is encrypted
+    pub fn seal(&self, json: String) -> anyhow::Result<String> {
+        match &self.cipher {
+            Some(cipher) => Ok(serde_json::to_string(&cipher.encrypt(json.as_bytes())?)?),
+            None => Ok(json),
         }
+    }
 
-        Ok(())
+    /// The JSON value of a stored value, decrypting it if it was sealed. Values written before the cache was encrypted are returned as they are.
+    pub fn unseal(&self, stored: &str) -> anyhow::Result<String> {
+        unseal(self.cipher.as_deref(), stored)
+    }
+
+    /// Copy every data set from the given store into this one, merging with any records already here. Returns the number of data sets copied.
+    pub async fn import_from(&self, source: &dyn CacheStore) -> anyhow::Result<usize> {
+        let store = self.store().await?;
+        // this store has its own key check, and the info of each bucket is copied along with it
+        let data_sets: Vec<DataSet> = source.data_sets().await?.into_iter()
+            .filter(|data_set| match data_set {
+                DataSet::One(hash_key) => hash_key != KEY_CHECK_HASH_KEY && !hash_key.ends_with(BUCKET_INFO_SUFFIX),
+                DataSet::Records(..) => true,
+            })
+            .collect();
+
+        for data_set in &data_sets {
+            match data_set {
+                DataSet::One(hash_key) => {
+                    if let Some(value) = source.read_one(hash_key).await? {
+                        store.write_one(hash_key, &value).await?;
+                    }
+                },
+                DataSet::Records(hash_key, Some(bucket)) => {
+                    let records = source.read_records(hash_key, Some(bucket)).await?;
+                    let info = match source.read_one(&Self::bucket_info_key(bucket, hash_key)).await? {
+                        Some(stored) => Some(self.unseal(stored.trim_end())?),
+                        None => None,
+                    };
+                    self.merge_bucket(hash_key, bucket, records, info).await?;
+                },
+                DataSet::Records(hash_key, None) => {
+                    let records = source.read_records(hash_key, None).await?;
+                    store.merge_records(hash_key, None, records).await?;
+                },
+            }
+        }
+        Ok(data_sets.len())
     }
 
+    /// The bucket of a time series containing date
+    pub fn bucket_for_date(&self, series: &TimeSeries, date: &Date) -> anyhow::Result<Bucket> {
+        Bucket::containing(self.granularity(series), date)
+    }
 
-    /// 
-    /// ////////////
+    /// The size of the buckets of a time series
+    fn granularity(&self, series: &TimeSeries) -> Granularity {
+        self.policy.buckets.get(series.data_set).copied().unwrap_or(series.granularity)
+    }
 
-    pub fn write<T: Serialize>(&self, hash_key: &str, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+    ////////////////
+    ///
+    ///
 
-        self.do_write(path, map, cached_cnt)
+    pub async fn write_vec<T: Serialize>(&self, hash_key: &str, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        self.do_write_vec(hash_key, None, vec, cached_cnt).await
     }
 
-    pub fn write_for_date<T: Serialize>(&self, date: &Date, hash_key: &str, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
+    pub async fn write_vec_for_date<T: Serialize>(&self, series: &TimeSeries, date: &Date, hash_key: &str, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        let bucket = self.bucket_for_date(series, date)?;
 
-        Self::path_for_date(&mut path, date);
+        self.do_write_vec(hash_key, Some(&bucket), vec, cached_cnt).await
+    }
 
-        fs::create_dir_all(&path)?;
+    /// Note that the bucket containing date is complete, i.e. its last record reaches the end of the bucket
+    pub async fn close_bucket_for_date(&self, series: &TimeSeries, date: &Date, hash_key: &str) -> anyhow::Result<()> {
+        let bucket = self.bucket_for_date(series, date)?;
 
-        Self::path_hash_key_for_date(&mut path, date, hash_key);
+        self.store().await?.close_bucket(hash_key, &bucket).await
+    }
 
-        self.do_write(path, map, cached_cnt)
+    /// The hash key of the single record which holds what a data set's module needs to know about one of its buckets, e.g. where to resume fetching it
+    pub fn bucket_info_key(bucket: &Bucket, hash_key: &str) -> String {
+        format!("{}#{}{}", hash_key, bucket_name(bucket), BUCKET_INFO_SUFFIX)
     }
 
-    fn do_write<T: Serialize>(&self, path: PathBuf, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+    pub async fn read_bucket_info_for_date<T: DeserializeOwned>(&self, series: &TimeSeries, date: &Date, hash_key: &str) -> anyhow::Result<Option<T>> {
+        self.read_one(&Self::bucket_info_key(&self.bucket_for_date(series, date)?, hash_key)).await
+    }
 
-        if cached_cnt == 0 {
-            let mut out = fs::File::create(path)?;
-            let _guard = out.lock_exclusive()?;
-            for (key, value) in map.values() {
-                writeln!(out, "{}\t{}", key, serde_json::to_string(&value)?)?;
-                if self.verbose 
-                {
-                    println!("WRITE {}", key);
-                }
-            }
-            // drop(guard);
+    pub async fn write_bucket_info_for_date<T: Serialize>(&self, series: &TimeSeries, date: &Date, hash_key: &str, info: &T) -> anyhow::Result<()> {
+        self.write_one(&Self::bucket_info_key(&self.bucket_for_date(series, date)?, hash_key), info).await
+    }
+
+    /// Merge the records of the bucket of a time series containing date into the cache, with its info (None for a bucket fetched from its start), as
+    /// CacheStore::merge_bucket(). Returns the number of records added.
+    pub async fn merge_vec_for_date<T: Serialize, I: Serialize>(&self, series: &TimeSeries, date: &Date, hash_key: &str, vec: &[(String, T)], info: Option<&I>)
+        -> anyhow::Result<usize> {
+        let bucket = self.bucket_for_date(series, date)?;
+        let mut records = Vec::new();
+        for (key, value) in vec {
+            trace!("WRITE {}", key);
+            records.push((key.clone(), self.seal(serde_json::to_string(&value)?)?));
+        }
+        let info = match info {
+            Some(info) => Some(serde_json::to_string(&Envelope { fetched_at: OffsetDateTime::now_utc().unix_timestamp(), value: info })?),
+            None => None,
+        };
+
+        self.merge_bucket(hash_key, &bucket, records, info).await
+    }
+
+    /// Merge a copy of a bucket of a time series, with stored records and the JSON of its info as written by write_one, into the cache as
+    /// CacheStore::merge_bucket(). Returns the number of records added.
+    pub async fn merge_bucket(&self, hash_key: &str, bucket: &Bucket, records: Vec<(String, String)>, info: Option<String>) -> anyhow::Result<usize> {
+        let info_key = Self::bucket_info_key(bucket, hash_key);
+        let origin = match &info {
+            Some(json) => Some(open_one(json)?.0),
+            None => None,
+        };
+        let cipher = self.cipher.clone();
+        let same_origin: SameOrigin = Arc::new(move |stored: Option<&str>| -> anyhow::Result<bool> {
+            let stored_origin = match stored {
+                Some(stored) => Some(open_one(&unseal(cipher.as_deref(), stored)?)?.0),
+                None => None,
+            };
+            Ok(stored_origin == origin)
+        });
+        let info = match info {
+            Some(json) => Some(self.seal(json)?),
+            None => None,
+        };
+
+        self.store().await?.merge_bucket(hash_key, bucket, &info_key, records, info, same_origin).await
+    }
+
+    async fn do_write_vec<T: Serialize>(&self, hash_key: &str, bucket: Option<&Bucket>, vec: &Vec<(String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        let mut records = Vec::new();
+        for (key, value) in vec.iter().skip(cached_cnt) {
+            trace!("WRITE {}", key);
+            records.push((key.clone(), self.seal(serde_json::to_string(&value)?)?));
+        }
+
+        if records.is_empty() {
+            return Ok(())
+        }
+        self.store().await?.merge_records(hash_key, bucket, records).await
+    }
+
+    pub async fn read_vec<T: DeserializeOwned>(&self, hash_key: &str, vec: &mut Vec<(String, T)>) -> anyhow::Result<()> {
+        self.do_read_vec(hash_key, None, vec).await
+    }
+
+    /// Read the bucket of a time series containing date, returning the start and end of the bucket
+    pub async fn read_vec_for_date<T: DeserializeOwned>(&self, series: &TimeSeries, date: &Date, hash_key: &str, vec: &mut Vec<(String, T)>) -> anyhow::Result<(Date, Date)> {
+        let bucket = self.bucket_for_date(series, date)?;
+
+        self.do_read_vec(hash_key, Some(&bucket), vec).await?;
+
+        Ok((bucket.start.clone(), bucket.end()?))
+    }
+
+    /// Read the buckets of a time series from the one containing from up to to, leaving out any which are empty. Stores which index buckets by time do this
+    /// in one query, so a long period is much quicker to read than bucket by bucket.
+    pub async fn read_vec_range<T: DeserializeOwned>(&self, series: &TimeSeries, from: &Date, to: &Date, hash_key: &str) -> anyhow::Result<Vec<(Bucket, Vec<(String, T)>)>> {
+        self.do_read_vec_range(hash_key, self.granularity(series), from, to).await
+    }
+
+    /// Read the monthly buckets of a time series from the one containing from up to to, as read_vec_range(), if the profile gives it buckets of another size.
+    /// These are what was cached before the size was changed, which the caller can use for the buckets of the new size which aren't cached yet. Empty if
+    /// the buckets of the series are months.
+    pub async fn read_monthly_vec_range<T: DeserializeOwned>(&self, series: &TimeSeries, from: &Date, to: &Date, hash_key: &str) -> anyhow::Result<Vec<(Bucket, Vec<(String, T)>)>> {
+        if self.granularity(series) == Granularity::Month {
+            return Ok(Vec::new())
         }
-        else {
-            let mut out = OpenOptions::new().append(true).open(path)?;
-            let _guard = out.lock_exclusive()?;
-
-            let mut i = cached_cnt;
-            while i < map.len() {
-                let (_index, (key, value)) = map.get_index(i).unwrap();
-                writeln!(out, "{}\t{}", key, serde_json::to_string(&value)?)?;
-                i += 1;
+        self.do_read_vec_range(hash_key, Granularity::Month, from, to).await
+    }
+
+    async fn do_read_vec_range<T: DeserializeOwned>(&self, hash_key: &str, granularity: Granularity, from: &Date, to: &Date) -> anyhow::Result<Vec<(Bucket, Vec<(String, T)>)>> {
+        let mut buckets = Vec::new();
+        for (bucket, records) in self.store().await?.read_records_range(hash_key, granularity, from, to).await? {
+            let mut vec = Vec::new();
+            for (key, value) in records {
+                trace!("READ {}\t{}", key, value);
+                vec.push((key, serde_json::from_str(&self.unseal(&value)?)?));
             }
+            buckets.push((bucket, vec));
+        }
+        Ok(buckets)
+    }
+
+    async fn do_read_vec<T: DeserializeOwned>(&self, hash_key: &str, bucket: Option<&Bucket>, vec: &mut Vec<(String, T)>) -> anyhow::Result<()> {
+        for (key, value) in self.store().await?.read_records(hash_key, bucket).await? {
+            trace!("READ {}\t{}", key, value);
+            vec.push((key, serde_json::from_str(&self.unseal(&value)?)?));
         }
 
         Ok(())
     }
 
-    // The output is wrapped in a Result to allow matching on errors.
-    // Returns an Iterator to the Reader of the lines of the file.
-    fn read_lines<P>(filename: P) -> std::io::Result<Lines<BufReader<File>>>
-    where P: AsRef<Path>, {
-        let file = File::open(filename)?;
-        let _guard = file.lock_shared()?;
-        Ok(BufReader::new(file).lines())
+
+    ///
+    /// ////////////
+
+    pub async fn write<T: Serialize>(&self, hash_key: &str, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        self.do_write(hash_key, None, map, cached_cnt).await
     }
 
-    pub fn read<T: DeserializeOwned>(&self, hash_key: &str, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+    pub async fn write_for_date<T: Serialize>(&self, series: &TimeSeries, date: &Date, hash_key: &str, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        let bucket = self.bucket_for_date(series, date)?;
 
-        self.do_read(path, map, indexer)
+        self.do_write(hash_key, Some(&bucket), map, cached_cnt).await
     }
 
-    pub fn read_for_date<T: DeserializeOwned>(&self, date: &Date, hash_key: &str, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<(Date, Date)> {
-        let start_date = Date::from_calendar_date(date.year(), date.month(), 1)?;
-        let end_date = if date.month() == Month::December {
-            Date::from_calendar_date(date.year() + 1, Month::January, 1)?
+    async fn do_write<T: Serialize>(&self, hash_key: &str, bucket: Option<&Bucket>, map: &IndexMap<String, (String, T)>, cached_cnt: usize) -> anyhow::Result<()> {
+        let mut records = Vec::new();
+        for (key, value) in map.values().skip(cached_cnt) {
+            trace!("WRITE {}", key);
+            records.push((key.clone(), self.seal(serde_json::to_string(&value)?)?));
         }
-        else {
-            Date::from_calendar_date(date.year(), date.month().next(), 1)?
-        };
-        let mut path = self.dir_path.clone();
 
-        Self::path_for_date(&mut path, date);
-        Self::path_hash_key_for_date(&mut path, date, hash_key);
-
-        self.do_read(path, map, indexer)?;
+        if records.is_empty() {
+            return Ok(())
+        }
+        self.store().await?.merge_records(hash_key, bucket, records).await
+    }
 
-        Ok((start_date, end_date))
+    pub async fn read<T: DeserializeOwned>(&self, hash_key: &str, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<()> {
+        self.do_read(hash_key, None, map, indexer).await
     }
 
-    fn do_read<T: DeserializeOwned>(&self, path: PathBuf, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<()> {
-        match Self::read_lines(path) {
-            Ok(lines) => {
-                // Consumes the iterator, returns an (Optional) String
-                for line in lines.map_while(Result::ok) {
-                    if self.verbose 
-                    {
-                        println!("READ {}", line);
-                    }
+    pub async fn read_for_date<T: DeserializeOwned>(&self, series: &TimeSeries, date: &Date, hash_key: &str, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<(Date, Date)> {
+        let bucket = self.bucket_for_date(series, date)?;
 
-                    match line.split_once('\t') {
-                        Some((key, value)) => {
-                            let value = serde_json::from_str(value)?;
-                            let index = indexer(&value);
-                            map.insert(index, (key.to_string(), value));
+        self.do_read(hash_key, Some(&bucket), map, indexer).await?;
 
-                            // vec.push((key.to_string(), value))
-                        },
-                        None => return Err(anyhow!(format!("Invalid cached object <{}>", line))),
-                    }
-                }
-            },
+        Ok((bucket.start.clone(), bucket.end()?))
+    }
 
-            Err(error) => {
-                if error.kind() != std::io::ErrorKind::NotFound {
-                    println!("ERROR {:?}", error);
-                    return Err(anyhow!(error))
-                }
-                
-            },
+    async fn do_read<T: DeserializeOwned>(&self, hash_key: &str, bucket: Option<&Bucket>, map: &mut IndexMap<String, (String, T)>, indexer: &Indexer<T>) -> anyhow::Result<()> {
+        for (key, value) in self.store().await?.read_records(hash_key, bucket).await? {
+            trace!("READ {}\t{}", key, value);
+
+            let value = serde_json::from_str(&self.unseal(&value)?)?;
+            let index = indexer(&value);
+            map.insert(index, (key, value));
         }
 
         Ok(())
     }
 
 
-    pub fn write_one<T: Serialize>(&self, hash_key: &str, value: &T) -> anyhow::Result<()> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+    pub async fn write_one<T: Serialize>(&self, hash_key: &str, value: &T) -> anyhow::Result<()> {
+        let envelope = Envelope {
+            fetched_at: OffsetDateTime::now_utc().unix_timestamp(),
+            value,
+        };
+        self.store().await?.write_one(hash_key, &self.seal(serde_json::to_string(&envelope)?)?).await
+    }
 
-        let mut out = fs::File::create(path)?;
-        let _guard = out.lock_exclusive()?;
+    pub async fn read_one<T: DeserializeOwned>(&self, hash_key: &str) -> anyhow::Result<Option<T>> {
+        Ok(self.read_one_with_time(hash_key).await?.map(|(value, _fetched_at)| value))
+    }
 
-        writeln!(out, "{}", serde_json::to_string(&value)?)?;
+    /// A single record and the unix time at which it was fetched, which is unknown for records written before fetch times were kept
+    async fn read_one_with_time<T: DeserializeOwned>(&self, hash_key: &str) -> anyhow::Result<Option<(T, Option<i64>)>> {
+        let json = match self.store().await?.read_one(hash_key).await? {
+            Some(stored) => self.unseal(stored.trim_end())?,
+            None => return Ok(None),
+        };
+        let invalid = |error: serde_json::Error| anyhow!("Invalid cached object {}: {}", hash_key, error);
 
-        Ok(())
+        let (value, fetched_at) = open_one(&json).map_err(invalid)?;
+        Ok(Some((serde_json::from_value(value).map_err(invalid)?, fetched_at)))
     }
 
-    pub fn read_one<T: DeserializeOwned>(&self, hash_key: &str) -> anyhow::Result<Option<T>> {
-        let mut path = self.dir_path.clone();
-        path.push(hash_key);
+    /// Whether this process should fetch the given data set (named as for --refresh) again regardless of its age, which it only does once
+    pub fn must_refresh(&self, hash_key: &str, data_set: &str) -> bool {
+        let requested = match &self.policy.refresh {
+            Some(data_sets) => data_sets.is_empty() || data_sets.iter().any(|name| name.eq_ignore_ascii_case(data_set)),
+            None => false,
+        };
+        requested && self.refreshed.lock().map(|mut refreshed| refreshed.insert(hash_key.to_string())).unwrap_or(false)
+    }
 
-        Ok(match File::open(path) {
-            Ok(file) => {
-                let _guard = file.lock_shared()?;
-                let reader = BufReader::new(file);
-                Some(serde_json::from_reader(reader)?)
-            },
-            Err(error) => {
-                if error.kind() != std::io::ErrorKind::NotFound {
-                    println!("ERROR {:?}", error);
-                    return Err(anyhow!(error))
+    /// Read a single record data set, fetching it with fetch if it isn't cached or has been refreshed on the command line. A record older than its TTL is
+    /// returned as it is and fetched again in the background, so the new value is seen by later reads.
+    pub async fn read_one_or_fetch<T, F, Fut>(self: &Arc<Self>, hash_key: &str, freshness: &Freshness, fetch: F) -> anyhow::Result<T>
+    where
+        T: Serialize + DeserializeOwned + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+    {
+        if !self.must_refresh(hash_key, freshness.data_set) {
+            if let Some((value, fetched_at)) = self.read_one_with_time(hash_key).await? {
+                let ttl = self.policy.ttl.get(freshness.data_set).map(|ttl| Duration::from_secs(*ttl)).unwrap_or(freshness.ttl);
+                let age = fetched_at.map(|fetched_at| OffsetDateTime::now_utc().unix_timestamp() - fetched_at);
+                let stale = age.is_none_or(|age| age < 0 || age as u64 > ttl.as_secs());
+
+                if !stale {
+                    return Ok(value)
                 }
-                None
-            },
-        })
+                if !self.policy.wait_for_stale {
+                    self.revalidate(hash_key, fetch);
+                    return Ok(value)
+                }
+            }
+        }
+
+        debug!("Fetching {}", hash_key);
+        let value = fetch().await?;
+        self.write_one(hash_key, &value).await?;
+        Ok(value)
     }
-}
\ No newline at end of file
+
+    /// Fetch a stale record again in the background, unless that is already under way
+    fn revalidate<T, F, Fut>(self: &Arc<Self>, hash_key: &str, fetch: F)
+    where
+        T: Serialize + Send + 'static,
+        F: FnOnce() -> Fut + Send + 'static,
+        Fut: Future<Output = anyhow::Result<T>> + Send,
+    {
+        if !self.revalidating.lock().map(|mut revalidating| revalidating.insert(hash_key.to_string())).unwrap_or(false) {
+            return
+        }
+
+        debug!("Revalidating {} in the background", hash_key);
+        let cache_manager = self.clone();
+        let hash_key = hash_key.to_string();
+        tokio::spawn(async move {
+            let result = match fetch().await {
+                Ok(value) => cache_manager.write_one(&hash_key, &value).await,
+                Err(error) => Err(error),
+            };
+            if let Err(error) = result {
+                warn!("Unable to refresh cached {}: {}", hash_key, error);
+            }
+            // so that the record is fetched again when it next goes stale
+            if let Ok(mut revalidating) = cache_manager.revalidating.lock() {
+                revalidating.remove(&hash_key);
+            }
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TEST_FRESHNESS: Freshness = Freshness {
+        data_set: "viewer",
+        ttl: Duration::from_secs(60),
+    };
+
+    fn test_cache_manager(dir: &tempfile::TempDir, policy: CachePolicy) -> Arc<CacheManager> {
+        Arc::new(CacheManager::new(Box::new(FsStore::new(dir.path().to_path_buf()).unwrap()), policy, None))
+    }
+
+    #[tokio::test]
+    async fn test_read_one_legacy_and_envelope() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache_manager = test_cache_manager(&dir, CachePolicy::default());
+
+        cache_manager.store().await.unwrap().write_one("#Legacy", "[1,2]").await.unwrap();
+        assert_eq!(cache_manager.read_one_with_time::<Vec<i32>>("#Legacy").await.unwrap(), Some((vec!(1, 2), None)));
+
+        cache_manager.write_one("#Viewer", &vec!(3)).await.unwrap();
+        let (value, fetched_at) = cache_manager.read_one_with_time::<Vec<i32>>("#Viewer").await.unwrap().unwrap();
+        assert_eq!(value, vec!(3));
+        assert!(fetched_at.is_some());
+    }
+
+    #[test]
+    fn test_must_refresh_once() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache_manager = test_cache_manager(&dir, CachePolicy { refresh: Some(vec!("Viewer".to_string())), ..Default::default() });
+
+        assert!(cache_manager.must_refresh("#Viewer", TEST_FRESHNESS.data_set));
+        assert!(!cache_manager.must_refresh("#Viewer", TEST_FRESHNESS.data_set));
+        assert!(!cache_manager.must_refresh("A#Properties", "properties"));
+    }
+
+    #[tokio::test]
+    async fn test_revalidate_stale() {
+        let dir = tempfile::TempDir::new().unwrap();
+
+        // a record written before fetch times were kept is always stale
+        let cache_manager = test_cache_manager(&dir, CachePolicy::default());
+        cache_manager.store().await.unwrap().write_one("#Viewer", "[1]").await.unwrap();
+        let value: Vec<i32> = cache_manager.read_one_or_fetch("#Viewer", &TEST_FRESHNESS, || async { Ok(vec!(2)) }).await.unwrap();
+        assert_eq!(value, vec!(1));
+
+        // the background fetch finishes, and a later one can start
+        for _ in 0..100 {
+            if cache_manager.revalidating.lock().unwrap().is_empty() {
+                break
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert!(cache_manager.revalidating.lock().unwrap().is_empty());
+        assert_eq!(cache_manager.read_one::<Vec<i32>>("#Viewer").await.unwrap(), Some(vec!(2)));
+
+        // a one-shot command waits for the fetch
+        cache_manager.store().await.unwrap().write_one("#Viewer", "[1]").await.unwrap();
+        let cache_manager = test_cache_manager(&dir, CachePolicy { wait_for_stale: true, ..Default::default() });
+        let value: Vec<i32> = cache_manager.read_one_or_fetch("#Viewer", &TEST_FRESHNESS, || async { Ok(vec!(3)) }).await.unwrap();
+        assert_eq!(value, vec!(3));
+    }
+}
@@ -0,0 +1,532 @@
+use std::collections::HashMap;
+use std::time::Duration;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use indexmap::IndexMap;
+use aws_config::{BehaviorVersion, Region};
+use aws_sdk_dynamodb::types::{AttributeDefinition, AttributeValue, BillingMode, DeleteRequest, KeySchemaElement, KeyType, PutRequest, ReturnValue, ScalarAttributeType, TableStatus, WriteRequest};
+use aws_sdk_dynamodb::Client;
+use tokio::sync::OnceCell;
+use time::OffsetDateTime;
+use tracing::{debug, info};
+
+use super::{Bucket, BucketMerge, CacheStore, DataSet, SameOrigin};
+
+/* ***************************************************************************************************************************************************************
+ * Cache store in a DynamoDB table, so that several machines can share one data set.
+ *
+ * Items use the layout described in docs/cachedData.md: the partition key hashKey is the hash key of the data set (e.g. A-B1C2B345#104910337#StatementTransactions)
+ * and the sort key sortKey is the record's sort key (usually the server's cursor). Time series buckets have the name of the bucket in front of the hash key
+ * (2025-03-01#A-B1C2B345#Consumption... for a month, 2025-03-10.week#... for a week), and single records have the sort key "#". DynamoDB orders items by sort key, which isn't the order in which cursors
+ * were returned, so each item also has a sequence number seq which gives the order in which records were written. The value attribute is the JSON.
+ *
+ * Sequence numbers are handed out by a counter item in each partition, with the sort key "#seq", which is updated atomically so that writers on different
+ * machines never give two records the same number.
+ *
+ * Merging a copy of a time series bucket can't be done in one transaction, because a bucket can hold more records than DynamoDB allows in one. Instead the
+ * item which holds the bucket's info has a version, which a merge claims by incrementing it with a condition that it hasn't changed since the merge read the
+ * bucket and that no other merge holds a lease on it. The merge then writes the records and replaces the info, releasing the lease, and starts again from
+ * reading the bucket if its claim fails. A lease left by a crash expires, so a crash part way through a merge can still leave the new records with the old info.
+ *
+ * The table is created, with on demand billing, if it doesn't exist. Credentials and the region come from the usual AWS configuration (environment variables,
+ * ~/.aws/config etc) unless the region is given in the profile, and an endpoint can be given to use DynamoDB Local.
+ *************************************************************************************************************************************************************** */
+
+const HASH_KEY: &str = "hashKey";
+const SORT_KEY: &str = "sortKey";
+const SEQ: &str = "seq";
+const VALUE: &str = "value";
+/// The sort key of single records
+const ONE_SORT_KEY: &str = "#";
+/// The sort key of the item in each partition of records which holds the last sequence number handed out
+const SEQ_SORT_KEY: &str = "#seq";
+/// The most items DynamoDB accepts in one BatchWriteItem
+const MAX_BATCH_WRITE: usize = 25;
+/// The version of a bucket's info item, incremented by each merge of the bucket
+const VERSION: &str = "version";
+/// When the claim of the merge which is writing a bucket expires, in seconds since the epoch
+const LEASE: &str = "leaseUntil";
+/// How long a merge may take to write a bucket before another may claim it
+const LEASE_SECS: i64 = 60;
+/// How many times a merge tries to claim a bucket which other merges keep changing, which waits longer than a lease in all
+const MAX_MERGE_ATTEMPTS: u32 = 10;
+
+pub struct DynamoStore {
+    table: String,
+    region: Option<String>,
+    endpoint: Option<String>,
+    /// Created on first use, because loading the AWS configuration is async
+    client: OnceCell<Client>,
+}
+
+impl DynamoStore {
+    pub fn new(table: String, region: Option<String>, endpoint: Option<String>) -> DynamoStore {
+        DynamoStore {
+            table,
+            region,
+            endpoint,
+            client: OnceCell::new(),
+        }
+    }
+
+    async fn client(&self) -> anyhow::Result<&Client> {
+        self.client.get_or_try_init(|| async {
+            let mut loader = aws_config::defaults(BehaviorVersion::latest());
+            if let Some(region) = &self.region {
+                loader = loader.region(Region::new(region.clone()));
+            }
+            if let Some(endpoint) = &self.endpoint {
+                loader = loader.endpoint_url(endpoint);
+            }
+
+            let client = Client::new(&loader.load().await);
+            self.create_table_if_missing(&client).await?;
+            Ok::<Client, anyhow::Error>(client)
+        }).await
+    }
+
+    async fn create_table_if_missing(&self, client: &Client) -> anyhow::Result<()> {
+        match client.describe_table().table_name(&self.table).send().await {
+            Ok(_) => return Ok(()),
+            Err(error) => {
+                if !error.as_service_error().is_some_and(|error| error.is_resource_not_found_exception()) {
+                    return Err(anyhow!("Unable to describe table {}: {}", self.table, error))
+                }
+            },
+        }
+
+        info!("Creating cache table {}", self.table);
+        client.create_table()
+            .table_name(&self.table)
+            .attribute_definitions(AttributeDefinition::builder().attribute_name(HASH_KEY).attribute_type(ScalarAttributeType::S).build()?)
+            .attribute_definitions(AttributeDefinition::builder().attribute_name(SORT_KEY).attribute_type(ScalarAttributeType::S).build()?)
+            .key_schema(KeySchemaElement::builder().attribute_name(HASH_KEY).key_type(KeyType::Hash).build()?)
+            .key_schema(KeySchemaElement::builder().attribute_name(SORT_KEY).key_type(KeyType::Range).build()?)
+            .billing_mode(BillingMode::PayPerRequest)
+            .send().await?;
+
+        for _ in 0..60 {
+            let description = client.describe_table().table_name(&self.table).send().await?;
+            if description.table().and_then(|table| table.table_status()) == Some(&TableStatus::Active) {
+                return Ok(())
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+        Err(anyhow!("Table {} did not become active", self.table))
+    }
+
+    fn partition_key(hash_key: &str, bucket: Option<&Bucket>) -> String {
+        match bucket {
+            Some(bucket) => format!("{}#{}", super::bucket_name(bucket), hash_key),
+            None => hash_key.to_string(),
+        }
+    }
+
+    /// The hash key and bucket of a partition key
+    fn data_set_from_partition_key(partition_key: &str) -> (String, Option<Bucket>) {
+        if let Some((prefix, hash_key)) = partition_key.split_once('#') {
+            if let Ok(bucket) = super::bucket_from_name(prefix) {
+                return (hash_key.to_string(), Some(bucket))
+            }
+        }
+        (partition_key.to_string(), None)
+    }
+
+    fn string(item: &HashMap<String, AttributeValue>, name: &str) -> anyhow::Result<String> {
+        item.get(name)
+            .and_then(|value| value.as_s().ok())
+            .cloned()
+            .ok_or(anyhow!("Cached item has no {}", name))
+    }
+
+    /// The records of a partition as (sort key, seq, value) in the order they were written
+    async fn query(&self, partition_key: &str) -> anyhow::Result<Vec<(String, i64, String)>> {
+        let items = self.client().await?.query()
+            .table_name(&self.table)
+            .key_condition_expression("#h = :h")
+            .expression_attribute_names("#h", HASH_KEY)
+            .expression_attribute_values(":h", AttributeValue::S(partition_key.to_string()))
+            .consistent_read(true)
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>().await?;
+
+        let mut records = Vec::new();
+        for item in items {
+            let sort_key = Self::string(&item, SORT_KEY)?;
+            if sort_key == ONE_SORT_KEY || sort_key == SEQ_SORT_KEY {
+                continue;
+            }
+            let seq = item.get(SEQ).and_then(|seq| seq.as_n().ok()).and_then(|seq| seq.parse().ok()).unwrap_or(0);
+            records.push((sort_key, seq, Self::string(&item, VALUE)?));
+        }
+        records.sort_by_key(|(_, seq, _)| *seq);
+        Ok(records)
+    }
+
+    /// Reserve count sequence numbers in a partition, returning the first of them. The counter starts from seen, the highest seq the caller has read, for
+    /// partitions written before there was a counter.
+    async fn reserve_seq(&self, partition_key: &str, count: usize, seen: i64) -> anyhow::Result<i64> {
+        let output = self.client().await?.update_item()
+            .table_name(&self.table)
+            .key(HASH_KEY, AttributeValue::S(partition_key.to_string()))
+            .key(SORT_KEY, AttributeValue::S(SEQ_SORT_KEY.to_string()))
+            .update_expression("SET #n = if_not_exists(#n, :seen) + :count")
+            .expression_attribute_names("#n", SEQ)
+            .expression_attribute_values(":seen", AttributeValue::N(seen.to_string()))
+            .expression_attribute_values(":count", AttributeValue::N(count.to_string()))
+            .return_values(ReturnValue::UpdatedNew)
+            .send().await?;
+
+        let last: i64 = output.attributes()
+            .and_then(|attributes| attributes.get(SEQ))
+            .and_then(|seq| seq.as_n().ok())
+            .and_then(|seq| seq.parse().ok())
+            .ok_or(anyhow!("No sequence number returned for {}", partition_key))?;
+        Ok(last - count as i64 + 1)
+    }
+
+    /// The value and version of a single record, None if it has no value (as the info item of a bucket which has no info may not) and 0 if it has no version
+    async fn read_info(&self, info_key: &str) -> anyhow::Result<(Option<String>, i64)> {
+        let output = self.client().await?.get_item()
+            .table_name(&self.table)
+            .key(HASH_KEY, AttributeValue::S(info_key.to_string()))
+            .key(SORT_KEY, AttributeValue::S(ONE_SORT_KEY.to_string()))
+            .consistent_read(true)
+            .send().await?;
+
+        match output.item() {
+            Some(item) => Ok((
+                item.get(VALUE).and_then(|value| value.as_s().ok()).cloned(),
+                item.get(VERSION).and_then(|version| version.as_n().ok()).and_then(|version| version.parse().ok()).unwrap_or(0),
+            )),
+            None => Ok((None, 0)),
+        }
+    }
+
+    /// Claim a bucket for a merge by moving its info item from the version the merge read to the next, which fails (returning false) if another merge has
+    /// changed it since or holds an unexpired lease on it
+    async fn claim_bucket(&self, info_key: &str, version: i64) -> anyhow::Result<bool> {
+        let now = OffsetDateTime::now_utc().unix_timestamp();
+        let result = self.client().await?.update_item()
+            .table_name(&self.table)
+            .key(HASH_KEY, AttributeValue::S(info_key.to_string()))
+            .key(SORT_KEY, AttributeValue::S(ONE_SORT_KEY.to_string()))
+            .update_expression("SET #v = :next, #l = :until")
+            .condition_expression("(attribute_not_exists(#v) OR #v = :v) AND (attribute_not_exists(#l) OR #l < :now)")
+            .expression_attribute_names("#v", VERSION)
+            .expression_attribute_names("#l", LEASE)
+            .expression_attribute_values(":v", AttributeValue::N(version.to_string()))
+            .expression_attribute_values(":next", AttributeValue::N((version + 1).to_string()))
+            .expression_attribute_values(":until", AttributeValue::N((now + LEASE_SECS).to_string()))
+            .expression_attribute_values(":now", AttributeValue::N(now.to_string()))
+            .send().await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(error) if error.as_service_error().is_some_and(|error| error.is_conditional_check_failed_exception()) => Ok(false),
+            Err(error) => Err(anyhow!(error)),
+        }
+    }
+
+    /// Release the claim of a merge on a bucket, replacing its info if it changes (None to remove it), which fails (returning false) if the lease expired
+    /// and another merge has claimed the bucket since
+    async fn release_bucket(&self, info_key: &str, version: i64, info: Option<Option<String>>) -> anyhow::Result<bool> {
+        let mut update = self.client().await?.update_item()
+            .table_name(&self.table)
+            .key(HASH_KEY, AttributeValue::S(info_key.to_string()))
+            .key(SORT_KEY, AttributeValue::S(ONE_SORT_KEY.to_string()))
+            .condition_expression("#v = :v")
+            .expression_attribute_names("#v", VERSION)
+            .expression_attribute_names("#l", LEASE)
+            .expression_attribute_values(":v", AttributeValue::N(version.to_string()));
+        update = match info {
+            Some(Some(info)) => update.update_expression("SET #val = :value REMOVE #l")
+                .expression_attribute_names("#val", VALUE)
+                .expression_attribute_values(":value", AttributeValue::S(info)),
+            Some(None) => update.update_expression("REMOVE #val, #l")
+                .expression_attribute_names("#val", VALUE),
+            None => update.update_expression("REMOVE #l"),
+        };
+
+        match update.send().await {
+            Ok(_) => Ok(true),
+            Err(error) if error.as_service_error().is_some_and(|error| error.is_conditional_check_failed_exception()) => Ok(false),
+            Err(error) => Err(anyhow!(error)),
+        }
+    }
+
+    async fn batch_write(&self, mut requests: Vec<WriteRequest>) -> anyhow::Result<()> {
+        let client = self.client().await?;
+
+        while !requests.is_empty() {
+            let batch: Vec<WriteRequest> = requests.drain(..requests.len().min(MAX_BATCH_WRITE)).collect();
+            let mut unprocessed = batch;
+
+            for attempt in 0..8 {
+                let output = client.batch_write_item()
+                    .request_items(&self.table, unprocessed)
+                    .send().await?;
+
+                unprocessed = output.unprocessed_items()
+                    .and_then(|items| items.get(&self.table))
+                    .cloned()
+                    .unwrap_or_default();
+                if unprocessed.is_empty() {
+                    break;
+                }
+                debug!("{} items unprocessed, retrying", unprocessed.len());
+                tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+            }
+            if !unprocessed.is_empty() {
+                return Err(anyhow!("DynamoDB did not accept {} cache records", unprocessed.len()))
+            }
+        }
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl CacheStore for DynamoStore {
+    async fn read_records(&self, hash_key: &str, bucket: Option<&Bucket>) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(self.query(&Self::partition_key(hash_key, bucket)).await?
+            .into_iter()
+            .map(|(sort_key, _seq, value)| (sort_key, value))
+            .collect())
+    }
+
+    async fn merge_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let partition_key = Self::partition_key(hash_key, bucket);
+        let existing: HashMap<String, i64> = self.query(&partition_key).await?
+            .into_iter()
+            .map(|(sort_key, seq, _value)| (sort_key, seq))
+            .collect();
+
+        // A batch may not contain the same key twice, the last value for a sort key wins as it does for the other stores
+        let mut values: HashMap<String, String> = HashMap::new();
+        let mut new_sort_keys = Vec::new();
+        for (sort_key, value) in records {
+            if !existing.contains_key(&sort_key) && !values.contains_key(&sort_key) {
+                new_sort_keys.push(sort_key.clone());
+            }
+            values.insert(sort_key, value);
+        }
+
+        // records which are already there keep their place, new ones get numbers from the partition's counter, in the order they were given
+        let mut puts: HashMap<String, (i64, String)> = HashMap::new();
+        if !new_sort_keys.is_empty() {
+            let seen = existing.values().max().copied().unwrap_or(0);
+            let first_seq = self.reserve_seq(&partition_key, new_sort_keys.len(), seen).await?;
+            for (seq, sort_key) in (first_seq..).zip(new_sort_keys) {
+                let value = values.remove(&sort_key).unwrap_or_default();
+                puts.insert(sort_key, (seq, value));
+            }
+        }
+        for (sort_key, value) in values {
+            puts.insert(sort_key.clone(), (existing[&sort_key], value));
+        }
+
+        let mut requests = Vec::new();
+        for (sort_key, (seq, value)) in puts {
+            let put = PutRequest::builder()
+                .item(HASH_KEY, AttributeValue::S(partition_key.clone()))
+                .item(SORT_KEY, AttributeValue::S(sort_key))
+                .item(SEQ, AttributeValue::N(seq.to_string()))
+                .item(VALUE, AttributeValue::S(value))
+                .build()?;
+            requests.push(WriteRequest::builder().put_request(put).build());
+        }
+        self.batch_write(requests).await
+    }
+
+    async fn replace_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let partition_key = Self::partition_key(hash_key, bucket);
+        let existing: Vec<(String, i64)> = self.query(&partition_key).await?.into_iter().map(|(sort_key, seq, _value)| (sort_key, seq)).collect();
+
+        // DynamoDB has no transaction across this many items, so the new records are written (after the old ones, in their order) before the old ones
+        // which aren't among them are deleted: a crash part way through leaves extra records rather than losing any
+        let mut values: IndexMap<String, String> = IndexMap::new();
+        for (sort_key, value) in records {
+            values.insert(sort_key, value);
+        }
+        let mut requests = Vec::new();
+        if !values.is_empty() {
+            let seen = existing.iter().map(|(_, seq)| *seq).max().unwrap_or(0);
+            let first_seq = self.reserve_seq(&partition_key, values.len(), seen).await?;
+            for (seq, (sort_key, value)) in (first_seq..).zip(values.iter()) {
+                let put = PutRequest::builder()
+                    .item(HASH_KEY, AttributeValue::S(partition_key.clone()))
+                    .item(SORT_KEY, AttributeValue::S(sort_key.clone()))
+                    .item(SEQ, AttributeValue::N(seq.to_string()))
+                    .item(VALUE, AttributeValue::S(value.clone()))
+                    .build()?;
+                requests.push(WriteRequest::builder().put_request(put).build());
+            }
+        }
+        self.batch_write(requests).await?;
+
+        let mut requests = Vec::new();
+        for (sort_key, _seq) in existing.into_iter().filter(|(sort_key, _)| !values.contains_key(sort_key)) {
+            let delete = DeleteRequest::builder()
+                .key(HASH_KEY, AttributeValue::S(partition_key.clone()))
+                .key(SORT_KEY, AttributeValue::S(sort_key))
+                .build()?;
+            requests.push(WriteRequest::builder().delete_request(delete).build());
+        }
+        self.batch_write(requests).await
+    }
+
+    async fn read_one(&self, hash_key: &str) -> anyhow::Result<Option<String>> {
+        Ok(self.read_info(hash_key).await?.0)
+    }
+
+    async fn write_one(&self, hash_key: &str, value: &str) -> anyhow::Result<()> {
+        // an update rather than a put, which keeps the version of a bucket's info item
+        self.client().await?.update_item()
+            .table_name(&self.table)
+            .key(HASH_KEY, AttributeValue::S(hash_key.to_string()))
+            .key(SORT_KEY, AttributeValue::S(ONE_SORT_KEY.to_string()))
+            .update_expression("SET #val = :value")
+            .expression_attribute_names("#val", VALUE)
+            .expression_attribute_values(":value", AttributeValue::S(value.to_string()))
+            .send().await?;
+        Ok(())
+    }
+
+    async fn merge_bucket(&self, hash_key: &str, bucket: &Bucket, info_key: &str, records: Vec<(String, String)>, info: Option<String>,
+        same_origin: SameOrigin) -> anyhow::Result<usize> {
+        for attempt in 0..MAX_MERGE_ATTEMPTS {
+            let (existing_info, version) = self.read_info(info_key).await?;
+            let existing = self.read_records(hash_key, Some(bucket)).await?;
+            let merge = BucketMerge::new(existing, existing_info.as_deref(), records.clone(), info.clone(), &same_origin)?;
+            let merged = match merge.records {
+                Some(merged) => merged,
+                None => return Ok(0),
+            };
+
+            if self.claim_bucket(info_key, version).await? {
+                if let Err(error) = self.replace_records(hash_key, Some(bucket), merged).await {
+                    // the lease expires if it can't be released
+                    let _ = self.release_bucket(info_key, version + 1, None).await;
+                    return Err(error)
+                }
+                if self.release_bucket(info_key, version + 1, merge.info).await? {
+                    return Ok(merge.added)
+                }
+            }
+            debug!("Bucket {} of {} changed during a merge, retrying", super::bucket_name(bucket), hash_key);
+            tokio::time::sleep(Duration::from_millis(100 << attempt)).await;
+        }
+        Err(anyhow!("Unable to merge bucket {} of {}, other merges kept changing it", super::bucket_name(bucket), hash_key))
+    }
+
+    async fn delete(&self, data_set: &DataSet) -> anyhow::Result<()> {
+        let (partition_key, sort_keys) = match data_set {
+            DataSet::One(hash_key) => (hash_key.clone(), vec!(ONE_SORT_KEY.to_string())),
+            DataSet::Records(hash_key, bucket) => {
+                let partition_key = Self::partition_key(hash_key, bucket.as_ref());
+                let mut sort_keys: Vec<String> = self.query(&partition_key).await?.into_iter().map(|(sort_key, _seq, _value)| sort_key).collect();
+                sort_keys.push(SEQ_SORT_KEY.to_string());
+                (partition_key, sort_keys)
+            },
+        };
+
+        let mut requests = Vec::new();
+        for sort_key in sort_keys {
+            let delete = DeleteRequest::builder()
+                .key(HASH_KEY, AttributeValue::S(partition_key.clone()))
+                .key(SORT_KEY, AttributeValue::S(sort_key))
+                .build()?;
+            requests.push(WriteRequest::builder().delete_request(delete).build());
+        }
+        self.batch_write(requests).await
+    }
+
+    async fn data_sets(&self) -> anyhow::Result<Vec<DataSet>> {
+        let items = self.client().await?.scan()
+            .table_name(&self.table)
+            .projection_expression("#h, #s")
+            .expression_attribute_names("#h", HASH_KEY)
+            .expression_attribute_names("#s", SORT_KEY)
+            .into_paginator()
+            .items()
+            .send()
+            .collect::<Result<Vec<_>, _>>().await?;
+
+        let mut data_sets = Vec::new();
+        for item in items {
+            let partition_key = Self::string(&item, HASH_KEY)?;
+            let sort_key = Self::string(&item, SORT_KEY)?;
+            if sort_key == SEQ_SORT_KEY {
+                continue;
+            }
+            let data_set = if sort_key == ONE_SORT_KEY {
+                DataSet::One(partition_key)
+            }
+            else {
+                let (hash_key, bucket) = Self::data_set_from_partition_key(&partition_key);
+                DataSet::Records(hash_key, bucket)
+            };
+            // Items come back grouped by partition, so this only needs to look at the last one
+            if data_sets.last() != Some(&data_set) {
+                data_sets.push(data_set);
+            }
+        }
+        Ok(data_sets)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use sparko_graphql::types::Date;
+    use time::Month;
+    use crate::cache_manager::{CacheManager, Granularity};
+
+    #[test]
+    fn test_partition_key() {
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+        let partition_key = DynamoStore::partition_key("A-B1C2B345#Consumption", Some(&bucket));
+
+        assert_eq!(partition_key, "2025-03-01#A-B1C2B345#Consumption");
+        assert_eq!(DynamoStore::data_set_from_partition_key(&partition_key), ("A-B1C2B345#Consumption".to_string(), Some(bucket)));
+        assert_eq!(DynamoStore::data_set_from_partition_key("A-B1C2B345#Bills"), ("A-B1C2B345#Bills".to_string(), None));
+    }
+
+    /// Needs DynamoDB Local, e.g. docker run --rm -p 8000:8000 amazon/dynamodb-local, run with cargo test --features dynamodb -- --ignored
+    #[tokio::test]
+    #[ignore]
+    async fn test_dynamodb_local() {
+        let endpoint = std::env::var("MARCO_SPARKO_DYNAMODB_ENDPOINT").unwrap_or("http://localhost:8000".to_string());
+        if std::env::var("AWS_ACCESS_KEY_ID").is_err() {
+            // DynamoDB Local accepts any credentials
+            std::env::set_var("AWS_ACCESS_KEY_ID", "local");
+            std::env::set_var("AWS_SECRET_ACCESS_KEY", "local");
+        }
+        let table = format!("marco-sparko-test-{}", std::process::id());
+        let store = DynamoStore::new(table, Some("eu-west-2".to_string()), Some(endpoint));
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+
+        store.merge_records("A#Consumption", Some(&bucket), vec!(("z".to_string(), "1".to_string()), ("b".to_string(), "2".to_string()))).await.unwrap();
+        store.merge_records("A#Consumption", Some(&bucket), vec!(("b".to_string(), "3".to_string()), ("c".to_string(), "4".to_string()))).await.unwrap();
+        store.write_one("#Viewer", "{}").await.unwrap();
+
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(),
+            vec!(("z".to_string(), "1".to_string()), ("b".to_string(), "3".to_string()), ("c".to_string(), "4".to_string())));
+        assert_eq!(store.read_one("#Viewer").await.unwrap(), Some("{}".to_string()));
+        assert_eq!(store.read_one("#Missing").await.unwrap(), None);
+
+        let info_key = CacheManager::bucket_info_key(&bucket, "A#Consumption");
+        let same_origin: SameOrigin = std::sync::Arc::new(|_: Option<&str>| -> anyhow::Result<bool> { Ok(true) });
+        let added = store.merge_bucket("A#Consumption", &bucket, &info_key, vec!(("d".to_string(), "5".to_string())), Some("{}".to_string()), same_origin).await.unwrap();
+        assert_eq!(added, 1);
+        assert_eq!(store.read_info(&info_key).await.unwrap(), (None, 1));
+
+        let data_sets = store.data_sets().await.unwrap();
+        assert!(data_sets.contains(&DataSet::One("#Viewer".to_string())));
+        assert!(data_sets.contains(&DataSet::Records("A#Consumption".to_string(), Some(bucket))));
+
+        store.client().await.unwrap().delete_table().table_name(&store.table).send().await.unwrap();
+    }
+}
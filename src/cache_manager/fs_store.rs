@@ -0,0 +1,571 @@
+use std::collections::BTreeSet;
+use std::fs::{File, OpenOptions};
+use std::io::{BufRead, BufReader, Write};
+use std::path::{Path, PathBuf};
+use std::fs;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use indexmap::IndexMap;
+use fs4::fs_std::FileExt; // Import the trait for fs4 methods
+use tracing::{error, trace, warn};
+
+use sparko_graphql::types::Date;
+use time::Month;
+
+use super::{bucket_from_name, Bucket, BucketMerge, CacheManager, CacheStore, DataSet, Granularity, SameOrigin, BUCKET_INFO_SUFFIX};
+
+/* ***************************************************************************************************************************************************************
+ * Cache store in a directory of files, one per hash key. Records are lines of the sort key, a TAB and the JSON. Time series buckets are in a directory for
+ * each year, in a file named for the bucket and the hash key: the month (March#hash_key), Day-MM-DD or Week-MM-DD for the day or week starting on that date, or
+ * Year.
+ *
+ * Writes are transactional: a writer takes an exclusive advisory lock on the cache directory, merges its new records with what is on disk by sort key (so that
+ * records added by another process are neither lost nor duplicated), writes the result to a temporary file and renames it over the original. A crash part way
+ * through a write therefore leaves either the old file or the new one, never a half written line. Readers don't take the directory lock because the rename is atomic.
+ * Waiting for the lock and the file and zstd work are done on tokio's blocking threads, as SqliteStore does with its connection.
+ *
+ * A time series bucket which is complete is closed, which compresses it with zstd into a file with the extension .zst in place of the plain file. Reads
+ * look for either, and records merged into a closed bucket (which is unusual) are written back compressed. The open bucket stays plain text.
+ *
+ * The info of a bucket (the single record CacheManager::bucket_info_key()) is kept in a header line at the start of the bucket's file rather than in a file of
+ * its own, so that merging a copy of a bucket replaces its records and its info with one rename. Caches written before this have separate info files, which
+ * are read while a bucket has no header and removed when its info is next written.
+ *************************************************************************************************************************************************************** */
+
+/// Held by writers, in the cache directory
+const LOCK_FILE_NAME: &str = ".lock";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const COMPRESSED_FILE_EXTENSION: &str = "zst";
+const COMPRESSION_LEVEL: i32 = 9;
+/// The sort key of the header line which holds a bucket's info
+const INFO_SORT_KEY: &str = "#info";
+/// The header of a bucket which has no info, so that a separate info file left from an older cache isn't read in its place
+const NO_INFO: &str = "null";
+
+#[derive(Clone)]
+pub struct FsStore {
+    pub dir_path: PathBuf,
+}
+
+impl FsStore {
+    pub fn new(dir_path: PathBuf) -> anyhow::Result<FsStore> {
+        fs::create_dir_all(&dir_path)?;
+
+        Ok(FsStore {
+            dir_path
+        })
+    }
+
+    fn path_for(&self, hash_key: &str, bucket: Option<&Bucket>) -> PathBuf {
+        let mut path = self.dir_path.clone();
+        if let Some(bucket) = bucket {
+            let date = &bucket.start;
+            path.push(date.year().to_string());
+            path.push(match bucket.granularity {
+                Granularity::Day => format!("Day-{:02}-{:02}#{}", date.month() as u8, date.day(), hash_key),
+                Granularity::Week => format!("Week-{:02}-{:02}#{}", date.month() as u8, date.day(), hash_key),
+                Granularity::Month => format!("{}#{}", date.month(), hash_key),
+                Granularity::Year => format!("Year#{}", hash_key),
+            });
+        }
+        else {
+            path.push(hash_key);
+        }
+        path
+    }
+
+    /// The hash key and bucket of the data set whose info is the single record hash_key, if it is the info of a bucket
+    fn info_location(hash_key: &str) -> Option<(&str, Bucket)> {
+        let (hash_key, name) = hash_key.strip_suffix(BUCKET_INFO_SUFFIX)?.rsplit_once('#')?;
+        Some((hash_key, bucket_from_name(name).ok()?))
+    }
+
+    /// The path of the compressed form of the file at path
+    fn compressed_path(path: &Path) -> PathBuf {
+        let mut name = path.as_os_str().to_os_string();
+        name.push(format!(".{}", COMPRESSED_FILE_EXTENSION));
+        PathBuf::from(name)
+    }
+
+    /// The contents of the file at path, or of its compressed form if there is one instead, None if there is neither
+    fn read_content(path: &Path) -> anyhow::Result<Option<String>> {
+        let not_found = |error: &std::io::Error| error.kind() == std::io::ErrorKind::NotFound;
+
+        match fs::read_to_string(path) {
+            Ok(content) => return Ok(Some(content)),
+            Err(error) if !not_found(&error) => {
+                error!("Unable to read cache file: {:?}", error);
+                return Err(anyhow!(error))
+            },
+            Err(_) => {},
+        }
+        match fs::read(Self::compressed_path(path)) {
+            Ok(compressed) => Ok(Some(String::from_utf8(zstd::decode_all(compressed.as_slice())?)?)),
+            Err(error) if not_found(&error) => Ok(None),
+            Err(error) => {
+                error!("Unable to read cache file: {:?}", error);
+                Err(anyhow!(error))
+            },
+        }
+    }
+
+    /// The single record in the file at path, None if there is none
+    fn read_one_file(path: &Path) -> anyhow::Result<Option<String>> {
+        match fs::read_to_string(path) {
+            Ok(content) => Ok(Some(content)),
+            Err(error) => {
+                if error.kind() != std::io::ErrorKind::NotFound {
+                    error!("Unable to read cache file: {:?}", error);
+                    return Err(anyhow!(error))
+                }
+                Ok(None)
+            },
+        }
+    }
+
+    /// Remove a file, which may not exist
+    fn remove_file(path: &Path) -> anyhow::Result<()> {
+        match fs::remove_file(path) {
+            Err(error) if error.kind() != std::io::ErrorKind::NotFound => Err(anyhow!(error)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The records of existing, in their current order and with any duplicate sort keys removed, updated by and followed by those of new_records
+    fn merge(existing: Vec<(String, String)>, new_records: Vec<(String, String)>) -> Vec<(String, String)> {
+        let mut merged: IndexMap<String, String> = IndexMap::new();
+        for (key, value) in existing {
+            merged.entry(key).or_insert(value);
+        }
+        for (key, value) in new_records {
+            merged.insert(key, value);
+        }
+        merged.into_iter().collect()
+    }
+
+    /// The raw records in a cache file, skipping any line which is not a complete record (e.g. left by a crash before writes were transactional)
+    fn read_file(path: &Path) -> anyhow::Result<Vec<(String, String)>> {
+        Ok(Self::read_bucket_file(path)?.1)
+    }
+
+    /// The header and raw records in a cache file, skipping any line which is not a complete record
+    fn read_bucket_file(path: &Path) -> anyhow::Result<(Option<String>, Vec<(String, String)>)> {
+        let (header, records, invalid_lines) = Self::read_file_lines(path)?;
+        for line in invalid_lines {
+            warn!("Dropping invalid cached object <{}> from {:?}", line, path);
+        }
+        Ok((header, records))
+    }
+
+    /// The header, raw records and any lines which are not complete records in a cache file
+    fn read_file_lines(path: &Path) -> anyhow::Result<(Option<String>, Vec<(String, String)>, Vec<String>)> {
+        let mut header = None;
+        let mut records = Vec::new();
+        let mut invalid_lines = Vec::new();
+        if let Some(content) = Self::read_content(path)? {
+            for line in content.lines() {
+                match line.split_once('\t') {
+                    Some((key, value)) if serde_json::from_str::<serde::de::IgnoredAny>(value).is_ok() => {
+                        if key == INFO_SORT_KEY {
+                            header = Some(value.to_string());
+                        }
+                        else {
+                            records.push((key.to_string(), value.to_string()));
+                        }
+                    },
+                    _ => invalid_lines.push(line.to_string()),
+                }
+            }
+        }
+        Ok((header, records, invalid_lines))
+    }
+
+    /// The header of the bucket file at path, or of its compressed form, without reading the records
+    fn read_header(path: &Path) -> anyhow::Result<Option<String>> {
+        let mut first_line = String::new();
+        match File::open(path) {
+            Ok(file) => BufReader::new(file).read_line(&mut first_line)?,
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => match File::open(Self::compressed_path(path)) {
+                Ok(file) => BufReader::new(zstd::Decoder::new(file)?).read_line(&mut first_line)?,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+                Err(error) => return Err(anyhow!(error)),
+            },
+            Err(error) => return Err(anyhow!(error)),
+        };
+        Ok(first_line.trim_end().split_once('\t')
+            .filter(|(key, _)| *key == INFO_SORT_KEY)
+            .map(|(_, value)| value.to_string()))
+    }
+
+    /// The info of a bucket from the header of its file, or from the info file of an older cache if it has no header. The caller holds the lock, or
+    /// accepts that the info may be replaced as it reads it.
+    fn read_info(&self, hash_key: &str, bucket: &Bucket, info_key: &str) -> anyhow::Result<Option<String>> {
+        match Self::read_header(&self.path_for(hash_key, Some(bucket)))? {
+            Some(header) if header == NO_INFO => Ok(None),
+            Some(header) => Ok(Some(header)),
+            None => Ok(Self::read_one_file(&self.path_for(info_key, None))?.map(|info| info.trim_end().to_string())),
+        }
+    }
+
+    /// Replace the info of a bucket, keeping its records, and remove any info file of an older cache. The caller holds the lock.
+    fn write_info(&self, hash_key: &str, bucket: &Bucket, info_key: &str, info: Option<&str>) -> anyhow::Result<()> {
+        let path = self.path_for(hash_key, Some(bucket));
+        let records = Self::read_file(&path)?;
+
+        Self::write_records(&path, Some(info.unwrap_or(NO_INFO)), &records)?;
+        Self::remove_file(&self.path_for(info_key, None))
+    }
+
+    /// Write content to a temporary file and rename it over path, so that path always holds either its old or its new content
+    fn replace_file(path: &Path, content: &[u8]) -> anyhow::Result<()> {
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let file_name = path.file_name().ok_or(anyhow!("Invalid cache file path {:?}", path))?.to_string_lossy();
+        let temp_path = path.with_file_name(format!("{}.{}", file_name, TEMP_FILE_EXTENSION));
+
+        let mut out = File::create(&temp_path)?;
+        out.write_all(content)?;
+        out.sync_all()?;
+        drop(out);
+
+        fs::rename(&temp_path, path)?;
+        Ok(())
+    }
+
+    /// Write the records of a data set, after the header if it has one, in place of what is in its file, compressed if the bucket has been closed. The
+    /// caller holds the lock.
+    fn write_records(path: &Path, header: Option<&str>, records: &[(String, String)]) -> anyhow::Result<()> {
+        let mut out = Vec::new();
+        if let Some(header) = header {
+            writeln!(out, "{}\t{}", INFO_SORT_KEY, header)?;
+        }
+        for (key, value) in records {
+            writeln!(out, "{}\t{}", key, value)?;
+            trace!("WRITE {}", key);
+        }
+
+        let compressed_path = Self::compressed_path(path);
+        if compressed_path.exists() {
+            Self::replace_file(&compressed_path, &zstd::encode_all(out.as_slice(), COMPRESSION_LEVEL)?)?;
+            Self::remove_file(path)
+        }
+        else {
+            Self::replace_file(path, &out)
+        }
+    }
+
+    /// Run file work, which includes waiting for the lock, on a blocking thread so that it doesn't hold up other tasks
+    async fn blocking<R, F>(f: F) -> anyhow::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce() -> anyhow::Result<R> + Send + 'static,
+    {
+        tokio::task::spawn_blocking(f).await?
+    }
+
+    /// Take the exclusive lock on the cache directory, which is released when the returned file is dropped
+    fn lock(&self) -> anyhow::Result<File> {
+        let mut path = self.dir_path.clone();
+        path.push(LOCK_FILE_NAME);
+
+        let file = OpenOptions::new().create(true).truncate(false).write(true).open(path)?;
+        file.lock_exclusive()?;
+        Ok(file)
+    }
+
+    /// Whether a file in the cache directory is one of the store's own, rather than a data set
+    fn is_internal(name: &str) -> bool {
+        name.starts_with('.') || name.ends_with(&format!(".{}", TEMP_FILE_EXTENSION))
+    }
+
+    fn month_from_name(name: &str) -> Option<Month> {
+        let mut month = Month::January;
+        for _ in 0..12 {
+            if month.to_string() == name {
+                return Some(month)
+            }
+            month = month.next();
+        }
+        None
+    }
+
+    /// The bucket in the given year named by the part of a file name before the hash key, as written by path_for()
+    fn bucket_from_name(year: i32, name: &str) -> Option<Bucket> {
+        let day_of_year = |granularity, month_day: &str| {
+            let (month, day) = month_day.split_once('-')?;
+            let month = Month::try_from(month.parse::<u8>().ok()?).ok()?;
+            Some(Bucket { granularity, start: Date::from_calendar_date(year, month, day.parse().ok()?).ok()? })
+        };
+
+        if let Some(month_day) = name.strip_prefix("Day-") {
+            day_of_year(Granularity::Day, month_day)
+        }
+        else if let Some(month_day) = name.strip_prefix("Week-") {
+            day_of_year(Granularity::Week, month_day)
+        }
+        else if name == "Year" {
+            Some(Bucket { granularity: Granularity::Year, start: Date::from_calendar_date(year, Month::January, 1).ok()? })
+        }
+        else {
+            Some(Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(year, Self::month_from_name(name)?, 1).ok()? })
+        }
+    }
+}
+
+#[async_trait]
+impl CacheStore for FsStore {
+    async fn read_records(&self, hash_key: &str, bucket: Option<&Bucket>) -> anyhow::Result<Vec<(String, String)>> {
+        let path = self.path_for(hash_key, bucket);
+        Self::blocking(move || Self::read_file(&path)).await
+    }
+
+    async fn merge_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let path = self.path_for(hash_key, bucket);
+        let store = self.clone();
+
+        Self::blocking(move || {
+            let _lock = store.lock()?;
+            let (header, existing) = Self::read_bucket_file(&path)?;
+            Self::write_records(&path, header.as_deref(), &Self::merge(existing, records))
+        }).await
+    }
+
+    async fn replace_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let path = self.path_for(hash_key, bucket);
+        let store = self.clone();
+
+        Self::blocking(move || {
+            let _lock = store.lock()?;
+            let header = Self::read_header(&path)?;
+            Self::write_records(&path, header.as_deref(), &records)
+        }).await
+    }
+
+    async fn merge_bucket(&self, hash_key: &str, bucket: &Bucket, info_key: &str, records: Vec<(String, String)>, info: Option<String>,
+        same_origin: SameOrigin) -> anyhow::Result<usize> {
+        let path = self.path_for(hash_key, Some(bucket));
+        let (store, hash_key, bucket, info_key) = (self.clone(), hash_key.to_string(), bucket.clone(), info_key.to_string());
+
+        Self::blocking(move || {
+            let _lock = store.lock()?;
+            let existing = Self::read_file(&path)?;
+            let existing_info = store.read_info(&hash_key, &bucket, &info_key)?;
+            let merge = BucketMerge::new(existing, existing_info.as_deref(), records, info, &same_origin)?;
+
+            // the records and the info are replaced together, the info file of an older cache is only removed once the header has replaced it
+            if let Some(records) = merge.records {
+                let info = merge.info.unwrap_or(existing_info);
+                Self::write_records(&path, Some(info.as_deref().unwrap_or(NO_INFO)), &records)?;
+                Self::remove_file(&store.path_for(&info_key, None))?;
+            }
+            Ok(merge.added)
+        }).await
+    }
+
+    async fn close_bucket(&self, hash_key: &str, bucket: &Bucket) -> anyhow::Result<()> {
+        let path = self.path_for(hash_key, Some(bucket));
+        let store = self.clone();
+
+        Self::blocking(move || {
+            if !path.exists() {
+                return Ok(())
+            }
+
+            let _lock = store.lock()?;
+            let content = match fs::read(&path) {
+                Ok(content) => content,
+                Err(error) if error.kind() == std::io::ErrorKind::NotFound => return Ok(()),
+                Err(error) => return Err(anyhow!(error)),
+            };
+            let compressed_path = Self::compressed_path(&path);
+
+            // a plain file alongside a compressed one can only be left by a crash part way through closing, and holds the same records or more
+            Self::replace_file(&compressed_path, &zstd::encode_all(content.as_slice(), COMPRESSION_LEVEL)?)?;
+            Self::remove_file(&path)?;
+            trace!("CLOSE {:?}", compressed_path);
+            Ok(())
+        }).await
+    }
+
+    async fn read_one(&self, hash_key: &str) -> anyhow::Result<Option<String>> {
+        let (store, hash_key) = (self.clone(), hash_key.to_string());
+
+        Self::blocking(move || match Self::info_location(&hash_key) {
+            Some((records_key, bucket)) => store.read_info(records_key, &bucket, &hash_key),
+            None => Self::read_one_file(&store.path_for(&hash_key, None)),
+        }).await
+    }
+
+    async fn write_one(&self, hash_key: &str, value: &str) -> anyhow::Result<()> {
+        let (store, hash_key, value) = (self.clone(), hash_key.to_string(), value.to_string());
+
+        Self::blocking(move || {
+            let _lock = store.lock()?;
+            match Self::info_location(&hash_key) {
+                Some((records_key, bucket)) => store.write_info(records_key, &bucket, &hash_key, Some(&value)),
+                None => Self::replace_file(&store.path_for(&hash_key, None), format!("{}\n", value).as_bytes()),
+            }
+        }).await
+    }
+
+    async fn delete(&self, data_set: &DataSet) -> anyhow::Result<()> {
+        let (store, data_set) = (self.clone(), data_set.clone());
+
+        Self::blocking(move || {
+            let path = match &data_set {
+                DataSet::One(hash_key) => {
+                    if let Some((records_key, bucket)) = Self::info_location(hash_key) {
+                        let _lock = store.lock()?;
+                        if Self::read_header(&store.path_for(records_key, Some(&bucket)))?.is_some() {
+                            return store.write_info(records_key, &bucket, hash_key, None)
+                        }
+                    }
+                    store.path_for(hash_key, None)
+                },
+                DataSet::Records(hash_key, bucket) => store.path_for(hash_key, bucket.as_ref()),
+            };
+
+            let _lock = store.lock()?;
+            Self::remove_file(&Self::compressed_path(&path))?;
+            Self::remove_file(&path)
+        }).await
+    }
+
+    async fn invalid_records(&self, hash_key: &str, bucket: Option<&Bucket>) -> anyhow::Result<Vec<String>> {
+        let path = self.path_for(hash_key, bucket);
+        Self::blocking(move || Ok(Self::read_file_lines(&path)?.2)).await
+    }
+
+    async fn data_sets(&self) -> anyhow::Result<Vec<DataSet>> {
+        let store = self.clone();
+
+        Self::blocking(move || {
+            let mut data_sets = Vec::new();
+            // the info keys of buckets with a header, which replaces any info file of an older cache left by a crash, and those whose header holds info
+            let mut headed = BTreeSet::new();
+            let mut info_keys = Vec::new();
+
+            for entry in fs::read_dir(&store.dir_path)? {
+                let entry = entry?;
+                let name = entry.file_name().to_string_lossy().to_string();
+                if Self::is_internal(&name) {
+                    continue;
+                }
+
+                if entry.file_type()?.is_dir() {
+                    let year: i32 = match name.parse() {
+                        Ok(year) => year,
+                        Err(_) => continue,
+                    };
+                    for bucket_entry in fs::read_dir(entry.path())? {
+                        let bucket_name = bucket_entry?.file_name().to_string_lossy().to_string();
+                        if Self::is_internal(&bucket_name) {
+                            continue;
+                        }
+                        let bucket_name = bucket_name.strip_suffix(&format!(".{}", COMPRESSED_FILE_EXTENSION)).unwrap_or(&bucket_name);
+                        if let Some((name, hash_key)) = bucket_name.split_once('#') {
+                            if let Some(bucket) = Self::bucket_from_name(year, name) {
+                                if let Some(header) = Self::read_header(&store.path_for(hash_key, Some(&bucket)))? {
+                                    let info_key = CacheManager::bucket_info_key(&bucket, hash_key);
+                                    if header != NO_INFO {
+                                        info_keys.push(DataSet::One(info_key.clone()));
+                                    }
+                                    headed.insert(info_key);
+                                }
+                                data_sets.push(DataSet::Records(hash_key.to_string(), Some(bucket)));
+                            }
+                        }
+                    }
+                }
+                else {
+                    // Single records are JSON on one line, multi record files have a sort key and a TAB at the start of each line
+                    let mut first_line = String::new();
+                    BufReader::new(File::open(entry.path())?).read_line(&mut first_line)?;
+                    if !first_line.is_empty() && !first_line.contains('\t') {
+                        data_sets.push(DataSet::One(name));
+                    }
+                    else {
+                        data_sets.push(DataSet::Records(name, None));
+                    }
+                }
+            }
+            data_sets.retain(|data_set| !matches!(data_set, DataSet::One(hash_key) if headed.contains(hash_key)));
+            data_sets.extend(info_keys);
+            Ok(data_sets)
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn records(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs.iter().map(|(key, value)| (key.to_string(), value.to_string())).collect()
+    }
+
+    #[test]
+    fn test_merge() {
+        let existing = records(&[("a", "1"), ("b", "2"), ("a", "3")]);
+        let new_records = records(&[("b", "4"), ("c", "5")]);
+
+        assert_eq!(FsStore::merge(existing, new_records), records(&[("a", "1"), ("b", "4"), ("c", "5")]));
+    }
+
+    #[tokio::test]
+    async fn test_close_bucket() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStore::new(dir.path().to_path_buf()).unwrap();
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+
+        store.merge_records("A#Consumption", Some(&bucket), records(&[("a", "1"), ("b", "2")])).await.unwrap();
+        store.close_bucket("A#Consumption", &bucket).await.unwrap();
+        assert!(!store.path_for("A#Consumption", Some(&bucket)).exists());
+
+        store.merge_records("A#Consumption", Some(&bucket), records(&[("c", "3")])).await.unwrap();
+        assert!(!store.path_for("A#Consumption", Some(&bucket)).exists());
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), records(&[("a", "1"), ("b", "2"), ("c", "3")]));
+        assert_eq!(store.data_sets().await.unwrap(), vec!(DataSet::Records("A#Consumption".to_string(), Some(bucket))));
+    }
+
+    #[tokio::test]
+    async fn test_bucket_info() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let store = FsStore::new(dir.path().to_path_buf()).unwrap();
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+        let info_key = CacheManager::bucket_info_key(&bucket, "A#Consumption");
+        let same_origin: SameOrigin = std::sync::Arc::new(|_: Option<&str>| -> anyhow::Result<bool> { Ok(true) });
+
+        // the info of an older cache is replaced by the header
+        store.merge_records("A#Consumption", Some(&bucket), records(&[("a", "1")])).await.unwrap();
+        FsStore::replace_file(&store.path_for(&info_key, None), b"{\"old\":true}\n").unwrap();
+        assert_eq!(store.read_one(&info_key).await.unwrap(), Some("{\"old\":true}".to_string()));
+
+        let added = store.merge_bucket("A#Consumption", &bucket, &info_key, records(&[("b", "2"), ("c", "3")]), Some("{}".to_string()), same_origin).await.unwrap();
+        assert_eq!(added, 2);
+        assert!(!store.path_for(&info_key, None).exists());
+        assert_eq!(store.read_one(&info_key).await.unwrap(), Some("{\"old\":true}".to_string()));
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), records(&[("b", "2"), ("c", "3"), ("a", "1")]));
+        assert_eq!(store.data_sets().await.unwrap(), vec!(DataSet::Records("A#Consumption".to_string(), Some(bucket.clone())), DataSet::One(info_key.clone())));
+
+        store.delete(&DataSet::One(info_key.clone())).await.unwrap();
+        assert_eq!(store.read_one(&info_key).await.unwrap(), None);
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(), records(&[("b", "2"), ("c", "3"), ("a", "1")]));
+    }
+
+    #[test]
+    fn test_bucket_names() {
+        let store = FsStore { dir_path: PathBuf::from("cache") };
+        let date = Date::from_calendar_date(2025, Month::March, 10).unwrap();
+
+        for granularity in [Granularity::Day, Granularity::Week, Granularity::Month, Granularity::Year] {
+            let bucket = Bucket::containing(granularity, &date).unwrap();
+            let path = store.path_for("A#Consumption", Some(&bucket));
+            let (name, hash_key) = path.file_name().unwrap().to_str().unwrap().split_once('#').unwrap();
+
+            assert_eq!(hash_key, "A#Consumption");
+            assert_eq!(FsStore::bucket_from_name(2025, name), Some(bucket));
+        }
+    }
+}
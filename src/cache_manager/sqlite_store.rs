@@ -0,0 +1,311 @@
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rusqlite::{params, Connection, OptionalExtension, TransactionBehavior};
+use sparko_graphql::types::Date;
+
+use super::{Bucket, BucketMerge, CacheStore, DataSet, Granularity, SameOrigin};
+
+/* ***************************************************************************************************************************************************************
+ * Cache store in an SQLite database.
+ *
+ * Records are rows keyed by hash key, bucket and sort key, with a sequence number which keeps them in the order they were written. The bucket is the start
+ * date of a time series bucket as YYYY-MM-DD (so that buckets sort by time) with its size after it unless it is a month, or empty for data sets which aren't
+ * time series, and is indexed so that reading a range of time is a range scan. Each write is one transaction, and SQLite takes care of concurrent access by
+ * several processes. Queries run on tokio's blocking threads, since rusqlite waits for the database.
+ *************************************************************************************************************************************************************** */
+
+/// The schema version which migrate() brings a database up to
+const SCHEMA_VERSION: i32 = 1;
+
+pub struct SqliteStore {
+    connection: Arc<Mutex<Connection>>,
+}
+
+impl SqliteStore {
+    pub fn open(path: &Path) -> anyhow::Result<SqliteStore> {
+        let connection = Connection::open(path)?;
+        connection.busy_timeout(Duration::from_secs(10))?;
+        Self::migrate(&connection)?;
+
+        Ok(SqliteStore {
+            connection: Arc::new(Mutex::new(connection)),
+        })
+    }
+
+    fn migrate(connection: &Connection) -> anyhow::Result<()> {
+        let version: i32 = connection.query_row("PRAGMA user_version", [], |row| row.get(0))?;
+
+        if version < 1 {
+            connection.execute_batch(
+                "BEGIN;
+                CREATE TABLE records (
+                    hash_key TEXT NOT NULL,
+                    bucket TEXT NOT NULL,
+                    seq INTEGER NOT NULL,
+                    sort_key TEXT NOT NULL,
+                    value TEXT NOT NULL,
+                    PRIMARY KEY (hash_key, bucket, sort_key)
+                );
+                CREATE INDEX records_by_time ON records (hash_key, bucket, seq);
+                CREATE TABLE single_records (
+                    hash_key TEXT PRIMARY KEY,
+                    value TEXT NOT NULL
+                );
+                PRAGMA user_version = 1;
+                COMMIT;")?;
+        }
+        if version > SCHEMA_VERSION {
+            return Err(anyhow!("The cache database was written by a newer version (schema {})", version))
+        }
+        Ok(())
+    }
+
+    /// Run f with the connection on a thread where blocking is allowed, so that a slow query (or waiting for another process's transaction) doesn't hold
+    /// up the async runtime
+    async fn with_connection<R, F>(&self, f: F) -> anyhow::Result<R>
+    where
+        R: Send + 'static,
+        F: FnOnce(&mut Connection) -> anyhow::Result<R> + Send + 'static,
+    {
+        let connection = self.connection.clone();
+        tokio::task::spawn_blocking(move || {
+            let mut connection = connection.lock().map_err(|_| anyhow!("Cache database lock poisoned"))?;
+            f(&mut connection)
+        }).await?
+    }
+
+    fn bucket_name(bucket: Option<&Bucket>) -> String {
+        bucket.map(super::bucket_name).unwrap_or_default()
+    }
+
+    fn bucket_from_name(name: &str) -> anyhow::Result<Option<Bucket>> {
+        if name.is_empty() {
+            return Ok(None)
+        }
+        Ok(Some(super::bucket_from_name(name)?))
+    }
+}
+
+#[async_trait]
+impl CacheStore for SqliteStore {
+    async fn read_records(&self, hash_key: &str, bucket: Option<&Bucket>) -> anyhow::Result<Vec<(String, String)>> {
+        let hash_key = hash_key.to_string();
+        let bucket = Self::bucket_name(bucket);
+
+        self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT sort_key, value FROM records WHERE hash_key = ?1 AND bucket = ?2 ORDER BY seq")?;
+
+            let records = statement.query_map(params![hash_key, bucket], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+            Ok(records)
+        }).await
+    }
+
+    async fn read_records_range(&self, hash_key: &str, granularity: Granularity, from: &Date, to: &Date) -> anyhow::Result<Vec<(Bucket, Vec<(String, String)>)>> {
+        let hash_key = hash_key.to_string();
+        // bucket names sort by their start date, so this is a range scan of the primary key
+        let from = super::date_name(&Bucket::containing(granularity, from)?.start);
+        let to = super::date_name(to);
+
+        let rows = self.with_connection(move |connection| {
+            let mut statement = connection.prepare_cached(
+                "SELECT bucket, sort_key, value FROM records WHERE hash_key = ?1 AND bucket >= ?2 AND bucket < ?3 ORDER BY bucket, seq")?;
+
+            let rows = statement.query_map(params![hash_key, from, to], |row| Ok((row.get(0)?, row.get(1)?, row.get(2)?)))?
+                .collect::<Result<Vec<(String, String, String)>, _>>()?;
+            Ok(rows)
+        }).await?;
+
+        let mut buckets: Vec<(Bucket, Vec<(String, String)>)> = Vec::new();
+        for (name, sort_key, value) in rows {
+            let bucket = super::bucket_from_name(&name)?;
+            // buckets of other sizes, left by an earlier setting, are in the same range
+            if bucket.granularity != granularity {
+                continue;
+            }
+            match buckets.last_mut() {
+                Some((last, records)) if *last == bucket => records.push((sort_key, value)),
+                _ => buckets.push((bucket, vec!((sort_key, value)))),
+            }
+        }
+        Ok(buckets)
+    }
+
+    async fn merge_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let hash_key = hash_key.to_string();
+        let bucket = Self::bucket_name(bucket);
+
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+
+            let mut seq: i64 = transaction.query_row(
+                "SELECT COALESCE(MAX(seq), 0) FROM records WHERE hash_key = ?1 AND bucket = ?2",
+                params![hash_key, bucket], |row| row.get(0))?;
+
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT INTO records (hash_key, bucket, seq, sort_key, value) VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (hash_key, bucket, sort_key) DO UPDATE SET value = excluded.value")?;
+                for (sort_key, value) in &records {
+                    seq += 1;
+                    statement.execute(params![hash_key, bucket, seq, sort_key, value])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn replace_records(&self, hash_key: &str, bucket: Option<&Bucket>, records: Vec<(String, String)>) -> anyhow::Result<()> {
+        let hash_key = hash_key.to_string();
+        let bucket = Self::bucket_name(bucket);
+
+        self.with_connection(move |connection| {
+            let transaction = connection.transaction()?;
+            transaction.execute("DELETE FROM records WHERE hash_key = ?1 AND bucket = ?2", params![hash_key, bucket])?;
+            {
+                let mut statement = transaction.prepare_cached(
+                    "INSERT INTO records (hash_key, bucket, seq, sort_key, value) VALUES (?1, ?2, ?3, ?4, ?5)
+                    ON CONFLICT (hash_key, bucket, sort_key) DO UPDATE SET value = excluded.value")?;
+                for (seq, (sort_key, value)) in records.iter().enumerate() {
+                    statement.execute(params![hash_key, bucket, seq as i64 + 1, sort_key, value])?;
+                }
+            }
+            transaction.commit()?;
+            Ok(())
+        }).await
+    }
+
+    async fn merge_bucket(&self, hash_key: &str, bucket: &Bucket, info_key: &str, records: Vec<(String, String)>, info: Option<String>,
+        same_origin: SameOrigin) -> anyhow::Result<usize> {
+        let hash_key = hash_key.to_string();
+        let bucket = Self::bucket_name(Some(bucket));
+        let info_key = info_key.to_string();
+
+        self.with_connection(move |connection| {
+            // an immediate transaction takes the write lock before reading, so that no other process writes the bucket in between
+            let transaction = connection.transaction_with_behavior(TransactionBehavior::Immediate)?;
+            let existing = transaction.prepare_cached("SELECT sort_key, value FROM records WHERE hash_key = ?1 AND bucket = ?2 ORDER BY seq")?
+                .query_map(params![hash_key, bucket], |row| Ok((row.get(0)?, row.get(1)?)))?
+                .collect::<Result<Vec<(String, String)>, _>>()?;
+            let existing_info: Option<String> = transaction.query_row(
+                "SELECT value FROM single_records WHERE hash_key = ?1", params![info_key], |row| row.get(0)).optional()?;
+            let merge = BucketMerge::new(existing, existing_info.as_deref(), records, info, &same_origin)?;
+
+            if let Some(records) = merge.records {
+                transaction.execute("DELETE FROM records WHERE hash_key = ?1 AND bucket = ?2", params![hash_key, bucket])?;
+                let mut statement = transaction.prepare_cached(
+                    "INSERT INTO records (hash_key, bucket, seq, sort_key, value) VALUES (?1, ?2, ?3, ?4, ?5)")?;
+                for (seq, (sort_key, value)) in records.iter().enumerate() {
+                    statement.execute(params![hash_key, bucket, seq as i64 + 1, sort_key, value])?;
+                }
+            }
+            match merge.info {
+                Some(Some(info)) => {
+                    transaction.execute(
+                        "INSERT INTO single_records (hash_key, value) VALUES (?1, ?2) ON CONFLICT (hash_key) DO UPDATE SET value = excluded.value",
+                        params![info_key, info])?;
+                },
+                Some(None) => {
+                    transaction.execute("DELETE FROM single_records WHERE hash_key = ?1", params![info_key])?;
+                },
+                None => {},
+            }
+            transaction.commit()?;
+            Ok(merge.added)
+        }).await
+    }
+
+    async fn read_one(&self, hash_key: &str) -> anyhow::Result<Option<String>> {
+        let hash_key = hash_key.to_string();
+
+        self.with_connection(move |connection| {
+            Ok(connection.query_row("SELECT value FROM single_records WHERE hash_key = ?1", params![hash_key], |row| row.get(0)).optional()?)
+        }).await
+    }
+
+    async fn write_one(&self, hash_key: &str, value: &str) -> anyhow::Result<()> {
+        let hash_key = hash_key.to_string();
+        let value = value.to_string();
+
+        self.with_connection(move |connection| {
+            connection.execute(
+                "INSERT INTO single_records (hash_key, value) VALUES (?1, ?2) ON CONFLICT (hash_key) DO UPDATE SET value = excluded.value",
+                params![hash_key, value])?;
+            Ok(())
+        }).await
+    }
+
+    async fn delete(&self, data_set: &DataSet) -> anyhow::Result<()> {
+        let data_set = data_set.clone();
+
+        self.with_connection(move |connection| {
+            match &data_set {
+                DataSet::One(hash_key) => connection.execute("DELETE FROM single_records WHERE hash_key = ?1", params![hash_key])?,
+                DataSet::Records(hash_key, bucket) => connection.execute("DELETE FROM records WHERE hash_key = ?1 AND bucket = ?2",
+                    params![hash_key, Self::bucket_name(bucket.as_ref())])?,
+            };
+            Ok(())
+        }).await
+    }
+
+    async fn data_sets(&self) -> anyhow::Result<Vec<DataSet>> {
+        self.with_connection(|connection| {
+            let mut data_sets = Vec::new();
+
+            let mut statement = connection.prepare("SELECT hash_key FROM single_records ORDER BY hash_key")?;
+            for hash_key in statement.query_map([], |row| row.get(0))? {
+                data_sets.push(DataSet::One(hash_key?));
+            }
+
+            let mut statement = connection.prepare("SELECT DISTINCT hash_key, bucket FROM records ORDER BY hash_key, bucket")?;
+            let rows = statement.query_map([], |row| Ok((row.get::<_, String>(0)?, row.get::<_, String>(1)?)))?;
+            for row in rows {
+                let (hash_key, bucket) = row?;
+                data_sets.push(DataSet::Records(hash_key, Self::bucket_from_name(&bucket)?));
+            }
+            Ok(data_sets)
+        }).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+
+    #[tokio::test]
+    async fn test_merge_records() {
+        let store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let bucket = Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, Month::March, 1).unwrap() };
+
+        store.merge_records("A#Consumption", Some(&bucket), vec!(("a".to_string(), "1".to_string()), ("b".to_string(), "2".to_string()))).await.unwrap();
+        store.merge_records("A#Consumption", Some(&bucket), vec!(("b".to_string(), "3".to_string()), ("c".to_string(), "4".to_string()))).await.unwrap();
+
+        assert_eq!(store.read_records("A#Consumption", Some(&bucket)).await.unwrap(),
+            vec!(("a".to_string(), "1".to_string()), ("b".to_string(), "3".to_string()), ("c".to_string(), "4".to_string())));
+        assert!(store.read_records("A#Consumption", None).await.unwrap().is_empty());
+        assert_eq!(store.data_sets().await.unwrap(), vec!(DataSet::Records("A#Consumption".to_string(), Some(bucket))));
+    }
+
+    #[tokio::test]
+    async fn test_read_records_range() {
+        let store = SqliteStore::open(Path::new(":memory:")).unwrap();
+        let month = |month| Bucket { granularity: Granularity::Month, start: Date::from_calendar_date(2025, month, 1).unwrap() };
+        let week = Bucket { granularity: Granularity::Week, start: Date::from_calendar_date(2025, Month::March, 3).unwrap() };
+
+        for (bucket, sort_key) in [(month(Month::February), "f"), (month(Month::March), "m"), (week.clone(), "w"), (month(Month::April), "a")] {
+            store.merge_records("A#Consumption", Some(&bucket), vec!((sort_key.to_string(), "1".to_string()))).await.unwrap();
+        }
+
+        let buckets = store.read_records_range("A#Consumption", Granularity::Month,
+            &Date::from_calendar_date(2025, Month::February, 14).unwrap(), &Date::from_calendar_date(2025, Month::April, 1).unwrap()).await.unwrap();
+        assert_eq!(buckets, vec!(
+            (month(Month::February), vec!(("f".to_string(), "1".to_string()))),
+            (month(Month::March), vec!(("m".to_string(), "1".to_string())))));
+    }
+}
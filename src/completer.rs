@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex};
+
+use reedline::{Completer, Span, Suggestion};
+
+/* ***************************************************************************************************************************************************************
+ * Tab completion for the REPL.
+ *
+ * Completes command names, option names and argument values. The values for an argument come from the possible values declared in the command's argument
+ * schema or, failing that, from the candidates registered under the argument's value name (e.g. "BILL_ID"). The candidates are shared with the Cli, which
+ * refreshes them after each command so that they include anything which has been fetched since the prompt was created.
+ *************************************************************************************************************************************************************** */
+
+pub type Candidates = Arc<Mutex<HashMap<String, Vec<String>>>>;
+
+pub struct ReplCompleter {
+    commands: Vec<(String, clap::Command)>,
+    candidates: Candidates,
+}
+
+impl ReplCompleter {
+    pub fn new(commands: Vec<(String, clap::Command)>, candidates: Candidates) -> ReplCompleter {
+        ReplCompleter {
+            commands,
+            candidates,
+        }
+    }
+
+    fn command_names(&self) -> Vec<String> {
+        self.commands.iter().map(|(name, _)| name.clone()).collect()
+    }
+
+    fn values_for(&self, arg: &clap::Arg) -> Vec<String> {
+        let possible_values: Vec<String> = arg.get_possible_values().iter()
+            .filter(|value| !value.is_hide_set())
+            .map(|value| value.get_name().to_string())
+            .collect();
+
+        if !possible_values.is_empty() {
+            return possible_values
+        }
+
+        if let Some(value_name) = arg.get_value_names().and_then(|names| names.first()) {
+            if value_name.as_str() == "COMMAND" {
+                return self.command_names()
+            }
+            if let Ok(candidates) = self.candidates.lock() {
+                if let Some(values) = candidates.get(value_name.as_str()) {
+                    return values.clone()
+                }
+            }
+        }
+        Vec::new()
+    }
+
+    fn values_for_position(&self, schema: &clap::Command, args: &[&str], prefix: &str) -> Vec<String> {
+        let takes_value = |long: &str| schema.get_opts().find(|option| option.get_long() == Some(long));
+
+        // The value of an option, e.g. "--meter <METER_NODE_ID>"
+        if let Some(option) = args.last().and_then(|word| word.strip_prefix("--")).and_then(takes_value) {
+            return self.values_for(option)
+        }
+
+        if prefix.starts_with('-') {
+            return schema.get_arguments()
+                .filter_map(|arg| arg.get_long())
+                .map(|long| format!("--{}", long))
+                .collect()
+        }
+
+        // Count the positional arguments already given, skipping options and their values
+        let mut index = 0;
+        let mut words = args.iter();
+        while let Some(word) = words.next() {
+            if let Some(long) = word.strip_prefix("--") {
+                if takes_value(long).is_some() {
+                    words.next();
+                }
+            }
+            else if !word.starts_with('-') {
+                index += 1;
+            }
+        }
+
+        match schema.get_positionals().nth(index) {
+            Some(arg) => self.values_for(arg),
+            None => Vec::new(),
+        }
+    }
+}
+
+impl Completer for ReplCompleter {
+    fn complete(&mut self, line: &str, pos: usize) -> Vec<Suggestion> {
+        let line = &line[..pos];
+        let start = line.rfind(char::is_whitespace).map(|i| i + 1).unwrap_or(0);
+        let prefix = &line[start..];
+        let words: Vec<&str> = line[..start].split_whitespace().collect();
+
+        let values = if let Some((command, args)) = words.split_first() {
+            match self.commands.iter().find(|(name, _)| name == command) {
+                Some((_, schema)) => self.values_for_position(schema, args, prefix),
+                None => Vec::new(),
+            }
+        }
+        else {
+            self.command_names()
+        };
+
+        values.into_iter()
+            .filter(|value| value.starts_with(prefix))
+            .map(|value| Suggestion {
+                value,
+                span: Span::new(start, pos),
+                append_whitespace: true,
+                ..Suggestion::default()
+            })
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use clap::Parser;
+
+    #[derive(Parser)]
+    struct TestArgs {
+        #[arg(long, value_name = "METER_NODE_ID")]
+        meter: Option<String>,
+        #[arg(value_name = "BILL_ID")]
+        bill_id: Option<String>,
+    }
+
+    fn complete(line: &str) -> Vec<String> {
+        use clap::CommandFactory;
+
+        let mut candidates = HashMap::new();
+        candidates.insert("BILL_ID".to_string(), vec!("1234".to_string(), "1299".to_string(), "5678".to_string()));
+        candidates.insert("METER_NODE_ID".to_string(), vec!("ESME-1".to_string()));
+
+        let mut completer = ReplCompleter::new(
+            vec!(("bill".to_string(), TestArgs::command()), ("bills".to_string(), clap::Command::default())),
+            Arc::new(Mutex::new(candidates)));
+        completer.complete(line, line.len()).into_iter().map(|suggestion| suggestion.value).collect()
+    }
+
+    #[test]
+    fn test_complete() {
+        assert_eq!(complete("bi"), vec!("bill", "bills"));
+        assert_eq!(complete("bill 12"), vec!("1234", "1299"));
+        assert_eq!(complete("bill --meter "), vec!("ESME-1"));
+        assert_eq!(complete("bill --meter ESME-1 5"), vec!("5678"));
+        assert_eq!(complete("bill 1234 "), Vec::<String>::new());
+    }
+}
@@ -2,6 +2,7 @@
 use std::sync::Arc;
 
 use dioxus::prelude::*;
+use tracing::trace;
 
 use crate::{MarcoSparkoContext, ModuleRegistrations, views::*};
 use dioxus::desktop::{use_window, LogicalSize};
@@ -45,7 +46,7 @@ const MAIN_CSS: Asset = asset!("/assets/styling/main.css");
 #[component]
 // pub fn App(profile_manager: Arc<ProfileManager>) -> Element {
 pub fn App() -> Element {
-    println!("Rendering App component MAIN_CSS={}", MAIN_CSS);
+    trace!("Rendering App component MAIN_CSS={}", MAIN_CSS);
     let mut init_signal = use_signal::<bool>(|| true);
     let init = *init_signal.read();
     let mut context_signal = use_signal::<Option<Arc<MarcoSparkoContext>>>(|| None);
@@ -61,18 +62,22 @@ pub fn App() -> Element {
     // window.set_window_icon(window_icon);
 
     if init {
-        let marco_sparko_context = MarcoSparkoContext::new()?;
+        let module_registrations = ModuleRegistrations::new();
+        let marco_sparko_context = MarcoSparkoContext::new(&module_registrations)?;
         context_signal.set(Some(marco_sparko_context));
         // let x: Signal<Arc<MarcoSparkoContext>>;
         use_context_provider::<Signal<Option<Arc<MarcoSparkoContext>>>>(move || context_signal);
 
-        let module_registrations = ModuleRegistrations::new();
         use_context_provider::<ModuleRegistrations>(move || module_registrations);
 
         init_signal.set(false);
         
         return rsx!{ "Loading..." };
     }
+
+    // A profile which encrypts its cache can't be used until it has been unlocked, and the navbar can switch to such a profile at any time
+    let locked = context_signal.read().as_ref().is_some_and(|context| context.is_locked());
+
     rsx! {
         ErrorBoundary {
             handle_error: |errors: ErrorContext| {
@@ -91,7 +96,12 @@ pub fn App() -> Element {
             document::Link { rel: "icon", href: FAVICON }
             document::Link { rel: "stylesheet", href: MAIN_CSS }
 
-            Router::<Route> {}
+            if locked {
+                Unlock {}
+            }
+            else {
+                Router::<Route> {}
+            }
         }
     }
 }
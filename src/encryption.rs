@@ -0,0 +1,171 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+use anyhow::anyhow;
+use argon2::Argon2;
+use base64::Engine;
+use base64::engine::general_purpose::STANDARD as BASE64;
+use chacha20poly1305::aead::{Aead, AeadCore, KeyInit, OsRng};
+use chacha20poly1305::aead::rand_core::RngCore;
+use chacha20poly1305::{XChaCha20Poly1305, XNonce};
+use serde::{Deserialize, Serialize};
+
+use crate::profile::EncryptionSettings;
+
+/* ***************************************************************************************************************************************************************
+ * Encryption at rest of the token cache and the data cache.
+ *
+ * The key is derived with Argon2 from a passphrase, or the contents of a key file, and a random salt which is kept with a check value in {profile}-key.json
+ * in the cache directory, so that a wrong passphrase is reported when the cache is unlocked rather than as a failure to read some data later on. Values are
+ * sealed with XChaCha20-Poly1305 under a new random nonce each time, and written as the prefix "msenc1:" and the nonce and ciphertext in base64.
+ *
+ * The salt and check value are kept in each data cache store as well, by CacheManager, which refuses to use a store (e.g. a shared DynamoDB table, or a
+ * copy of another machine's cache) that was encrypted with a different key. Values which don't have the prefix are read as they are, and the first time
+ * a store is used with a key they are all encrypted.
+ *************************************************************************************************************************************************************** */
+
+const PREFIX: &str = "msenc1:";
+const SALT_LEN: usize = 16;
+/// Sealed in the key file to check the key on unlock
+const CHECK_TEXT: &[u8] = b"marco-sparko";
+/// The environment variable from which the passphrase is taken instead of asking for it, e.g. for --sync run as a service
+pub const PASSPHRASE_ENV: &str = "MARCO_SPARKO_PASSPHRASE";
+
+#[derive(Serialize, Deserialize, Clone, PartialEq)]
+#[serde(rename_all = "camelCase")]
+struct KeyCheck {
+    salt: String,
+    check: String,
+}
+
+pub struct Cipher {
+    cipher: XChaCha20Poly1305,
+    /// The salt from which the key was derived, and the check value sealed with it
+    key_check: KeyCheck,
+}
+
+impl Cipher {
+    fn derive(secret: &[u8], salt: &[u8]) -> anyhow::Result<XChaCha20Poly1305> {
+        let mut key = [0u8; 32];
+        Argon2::default().hash_password_into(secret, salt, &mut key).map_err(|error| anyhow!("Unable to derive cache key: {}", error))?;
+
+        Ok(XChaCha20Poly1305::new(&key.into()))
+    }
+
+    /// Unlock the cache with the given secret, checking it against key_check_path, which is created (with a new salt) if it doesn't exist
+    pub fn unlock(secret: &[u8], key_check_path: &Path) -> anyhow::Result<Cipher> {
+        match fs::read_to_string(key_check_path) {
+            Ok(json) => {
+                let key_check: KeyCheck = serde_json::from_str(&json)?;
+                let cipher = Cipher {
+                    cipher: Self::derive(secret, &BASE64.decode(&key_check.salt)?)?,
+                    key_check,
+                };
+
+                match cipher.decrypt(&cipher.key_check.check) {
+                    Ok(check) if check == CHECK_TEXT => Ok(cipher),
+                    _ => Err(anyhow!("Incorrect passphrase or key file")),
+                }
+            },
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => {
+                let mut salt = [0u8; SALT_LEN];
+                OsRng.fill_bytes(&mut salt);
+                let mut cipher = Cipher {
+                    cipher: Self::derive(secret, &salt)?,
+                    key_check: KeyCheck {
+                        salt: BASE64.encode(salt),
+                        check: String::new(),
+                    },
+                };
+                cipher.key_check.check = cipher.encrypt(CHECK_TEXT)?;
+
+                fs::write(key_check_path, serde_json::to_string_pretty(&cipher.key_check)?)?;
+                Ok(cipher)
+            },
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    /// The salt and check value of the key as JSON, as kept in the key file, for a store to keep too
+    pub fn key_check(&self) -> anyhow::Result<String> {
+        Ok(serde_json::to_string(&self.key_check)?)
+    }
+
+    /// Whether the given JSON, from key_check(), is for this key
+    pub fn has_key_check(&self, json: &str) -> anyhow::Result<bool> {
+        let key_check: KeyCheck = serde_json::from_str(json)?;
+        if key_check == self.key_check {
+            return Ok(true)
+        }
+        // the check value is sealed under a random nonce, so it differs between copies of the same key
+        Ok(key_check.salt == self.key_check.salt && self.decrypt(&key_check.check).is_ok_and(|check| check == CHECK_TEXT))
+    }
+
+    /// Whether the given text was written by encrypt()
+    pub fn is_encrypted(text: &str) -> bool {
+        text.starts_with(PREFIX)
+    }
+
+    pub fn encrypt(&self, plaintext: &[u8]) -> anyhow::Result<String> {
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let mut sealed = nonce.to_vec();
+        sealed.extend(self.cipher.encrypt(&nonce, plaintext).map_err(|_| anyhow!("Unable to encrypt cached data"))?);
+
+        Ok(format!("{}{}", PREFIX, BASE64.encode(sealed)))
+    }
+
+    pub fn decrypt(&self, text: &str) -> anyhow::Result<Vec<u8>> {
+        let sealed = BASE64.decode(text.strip_prefix(PREFIX).ok_or(anyhow!("Cached data is not encrypted"))?)?;
+        if sealed.len() < 24 {
+            return Err(anyhow!("Encrypted cached data is truncated"))
+        }
+        let (nonce, ciphertext) = sealed.split_at(24);
+
+        self.cipher.decrypt(XNonce::from_slice(nonce), ciphertext).map_err(|_| anyhow!("Unable to decrypt cached data, it may be corrupt"))
+    }
+}
+
+/// The secret from which the key is derived: the contents of the key file if the profile names one, otherwise the passphrase from PASSPHRASE_ENV or
+/// passphrase(), which asks the user for it
+pub fn secret(settings: &EncryptionSettings, passphrase: impl FnOnce() -> anyhow::Result<String>) -> anyhow::Result<Vec<u8>> {
+    if let Some(key_file) = &settings.key_file {
+        let path = PathBuf::from(shellexpand_home(key_file));
+        let key = fs::read(&path).map_err(|error| anyhow!("Unable to read key file {:?}: {}", path, error))?;
+        return Ok(key.trim_ascii().to_vec())
+    }
+    if let Ok(passphrase) = std::env::var(PASSPHRASE_ENV) {
+        return Ok(passphrase.into_bytes())
+    }
+    Ok(passphrase()?.into_bytes())
+}
+
+/// A path with a leading ~ replaced by the home directory
+fn shellexpand_home(path: &str) -> String {
+    match (path.strip_prefix("~/"), dirs::home_dir()) {
+        (Some(rest), Some(home)) => home.join(rest).to_string_lossy().to_string(),
+        _ => path.to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_unlock_and_round_trip() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let path = dir.path().join("test-key.json");
+
+        let cipher = Cipher::unlock(b"secret", &path).unwrap();
+        let sealed = cipher.encrypt(b"{\"token\":\"abc\"}").unwrap();
+        assert!(Cipher::is_encrypted(&sealed));
+
+        let unlocked = Cipher::unlock(b"secret", &path).unwrap();
+        assert_eq!(unlocked.decrypt(&sealed).unwrap(), b"{\"token\":\"abc\"}");
+        assert!(unlocked.has_key_check(&cipher.key_check().unwrap()).unwrap());
+        assert!(Cipher::unlock(b"wrong", &path).is_err());
+
+        // the same secret on another machine makes a different key
+        let other = Cipher::unlock(b"secret", &dir.path().join("other-key.json")).unwrap();
+        assert!(!other.has_key_check(&cipher.key_check().unwrap()).unwrap());
+    }
+}
@@ -1,12 +1,25 @@
+#[cfg(feature = "octopus")]
 pub mod octopus;
 pub mod system;
 pub mod util;
 pub mod views;
 pub mod components;
 pub mod profile;
+pub mod output;
 
+mod alerts;
 mod cache_manager;
+mod cache_command;
+mod encryption;
 pub use cache_manager::CacheManager;
+mod completer;
+mod aggregate;
+mod logging;
+mod metrics;
+#[cfg(feature = "mqtt")]
+mod mqtt;
+mod server;
+mod sync;
 
 
 use std::collections::BTreeMap;
@@ -17,10 +30,13 @@ use dioxus::core::Element;
 use dirs::home_dir;
 use serde::de::DeserializeOwned;
 use serde::Serialize;
-use clap::{Parser};
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, ValueEnum};
+use tracing::{debug, warn};
 
 use reedline::{Emacs, ExampleHighlighter, FileBackedHistory, MenuBuilder, ReedlineMenu};
-use reedline::{default_emacs_keybindings, ColumnarMenu, DefaultCompleter, DefaultPrompt, DefaultPromptSegment, KeyCode, KeyModifiers, Reedline, ReedlineEvent, Signal};
+use reedline::{default_emacs_keybindings, ColumnarMenu, DefaultPrompt, DefaultPromptSegment, KeyCode, KeyModifiers, Reedline, ReedlineEvent, Signal};
+use crate::completer::{Candidates, ReplCompleter};
+use crate::output::{Document, OutputFormat, Table, Value};
 use crate::profile::ActiveProfile;
 
 use {
@@ -32,10 +48,131 @@ use {
 use std::os::unix::fs::OpenOptionsExt;
 
 pub const CHECK_FOR_UPDATES: bool = true;
+
+/// Exit status when a one-shot command executed but failed.
+pub const EXIT_COMMAND_FAILED: u8 = 1;
+/// Exit status when a one-shot command line could not be understood.
+pub const EXIT_USAGE: u8 = 2;
+/// Exit status when the application or a module could not be initialized.
+pub const EXIT_INITIALIZATION_FAILED: u8 = 3;
+
+/// Errors raised by the CLI itself, as opposed to the commands it runs, which determine the process exit status.
+#[derive(Debug)]
+pub enum CliError {
+    Usage(String),
+    Initialization(anyhow::Error),
+}
+
+impl CliError {
+    pub fn exit_code(&self) -> u8 {
+        match self {
+            CliError::Usage(_) => EXIT_USAGE,
+            CliError::Initialization(_) => EXIT_INITIALIZATION_FAILED,
+        }
+    }
+
+    /// The exit status for any error returned by Cli::run()
+    pub fn exit_code_for(error: &anyhow::Error) -> u8 {
+        if let Some(cli_error) = error.downcast_ref::<CliError>() {
+            cli_error.exit_code()
+        }
+        else {
+            EXIT_COMMAND_FAILED
+        }
+    }
+}
+
+impl std::fmt::Display for CliError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CliError::Usage(message) => write!(f, "{}", message),
+            CliError::Initialization(error) => write!(f, "Initialization failed: {}", error),
+        }
+    }
+}
+
+impl std::error::Error for CliError {}
+
 pub struct ReplCommand {
     pub command: &'static str,
     pub description: &'static str,
     pub help: &'static str,
+    /// The arguments accepted by the command, used to validate them before it is executed and for tab completion
+    pub args: clap::Command,
+}
+
+impl ReplCommand {
+    pub fn parse_args<'a>(&self, args: impl IntoIterator<Item = &'a str>) -> Result<ArgMatches, clap::Error> {
+        self.args.clone()
+            .name(self.command)
+            .no_binary_name(true)
+            .try_get_matches_from(args)
+    }
+}
+
+#[derive(ValueEnum, Debug, Clone, Copy)]
+enum ListTarget {
+    Modules,
+    Profiles,
+}
+
+#[derive(Parser, Debug)]
+struct ListArgs {
+    #[arg(value_enum)]
+    target: ListTarget,
+}
+
+#[derive(Parser, Debug)]
+struct ModuleArgs {
+    #[arg(value_name = "MODULE")]
+    module_id: String,
+}
+
+#[derive(Parser, Debug)]
+struct LogoutArgs {
+    /// Keep the module's settings in the profile, only deleting the cached token
+    #[arg(long)]
+    keep_profile: bool,
+    #[arg(value_name = "MODULE")]
+    module_id: String,
+}
+
+#[derive(Parser, Debug)]
+struct PeriodArgs {
+    /// Start of the period, either a date (YYYY-MM-DD) or a keyword such as last-month
+    #[arg(value_name = "DATE")]
+    from: Option<String>,
+    /// End of the period (YYYY-MM-DD), defaults to today
+    #[arg(value_name = "DATE")]
+    to: Option<String>,
+}
+
+#[derive(Parser, Debug)]
+struct FormatArgs {
+    #[arg(value_enum)]
+    format: Option<OutputFormat>,
+}
+
+#[derive(Parser, Debug)]
+struct SetArgs {
+    name: Option<String>,
+    #[arg(requires = "name", trailing_var_arg = true, allow_hyphen_values = true)]
+    value: Vec<String>,
+}
+
+#[derive(Parser, Debug)]
+struct SourceArgs {
+    /// Carry on with the rest of the script when a command fails
+    #[arg(long)]
+    continue_on_error: bool,
+    #[arg(value_name = "FILE")]
+    file: PathBuf,
+}
+
+#[derive(Parser, Debug)]
+struct HelpArgs {
+    #[arg(value_name = "COMMAND")]
+    command: Option<String>,
 }
 
 #[derive(Parser, Debug, Clone, PartialEq)]
@@ -46,13 +183,47 @@ pub struct Args {
     profile: Option<String>,
     #[arg(short, long, value_delimiter = ',', num_args = 1..)]
     modules: Vec<String>,
+    /// Write debug messages to the log file
     #[arg(short, long)]
     debug: bool,
+    /// Write informational messages to the log file, with --debug write everything
     #[arg(short, long)]
     verbose: bool,
-
-    #[clap(flatten)]
-    pub octopus: octopus::OctopusArgs, // TODO: remove code dependency on module octopus
+    /// Format in which command output is printed
+    #[arg(long, value_enum, default_value_t)]
+    format: OutputFormat,
+    /// Execute the commands in the given file and exit instead of starting the interactive command line
+    #[arg(long, value_name = "FILE", conflicts_with = "command")]
+    script: Option<PathBuf>,
+    /// Carry on with the rest of the script when a command fails
+    #[arg(long, requires = "script")]
+    continue_on_error: bool,
+    /// Serve the data of the active modules as a JSON API over HTTP instead of starting the interactive command line
+    #[arg(long, conflicts_with_all = ["command", "script"])]
+    serve: bool,
+    /// Publish the data of the active modules to the MQTT broker given in the profile instead of starting the interactive command line
+    #[cfg(feature = "mqtt")]
+    #[arg(long, conflicts_with_all = ["command", "script", "serve", "sync"])]
+    mqtt: bool,
+    /// Keep the local cache of the active modules up to date, on the schedule given in the profile, instead of starting the interactive command line
+    #[arg(long, conflicts_with_all = ["command", "script", "serve"])]
+    sync: bool,
+    /// The address on which to serve the API
+    #[arg(long, value_name = "ADDRESS", default_value = server::DEFAULT_BIND_ADDRESS)]
+    bind: String,
+    /// How long the API server keeps the metrics it has read for /metrics before reading them again, in seconds
+    #[arg(long, value_name = "SECONDS", default_value_t = 300)]
+    metrics_interval: u64,
+    /// Require API requests to carry this bearer token
+    #[arg(long, value_name = "TOKEN", env = "MARCO_SPARKO_API_TOKEN", hide_env_values = true)]
+    api_token: Option<String>,
+    /// Fetch cached data sets again instead of waiting for them to go stale, e.g. --refresh=viewer,properties or --refresh for all of them
+    #[arg(long, value_name = "DATA_SET", require_equals = true, num_args = 0.., value_delimiter = ',')]
+    pub refresh: Option<Vec<String>>,
+
+    /// Run a single command and exit instead of starting the interactive command line, e.g. "octopus bill 12345"
+    #[arg(trailing_var_arg = true, allow_hyphen_values = true, value_name = "MODULE COMMAND [ARGS]")]
+    command: Vec<String>,
 }
 
 
@@ -68,12 +239,29 @@ pub trait Module: CommandProvider {
     fn get_page_list(&self) -> Vec<PageInfo>;
     fn module_id(&self) -> &'static str;
     fn get_component<'a>(&'a self, page_id: &'a str, path: Vec<String>) -> Box<dyn Fn() -> Element + 'a>;
+
+    /// The module's data in provider-neutral form, if it has any
+    fn energy_data_source(&self) -> Option<&dyn system::EnergyDataSource> {
+        None
+    }
 }
 
 #[async_trait(?Send)]
 pub trait CommandProvider {
     fn get_repl_commands(&self) -> Vec<ReplCommand>;
-    async fn exec_repl_command(&mut self, command: &str, args: std::str::SplitWhitespace<'_>) ->  anyhow::Result<()>;
+    /// Execute a command. Commands which produce output over a period, such as demand, can print it as it is produced in the given format, which is None
+    /// when the output is collected rather than printed, as by --serve.
+    async fn exec_repl_command(&self, command: &str, args: &ArgMatches, format: Option<OutputFormat>) ->  anyhow::Result<Document>;
+
+    /// Candidate values for tab completion of command arguments, keyed by the value name of the argument, e.g. "BILL_ID"
+    async fn get_completions(&self) -> HashMap<String, Vec<String>> {
+        HashMap::new()
+    }
+
+    /// The value of a variable provided by this module, e.g. "LATEST_BILL", for use in commands as $LATEST_BILL
+    async fn get_variable(&self, _name: &str) -> anyhow::Result<Option<String>> {
+        Ok(None)
+    }
 }
 
 #[async_trait]
@@ -85,9 +273,44 @@ pub trait ModuleFactory: Send {
 
 pub type ModuleFactoryConstructor = dyn Fn(Arc<MarcoSparkoContext>, Option<serde_json::Value>) -> anyhow::Result<Arc<dyn ModuleFactory>>;
 
+/// Adds a module's own command line arguments to those of the application, typically the augment_args function of a type deriving clap::Args.
+///
+/// Argument names should be prefixed with the module id, e.g. --octopus-api-key, so that they don't clash with those of other modules.
+pub type ModuleArgsAugmenter = fn(clap::Command) -> clap::Command;
+
 pub struct ModuleRegistration {
     pub module_id: String,
     pub constructor: Arc<ModuleFactoryConstructor>,
+    pub args: ModuleArgsAugmenter,
+    /// The names of the module's cached data sets which can be given to --refresh
+    pub cache_data_sets: &'static [&'static str],
+}
+
+pub struct ModuleRegistrationsBuilder {
+    module_registrations: HashMap<String, ModuleRegistration>,
+}
+
+impl ModuleRegistrationsBuilder {
+    pub fn with_module(mut self, registration: ModuleRegistration) -> ModuleRegistrationsBuilder {
+        debug!("Register module {}", &registration.module_id);
+        self.module_registrations.insert(registration.module_id.clone(), registration);
+        self
+    }
+
+    /// Add each of the modules in this crate which is enabled by its cargo feature
+    #[allow(unused_mut)] // in a build with no modules enabled
+    pub fn with_compiled_in_modules(mut self) -> ModuleRegistrationsBuilder {
+        #[cfg(feature = "octopus")]
+        {
+            self = self.with_module(octopus::OctopusModule::registration());
+        }
+        self
+    }
+
+    pub fn build(self) -> ModuleRegistrations {
+        debug!("Registered {} modules", self.module_registrations.len());
+        ModuleRegistrations(Arc::new(self.module_registrations))
+    }
 }
 
 #[derive(Clone, Default)]
@@ -123,64 +346,179 @@ impl PartialEq for ModuleRegistrations {
 }
 
 impl ModuleRegistrations {
-    fn new() -> ModuleRegistrations {
-
-
-        let dir = std::env::current_dir().unwrap();
-        println!("Current directory is {}", dir.display());
-println!("No assertion failure here");
-        //assert!(cfg!(debug_assertions));
-
+    /// The registrations of all the modules compiled into this build
+    pub fn new() -> ModuleRegistrations {
+        Self::builder()
+            .with_compiled_in_modules()
+            .build()
+    }
 
-        let mut module_registrations = HashMap::new();
+    /// A builder to which additional modules, which are not part of this crate, can be added
+    pub fn builder() -> ModuleRegistrationsBuilder {
+        ModuleRegistrationsBuilder {
+            module_registrations: HashMap::new(),
+        }
+    }
 
-        Self::load_module(&mut module_registrations, octopus::OctopusModule::registration());
+    /// Parse the command line, including the arguments of all registered modules
+    fn parse_args(&self) -> (Args, ArgMatches) {
+        let mut registrations: Vec<&ModuleRegistration> = self.0.values().collect();
+        registrations.sort_by(|a, b| a.module_id.cmp(&b.module_id));
 
-        println!("Loaded {} modules", module_registrations.len());
+        let mut command = Args::command();
+        for registration in registrations {
+            command = (registration.args)(command);
+        }
 
-        for (k, _v) in &module_registrations {
-            println!(" Module {}", k);
+        let matches = command.get_matches();
+        let args = Args::from_arg_matches(&matches).unwrap_or_else(|error| error.exit());
+        if let Err(error) = self.check_refresh(&args) {
+            error.exit()
         }
-        ModuleRegistrations(Arc::new(module_registrations))
+        (args, matches)
     }
 
-    fn load_module(module_registrations: &mut HashMap<String, ModuleRegistration> , registration: ModuleRegistration) {
-        println!("Load module {}", &registration.module_id);
-        module_registrations.insert(registration.module_id.clone(), registration);
+    /// Check that the data sets named by --refresh are those of a registered module
+    fn check_refresh(&self, args: &Args) -> Result<(), clap::Error> {
+        let mut known: Vec<&str> = self.0.values().flat_map(|registration| registration.cache_data_sets.iter().copied()).collect();
+        known.sort();
+        known.dedup();
+
+        for data_set in args.refresh.iter().flatten() {
+            if !known.iter().any(|name| name.eq_ignore_ascii_case(data_set)) {
+                return Err(clap::Error::raw(clap::error::ErrorKind::InvalidValue,
+                    format!("Unknown data set '{}' for --refresh, expected one of {}\n", data_set, known.join(", "))))
+            }
+        }
+        Ok(())
     }
 }
 
  pub struct MarcoSparkoContext {
     pub args: Args,
+    /// The whole command line, from which each module extracts its own arguments with module_args()
+    matches: ArgMatches,
     pub profile: ActiveProfile,
+    /// The key for the cache when the profile asks for encryption, None until it has been unlocked
+    cipher: Option<Arc<encryption::Cipher>>,
 }
 
 impl PartialEq for MarcoSparkoContext {
     fn eq(&self, other: &Self) -> bool {
-        self.args == other.args && self.profile == other.profile
+        self.args == other.args && self.matches == other.matches && self.profile == other.profile && self.cipher.is_some() == other.cipher.is_some()
     }
 }
 
 impl MarcoSparkoContext {
-    pub fn new() -> anyhow::Result<Arc<MarcoSparkoContext>> {
+    pub fn new(module_registrations: &ModuleRegistrations) -> anyhow::Result<Arc<MarcoSparkoContext>> {
 
-        let args = Args::parse();
+        let (args, matches) = module_registrations.parse_args();
         let profile = crate::profile::fetch_active_profile(&args.profile)?;
-        
 
-        Ok(Arc::new(MarcoSparkoContext {
+        logging::init(&args, &profile.active_profile);
+
+        Arc::new(MarcoSparkoContext {
             args,
+            matches,
             profile,
-       }))
+            cipher: None,
+       }).unlock_without_asking()
     }
 
     pub fn with_profile(&self, profile_name: &String) -> anyhow::Result<Arc<MarcoSparkoContext>> {
-        Ok(Arc::new(MarcoSparkoContext {
+        Arc::new(MarcoSparkoContext {
             args: self.args.clone(),
+            matches: self.matches.clone(),
             profile: crate::profile::set_active_profile(profile_name)?,
+            cipher: None,
+       }).unlock_without_asking()
+    }
+
+    /// A copy of this context with the active profile read again from the profile file, e.g. after a module's entry has been changed
+    pub fn reload_profile(&self) -> anyhow::Result<Arc<MarcoSparkoContext>> {
+        Ok(Arc::new(MarcoSparkoContext {
+            args: self.args.clone(),
+            matches: self.matches.clone(),
+            profile: crate::profile::fetch_active_profile(&Some(self.profile.active_profile.name.clone()))?,
+            cipher: self.cipher.clone(),
+       }))
+    }
+
+    /// Whether the profile encrypts the cache and it hasn't been unlocked yet, in which case unlock() must be called before any module is initialized
+    pub fn is_locked(&self) -> bool {
+        self.profile.active_profile.encryption.is_some() && self.cipher.is_none()
+    }
+
+    /// Whether unlocking the cache will create a new key, in which case the passphrase should be confirmed
+    pub fn is_new_key(&self) -> bool {
+        self.get_key_check_file_path().map(|path| !path.exists()).unwrap_or(false)
+    }
+
+    /// A copy of this context with the cache unlocked by the profile's key file, the passphrase in MARCO_SPARKO_PASSPHRASE or the given passphrase
+    pub fn unlock(&self, passphrase: Option<&str>) -> anyhow::Result<Arc<MarcoSparkoContext>> {
+        let cipher = match &self.profile.active_profile.encryption {
+            Some(settings) if self.cipher.is_none() => {
+                let secret = encryption::secret(settings, || passphrase.map(str::to_string).ok_or(anyhow!("A passphrase is needed to unlock the cache")))?;
+                Some(Arc::new(encryption::Cipher::unlock(&secret, &self.get_key_check_file_path()?)?))
+            },
+            _ => self.cipher.clone(),
+        };
+
+        Ok(Arc::new(MarcoSparkoContext {
+            args: self.args.clone(),
+            matches: self.matches.clone(),
+            profile: self.profile.clone(),
+            cipher,
        }))
     }
 
+    /// Unlock the cache if that doesn't need the user to give a passphrase, i.e. the profile names a key file or MARCO_SPARKO_PASSPHRASE is set
+    fn unlock_without_asking(self: Arc<Self>) -> anyhow::Result<Arc<MarcoSparkoContext>> {
+        match &self.profile.active_profile.encryption {
+            Some(settings) if settings.key_file.is_some() || std::env::var(encryption::PASSPHRASE_ENV).is_ok() => self.unlock(None),
+            _ => Ok(self),
+        }
+    }
+
+    fn get_key_check_file_path(&self) -> anyhow::Result<PathBuf> {
+        let profile_name = &self.profile.active_profile.name;
+        let mut path = home_dir().ok_or(anyhow!("Unable to locate home directory"))?;
+        path.push(".marco-sparko-cache");
+        fs::create_dir_all(&path)?;
+        path.push(format!("{}-key.json", profile_name));
+        Ok(path)
+    }
+
+    /// The key for the cache, None if the profile doesn't encrypt it and an error if it hasn't been unlocked
+    fn cipher(&self) -> anyhow::Result<Option<Arc<encryption::Cipher>>> {
+        if self.is_locked() {
+            return Err(anyhow!("The cache for profile {} is encrypted and has not been unlocked", self.profile.active_profile.name))
+        }
+        Ok(self.cipher.clone())
+    }
+
+    /// The command line arguments contributed by a module through its ModuleRegistration
+    pub fn module_args<T: FromArgMatches>(&self) -> anyhow::Result<T> {
+        Ok(T::from_arg_matches(&self.matches)?)
+    }
+
+    fn get_sync_status_file_path(&self, module_id: &str) -> anyhow::Result<PathBuf> {
+        let profile_name = &self.profile.active_profile.name;
+        let mut path = home_dir().ok_or(anyhow!("Unable to locate home directory"))?;
+        path.push(".marco-sparko-cache");
+        path.push(format!("{}-{}-sync.json", profile_name, module_id));
+        Ok(path)
+    }
+
+    fn get_alert_state_file_path(&self) -> anyhow::Result<PathBuf> {
+        let profile_name = &self.profile.active_profile.name;
+        let mut path = home_dir().ok_or(anyhow!("Unable to locate home directory"))?;
+        path.push(".marco-sparko-cache");
+        fs::create_dir_all(&path)?;
+        path.push(format!("{}-alerts.json", profile_name));
+        Ok(path)
+    }
+
     fn get_cache_file_path(&self, module_id: &str) -> anyhow::Result<PathBuf> {
         let profile_name = &self.profile.active_profile.name;
         let mut path = home_dir().ok_or(anyhow!("Unable to locate home directory"))?;
@@ -211,14 +549,18 @@ impl MarcoSparkoContext {
         Ok(path)
     }
       
-    fn create_cache_manager(&self, module_id: &str, verbose: bool) -> anyhow::Result<Arc<CacheManager>> {
+    fn create_cache_manager(&self, module_id: &str) -> anyhow::Result<Arc<CacheManager>> {
         let dir_path = self.get_cache_data_dir_path(module_id)?;
-        fs::create_dir_all(&dir_path)?;
 
-        Ok(Arc::new(CacheManager {
-            dir_path,
-            verbose,
-        }))
+        let policy = cache_manager::CachePolicy {
+            ttl: self.profile.active_profile.cache_ttl.clone(),
+            refresh: self.args.refresh.clone(),
+            buckets: self.profile.active_profile.cache_buckets.clone(),
+            // a one-shot command or script exits before a background fetch would finish
+            wait_for_stale: !self.args.command.is_empty() || self.args.script.is_some(),
+        };
+
+        Ok(Arc::new(CacheManager::open(self.profile.active_profile.cache.as_ref(), dir_path, policy, self.cipher()?)?))
     }
 
     pub fn read_cache<T>(&self, module_id: &str) -> Option<T>
@@ -226,34 +568,75 @@ impl MarcoSparkoContext {
         T: DeserializeOwned
      {
         if let Ok(path) = self.get_cache_file_path(module_id) {
-            if let Ok(reader) = fs::File::open(path) {
-                match serde_json::from_reader(reader) {
+            if let Ok(content) = fs::read_to_string(path) {
+                match self.open_cached_token(&content) {
                     Ok(result) => return Some(result),
-                    Err(error) => println!("ERROR reading cached token: {:?}", error),
+                    Err(error) => warn!("Unable to read cached token for {}: {}", module_id, error),
                 }
             }
         }
         return None
     }
 
+    fn open_cached_token<T>(&self, content: &str) -> anyhow::Result<T>
+    where
+        T: DeserializeOwned
+    {
+        if encryption::Cipher::is_encrypted(content.trim()) {
+            let cipher = self.cipher()?.ok_or(anyhow!("The cached token is encrypted but the profile has no encryption settings"))?;
+            Ok(serde_json::from_slice(&cipher.decrypt(content.trim())?)?)
+        }
+        else {
+            Ok(serde_json::from_str(content)?)
+        }
+    }
+
+    /// Delete the cached token written by update_cache, returning false if there wasn't one
+    pub fn delete_cache(&self, module_id: &str) -> anyhow::Result<bool> {
+        let path = self.get_cache_file_path(module_id)?;
+
+        match fs::remove_file(path) {
+            Ok(()) => Ok(true),
+            Err(error) if error.kind() == std::io::ErrorKind::NotFound => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
     pub fn update_cache<T>(&self, module_id: &str, profile: &T) -> anyhow::Result<()>
     where
         T: Serialize
     {
         let path = self.get_cache_file_path(module_id)?;
 
-        serde_json::to_writer_pretty(fs::File::create(path)?, &profile)?;
+        match self.cipher()? {
+            Some(cipher) => fs::write(path, cipher.encrypt(&serde_json::to_vec(&profile)?)?)?,
+            None => serde_json::to_writer_pretty(fs::File::create(path)?, &profile)?,
+        }
         // fs::File::options()
 
         Ok(())
     }
 }
 
+/// The limit on scripts which source other scripts, to stop a script which sources itself from recursing forever
+const MAX_SCRIPT_DEPTH: usize = 16;
+
+/// The effect of a command line on the REPL itself
+enum LineResult {
+    Continue,
+    /// The command context (or the set of active modules) has changed, so the prompt and completions need to be rebuilt
+    ContextChanged,
+    Quit,
+}
+
 pub struct Cli {
     context: Arc<MarcoSparkoContext>,
     module_registrations: ModuleRegistrations,
     modules: HashMap<String, Box<dyn Module>>,
     current_module: Option<String>,
+    format: OutputFormat,
+    variables: BTreeMap<String, String>,
+    script_depth: usize,
 }
 
 
@@ -278,6 +661,7 @@ usage: list modules|profiles
 "modules" lists all known modules and whether they are activated.
 "profiles" lists all known profiles (run configurations) and indicates the active one.
 "#,
+                args: ListArgs::command(),
             },
             ReplCommand {
                 command:"init",
@@ -288,6 +672,139 @@ usage: init module_id
 
 Initialize (activate) the given module.
 "#,
+                args: ModuleArgs::command(),
+            },
+            ReplCommand {
+                command:"deinit",
+                description: "Deactivate a module",
+                help:
+r#"
+usage: deinit module_id
+
+Deactivate the given module. It remains in the profile, so it will be initialized again the next time the
+application starts, or by the init command.
+"#,
+                args: ModuleArgs::command(),
+            },
+            ReplCommand {
+                command:"reload",
+                description: "Deactivate and initialize a module again",
+                help:
+r#"
+usage: reload module_id
+
+Initialize the given module again, with its settings read afresh from the profile, e.g. after credentials
+have been changed.
+"#,
+                args: ModuleArgs::command(),
+            },
+            ReplCommand {
+                command:"logout",
+                description: "Deactivate a module and forget its credentials",
+                help:
+r#"
+usage: logout [--keep-profile] module_id
+
+Deactivate the given module, delete its cached token and remove its settings from the profile. With
+--keep-profile the settings are kept and only the cached token is deleted.
+"#,
+                args: LogoutArgs::command(),
+            },
+            ReplCommand {
+                command:"sync",
+                description: "Fetch new data for all active modules into the local cache",
+                help:
+r#"
+usage: sync
+
+Fetch any new bills, and this month's consumption, for all active modules so that later commands don't have
+to wait for them. The outcome is shown by "list modules". Start the cli with --sync to do this on a schedule.
+"#,
+                args: clap::Command::default(),
+            },
+            ReplCommand {
+                command:"alerts",
+                description: "Check the alert rules in the profile now and list recent alerts",
+                help:
+r#"
+usage: alerts
+
+Check the alert rules in the active profile against the data of all active modules, send any alerts
+which are raised and list the recent alerts. The rules are also checked after each sync and MQTT update.
+"#,
+                args: clap::Command::default(),
+            },
+            ReplCommand {
+                command:"cache",
+                description: "Work on the cached data of a module",
+                help:
+r#"
+usage: cache list module_id
+       cache show [--bucket DATE] module_id hash_key
+       cache verify module_id
+       cache purge module_id [hash_key] [--from DATE [--to DATE]]
+       cache stats module_id
+       cache migrate [--from DIR] module_id
+       cache export [--module module_id]... [--exclude-tokens] [--plaintext [--include-tokens]] file
+       cache import [--list] file
+
+list        List each data set, and each bucket of time series, with its record count and size.
+show        Print the records of a data set, or of the time series bucket containing DATE.
+verify      Check that every record can be read, that no sort key appears twice in a data set and that
+            time series have no missing buckets.
+purge       Delete a data set, or the time series buckets which start in a range of dates (optionally
+            only those of one data set). The data is fetched again when it is next needed.
+stats       Print the number of buckets, records and bytes of each data set.
+migrate     Import the module's cache directory into the cache store selected by the "cache" setting of
+            the profile (e.g. an SQLite database). Records already in the store are kept, and records
+            with the same sort key are replaced.
+export      Write the cached data and tokens of the modules in the profile (or just those given) to
+            a compressed archive, with a manifest of the data sets and the dates they cover. The archive
+            is not encrypted, so a profile which encrypts its cache needs --plaintext, and its tokens
+            are only included with --include-tokens.
+import      Merge an archive written by export into the cache, keeping one copy of each sort key, so
+            partial histories from two machines can be combined. --list shows the manifest only.
+
+The cache command can also be given on the command line, e.g. "cli cache verify octopus".
+"#,
+                args: cache_command::CacheArgs::command(),
+            },
+            ReplCommand {
+                command:"consumption",
+                description: "Print total consumption of all active modules",
+                help:
+r#"
+usage: consumption [from [to]]
+
+Print the total consumption (and export) for each commodity, over all the meters of all active modules. The
+period is either a keyword (today, yesterday, this-week, last-week, this-month, last-month, this-year or
+last-year) or a start date and an optional end date in the form YYYY-MM-DD. The default period is this-month.
+"#,
+                args: PeriodArgs::command(),
+            },
+            ReplCommand {
+                command:"spend",
+                description: "Print total spend of all active modules",
+                help:
+r#"
+usage: spend [from [to]]
+
+Print the total charges and credits of the bills of each active module whose billing period ends in the given
+period, which is given as for the consumption command.
+"#,
+                args: PeriodArgs::command(),
+            },
+            ReplCommand {
+                command:"series",
+                description: "Print combined half hourly electricity import and export",
+                help:
+r#"
+usage: series [from [to]]
+
+Print the electricity imported and exported in each half hour of the given period, added up over all the
+meters of all active modules. The period is given as for the consumption command.
+"#,
+                args: PeriodArgs::command(),
             }
         )
     }
@@ -298,6 +815,7 @@ Initialize (activate) the given module.
                 command:"quit",
                 description: "Quit Marco Sparko (also Ctrl-D)",
                 help: "Terminates the application",
+                args: clap::Command::default(),
             },
 
             ReplCommand {
@@ -310,6 +828,7 @@ usage: home
 The main command context allows you to manage modules and the application as a whole. Each module has its own command context 
 which provides access to the features of that module.
 "#,
+                args: clap::Command::default(),
             },
             ReplCommand {
                 command:"module",
@@ -320,6 +839,45 @@ usage: module module_id
 
 Switch to the command context of the given active module. To activate an inactive module use the init command.
 "#,
+                args: ModuleArgs::command(),
+            },
+            ReplCommand {
+                command:"format",
+                description: "Show or set the output format",
+                help:
+r#"
+usage: format [text|json|csv|markdown]
+
+Without any arguments prints the current output format, otherwise sets the format in which the results of
+subsequent commands are printed. The initial format can be set with the --format command line option.
+"#,
+                args: FormatArgs::command(),
+            },
+            ReplCommand {
+                command:"set",
+                description: "Set or list variables",
+                help:
+r#"
+usage: set [name value]
+
+Without any arguments lists all variables, otherwise sets the named variable to the given value. Anywhere in a
+command $name is replaced by the value of the variable. Modules also provide variables, e.g. $LATEST_BILL is the
+id of the most recent bill.
+"#,
+                args: SetArgs::command(),
+            },
+            ReplCommand {
+                command:"source",
+                description: "Execute the commands in a file",
+                help:
+r#"
+usage: source [--continue-on-error] file
+
+Execute each line of the given file as a command. Blank lines and lines starting with # are ignored. Execution
+stops at the first command which fails, unless --continue-on-error is given. The current command context is
+carried from one line to the next, so a script can use the module command to switch between modules.
+"#,
+                args: SourceArgs::command(),
             },
             ReplCommand {
                 command:"help",
@@ -331,86 +889,197 @@ usage: help [command]
 Without any arguments lists all the currently available commands, with a single command parameter,
 prints more detailed help on that specific command.
 "#,
+                args: HelpArgs::command(),
             }
         )
     }
 
-    pub async fn init_handler(&mut self, mut args: std::str::SplitWhitespace<'_>) -> anyhow::Result<()> {
-        if let Some(module_id) = args.next() {
-            if let Some(module_registration) = self.module_registrations.0.get(module_id) {
-                if self.modules.contains_key(module_id) {
-                    println!("ERROR: module '{}' is already active", module_id); 
-                }
-                else {
-                    let constructor = module_registration.constructor.as_ref();
-                    let profile = if let Some(value) = self.context.profile.active_profile.modules.get(module_id) {
-                        Some(value.clone())
+    async fn init_handler(&mut self, args: ModuleArgs) -> anyhow::Result<Document> {
+        let module_id = &args.module_id;
+        if !self.module_registrations.0.contains_key(module_id) {
+            return Err(CliError::Usage(format!("Unknown module '{}'", module_id)).into())
+        }
+        if self.modules.contains_key(module_id) {
+            return Err(CliError::Usage(format!("Module '{}' is already active", module_id)).into())
+        }
+
+        self.initialize(module_id).await?;
+        Ok(Document::new())
+    }
+
+    fn deinit_handler(&mut self, args: ModuleArgs) -> anyhow::Result<Document> {
+        if self.modules.remove(&args.module_id).is_none() {
+            return Err(CliError::Usage(format!("Module '{}' is not active", args.module_id)).into())
+        }
+
+        if self.current_module.as_ref() == Some(&args.module_id) {
+            self.current_module = None;
+        }
+        Ok(Document::new())
+    }
+
+    async fn reload_handler(&mut self, args: ModuleArgs) -> anyhow::Result<Document> {
+        if !self.module_registrations.0.contains_key(&args.module_id) {
+            return Err(CliError::Usage(format!("Unknown module '{}'", args.module_id)).into())
+        }
+
+        self.modules.remove(&args.module_id);
+        self.context = self.context.reload_profile()?;
+        self.initialize(&args.module_id).await?;
+        Ok(Document::new())
+    }
+
+    fn logout_handler(&mut self, args: LogoutArgs) -> anyhow::Result<Document> {
+        if !self.module_registrations.0.contains_key(&args.module_id) {
+            return Err(CliError::Usage(format!("Unknown module '{}'", args.module_id)).into())
+        }
+
+        if self.modules.remove(&args.module_id).is_some() && self.current_module.as_ref() == Some(&args.module_id) {
+            self.current_module = None;
+        }
+        self.context = Self::logout_module(&self.context, &args.module_id, args.keep_profile)?;
+        Ok(Document::new())
+    }
+
+    /// Forget a module's credentials by deleting its cached token and, unless keep_profile is set, its entry in the active profile.
+    ///
+    /// Returns a context with the updated profile.
+    pub fn logout_module(context: &Arc<MarcoSparkoContext>, module_id: &str, keep_profile: bool) -> anyhow::Result<Arc<MarcoSparkoContext>> {
+        context.delete_cache(module_id)?;
+
+        if !keep_profile {
+            crate::profile::remove_module_profile(&context.profile.active_profile.name, module_id)?;
+        }
+        context.reload_profile()
+    }
+
+    /// The energy data of each active module which provides it, in order of module id
+    fn data_sources(&self) -> aggregate::DataSources<'_> {
+        let mut sources: aggregate::DataSources = self.modules.iter()
+            .filter_map(|(module_id, module)| module.energy_data_source().map(|source| (module_id.as_str(), source)))
+            .collect();
+        sources.sort_by_key(|(module_id, _)| *module_id);
+        sources
+    }
+
+    async fn aggregate_handler(&self, command: &str, args: PeriodArgs) -> anyhow::Result<Document> {
+        let date_range = util::parse_date_range(args.from.as_deref(), args.to.as_deref())?;
+        let sources = self.data_sources();
+
+        if sources.is_empty() {
+            return Err(CliError::Usage("There are no active modules which provide energy data".to_string()).into())
+        }
+
+        match command {
+            "consumption" => aggregate::consumption(&sources, &date_range).await,
+            "spend" => aggregate::spend(&sources, &date_range).await,
+            _ => aggregate::series(&sources, &date_range).await,
+        }
+    }
+
+    async fn list_handler(&self, args: ListArgs) -> anyhow::Result<Document> {
+        match args.target {
+            ListTarget::Modules => {
+                let mut table = Table::new(&["Module", "Status", "Last Sync"]);
+                for reg in &*self.module_registrations.0 {
+                    let status = if let Some(_module) = self.modules.get(reg.0) {
+                        "Active"
                     }
                     else {
-                        None
+                        "Uninitialized"
                     };
-                    let builder = constructor(self.context.clone(), profile)?;
-                    let module = builder.build().await?;
-                    self.modules.insert(module_id.to_string(),module);
-
-                    if self.current_module.is_none() {
-                        self.current_module = Some(module_id.to_string());
+                    let last_sync = match sync::SyncStatus::read(&self.context, reg.0) {
+                        Some(sync_status) => Value::from(sync_status.summary()),
+                        None => Value::Empty,
+                    };
+                    table.push(vec!(Value::from(reg.0.as_str()), Value::from(status), last_sync));
+                }
+                Ok(table.into())
+            },
+            ListTarget::Profiles => {
+                let mut table = Table::new(&["Profile", "Status"]);
+                for profile_name in &self.context.profile.all_profiles {
+                    let status = if profile_name == &self.context.profile.active_profile.name {
+                        Value::from("Active")
                     }
+                    else {
+                        Value::Empty
+                    };
+                    table.push(vec!(Value::from(profile_name.as_str()), status));
                 }
-            }
-            else {
-                println!("ERROR: unknown module '{}'", module_id); 
-            }
+                Ok(table.into())
+            },
         }
-        Ok(())
     }
 
-    pub async fn list_handler(&self, mut args: std::str::SplitWhitespace<'_>) -> anyhow::Result<()> {
-        if let Some(target) = args.next() {
+    fn format_handler(&mut self, args: FormatArgs) -> anyhow::Result<Document> {
+        if let Some(format) = args.format {
+            self.format = format;
+            return Ok(Document::new())
+        }
 
-            match target.as_ref() {
-                "modules" => {
-                    for reg in &*self.module_registrations.0 {
-                        let status = if let Some(_module) = self.modules.get(reg.0) {
-                            "Active"
-                        }
-                        else {
-                            "Uninitialized"
-                        };
-                        println!("{} [{}]", reg.0, status);
-                    }
-                },
-                "profiles" => {
-                    for profile_name in &self.context.profile.all_profiles {
-                        if profile_name == &self.context.profile.active_profile.name {
-                            println!("{} [Active]", profile_name);
-                        }
-                        else {
-                            println!("{}", profile_name);
-                        }
-                    }
-                },
-                _ => {
-                    println!("ERROR: usage: list modules|profiles");
-                },
-            };
+        let name = self.format.to_possible_value().map(|value| value.get_name().to_string()).unwrap_or_default();
+        let mut document = Document::new();
+        document.fields(vec!(("Format".to_string(), Value::from(name))));
+        Ok(document)
+    }
+
+    /// Candidate values for tab completion, from the application itself and the current module
+    async fn get_completions(&self) -> HashMap<String, Vec<String>> {
+        let mut completions = HashMap::new();
+
+        completions.insert("MODULE".to_string(), self.module_registrations.0.keys().cloned().collect());
+        completions.insert("PROFILE".to_string(), self.context.profile.all_profiles.clone());
+        completions.insert("DATE".to_string(), util::DATE_KEYWORDS.iter().map(|keyword| keyword.to_string()).collect());
+
+        if let Some(module) = self.current_module.as_ref().and_then(|module_id| self.modules.get(module_id)) {
+            completions.extend(module.get_completions().await);
         }
-        else {
-            println!("ERROR: usage: list modules|profiles");
+        completions
+    }
+
+    async fn refresh_completions(&self, candidates: &Candidates) {
+        let completions = self.get_completions().await;
+
+        if let Ok(mut candidates) = candidates.lock() {
+            *candidates = completions;
         }
+    }
 
-        Ok(())
+    fn print_output(&self, output: &Document) {
+        if !output.is_empty() {
+            print!("{}", output.render(self.format));
+        }
     }
 
     pub async fn new() -> anyhow::Result<Cli> {
+        Self::with_modules(ModuleRegistrations::new()).await
+    }
+
+    /// Create a Cli with the given modules, which can include modules from other crates, e.g.
+    /// `Cli::with_modules(ModuleRegistrations::builder().with_compiled_in_modules().with_module(my_module::registration()).build())`
+    pub async fn with_modules(module_registrations: ModuleRegistrations) -> anyhow::Result<Cli> {
 
+        let mut context = MarcoSparkoContext::new(&module_registrations)?;
+        if context.is_locked() {
+            context = Self::ask_to_unlock(&context)?;
+        }
+        let format = context.args.format;
         let mut marco_sparko_manager = Cli {
-            context: MarcoSparkoContext::new()?,
-            module_registrations: ModuleRegistrations::new(), //Self::load_modules(),
+            context,
+            module_registrations,
             modules: HashMap::new(),
             current_module: None,
+            format,
+            variables: BTreeMap::new(),
+            script_depth: 0,
         };
 
+        // A one-shot command initializes only the module it names, in run()
+        if marco_sparko_manager.is_one_shot() {
+            return Ok(marco_sparko_manager)
+        }
+
         let list = marco_sparko_manager.get_module_list();
 
         if list.is_empty() {
@@ -431,6 +1100,17 @@ prints more detailed help on that specific command.
         Ok(marco_sparko_manager)
     }
 
+    /// Ask for the passphrase which unlocks the cache, twice if it is a new one
+    fn ask_to_unlock(context: &Arc<MarcoSparkoContext>) -> anyhow::Result<Arc<MarcoSparkoContext>> {
+        let profile_name = &context.profile.active_profile.name;
+        let passphrase = rpassword::prompt_password(format!("Passphrase for profile {}: ", profile_name))?;
+
+        if context.is_new_key() && rpassword::prompt_password("Confirm passphrase: ")? != passphrase {
+            return Err(anyhow!("The passphrases do not match"))
+        }
+        context.unlock(Some(&passphrase))
+    }
+
     fn get_module_list(&self) -> Vec<String> {
         self.context.args.modules.clone()
     }
@@ -441,52 +1121,127 @@ prints more detailed help on that specific command.
 
 
 
+    fn is_one_shot(&self) -> bool {
+        !self.context.args.command.is_empty()
+    }
+
     pub async fn run(&mut self) -> anyhow::Result<()> {
+        if self.is_one_shot() {
+            let command = self.context.args.command.clone();
+            return self.one_shot(command).await
+        }
+
+        if let Some(script) = self.context.args.script.clone() {
+            return self.run_script(&script, self.context.args.continue_on_error).await
+        }
+
+        if self.context.args.sync {
+            return sync::run(self).await
+        }
+
+        #[cfg(feature = "mqtt")]
+        if self.context.args.mqtt {
+            return mqtt::publish(self).await
+        }
+
+        if self.context.args.serve {
+            let bind = self.context.args.bind.clone();
+            let api_token = self.context.args.api_token.clone();
+            return server::serve(self, &bind, api_token.as_deref()).await
+        }
+
         self.repl().await?;
 
         return Ok(())
     }
 
+    /// Execute a single module command given on the command line, e.g. "octopus bill 12345"
+    async fn one_shot(&mut self, command_line: Vec<String>) -> anyhow::Result<()> {
+        let mut words = command_line.into_iter();
+
+        let module_id = words.next().ok_or(CliError::Usage("usage: module_id command [args]".to_string()))?;
+
+        if module_id == "cache" {
+            let args = cache_command::CacheArgs::try_parse_from(std::iter::once(module_id).chain(words)).map_err(|error| CliError::Usage(error.to_string()))?;
+            let output = cache_command::exec(&self.context, args).await?;
+            self.print_output(&output);
+            return Ok(())
+        }
+
+        if !self.module_registrations.0.contains_key(&module_id) {
+            return Err(CliError::Usage(format!("Unknown module '{}'", module_id)).into())
+        }
+
+        let command = words.next().ok_or(CliError::Usage(format!("usage: {} command [args]", module_id)))?;
+        let args = words.collect::<Vec<String>>();
+
+        if !self.modules.contains_key(&module_id) {
+            if let Err(error) = self.initialize(&module_id).await {
+                return Err(CliError::Initialization(error).into())
+            }
+        }
+
+        let output = self.exec_module_command(&module_id, &command, &args, Some(self.format)).await?;
+        self.print_output(&output);
+        Ok(())
+    }
+
+    /// Execute a command of an active module without switching to its command context
+    async fn exec_module_command(&self, module_id: &str, command: &str, args: &[String], format: Option<OutputFormat>) -> anyhow::Result<Document> {
+        let module = self.modules.get(module_id)
+            .ok_or(CliError::Usage(format!("Module '{}' is not active", module_id)))?;
+
+        let commands = module.get_repl_commands();
+        let repl_command = commands.iter().find(|cmd| cmd.command == command)
+            .ok_or(CliError::Usage(format!("Invalid command '{}' for module '{}'", command, module_id)))?;
+        let matches = repl_command.parse_args(args.iter().map(String::as_str))
+            .map_err(|error| CliError::Usage(error.to_string()))?;
+
+        module.exec_repl_command(command, &matches, format).await
+    }
+
+    /// The commands available in the current command context, including the global ones
+    fn get_current_commands(&self) -> BTreeMap<&'static str, ReplCommand> {
+        let commands = if let Some(module_id) = &self.current_module {
+            if let Some(module) = self.modules.get(module_id) {
+                module.get_repl_commands()
+            }
+            else {
+                Vec::new()
+            }
+        }
+        else {
+            self.get_repl_commands()
+        };
+
+        let mut command_map = BTreeMap::new();
+
+        for cmd in commands.into_iter().chain(self.get_global_repl_commands()) {
+            command_map.insert(cmd.command, cmd);
+        }
+        command_map
+    }
+
     async fn repl(&mut self) -> anyhow::Result<()> {
         let marco_sparko_prompt = "Marco Sparko".to_string();
         
 
         loop {
-            let (module_id, commands) = if let Some(module_id) = &self.current_module {
-                let commands = if let Some(module) = self.modules.get(module_id) {
-                    module.get_repl_commands()
-                }
-                else {
-                    Vec::new()
-                };
-                (&module_id.clone(), commands)
+            let module_id = if let Some(module_id) = &self.current_module {
+                module_id.clone()
             }
             else {
-                (&marco_sparko_prompt, self.get_repl_commands())
+                marco_sparko_prompt.clone()
             };
 
-            let mut command_map = BTreeMap::new();
-            let mut command_list = Vec::new();
-            let mut max_command_len = 0;
-
-            for cmd in commands {
-                if cmd.command.len() > max_command_len {
-                    max_command_len = cmd.command.len();
-                }
-                command_list.push(cmd.command.to_string());
-                command_map.insert(cmd.command, cmd);
-            }
+            let command_map = self.get_current_commands();
+            let command_list: Vec<String> = command_map.keys().map(|name| name.to_string()).collect();
 
-            for cmd in self.get_global_repl_commands() {
-                if cmd.command.len() > max_command_len {
-                    max_command_len = cmd.command.len();
-                }
-                command_list.push(cmd.command.to_string());
-                command_map.insert(cmd.command, cmd);
-            }
-
-
-            let completer = Box::new(DefaultCompleter::new_with_wordlen(command_list.clone(), 2));
+            let candidates = Candidates::default();
+            self.refresh_completions(&candidates).await;
+            let completer = Box::new(ReplCompleter::new(
+                command_map.values().map(|cmd| (cmd.command.to_string(), cmd.args.clone())).collect(),
+                candidates.clone()));
             let validator = Box::new(DefaultValidator);
             // Use the interactive menu to select options from the completer
             let completion_menu = Box::new(ColumnarMenu::default().with_name("completion_menu"));
@@ -511,7 +1266,7 @@ prints more detailed help on that specific command.
                             .with_style(Style::new().italic().fg(Color::LightGray)),
                 )).with_validator(validator)
 
-                .with_highlighter(Box::new(ExampleHighlighter::new(command_list.clone())))
+                .with_highlighter(Box::new(ExampleHighlighter::new(command_list)))
                 .with_completer(completer)
                 .with_partial_completions(true)
                 .with_quick_completions(true)
@@ -522,7 +1277,7 @@ prints more detailed help on that specific command.
 
             let prompt = //SparkoPrompt::new(); //DefaultPrompt::default();
             DefaultPrompt {
-                left_prompt: DefaultPromptSegment::Basic(module_id.clone()),
+                left_prompt: DefaultPromptSegment::Basic(module_id),
                 right_prompt: DefaultPromptSegment::CurrentDateTime,
             };
 
@@ -530,84 +1285,22 @@ prints more detailed help on that specific command.
                 let out = line_editor.read_line(&prompt).unwrap();
                 match out {
                     Signal::Success(content) => {
+                        let result = self.dispatch_line(&content).await;
 
-                        let mut arg_iterator = content.split_whitespace();
-                        if let Some(command) = arg_iterator.next() {
-                            match command {
-                                "quit" => return Ok(()),
-                                "home" => {
-                                    if self.current_module.is_none() {
-                                        println!("You are already in the main command context.")
-                                    }
-                                    else {
-                                        self.current_module = None;
-                                        break;
-                                    }
-                                },
-                                "module" => {
-                                    if let Some(new_module) = arg_iterator.next() {
-                                        if let Some(module_id) = &self.current_module {
-                                            if module_id == new_module {
-                                                println!("You are already in the '{}' command context.", new_module);
-                                                continue;
-                                            }
-                                        }
-                                        if self.module_registrations.0.contains_key(new_module) {
-                                            if self.modules.contains_key(new_module) {
-                                                self.current_module = Some(new_module.to_string());
-                                                break;
-                                            }
-                                            else {
-                                                println!("Module '{}' is inactive", new_module);
-                                            }
-                                        }
-                                        else {
-                                            println!("Unknown module '{}'", new_module);
-                                        }
-                                        break;
-                                    }
-                                    else {
-                                        println!("usage: module module_id");
-                                    }
-                                },
-                                "help" => {
-                                    if let Some(param) = arg_iterator.next() {
-                                        if let Some(cmd) = command_map.get(param) {
-                                            println!("{}", cmd.help);
-                                        }
-                                        else {
-                                            println!("Unrecognized command '{}'", param);
-                                        }
-                                    }
-                                    else {
-                                        for (name, command) in &command_map {
-                                            println!("{:l$} {}", name, command.description, l = max_command_len);
-                                        }
-                                    }
-                                },
-                                _ => {
-
-                                    let result = if let Some(module_id) = &self.current_module {
-                                        let module: &mut Box<dyn Module> = self.modules.get_mut(module_id).unwrap();
-                                        module.exec_repl_command(&command, arg_iterator).await
-                                    }
-                                    else {
-                                        // self.exec_repl_command(&command, arg_iterator).await
-                                        match command {
-                                            "list" => self.list_handler(arg_iterator).await,
-                                            "init" => {
-                                                self.init_handler(arg_iterator).await?;
-                                                break;
-                                            },
-                                            _ => Err(anyhow!(format!("Invalid command '{}'", command)))
-                                        }
-                                    };
-                                    if let Err(error) = result {
-                                        println!("\n\n\nERROR============================================================\n{}", error);
-                                    }
-                                }
-                            }
+                        self.refresh_completions(&candidates).await;
 
+                        match result {
+                            Ok(LineResult::Continue) => {},
+                            Ok(LineResult::ContextChanged) => break,
+                            Ok(LineResult::Quit) => return Ok(()),
+                            Err(error) => {
+                                if let Some(CliError::Usage(message)) = error.downcast_ref::<CliError>() {
+                                    println!("{}", message);
+                                }
+                                else {
+                                    println!("\n\n\nERROR============================================================\n{}", error);
+                                }
+                            },
                         }
                     }
                     Signal::CtrlD => return Ok(()),
@@ -619,6 +1312,225 @@ prints more detailed help on that specific command.
             }
         }
     }
+
+    /// Execute one line of input, from the REPL or a script, in the current command context.
+    ///
+    /// Blank lines and comments (starting with #) are ignored, and variables ($NAME) are replaced by their values before the line is parsed.
+    async fn dispatch_line(&mut self, line: &str) -> anyhow::Result<LineResult> {
+        let line = line.trim();
+
+        if line.is_empty() || line.starts_with('#') {
+            return Ok(LineResult::Continue)
+        }
+
+        let line = self.expand_variables(line).await?;
+        let command_map = self.get_current_commands();
+        let mut arg_iterator = line.split_whitespace();
+
+        let Some(command) = arg_iterator.next() else {
+            return Ok(LineResult::Continue)
+        };
+        let cmd = command_map.get(command)
+            .ok_or(CliError::Usage(format!("Invalid command '{}'", command)))?;
+        let matches = cmd.parse_args(arg_iterator)
+            .map_err(|error| CliError::Usage(error.to_string()))?;
+
+        match command {
+            "quit" => return Ok(LineResult::Quit),
+            "home" => {
+                if self.current_module.is_none() {
+                    println!("You are already in the main command context.")
+                }
+                else {
+                    self.current_module = None;
+                    return Ok(LineResult::ContextChanged)
+                }
+            },
+            "module" => {
+                let new_module = ModuleArgs::from_arg_matches(&matches)?.module_id;
+                if let Some(module_id) = &self.current_module {
+                    if *module_id == new_module {
+                        println!("You are already in the '{}' command context.", new_module);
+                        return Ok(LineResult::Continue)
+                    }
+                }
+                if !self.module_registrations.0.contains_key(&new_module) {
+                    return Err(CliError::Usage(format!("Unknown module '{}'", new_module)).into())
+                }
+                if !self.modules.contains_key(&new_module) {
+                    return Err(CliError::Usage(format!("Module '{}' is inactive", new_module)).into())
+                }
+                self.current_module = Some(new_module);
+                return Ok(LineResult::ContextChanged)
+            },
+            "format" => {
+                self.format_handler(FormatArgs::from_arg_matches(&matches)?)?;
+            },
+            "set" => {
+                let output = self.set_handler(SetArgs::from_arg_matches(&matches)?)?;
+                self.print_output(&output);
+            },
+            "source" => {
+                let args = SourceArgs::from_arg_matches(&matches)?;
+                Box::pin(self.run_script(&args.file, args.continue_on_error)).await?;
+                return Ok(LineResult::ContextChanged)
+            },
+            "help" => {
+                if let Some(param) = HelpArgs::from_arg_matches(&matches)?.command {
+                    if let Some(cmd) = command_map.get(param.as_str()) {
+                        println!("{}", cmd.help);
+                    }
+                    else {
+                        println!("Unrecognized command '{}'", param);
+                    }
+                }
+                else {
+                    let max_command_len = command_map.keys().map(|name| name.len()).max().unwrap_or(0);
+                    for (name, command) in &command_map {
+                        println!("{:l$} {}", name, command.description, l = max_command_len);
+                    }
+                }
+            },
+            _ => {
+                let output = if let Some(module_id) = &self.current_module {
+                    let module = self.modules.get(module_id).unwrap();
+                    module.exec_repl_command(command, &matches, Some(self.format)).await?
+                }
+                else {
+                    match command {
+                        "list" => self.list_handler(ListArgs::from_arg_matches(&matches)?).await?,
+                        "sync" => sync::sync_all(self).await,
+                        "cache" => cache_command::exec(&self.context, cache_command::CacheArgs::from_arg_matches(&matches)?).await?,
+                        "alerts" => {
+                            alerts::check(self).await;
+                            alerts::recent_alerts(&self.context)
+                        },
+                        "consumption" | "spend" | "series" => self.aggregate_handler(command, PeriodArgs::from_arg_matches(&matches)?).await?,
+                        "init" => {
+                            self.init_handler(ModuleArgs::from_arg_matches(&matches)?).await?;
+                            return Ok(LineResult::ContextChanged)
+                        },
+                        "deinit" => {
+                            self.deinit_handler(ModuleArgs::from_arg_matches(&matches)?)?;
+                            return Ok(LineResult::ContextChanged)
+                        },
+                        "reload" => {
+                            self.reload_handler(ModuleArgs::from_arg_matches(&matches)?).await?;
+                            return Ok(LineResult::ContextChanged)
+                        },
+                        "logout" => {
+                            self.logout_handler(LogoutArgs::from_arg_matches(&matches)?)?;
+                            return Ok(LineResult::ContextChanged)
+                        },
+                        _ => return Err(anyhow!(format!("Invalid command '{}'", command)))
+                    }
+                };
+                self.print_output(&output);
+            }
+        }
+        Ok(LineResult::Continue)
+    }
+
+    /// Execute each line of the given file, stopping at the first failure unless continue_on_error is set
+    async fn run_script(&mut self, path: &PathBuf, continue_on_error: bool) -> anyhow::Result<()> {
+        if self.script_depth >= MAX_SCRIPT_DEPTH {
+            return Err(anyhow!("Scripts nested too deeply in {}", path.display()))
+        }
+
+        let script = fs::read_to_string(path)
+            .map_err(|error| anyhow!("Unable to read script {}: {}", path.display(), error))?;
+
+        self.script_depth += 1;
+        let mut failures = 0;
+        let mut result = Ok(());
+
+        for (index, line) in script.lines().enumerate() {
+            match self.dispatch_line(line).await {
+                Ok(LineResult::Quit) => break,
+                Ok(_) => {},
+                Err(error) => {
+                    let error = anyhow!("{}:{}: {}", path.display(), index + 1, error);
+                    if continue_on_error {
+                        eprintln!("{}", error);
+                        failures += 1;
+                    }
+                    else {
+                        result = Err(error);
+                        break;
+                    }
+                },
+            }
+        }
+        self.script_depth -= 1;
+
+        if failures > 0 {
+            result = Err(anyhow!("{} command(s) in {} failed", failures, path.display()));
+        }
+        result
+    }
+
+    /// Replace each $NAME in the line with the value of the variable, which is either one set with the set command or one provided by
+    /// an active module, such as $LATEST_BILL.
+    async fn expand_variables(&self, line: &str) -> anyhow::Result<String> {
+        let mut result = String::new();
+        let mut rest = line;
+
+        while let Some(index) = rest.find('$') {
+            result.push_str(&rest[..index]);
+            rest = &rest[index + 1..];
+
+            let len = rest.find(|c: char| !(c.is_ascii_alphanumeric() || c == '_')).unwrap_or(rest.len());
+            if len == 0 {
+                result.push('$');
+                continue;
+            }
+
+            let name = &rest[..len];
+            let value = self.get_variable(name).await?
+                .ok_or(CliError::Usage(format!("Undefined variable '${}'", name)))?;
+
+            result.push_str(&value);
+            rest = &rest[len..];
+        }
+        result.push_str(rest);
+        Ok(result)
+    }
+
+    async fn get_variable(&self, name: &str) -> anyhow::Result<Option<String>> {
+        if let Some(value) = self.variables.get(name) {
+            return Ok(Some(value.clone()))
+        }
+
+        // Ask the current module first, then any other active module
+        let current = self.current_module.as_ref().and_then(|module_id| self.modules.get(module_id));
+        let others = self.modules.iter()
+            .filter(|(module_id, _)| Some(*module_id) != self.current_module.as_ref())
+            .map(|(_, module)| module);
+
+        for module in current.into_iter().chain(others) {
+            if let Some(value) = module.get_variable(name).await? {
+                return Ok(Some(value))
+            }
+        }
+        Ok(None)
+    }
+
+    fn set_handler(&mut self, args: SetArgs) -> anyhow::Result<Document> {
+        if let Some(name) = args.name {
+            if args.value.is_empty() {
+                return Err(CliError::Usage("usage: set [name value]".to_string()).into())
+            }
+            self.variables.insert(name, args.value.join(" "));
+            Ok(Document::new())
+        }
+        else {
+            let mut table = Table::new(&["Name", "Value"]);
+            for (name, value) in &self.variables {
+                table.push(vec!(Value::from(name.as_str()), Value::from(value.as_str())));
+            }
+            Ok(table.into())
+        }
+    }
     
     async fn initialize(&mut self, module_id: &String) -> anyhow::Result<()> {
         let module = Self::do_initialize(module_id, &self.module_registrations, &self.context).await?;
@@ -642,7 +1554,8 @@ prints more detailed help on that specific command.
                 None
             };
 
-            println!("Initializing module '{}' with profile '{:?}'", module_id, profile);
+            // The profile may hold credentials, so it isn't logged
+            debug!("Initializing module '{}'", module_id);
             let builder = constructor(context.clone(), profile)?;
             let module = builder.build().await?;
             
@@ -666,7 +1579,8 @@ prints more detailed help on that specific command.
                 None
             };
 
-            println!("Initializing module '{}' with profile '{:?}'", module_id, profile);
+            // The profile may hold credentials, so it isn't logged
+            debug!("Initializing module '{}'", module_id);
             let builder = constructor(context.clone(), profile)?;
 
             Ok(builder)
@@ -676,3 +1590,67 @@ prints more detailed help on that specific command.
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn test_cli() -> Cli {
+        let context = Arc::new(MarcoSparkoContext {
+            args: Args::parse_from(["marco-sparko"]),
+            matches: Args::command().get_matches_from(["marco-sparko"]),
+            profile: ActiveProfile::new(),
+            cipher: None,
+        });
+
+        Cli {
+            context,
+            module_registrations: ModuleRegistrations::builder().build(),
+            modules: HashMap::new(),
+            current_module: None,
+            format: OutputFormat::Text,
+            variables: BTreeMap::new(),
+            script_depth: 0,
+        }
+    }
+
+    #[tokio::test]
+    async fn test_expand_variables() {
+        let mut cli = test_cli();
+        cli.dispatch_line("set FROM 2025-01-01").await.unwrap();
+
+        assert_eq!(cli.expand_variables("bill --from $FROM $ 10$").await.unwrap(), "bill --from 2025-01-01 $ 10$");
+        assert!(cli.expand_variables("bill $UNDEFINED").await.is_err());
+    }
+
+    #[tokio::test]
+    async fn test_source_and_errors() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("script.txt");
+        fs::write(&script, "# a comment\nset FROM 2025-01-01\nset TO $FROM\n").unwrap();
+
+        let mut cli = test_cli();
+        cli.dispatch_line(&format!("source {}", script.display())).await.unwrap();
+        assert_eq!(cli.variables.get("TO").map(String::as_str), Some("2025-01-01"));
+
+        // a failed init stops the script unless it carries on after errors
+        fs::write(&script, "init nonexistent\nset AFTER yes\n").unwrap();
+        let mut cli = test_cli();
+        assert!(cli.run_script(&script, false).await.is_err());
+        assert!(!cli.variables.contains_key("AFTER"));
+        assert!(cli.run_script(&script, true).await.is_err());
+        assert!(cli.variables.contains_key("AFTER"));
+    }
+
+    #[tokio::test]
+    async fn test_script_depth() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let script = dir.path().join("recursive.txt");
+        fs::write(&script, format!("source {}\n", script.display())).unwrap();
+
+        let mut cli = test_cli();
+        let error = cli.run_script(&script, false).await.unwrap_err();
+        assert!(error.to_string().contains("nested too deeply"));
+        assert_eq!(cli.script_depth, 0);
+    }
+}
@@ -0,0 +1,93 @@
+use std::path::PathBuf;
+use std::sync::Once;
+
+use anyhow::anyhow;
+use dirs::home_dir;
+use tracing_appender::rolling::{RollingFileAppender, Rotation};
+use tracing_subscriber::EnvFilter;
+
+use crate::profile::Profile;
+use crate::Args;
+
+/* ***************************************************************************************************************************************************************
+ * Diagnostic logging.
+ *
+ * Log output goes to a daily log file in ~/.marco-sparko-cache/logs, never to the terminal, so that it doesn't get mixed up with command output in the REPL or
+ * disrupt the GUI. Events are logged with the tracing macros, and their target is the module path (e.g. marco_sparko::cache_manager) so the level can be set
+ * per module with a filter like "info,marco_sparko::cache_manager=trace".
+ *
+ * The filter is taken from the first of these which is set:
+ *  - the MARCO_SPARKO_LOG environment variable
+ *  - the --debug and --verbose command line options
+ *  - the logLevel setting of the active profile
+ *************************************************************************************************************************************************************** */
+
+pub const LOG_ENV_VAR: &str = "MARCO_SPARKO_LOG";
+
+const DEFAULT_FILTER: &str = "warn";
+const LOG_FILE_PREFIX: &str = "marco-sparko";
+const MAX_LOG_FILES: usize = 7;
+
+static INIT: Once = Once::new();
+
+/// Start logging, the first call takes effect and any later ones (e.g. when the GUI switches profile) are ignored
+pub fn init(args: &Args, profile: &Profile) {
+    INIT.call_once(|| {
+        let filter = filter_directives(std::env::var(LOG_ENV_VAR).ok(), args.debug, args.verbose, profile.log_level.as_deref());
+
+        if let Err(error) = start(&filter) {
+            eprintln!("Unable to start logging: {}", error);
+        }
+    });
+}
+
+fn start(filter: &str) -> anyhow::Result<()> {
+    let appender = RollingFileAppender::builder()
+        .rotation(Rotation::DAILY)
+        .filename_prefix(LOG_FILE_PREFIX)
+        .filename_suffix("log")
+        .max_log_files(MAX_LOG_FILES)
+        .build(log_dir_path()?)?;
+
+    tracing_subscriber::fmt()
+        .with_env_filter(EnvFilter::try_new(filter)?)
+        .with_writer(appender)
+        .with_ansi(false)
+        .try_init()
+        .map_err(|error| anyhow!(error))
+}
+
+fn log_dir_path() -> anyhow::Result<PathBuf> {
+    let mut path = home_dir().ok_or(anyhow!("Unable to locate home directory"))?;
+    path.push(".marco-sparko-cache");
+    path.push("logs");
+    Ok(path)
+}
+
+fn filter_directives(env: Option<String>, debug: bool, verbose: bool, setting: Option<&str>) -> String {
+    if let Some(env) = env {
+        return env
+    }
+
+    // Only our own crate gets the more detailed levels, the libraries we use are too noisy at debug
+    match (debug, verbose) {
+        (true, true) => format!("{},marco_sparko=trace", DEFAULT_FILTER),
+        (true, false) => format!("{},marco_sparko=debug", DEFAULT_FILTER),
+        (false, true) => format!("{},marco_sparko=info", DEFAULT_FILTER),
+        (false, false) => setting.unwrap_or(DEFAULT_FILTER).to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_filter_directives() {
+        assert_eq!(filter_directives(None, false, false, None), "warn");
+        assert_eq!(filter_directives(None, false, false, Some("info")), "info");
+        assert_eq!(filter_directives(None, true, false, Some("info")), "warn,marco_sparko=debug");
+        assert_eq!(filter_directives(None, true, true, None), "warn,marco_sparko=trace");
+        assert_eq!(filter_directives(Some("error".to_string()), true, false, Some("info")), "error");
+    }
+}
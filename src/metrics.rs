@@ -0,0 +1,220 @@
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::future::Future;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use rust_decimal::Decimal;
+use sparko_graphql::types::{Date, DateRange};
+use time::OffsetDateTime;
+
+use crate::aggregate::DataSources;
+use crate::system::EnergyDataSource;
+
+/* ***************************************************************************************************************************************************************
+ * Metrics in the Prometheus text exposition format, served at /metrics by the API server.
+ *
+ * The gauges are read from each module's EnergyDataSource by a scrape, and kept for the following scrapes until they are older than the interval given
+ * with --metrics-interval, so that a short scrape interval doesn't make a call to the provider's API every time. They are only as fresh as the data which the
+ * module has in any case (most providers publish half hourly consumption some hours after the event). The API call counters are kept in memory by
+ * count_api_call() for the life of the process, and are always current.
+ *************************************************************************************************************************************************************** */
+
+pub const CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+#[derive(Default, Clone, Copy)]
+struct ApiCallCounts {
+    calls: u64,
+    errors: u64,
+}
+
+static API_CALLS: Mutex<BTreeMap<&'static str, ApiCallCounts>> = Mutex::new(BTreeMap::new());
+
+/// The rendered gauges and when they were read, held by a scrape while it reads them so that scrapes at the same time read them once
+static GAUGES: tokio::sync::Mutex<Option<(Instant, String)>> = tokio::sync::Mutex::const_new(None);
+
+/// Count a call made by a module to its provider's API, and whether it failed
+pub async fn count_api_call<T, E>(module_id: &'static str, call: impl Future<Output = Result<T, E>>) -> Result<T, E> {
+    let result = call.await;
+
+    if let Ok(mut api_calls) = API_CALLS.lock() {
+        let counts = api_calls.entry(module_id).or_default();
+        counts.calls += 1;
+        if result.is_err() {
+            counts.errors += 1;
+        }
+    }
+    result
+}
+
+/// One metric and its samples, each of which is a set of labels and a value
+struct Metric {
+    name: &'static str,
+    help: &'static str,
+    metric_type: &'static str,
+    samples: Vec<(Vec<(&'static str, String)>, String)>,
+}
+
+impl Metric {
+    fn new(name: &'static str, metric_type: &'static str, help: &'static str) -> Metric {
+        Metric {
+            name,
+            help,
+            metric_type,
+            samples: Vec::new(),
+        }
+    }
+
+    fn push<T: ToString>(&mut self, labels: Vec<(&'static str, String)>, value: T) {
+        self.samples.push((labels, value.to_string()));
+    }
+
+    fn render(&self, out: &mut String) {
+        if self.samples.is_empty() {
+            return
+        }
+
+        let _ = writeln!(out, "# HELP {} {}", self.name, self.help);
+        let _ = writeln!(out, "# TYPE {} {}", self.name, self.metric_type);
+        for (labels, value) in &self.samples {
+            let labels: Vec<String> = labels.iter()
+                .map(|(name, value)| format!("{}=\"{}\"", name, escape(value)))
+                .collect();
+            let _ = writeln!(out, "{}{{{}}} {}", self.name, labels.join(","), value);
+        }
+    }
+}
+
+fn escape(value: &str) -> String {
+    value.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+/// The metrics of all the given modules, reading the gauges again if they are older than interval
+pub async fn render(sources: &DataSources<'_>, interval: Duration) -> String {
+    let mut gauges = GAUGES.lock().await;
+    if !gauges.as_ref().is_some_and(|(read_at, _)| read_at.elapsed() < interval) {
+        *gauges = Some((Instant::now(), read_gauges(sources).await));
+    }
+    let mut out = gauges.as_ref().map(|(_, out)| out.clone()).unwrap_or_default();
+    drop(gauges);
+
+    let mut api_calls = Metric::new("marco_sparko_api_calls_total", "counter", "Calls made to the provider's API");
+    let mut api_errors = Metric::new("marco_sparko_api_errors_total", "counter", "Calls made to the provider's API which failed");
+    if let Ok(counts) = API_CALLS.lock() {
+        for (module_id, counts) in counts.iter() {
+            api_calls.push(vec!(("module", module_id.to_string())), counts.calls);
+            api_errors.push(vec!(("module", module_id.to_string())), counts.errors);
+        }
+    }
+    api_calls.render(&mut out);
+    api_errors.render(&mut out);
+    out
+}
+
+/// The gauges of all the given modules, read from their data sources
+async fn read_gauges(sources: &DataSources<'_>) -> String {
+    let mut scrape_errors = Metric::new("marco_sparko_scrape_errors", "gauge", "Number of the module's metrics which could not be read in this scrape");
+    let mut demand = Metric::new("marco_sparko_demand_watts", "gauge", "Latest power demand reported by a smart meter");
+    let mut consumption = Metric::new("marco_sparko_consumption_latest", "gauge", "Consumption (or export) in the latest interval for which a meter has data, in kWh for electricity");
+    let mut consumption_end = Metric::new("marco_sparko_consumption_latest_end_timestamp_seconds", "gauge", "End of the latest interval for which a meter has data");
+    let mut unit_rate = Metric::new("marco_sparko_unit_rate_pence", "gauge", "Current unit rate of a meter's tariff, including VAT");
+    let mut standing_charge = Metric::new("marco_sparko_standing_charge_pence", "gauge", "Current daily standing charge of a meter's tariff, including VAT");
+    let mut balance = Metric::new("marco_sparko_balance_pounds", "gauge", "Account balance at the end of the latest statement");
+
+    for (module_id, source) in sources {
+        let module = || vec!(("module", module_id.to_string()));
+        let mut errors = 0;
+
+        match source.get_current_demand().await {
+            Ok(readings) => {
+                for reading in readings {
+                    demand.push(vec!(("module", module_id.to_string()), ("device", reading.device_id)), reading.demand);
+                }
+            },
+            Err(_) => errors += 1,
+        }
+
+        if meter_metrics(*source, module_id, &mut consumption, &mut consumption_end, &mut unit_rate, &mut standing_charge).await.is_err() {
+            errors += 1;
+        }
+
+        match source.get_bills().await {
+            Ok(bills) => {
+                if let Some(closing_balance) = bills.iter()
+                    .filter(|bill| bill.closing_balance.is_some())
+                    .max_by_key(|bill| *bill.to_date)
+                    .and_then(|bill| bill.closing_balance) {
+                    balance.push(module(), closing_balance);
+                }
+            },
+            Err(_) => errors += 1,
+        }
+
+        scrape_errors.push(module(), errors);
+    }
+
+    let mut out = String::new();
+    for metric in [scrape_errors, demand, consumption, consumption_end, unit_rate, standing_charge, balance] {
+        metric.render(&mut out);
+    }
+    out
+}
+
+async fn meter_metrics(source: &dyn EnergyDataSource, module_id: &str, consumption: &mut Metric, consumption_end: &mut Metric,
+    unit_rate: &mut Metric, standing_charge: &mut Metric) -> anyhow::Result<()> {
+    let now = OffsetDateTime::now_utc();
+    // Data is often a day or more behind, so look back far enough to find the latest interval
+    let today = now.date();
+    let week_ago = today - time::Duration::days(7);
+    let date_range = DateRange {
+        start: Date::from_calendar_date(week_ago.year(), week_ago.month(), week_ago.day())?,
+        end: Date::from_calendar_date(today.year(), today.month(), today.day())?,
+    };
+
+    for meter_point in source.get_meter_points().await? {
+        let labels = || vec!(
+            ("module", module_id.to_string()),
+            ("meter_point", meter_point.name.clone()),
+            ("commodity", format!("{:?}", meter_point.commodity)),
+            ("meter_type", format!("{:?}", meter_point.meter_type)),
+        );
+
+        if let Some(latest) = source.get_consumption(&meter_point.id, &date_range).await?.last() {
+            consumption.push(labels(), latest.value.round_dp(3));
+            consumption_end.push(labels(), latest.end.unix_timestamp());
+        }
+
+        for tariff in source.get_tariffs(&meter_point.id).await? {
+            let current = *tariff.valid_from <= now && tariff.valid_to.as_ref().map_or(true, |valid_to| **valid_to > now);
+            if !current {
+                continue;
+            }
+
+            let mut tariff_labels = labels();
+            tariff_labels.push(("tariff", tariff.code.clone()));
+
+            if let Some(rate) = tariff.unit_rate {
+                unit_rate.push(tariff_labels.clone(), Decimal::try_from(rate).unwrap_or_default().round_dp(4));
+            }
+            if let Some(charge) = tariff.standing_charge {
+                standing_charge.push(tariff_labels, Decimal::try_from(charge).unwrap_or_default().round_dp(4));
+            }
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_render_metric() {
+        let mut metric = Metric::new("test_metric", "gauge", "A test");
+        metric.push(vec!(("module", "octopus".to_string()), ("device", "a\"b".to_string())), 42);
+
+        let mut out = String::new();
+        metric.render(&mut out);
+        assert_eq!(out, "# HELP test_metric A test\n# TYPE test_metric gauge\ntest_metric{module=\"octopus\",device=\"a\\\"b\"} 42\n");
+    }
+}
@@ -0,0 +1,234 @@
+use std::collections::HashSet;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use rumqttc::{AsyncClient, MqttOptions, QoS};
+use rust_decimal::Decimal;
+use serde_json::json;
+use sparko_graphql::types::{Date, DateRange};
+use time::OffsetDateTime;
+use tracing::{debug, info, warn};
+
+use crate::profile::MqttSettings;
+use crate::system::{Commodity, EnergyDataSource};
+use crate::Cli;
+
+/* ***************************************************************************************************************************************************************
+ * Publisher of energy data to an MQTT broker, with Home Assistant discovery.
+ *
+ * Every interval the data of each active module is read through its EnergyDataSource and the value of each sensor is published, retained, to
+ * TOPIC_PREFIX/MODULE/SENSOR/state. The first time a sensor is seen its Home Assistant discovery configuration is published to
+ * DISCOVERY_PREFIX/sensor/marco_sparko_MODULE/SENSOR/config so that it appears in Home Assistant without any manual configuration. The sensors of each
+ * module are grouped into one Home Assistant device.
+ *************************************************************************************************************************************************************** */
+
+const CLIENT_ID: &str = "marco-sparko";
+
+/// The value of one sensor, and the Home Assistant attributes which describe it
+#[derive(Debug, PartialEq)]
+struct Sensor {
+    object_id: String,
+    name: String,
+    value: String,
+    unit: &'static str,
+    /// None if no Home Assistant device class fits, e.g. a price per kWh since monetary sensors must be in a currency
+    device_class: Option<&'static str>,
+    state_class: Option<&'static str>,
+}
+
+impl Sensor {
+    fn new(object_id: &str, name: String, value: Decimal, unit: &'static str, device_class: Option<&'static str>, state_class: Option<&'static str>) -> Sensor {
+        Sensor {
+            object_id: object_id_from(object_id),
+            name,
+            value: value.normalize().to_string(),
+            unit,
+            device_class,
+            state_class,
+        }
+    }
+}
+
+/// Home Assistant object ids may only contain lower case letters, digits and underscores
+fn object_id_from(text: &str) -> String {
+    text.chars()
+        .map(|c| if c.is_ascii_alphanumeric() { c.to_ascii_lowercase() } else { '_' })
+        .collect()
+}
+
+fn state_topic(settings: &MqttSettings, module_id: &str, sensor: &Sensor) -> String {
+    format!("{}/{}/{}/state", settings.topic_prefix, module_id, sensor.object_id)
+}
+
+fn discovery_topic(settings: &MqttSettings, module_id: &str, sensor: &Sensor) -> String {
+    format!("{}/sensor/marco_sparko_{}/{}/config", settings.discovery_prefix, object_id_from(module_id), sensor.object_id)
+}
+
+fn discovery_config(settings: &MqttSettings, module_id: &str, sensor: &Sensor) -> serde_json::Value {
+    let mut config = json!({
+        "name": sensor.name,
+        "unique_id": format!("marco_sparko_{}_{}", object_id_from(module_id), sensor.object_id),
+        "state_topic": state_topic(settings, module_id, sensor),
+        "unit_of_measurement": sensor.unit,
+        "device": {
+            "identifiers": [format!("marco_sparko_{}", object_id_from(module_id))],
+            "name": format!("Marco Sparko {}", module_id),
+            "manufacturer": "Marco Sparko",
+            "sw_version": env!("CARGO_PKG_VERSION"),
+        },
+    });
+
+    if let Some(device_class) = sensor.device_class {
+        config["device_class"] = json!(device_class);
+    }
+    if let Some(state_class) = sensor.state_class {
+        config["state_class"] = json!(state_class);
+    }
+    config
+}
+
+/// Publish the data of the active modules until the process is killed
+pub async fn publish(cli: &Cli) -> anyhow::Result<()> {
+    let settings = cli.context.profile.active_profile.mqtt.clone()
+        .ok_or(anyhow!("There are no MQTT settings in profile '{}'", cli.context.profile.active_profile.name))?;
+
+    let mut options = MqttOptions::new(CLIENT_ID, &settings.host, settings.port);
+    options.set_keep_alive(Duration::from_secs(30));
+    if let Some(username) = &settings.username {
+        options.set_credentials(username, settings.password.clone().unwrap_or_default());
+    }
+
+    let (client, mut event_loop) = AsyncClient::new(options, 100);
+
+    // The event loop does the actual network I/O, and reconnects if the connection to the broker is lost
+    tokio::spawn(async move {
+        loop {
+            if let Err(error) = event_loop.poll().await {
+                warn!("MQTT connection failed: {}", error);
+                tokio::time::sleep(Duration::from_secs(5)).await;
+            }
+        }
+    });
+
+    info!("Publishing to MQTT broker {}:{} every {}s", settings.host, settings.port, settings.interval);
+    println!("Publishing to MQTT broker {}:{} every {}s", settings.host, settings.port, settings.interval);
+
+    let mut announced = HashSet::new();
+    let mut interval = tokio::time::interval(Duration::from_secs(settings.interval.max(10)));
+
+    loop {
+        interval.tick().await;
+
+        for (module_id, source) in cli.data_sources() {
+            let sensors = match read_sensors(source).await {
+                Ok(sensors) => sensors,
+                Err(error) => {
+                    warn!("Unable to read sensors of module {}: {}", module_id, error);
+                    continue;
+                },
+            };
+
+            // The broker may be unreachable for a while, the sensors are published again on the next tick
+            if let Err(error) = publish_sensors(&client, &settings, module_id, sensors, &mut announced).await {
+                warn!("Unable to publish sensors of module {}: {}", module_id, error);
+            }
+        }
+
+        crate::alerts::check(cli).await;
+    }
+}
+
+async fn publish_sensors(client: &AsyncClient, settings: &MqttSettings, module_id: &str, sensors: Vec<Sensor>, announced: &mut HashSet<(String, String)>)
+    -> anyhow::Result<()> {
+    for sensor in sensors {
+        let key = (module_id.to_string(), sensor.object_id.clone());
+        if !announced.contains(&key) {
+            client.publish(discovery_topic(settings, module_id, &sensor), QoS::AtLeastOnce, true,
+                discovery_config(settings, module_id, &sensor).to_string()).await?;
+            announced.insert(key);
+        }
+
+        debug!("Publish {}/{} = {}", module_id, sensor.object_id, sensor.value);
+        client.publish(state_topic(settings, module_id, &sensor), QoS::AtLeastOnce, true, sensor.value).await?;
+    }
+    Ok(())
+}
+
+async fn read_sensors(source: &dyn EnergyDataSource) -> anyhow::Result<Vec<Sensor>> {
+    let mut sensors = Vec::new();
+
+    for demand in source.get_current_demand().await? {
+        sensors.push(Sensor::new(&format!("demand_{}", demand.device_id), format!("Demand {}", demand.device_id),
+            demand.demand, "W", Some("power"), Some("measurement")));
+    }
+
+    let now = OffsetDateTime::now_utc();
+    let today = Date::from_calendar_date(now.year(), now.month(), now.day())?;
+
+    for meter_point in source.get_meter_points().await? {
+        let direction = if meter_point.meter_type == crate::system::MeterType::Export { "export" } else { "consumption" };
+        let commodity = match meter_point.commodity {
+            Commodity::Electricity => "electricity",
+            Commodity::Gas => "gas",
+            Commodity::Water => continue,
+        };
+
+        let consumption: Decimal = source.get_consumption(&meter_point.id, &DateRange { start: today.clone(), end: today.clone() }).await?
+            .iter()
+            .map(|item| item.value)
+            .sum();
+        sensors.push(Sensor::new(&format!("{}_{}_today_{}", commodity, direction, meter_point.name),
+            format!("{} {} today {}", commodity, direction, meter_point.name), consumption.round_dp(3), "kWh", Some("energy"), Some("total_increasing")));
+
+        for tariff in source.get_tariffs(&meter_point.id).await? {
+            let current = *tariff.valid_from <= now && tariff.valid_to.as_ref().map_or(true, |valid_to| **valid_to > now);
+            if !current {
+                continue;
+            }
+            if let Some(unit_rate) = tariff.unit_rate.and_then(|rate| Decimal::try_from(rate).ok()) {
+                sensors.push(Sensor::new(&format!("{}_unit_rate_{}", commodity, meter_point.name),
+                    format!("{} unit rate {}", commodity, meter_point.name), unit_rate.round_dp(4) / Decimal::ONE_HUNDRED, "GBP/kWh", None, None));
+            }
+            if let Some(standing_charge) = tariff.standing_charge.and_then(|charge| Decimal::try_from(charge).ok()) {
+                sensors.push(Sensor::new(&format!("{}_standing_charge_{}", commodity, meter_point.name),
+                    format!("{} standing charge {}", commodity, meter_point.name), standing_charge.round_dp(4) / Decimal::ONE_HUNDRED, "GBP", Some("monetary"), None));
+            }
+        }
+    }
+
+    if let Some(bill) = source.get_bills().await?.into_iter().max_by_key(|bill| *bill.to_date) {
+        for (object_id, name, amount) in [
+            ("last_bill_charges", "Last bill charges", bill.charges),
+            ("last_bill_credits", "Last bill credits", bill.credits),
+            ("balance", "Balance", bill.closing_balance),
+        ] {
+            if let Some(amount) = amount {
+                sensors.push(Sensor::new(object_id, name.to_string(), amount, "GBP", Some("monetary"), None));
+            }
+        }
+    }
+
+    Ok(sensors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_discovery_config() {
+        let settings: MqttSettings = serde_json::from_str(r#"{"host": "localhost"}"#).unwrap();
+        let sensor = Sensor::new("demand_00-11-22", "Demand 00-11-22".to_string(), Decimal::new(12345, 1), "W", Some("power"), Some("measurement"));
+
+        assert_eq!(sensor.object_id, "demand_00_11_22");
+        assert_eq!(sensor.value, "1234.5");
+        assert_eq!(discovery_topic(&settings, "octopus", &sensor), "homeassistant/sensor/marco_sparko_octopus/demand_00_11_22/config");
+
+        let config = discovery_config(&settings, "octopus", &sensor);
+        assert_eq!(config["state_topic"], "marco-sparko/octopus/demand_00_11_22/state");
+        assert_eq!(config["unique_id"], "marco_sparko_octopus_demand_00_11_22");
+        assert_eq!(config["state_class"], "measurement");
+        assert_eq!(config["device_class"], "power");
+        assert_eq!(config["device"]["identifiers"][0], "marco_sparko_octopus");
+    }
+}
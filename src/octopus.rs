@@ -5,24 +5,29 @@ pub mod decimal;
 mod account;
 mod bill;
 mod meter;
+mod data_source;
 
+use std::collections::HashMap;
 use std::sync::Arc;
 
 use anyhow::anyhow;
+use tracing::{debug, error, info};
 use account::AccountManager;
 use async_trait::async_trait;
 
 use dioxus::prelude::*;
 
-use bill::BillManager;
-use meter::MeterManager;
+use bill::{BillArgs, BillManager};
+use meter::{ConsumptionArgs, MeterManager};
 use serde::{Deserialize, Serialize};
 
 use time_tz::{Tz, timezones};
 use token::{OctopusTokenManager};
-use clap::Parser;
+use clap::{ArgMatches, Args, CommandFactory, FromArgMatches};
 
 use sparko_graphql::TokenManager;
+use crate::output::{Document, OutputFormat};
+use crate::system::EnergyDataSource;
 use crate::{CacheManager, CommandProvider, MarcoSparkoContext, Module, ModuleFactory, ModuleRegistration, PageInfo, ReplCommand, octopus::{bill::{AbstractBill, BillList}, token::OctopusAuthenticator}};
 
 // include!("octopus/graphql.rs");
@@ -35,10 +40,10 @@ compile_error!("graphql built with Errors");
 
 pub type RequestManager = sparko_graphql::AuthenticatedRequestManager<OctopusTokenManager>;
 
-#[derive(Parser, Debug, Clone, PartialEq)]
+#[derive(Args, Debug, Clone, PartialEq)]
 pub struct OctopusArgs {
     /// The Octopus API_KEY to use
-    #[arg(short, long, env)]
+    #[arg(long, env)]
     octopus_api_key: Option<String>
 }
 
@@ -71,30 +76,64 @@ pub struct OctopusModule{
 }
 
 const MODULE_ID: &str = "octopus";
+/// The data sets which --refresh can fetch again
+const CACHE_DATA_SETS: &[&str] = &[account::VIEWER_FRESHNESS.data_set, meter::PROPERTIES_FRESHNESS.data_set, meter::METER_AGREEMENTS_DATA_SET];
 
 #[async_trait(?Send)]
 impl CommandProvider for OctopusModule {
-    async fn exec_repl_command(&mut self, command: &str, args: std::str::SplitWhitespace<'_>) ->  anyhow::Result<()> {
+    async fn exec_repl_command(&self, command: &str, args: &ArgMatches, format: Option<OutputFormat>) ->  anyhow::Result<Document> {
         let account_id = self.account_id.clone();
         match command {
             "bills" => {
                 Ok(self.bill_manager
-                .bills_handler(args, account_id)
+                .bills_handler(account_id)
                 .await?)
             },
             "bill" => {
-                Ok(self.bill_manager.bill_handler(args, account_id, self.billing_timezone).await?)
+                Ok(self.bill_manager.bill_handler(BillArgs::from_arg_matches(args)?, account_id, self.billing_timezone).await?)
             },
             "demand" => {
-                Ok(self.meter_manager.demand_handler(args, &account_id).await?)
+                Ok(self.meter_manager.demand_handler(&account_id, format).await?)
             },
             "consumption" => {
-                Ok(self.meter_manager.consumption_handler(args, &account_id, self.billing_timezone).await?)
+                Ok(self.meter_manager.consumption_handler(ConsumptionArgs::from_arg_matches(args)?, &account_id, self.billing_timezone).await?)
             },
             _ => Err(anyhow!(format!("Invalid command '{}'", command)))
         }
     }
 
+    async fn get_completions(&self) -> HashMap<String, Vec<String>> {
+        let mut completions = HashMap::new();
+
+        // Only offer bills which have already been loaded, rather than calling the API on every prompt
+        if let Some(bills) = self.bill_manager.bills.lock().await.get(&self.account_id) {
+            completions.insert("BILL_ID".to_string(), bills.bills.keys().cloned().collect());
+        }
+        if let Ok(properties) = self.meter_manager.get_properties(&self.account_id).await {
+            completions.insert("METER_NODE_ID".to_string(), properties.meter_node_ids);
+        }
+        completions
+    }
+
+    async fn get_variable(&self, name: &str) -> anyhow::Result<Option<String>> {
+        match name {
+            "ACCOUNT" => Ok(Some(self.account_id.clone())),
+            "LATEST_BILL" | "LATEST_BILL_FROM" | "LATEST_BILL_TO" => {
+                let bills = self.bill_manager.fetch_bills(self.account_id.clone()).await?;
+
+                Ok(bills.bills.values().last().map(|(_, bill)| {
+                    let bill = bill.as_bill_interface();
+                    match name {
+                        "LATEST_BILL" => bill.id_.clone(),
+                        "LATEST_BILL_FROM" => bill.from_date_.to_string(),
+                        _ => bill.to_date_.to_string(),
+                    }
+                }))
+            },
+            _ => Ok(None),
+        }
+    }
+
     fn get_repl_commands(&self) -> Vec<ReplCommand> {
         vec!(
             ReplCommand {
@@ -106,6 +145,7 @@ usage: bills
 
 Print a one line summary of all bills in the account.
 "#,
+                args: clap::Command::default(),
             },
 
             ReplCommand {
@@ -117,6 +157,7 @@ usage: bill [bill_id]
 
 Print the contents of the bill whose id is given, or the most recent bill, if none.
 "#,
+                args: BillArgs::command(),
             },
 
             ReplCommand {
@@ -128,6 +169,7 @@ usage: demand
 
 Print the current electricity demand (power imported from or exported to the grid)
 "#,
+                args: clap::Command::default(),
             },
 
             ReplCommand {
@@ -135,10 +177,13 @@ Print the current electricity demand (power imported from or exported to the gri
                 description: "Print electricity consumption",
                 help:
 r#"
-usage: demand
+usage: consumption [--meter meter_node_id] [from [to]]
 
-Print the current electricity consumption
+Print electricity consumption for each meter, or only the given meter, over a period. The period is either a keyword
+(today, yesterday, this-week, last-week, this-month, last-month, this-year or last-year) or a start date and an optional
+end date in the form YYYY-MM-DD, which defaults to today. The default period is this-month.
 "#,
+                args: ConsumptionArgs::command(),
             }
         )
     }
@@ -187,6 +232,8 @@ impl OctopusModule {
         ModuleRegistration {
             module_id: MODULE_ID.to_string(),
             constructor: Arc::new(OctopusModule::constructor),
+            args: OctopusArgs::augment_args,
+            cache_data_sets: CACHE_DATA_SETS,
         }
     }
     
@@ -223,6 +270,10 @@ impl Module for OctopusModule {
         MODULE_ID
     }
 
+    fn energy_data_source(&self) -> Option<&dyn EnergyDataSource> {
+        Some(self)
+    }
+
     fn get_page_list(&self) -> Vec<PageInfo> {
         vec!(
             PageInfo {
@@ -531,7 +582,7 @@ impl OctopusModuleFactory {
 
             match token_manager.get_authenticator(true).await {
                 Ok(_token) => {
-                    println!("Logged in OK");
+                    info!("Logged in to Octopus");
                     Ok(())
                 },
                 Err(error) => {
@@ -565,8 +616,6 @@ impl OctopusModuleFactory {
         error_signal: &mut Signal<Vec<String>>) {
         let mut errors = Vec::new();
 
-        println!("Octopus login submitted for: {:?}", values);
-        
         let login_method = values.login_method.trim().to_string();
         
         if login_method == "email" {
@@ -581,7 +630,7 @@ impl OctopusModuleFactory {
             }
 
             if errors.is_empty() {
-                println!("Performing login for email: {}", email);
+                debug!("Performing login with email and password");
                 token_manager.set_authenticator(
                     OctopusAuthenticator::from_email_password(email.clone(), password.clone())
                 ).await;
@@ -602,21 +651,20 @@ impl OctopusModuleFactory {
             }
 
             if errors.is_empty() {
-                println!("Performing login with API key");
+                debug!("Performing login with API key");
                 token_manager.set_authenticator(
                     OctopusAuthenticator::from_api_key(api_key.clone())
                 ).await;
                 // match token_manager.get_authenticator(true).await {
                 match Self::login(&mut errors, &token_manager).await {
                     Ok(_authenticator) => {
-                        println!("Login successful!");
                         // Store the api_key into the profile
                         let new_profile = Profile {
                             api_key: Some(values.api_key.as_ref().unwrap_or(&String::new()).trim().to_string()),
                             ..profile.clone()
                         };
 
-                        crate::profile::update_profile(&context.profile.active_profile.name, MODULE_ID, &new_profile).unwrap_or_else(|e| println!("profile update failed: {}", e));
+                        crate::profile::update_profile(&context.profile.active_profile.name, MODULE_ID, &new_profile).unwrap_or_else(|e| error!("Profile update failed: {}", e));
 
                         // Reset the app initialization to reload context with new profile
                         let init_signal = try_consume_context::<Signal<bool>>();
@@ -800,7 +848,9 @@ impl OctopusModuleFactoryBuilder {
             Profile::new()
         };
 
-        let option_api_key = if let Some(api_key) = &context.args.octopus.octopus_api_key {
+        let args: OctopusArgs = context.module_args()?;
+
+        let option_api_key = if let Some(api_key) = &args.octopus_api_key {
             Some(api_key.to_string())
         }
         else {
@@ -860,9 +910,8 @@ impl OctopusModuleFactoryBuilder {
             "https://api.octopus.energy/v1/graphql/".to_string()
         };
 
-        let verbose = self.context.args.verbose;
         let request_manager = Arc::new(sparko_graphql::RequestManager::new(url, self.context.args.verbose, create_info::USER_AGENT)?);
-        let cache_manager = self.context.create_cache_manager(crate::octopus::MODULE_ID, verbose)?;
+        let cache_manager = self.context.create_cache_manager(crate::octopus::MODULE_ID)?;
 
         Ok(OctopusModuleFactory {
             context: self.context.clone(),
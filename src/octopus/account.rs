@@ -1,12 +1,11 @@
 use std::sync::Arc;
-
-use sparko_graphql::AuthenticatedRequestManager;
+use std::time::Duration;
 
 use crate::CacheManager;
+use crate::cache_manager::Freshness;
 
 use super::graphql::account;
 use super::RequestManager;
-use super::{token::OctopusTokenManager};
 
 pub struct AccountManager {
     // pub cache_manager: Arc<CacheManager>,
@@ -36,23 +35,21 @@ pub struct Viewer {
     // hash_key: String,
 }
 
+/// The viewer rarely changes, but does when an account is opened or closed
+pub const VIEWER_FRESHNESS: Freshness = Freshness {
+    data_set: "viewer",
+    ttl: Duration::from_secs(24 * 60 * 60),
+};
+
 impl Viewer {
-    async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>) -> anyhow::Result<Self> {
+    async fn new(cache_manager: &Arc<CacheManager>, request_manager: &Arc<RequestManager>) -> anyhow::Result<Self> {
         let hash_key = format!("#Viewer");
+        let request_manager = request_manager.clone();
 
-        let opt_viewer: Option<account::viewer::Response> = cache_manager.read_one(&hash_key)?;
-
-        let viewer = if let Some(viewer) = opt_viewer {
-            viewer
-        }
-        else {
+        let viewer: account::viewer::Response = cache_manager.read_one_or_fetch(&hash_key, &VIEWER_FRESHNESS, move || async move {
             let query = account::viewer::Query::new();
-            let viewer = request_manager.call(&query).await?;
-
-            cache_manager.write_one(&hash_key, &viewer)?;
-
-            viewer
-        };
+            crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await
+        }).await?;
 
         let default_account_id = viewer.viewer_.accounts_.get(0).unwrap().number_.clone();
 
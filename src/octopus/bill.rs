@@ -4,11 +4,13 @@ use std::fmt;
 use indexmap::IndexMap;
 
 use anyhow::anyhow;
+use tracing::{debug, error};
 
 use sparko_graphql::AuthenticatedRequestManager;
 
 use crate::cache_manager::Indexer;
 use crate::octopus::decimal::Decimal;
+use crate::output::{Document, Table, Value};
 use crate::util::as_decimal;
 use crate::CacheManager;
 
@@ -18,7 +20,7 @@ use bill::get_statement_transactions::TransactionType;
 use super::RequestManager;
 use super::{token::OctopusTokenManager};
 mod manager;
-pub use manager::BillManager;
+pub use manager::{BillArgs, BillManager};
 
 // const one_hundred: Decimal = Decimal::new(100, 0);
 // const format: time::format_description = time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
@@ -86,83 +88,60 @@ impl AbstractBill {
     //     Ok(())
     // }
 
-    pub fn print_summary_line_headers() {
-        println!("{:-^54} {:-^10} {:-^32} {:-^32} {:-^10}",
-            "",
-            "Balance",
-            "Charges",
-            "Credits",
-            "Balance"
-        );
-        println!("{:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10}", 
-            "Date",
-            "Ref",
-            "From",
-            "To",
-            "Type",
-            "b/f",
-            "Net",
-            "Tax",
-            "Gross",
-            "Net",
-            "Tax",
-            "Gross",
-            "c/f"
-        );
+    pub fn summary_table() -> Table {
+        Table::new(&["Date", "Ref", "From", "To", "Type", "Balance b/f",
+            "Charges Net", "Charges Tax", "Charges Gross",
+            "Credits Net", "Credits Tax", "Credits Gross",
+            "Balance c/f"])
     }
 
-    pub fn print_summary_line(&self) {
+    pub fn summary_row(&self) -> Vec<Value> {
         let abstract_bill = self.as_bill_interface();
 
-        print!("{:10} {:>10} {:10} {:10} {:10}", 
-            abstract_bill.issued_date_,
-            abstract_bill.id_,
-            abstract_bill.from_date_,
-            abstract_bill.to_date_,
-            abstract_bill.bill_type_.as_str(),
+        let mut row = vec!(
+            Value::from(abstract_bill.issued_date_.to_string()),
+            Value::from(abstract_bill.id_.as_str()),
+            Value::from(abstract_bill.from_date_.to_string()),
+            Value::from(abstract_bill.to_date_.to_string()),
+            Value::from(abstract_bill.bill_type_.as_str()),
         );
 
         match self {
             AbstractBill::StatementType(statement) => {
-                print!(" {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
-                    as_decimal(statement.opening_balance_, 2),
-                    as_decimal(statement.total_charges_.net_total_, 2),
-                    as_decimal(statement.total_charges_.tax_total_, 2),
-                    as_decimal(statement.total_charges_.gross_total_, 2),
-                    as_decimal(statement.total_credits_.net_total_, 2),
-                    as_decimal(statement.total_credits_.tax_total_, 2),
-                    as_decimal(statement.total_credits_.gross_total_, 2),
-                    as_decimal(statement.closing_balance_, 2)
-                );
+                row.extend([
+                    Value::Number(as_decimal(statement.opening_balance_, 2)),
+                    Value::Number(as_decimal(statement.total_charges_.net_total_, 2)),
+                    Value::Number(as_decimal(statement.total_charges_.tax_total_, 2)),
+                    Value::Number(as_decimal(statement.total_charges_.gross_total_, 2)),
+                    Value::Number(as_decimal(statement.total_credits_.net_total_, 2)),
+                    Value::Number(as_decimal(statement.total_credits_.tax_total_, 2)),
+                    Value::Number(as_decimal(statement.total_credits_.gross_total_, 2)),
+                    Value::Number(as_decimal(statement.closing_balance_, 2)),
+                ]);
+            },
+            AbstractBill::PreKrakenBillType(_) => {
+                row.extend(vec![Value::Empty; 8]);
             },
-            AbstractBill::PreKrakenBillType(_) => {},
             AbstractBill::PeriodBasedDocumentType(period_based_document) => {
-                print!(" {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
-                    "",
-                    as_decimal(period_based_document.total_charges_.net_total_, 2),
-                    as_decimal(period_based_document.total_charges_.tax_total_, 2),
-                    as_decimal(period_based_document.total_charges_.gross_total_, 2),
-                    as_decimal(period_based_document.total_credits_.net_total_, 2),
-                    as_decimal(period_based_document.total_credits_.tax_total_, 2),
-                    as_decimal(period_based_document.total_credits_.gross_total_, 2),
-                    ""
-                );
+                row.extend([
+                    Value::Empty,
+                    Value::Number(as_decimal(period_based_document.total_charges_.net_total_, 2)),
+                    Value::Number(as_decimal(period_based_document.total_charges_.tax_total_, 2)),
+                    Value::Number(as_decimal(period_based_document.total_charges_.gross_total_, 2)),
+                    Value::Number(as_decimal(period_based_document.total_credits_.net_total_, 2)),
+                    Value::Number(as_decimal(period_based_document.total_credits_.tax_total_, 2)),
+                    Value::Number(as_decimal(period_based_document.total_credits_.gross_total_, 2)),
+                    Value::Empty,
+                ]);
             },
             AbstractBill::InvoiceType(invoice) => {
-                print!(" {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10} {:>10}",
-                    "",
-                    "",
-                    "",
-                    "",
-                    "",
-                    "",
-                    as_decimal(invoice.gross_amount_, 2),
-                    "",
-                );
+                row.extend(vec![Value::Empty; 6]);
+                row.push(Value::Number(as_decimal(invoice.gross_amount_, 2)));
+                row.push(Value::Empty);
             },
         }
 
-        println!();
+        row
     }
 
      pub fn gui_summary_header() -> Element{
@@ -359,60 +338,50 @@ impl AbstractBill {
         }
     }
 
-    pub fn print(&self, transactions: Option<Vec<BillTransactionBreakDown>>) {
+    pub fn to_document(&self, transactions: Option<Vec<BillTransactionBreakDown>>) -> Document {
         let abstract_bill = self.as_bill_interface();
+        let mut document = Document::new();
 
-        println!("Energy Account Statement");
-        println!("========================");
-        println!("Date                 {}", abstract_bill.issued_date_);
-        println!("Ref                  {}", abstract_bill.id_);
-        println!("From                 {}", abstract_bill.from_date_);
-        println!("To                   {}", abstract_bill.to_date_);
-        println!();
+        document.heading("Energy Account Statement");
+        document.fields(vec!(
+            ("Date".to_string(), Value::from(abstract_bill.issued_date_.to_string())),
+            ("Ref".to_string(), Value::from(abstract_bill.id_.as_str())),
+            ("From".to_string(), Value::from(abstract_bill.from_date_.to_string())),
+            ("To".to_string(), Value::from(abstract_bill.to_date_.to_string())),
+        ));
 
         if let Some(transactions) = transactions {
             let mut total_charges = TotalCharges::new();
-            TransactionType::print_summary_line_headers();
+            let mut table = TransactionType::summary_table();
             for transaction in &transactions {
-                transaction.print_summary_line(&mut total_charges);
+                table.push(transaction.summary_row(&mut total_charges));
             }
+            document.table(table);
 
             if total_charges.units.is_positive() {
-                println!("\nTOTALS");
                 let rate = Decimal::from(total_charges.charge) / total_charges.units;
-
-                print!("{:30} {:10} ", 
-                    "Electricity Import",
-                    ""
-                );
-                print!("{:>10} {:>10} {:>10} {:>10} ", 
-                    "",
-                    "", 
-                    as_decimal(total_charges.charge, 2),
-                    ""
-                );
-                print!("{:10} {:10} {:10} {:>12.4} ", 
-                    "",
-                    "",
-                    "",
-                    total_charges.units
-                );
-                print!("{:>10.3}", rate);
-                println!();
+                let mut totals = Table::new(&["Description", "Total", "Units", "p/unit"]).with_title("TOTALS".to_string());
+
+                totals.push(vec!(
+                    Value::from("Electricity Import"),
+                    Value::Number(as_decimal(total_charges.charge, 2)),
+                    Value::number(format!("{:.4}", total_charges.units)),
+                    Value::number(format!("{:.3}", rate)),
+                ));
+                document.table(totals);
             }
-            println!();
-            println!("Detailed Breakdown");
-            println!("==================");
+
+            document.heading("Detailed Breakdown");
             
             for transaction in &transactions {
-                transaction.print();
+                document.append(transaction.to_document());
             }
         }
         
+        document
     }
 }
 
-
 pub struct TotalCharges {
     charge: i32,
     units: Decimal,
@@ -428,22 +397,8 @@ impl TotalCharges {
 }
 
 impl TransactionType {
-    pub fn print_summary_line_headers() {
-        print!("{:-^30} {:-^10} ", 
-            "Description",
-            "Posted"
-        );
-        print!("{:-^10} {:-^10} {:-^10} {:-^10} ", 
-            "Net",
-            "Tax", 
-            "Total",
-            "Balance"
-        );
-        println!("{:-^10} {:-^10} {:-^10} {:-^12} {:-^10}", "From", "To", "Amount", "Units", "p/unit");
-    }
-
-    pub fn print_break_down_line_headers() {
-        println!("{:-^20} {:-^20} {:-^10} {:-^12} {:-^10}", "From", "To", "Amount", "Units", "p/unit");
+    pub fn summary_table() -> Table {
+        Table::new(&["Description", "Posted", "Net", "Tax", "Total", "Balance", "From", "To", "Amount", "Units", "p/unit", "Note"])
     }
 
     pub fn gui_break_down_line_headers() -> Element {
@@ -458,42 +413,43 @@ impl TransactionType {
         }
     }
 
-    pub fn print_summary_line(&self, total_charges: &mut TotalCharges) {
+    pub fn summary_row(&self, total_charges: &mut TotalCharges) -> Vec<Value> {
             let txn = self.as_transaction_type();
 
-            if let TransactionType::Charge(charge) = &self {
+            let description = if let TransactionType::Charge(charge) = &self {
                 if charge.is_export_ {
-                    print!("{} {:width$} ", txn.title_, "Export", width = 30 - txn.title_.len() - 1);
+                    format!("{} Export", txn.title_)
                 }
                 else {
-                        print!("{:30} ", txn.title_);
+                    txn.title_.clone()
                 }
             }
             else {
-                print!("{:30} ", txn.title_);
-            }
-            print!("{:10} ", 
-                        txn.posted_date_
-                    );
+                txn.title_.clone()
+            };
+
+            let mut row = vec!(
+                Value::from(description),
+                Value::from(txn.posted_date_.to_string()),
+            );
 
             if let TransactionType::Charge(charge) = &self {
-                print!("{:>10} {:>10} {:>10} {:>10} ", 
-                    as_decimal(txn.amounts_.net_, 2),
-                    as_decimal(txn.amounts_.tax_, 2), 
-                    as_decimal(txn.amounts_.gross_, 2),
-                    as_decimal(txn.balance_carried_forward_, 2)
-                );
+                row.extend([
+                    Value::Number(as_decimal(txn.amounts_.net_, 2)),
+                    Value::Number(as_decimal(txn.amounts_.tax_, 2)),
+                    Value::Number(as_decimal(txn.amounts_.gross_, 2)),
+                    Value::Number(as_decimal(txn.balance_carried_forward_, 2)),
+                ]);
                 if let Some(consumption) = &charge.consumption_ {
-                    print!("{:10} {:10} {:>10} {:>12.4} ", 
-                        consumption.start_date_,
-                        consumption.end_date_,
-                        as_decimal(txn.amounts_.net_, 3),
-                        consumption.quantity_
-                    );
-
                     let rate = if consumption.quantity_.is_non_zero() {Decimal::from(txn.amounts_.gross_) / consumption.quantity_} else {Decimal::new(0, 0)};
 
-                    print!("{:>10.3}", rate); //.round_dp(2));
+                    row.extend([
+                        Value::from(consumption.start_date_.to_string()),
+                        Value::from(consumption.end_date_.to_string()),
+                        Value::Number(as_decimal(txn.amounts_.net_, 3)),
+                        Value::number(format!("{:.4}", consumption.quantity_)),
+                        Value::number(format!("{:.3}", rate)),
+                    ]);
 
                     if charge.is_export_ {
                         
@@ -506,23 +462,20 @@ impl TransactionType {
                         }
                 }
                 else {
-                    print!("{:56}","");
+                    row.extend(vec![Value::Empty; 5]);
                 }
             }
             else {
-                print!("{:>10} {:>10} {:>10} {:>10} ", 
-                    as_decimal(-txn.amounts_.net_, 2),
-                    as_decimal(-txn.amounts_.tax_, 2), 
-                    as_decimal(-txn.amounts_.gross_, 2),
-                    as_decimal(txn.balance_carried_forward_, 2)
-                );
-                print!("{:56}","");
+                row.extend([
+                    Value::Number(as_decimal(-txn.amounts_.net_, 2)),
+                    Value::Number(as_decimal(-txn.amounts_.tax_, 2)),
+                    Value::Number(as_decimal(-txn.amounts_.gross_, 2)),
+                    Value::Number(as_decimal(txn.balance_carried_forward_, 2)),
+                ]);
+                row.extend(vec![Value::Empty; 5]);
             }
-            if let Some(note) = &txn.note_ {
-                let note = note.trim();
-                print!(" {}", note);
-            }
-            println!();
+            row.push(Value::from(txn.note_.as_deref().map(str::trim)));
+            row
     }
 
     pub fn gui_summary_headers() -> Element {
@@ -670,8 +623,8 @@ pub struct BillTransactionBreakDown {
 }
 
 impl BillTransactionBreakDown {
-    pub fn print_summary_line(&self, total_charges: &mut TotalCharges) {
-        self.transaction.print_summary_line(total_charges);
+    pub fn summary_row(&self, total_charges: &mut TotalCharges) -> Vec<Value> {
+        self.transaction.summary_row(total_charges)
     }
 
     pub fn gui_summary_line(&self, total_charges: &mut TotalCharges) -> Element{
@@ -697,7 +650,6 @@ impl BillTransactionBreakDown {
                 let mut total_amount = Decimal::new(0,0);
                 let mut total_units = Decimal::new(0,0);
 
-                println!();
                 parts.push(
                     rsx!{
                         {tariff.gui_display()}
@@ -881,10 +833,10 @@ impl BillTransactionBreakDown {
         }
     }
 
-    pub fn print(&self) {
+    pub fn to_document(&self) -> Document {
         let one_hundred = Decimal::new(100, 0);
         let format = time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
-        let time_format = time::format_description::parse("           [hour]:[minute]:[second]").unwrap();
+        let mut document = Document::new();
 
         if let Some(line_item_map) = &self.line_items {
 
@@ -896,15 +848,10 @@ impl BillTransactionBreakDown {
                 let mut total_amount = Decimal::new(0,0);
                 let mut total_units = Decimal::new(0,0);
 
-                println!();
-                tariff.print();
-                println!();
+                document.fields(tariff.fields());
 
-                TransactionType::print_break_down_line_headers();
-                // println!("Line items for agreement {}", agreement_id);
-                // println!("{:-^20} {:-^20} {:-^10} {:-^10} {:-^10}", "From", "To", "Amount", "Units", "p / unit");
+                let mut table = Table::new(&["From", "To", "Amount", "Units", "p/unit"]);
         
-                let mut prev = None;
                 for item in line_items {
                     let amount = item.net_amount_ / one_hundred;
 
@@ -913,20 +860,13 @@ impl BillTransactionBreakDown {
 
                     let unit_cost = if item.number_of_units_.is_non_zero() {item.net_amount_ / item.number_of_units_} else { item.net_amount_ };
 
-
-                    println!("{:20} {:20} {:10.3} {:12.4} {:10.3}", 
-                        if let Some(prev) = prev {
-                            if prev == item.start_at_.date() {
-                                item.start_at_.format(&time_format).unwrap()
-                            } else {
-                                item.start_at_.format(&format).unwrap()
-                            }
-                        } else {
-                            item.start_at_.format(&format).unwrap()
-                        },
-                        if item.end_at_.date() == item.start_at_.date() {item.end_at_.format(&time_format).unwrap()} else {item.end_at_.format(&format).unwrap()},
-                             amount, item.number_of_units_, 
-                        &unit_cost  );
+                    table.push(vec!(
+                        Value::from(item.start_at_.format(&format).unwrap()),
+                        Value::from(item.end_at_.format(&format).unwrap()),
+                        Value::number(format!("{:.3}", amount)),
+                        Value::number(format!("{:.4}", item.number_of_units_)),
+                        Value::number(format!("{:.3}", unit_cost)),
+                    ));
                     
                     if item.number_of_units_.is_positive() {
                         let key = format!("{:.2}", unit_cost);
@@ -937,61 +877,63 @@ impl BillTransactionBreakDown {
                             amount_map.insert(key, (amount, item.number_of_units_));
                         }
                     }
-                    
-                    prev = Some(item.start_at_.date());
                 }
-                println!("{:41} {:10.3} {:12.4}", "Total Consumption", total_amount, total_units);
+                document.table(table);
+
                 if line_items.len() > 0 {
                     let start_date = line_items.get(0).unwrap().start_at_.date();
                     let end_date = line_items.get(line_items.len() - 1).unwrap().end_at_.date();
                     let days = end_date.to_julian_day() - start_date.to_julian_day();
                     let standing_charge = Decimal::new((tariff.standing_charge() * (10000 * days) as f64) as i64,6);
-                    println!("{:41} {:10.3}", format!("Standing charge ({} days @ {:.3})", days,tariff.standing_charge()) , standing_charge);
-                    println!("{:41} {:10.3}", "Total", total_amount + standing_charge);
+
+                    let mut totals = vec!(
+                        ("Total Consumption".to_string(), Value::number(format!("{:.3}", total_amount))),
+                        ("Total Units".to_string(), Value::number(format!("{:.4}", total_units))),
+                        (format!("Standing charge ({} days @ {:.3})", days, tariff.standing_charge()), Value::number(format!("{:.3}", standing_charge))),
+                        ("Total".to_string(), Value::number(format!("{:.3}", total_amount + standing_charge))),
+                    );
             
-        
                     let txn = self.transaction.as_transaction_type();
-                    print!("{:30} {:10} ", "as shown on bill", "");
             
                     if let TransactionType::Charge(charge) = &self.transaction {
-                        print!("{:>9}  ", as_decimal(txn.amounts_.net_, 2));
+                        totals.push(("Net as shown on bill".to_string(), Value::Number(as_decimal(txn.amounts_.net_, 2))));
                         if let Some(consumption) = &charge.consumption_ {
-                            print!("{:>12.4} ",consumption.quantity_);
-            
                             let rate = if consumption.quantity_.is_non_zero() {Decimal::from(txn.amounts_.gross_) / consumption.quantity_} else {Decimal::new(0, 0)};
             
-                            print!("{:>10.3}", rate);
+                            totals.push(("Units as shown on bill".to_string(), Value::number(format!("{:.4}", consumption.quantity_))));
+                            totals.push(("p/unit as shown on bill".to_string(), Value::number(format!("{:.3}", rate))));
                         }
-                        println!("");
                     }
-                    println!("");
-                    println!("Analysis");
-                    println!("--------");
+                    document.fields(totals);
             
                     if !amount_map.is_empty() {
-                        println!("{:-^15} {:-^10} {:-^10} {:-^10} {:-^10} {:-^10}", "Unit Rate", "Cost", "Units", "% Cost", "% Units", "% Bill");
+                        let mut analysis = Table::new(&["Unit Rate", "Cost", "Units", "% Cost", "% Units", "% Bill"]).with_title("Analysis".to_string());
+
                         for (key, (amount, units)) in amount_map {
-                            println!("{:>15} {:10.2} {:10.2} {:10.2} {:10.2} {:10.2}",
-                                key,
-                                amount,
-                                units,
-                                one_hundred * amount / total_amount,
-                                one_hundred * units / total_units,
-                                one_hundred * amount / (standing_charge + total_amount)
-                            );
+                            analysis.push(vec!(
+                                Value::Number(key),
+                                Value::number(format!("{:.2}", amount)),
+                                Value::number(format!("{:.2}", units)),
+                                Value::number(format!("{:.2}", one_hundred * amount / total_amount)),
+                                Value::number(format!("{:.2}", one_hundred * units / total_units)),
+                                Value::number(format!("{:.2}", one_hundred * amount / (standing_charge + total_amount))),
+                            ));
                         }
-                        println!("{:60}{:10.2}",
-                            "Standing Charge",
-                            one_hundred * standing_charge / (standing_charge + total_amount)
-                        );
+                        analysis.push(vec!(
+                            Value::from("Standing Charge"),
+                            Value::Empty,
+                            Value::Empty,
+                            Value::Empty,
+                            Value::Empty,
+                            Value::number(format!("{:.2}", one_hundred * standing_charge / (standing_charge + total_amount))),
+                        ));
+                        document.table(analysis);
                     }
-                    println!("");
-                    println!("");
-                    println!("");
                 }
             }
         }
 
+        document
     }
 }
 
@@ -1023,18 +965,19 @@ impl fmt::Debug for BillList {
 }
 
 impl BillList {
-    pub fn print_summary_lines(&self) {
-        AbstractBill::print_summary_line_headers();
+    pub fn summary_table(&self) -> Table {
+        let mut table = AbstractBill::summary_table();
 
         for (_bill_id, (_key, bill)) in &self.bills {
-            bill.print_summary_line();
+            table.push(bill.summary_row());
         }
+        table
     }
 
     pub async fn fetch_all(&mut self, request_manager: &RequestManager)  -> anyhow::Result<()> {
         let mut has_previous_page = self.has_previous_page;
 
-        println!("fetch_all bills {} in buffer has_previous_page={}", self.bills.len(), has_previous_page);
+        debug!("fetch_all bills {} in buffer has_previous_page={}", self.bills.len(), has_previous_page);
 
         while has_previous_page 
         {
@@ -1048,12 +991,12 @@ impl BillList {
 
             let query = builder.build()?;
             // let query = super::graphql::bill::get_bills::Query::from(builder.build()?);
-            let result = request_manager.call(&query).await;
+            let result = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await;
 
             let response = match result {
                 Ok(response) => response,
                 Err(e) => {
-                    println!("Error fetching bills: {:?}", e);
+                    error!("Error fetching bills: {:?}", e);
                     return Err(e.into());
                 }
             };
@@ -1086,7 +1029,7 @@ impl BillList {
 
         let indexer: Indexer<AbstractBill> = Box::new(|bill: &AbstractBill| bill.as_bill_interface().id_.clone());
 
-        cache_manager.read(&hash_key, &mut bills, &indexer)?;
+        cache_manager.read(&hash_key, &mut bills, &indexer).await?;
 
         let cached_cnt = bills.len();
 
@@ -1096,7 +1039,7 @@ impl BillList {
                 .with_account_number(account_number.clone())
                 .with_last(1)
                 .build()?;
-            let response = request_manager.call(&query).await?;
+            let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
             for edge in response.account_.bills_.edges {
                 let sort_key = edge.cursor; //format!("{}#{}", &edge.node.as_bill_interface().issued_date_, &edge.cursor);
@@ -1125,12 +1068,12 @@ impl BillList {
         };
 
         if check_for_updates {
-            println!("Checking for bill updates, result = {:?}", result);
+            debug!("Checking for bill updates, {} bills cached", cached_cnt);
             result.fetch_all(request_manager).await?;
         }
 
         if result.bills.len() > cached_cnt {
-            cache_manager.write(&result.hash_key, &result.bills, cached_cnt)?;
+            cache_manager.write(&result.hash_key, &result.bills, cached_cnt).await?;
         }
         
         Ok(result)
@@ -1154,7 +1097,7 @@ impl BillTransactionList {
             let indexer: Indexer<TransactionType> = Box::new(|txn: &TransactionType| txn.as_transaction_type().id_.clone());
             let mut transactions = IndexMap::new();
     
-            cache_manager.read(&hash_key, &mut transactions, &indexer)?;
+            cache_manager.read(&hash_key, &mut transactions, &indexer).await?;
     
             let cached_cnt = transactions.len();
     
@@ -1165,7 +1108,7 @@ impl BillTransactionList {
                         .with_statement_id(statement_id.clone())
                         .with_transactions_last(1)
                         .build()?;
-                let response = request_manager.call(&query).await?;
+                let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
                 let bill = response.account_.bill_;
 
                 if let bill::get_statement_transactions::BillInterface::StatementType(statement) = bill {
@@ -1211,7 +1154,7 @@ impl BillTransactionList {
             // result.fetch_all(request_manager).await?;
     
             if result.transactions.len() > cached_cnt {
-                cache_manager.write(&result.hash_key, &result.transactions, cached_cnt)?;
+                cache_manager.write(&result.hash_key, &result.transactions, cached_cnt).await?;
             }
             
             Ok(result)
@@ -1235,7 +1178,7 @@ impl BillTransactionList {
             }
             let query = //super::graphql::bill::get_statement_transactions::Query::from(
                 builder.build()?;
-            let response = request_manager.call(&query).await?;
+            let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
             
             if let super::graphql::bill::get_statement_transactions::BillInterface::StatementType(statement) = response.account_.bill_ {
@@ -3,8 +3,10 @@ use tokio::sync::Mutex;
 use std::sync::Arc;
 
 use crate::octopus::bill::{BillList, BillTransactionBreakDown, BillTransactionList};
-// use anyhow::anyhow;
+use anyhow::anyhow;
+use clap::Parser;
 use crate::octopus::meter::MeterType;
+use crate::output::Document;
 use crate::CacheManager;
 
 use super::super::graphql::bill;
@@ -31,6 +33,14 @@ use super::super::RequestManager;
  * 
  * 
  */
+/// Arguments of the bill command
+#[derive(Parser, Debug)]
+pub struct BillArgs {
+    /// The bill to print, defaults to the most recent
+    #[arg(value_name = "BILL_ID")]
+    bill_id: Option<String>,
+}
+
 pub struct BillManager {
     pub cache_manager: Arc<CacheManager>,
     pub request_manager: Arc<RequestManager>,
@@ -59,6 +69,14 @@ impl BillManager {
         // BillList::new(&self.cache_manager, &self.request_manager, &account_number, crate::CHECK_FOR_UPDATES).await
     }
 
+    /// Check for new bills, replacing the ones held in memory by fetch_bills
+    pub async fn refresh_bills(&self, account_number: String) -> anyhow::Result<Arc<BillList>> {
+        let bills = Arc::new(BillList::fetch(&self.cache_manager, &self.request_manager, &account_number, true).await?);
+
+        self.bills.lock().await.insert(account_number, bills.clone());
+        Ok(bills)
+    }
+
     // pub async fn get_statement_transactions(&self, account_number: String, statement_id: String)  -> anyhow::Result<BillTransactionList> {
     //     BillTransactionList::new(&self.cache_manager, &self.request_manager, account_number, statement_id).await
     // }
@@ -100,24 +118,23 @@ impl BillManager {
         Ok(result)
     }
 
-    pub async fn bills_handler(&self, _args: std::str::SplitWhitespace<'_>, account_number: String) ->  anyhow::Result<()> {
-        self.fetch_bills(account_number).await?.print_summary_lines();
-        Ok(())
+    pub async fn bills_handler(&self, account_number: String) ->  anyhow::Result<Document> {
+        Ok(self.fetch_bills(account_number).await?.summary_table().into())
     }
 
 
-    pub async fn bill_handler(&self, mut args: std::str::SplitWhitespace<'_>, account_number: String, billing_timezone: &time_tz::Tz) ->  anyhow::Result<()> {
+    pub async fn bill_handler(&self, args: BillArgs, account_number: String, billing_timezone: &time_tz::Tz) ->  anyhow::Result<Document> {
         // let one_hundred = Decimal::new(100, 0);
         // let format = time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
         let bills = self.fetch_bills(account_number.clone()).await?;
 
-        if let Some(bill_id) = args.next() {
+        if let Some(bill_id) = &args.bill_id {
             for (_id, bill) in bills.bills.values() {
-                if bill_id == bill.as_bill_interface().id_ {
+                if *bill_id == bill.as_bill_interface().id_ {
                     let transactions = if let bill::get_bills::BillInterface::StatementType(_) = bill {
 
 
-                        let transactions = self.fetch_bill_transaction_breakdown(account_number, bill_id.to_string(), billing_timezone).await?;
+                        let transactions = self.fetch_bill_transaction_breakdown(account_number, bill_id.clone(), billing_timezone).await?;
 
                         Some(transactions)
                     }
@@ -125,15 +142,16 @@ impl BillManager {
                         None
                     };
 
-                    bill.print(transactions);
-                    return Ok(())
+                    return Ok(bill.to_document(transactions))
                 }
             }
-            //println!("Unknown bill '{}'", bill_id);
+            Err(anyhow!("Unknown bill '{}'", bill_id))
         }
         else {
             if bills.bills.is_empty() {
-                //println!("There are no bills in this account");
+                let mut document = Document::new();
+                document.text("There are no bills in this account");
+                Ok(document)
             }
             else {
                 let (_key, (_id, bill)) = bills.bills.get_index(bills.bills.len() - 1).unwrap();
@@ -143,9 +161,8 @@ impl BillManager {
                 else {
                     None
                 };
-                bill.print(transactions);
+                Ok(bill.to_document(transactions))
             }
         }
-        Ok(())
     }
 }
\ No newline at end of file
@@ -0,0 +1,203 @@
+use anyhow::anyhow;
+use async_trait::async_trait;
+use rust_decimal::Decimal;
+use sparko_graphql::types::DateRange;
+use tracing::warn;
+
+use crate::system::{Bill, Commodity, Consumption, Demand, EnergyDataSource, MeterPoint, MeterType, Site, Tariff};
+use crate::util;
+
+use super::graphql::bill::get_bills::BillInterface;
+use super::meter;
+use super::OctopusModule;
+
+/* ***************************************************************************************************************************************************************
+ * Mapping from the Octopus GraphQL types to the provider-neutral types in crate::system.
+ *
+ * Everything here goes through the same managers as the REPL commands, so it is served from the local cache where possible.
+ *************************************************************************************************************************************************************** */
+
+/// Octopus amounts are integer pence
+fn pounds(pence: i32) -> Decimal {
+    Decimal::new(pence as i64, 2)
+}
+
+#[async_trait]
+impl EnergyDataSource for OctopusModule {
+    async fn get_sites(&self) -> anyhow::Result<Vec<Site>> {
+        let properties = self.meter_manager.get_properties(&self.account_id).await?;
+
+        Ok(properties.properties.account_.properties_.iter()
+            .map(|property| Site {
+                id: property.id_.to_string(),
+                name: property.address_.clone(),
+                postcode: Some(property.postcode_.clone()),
+            })
+            .collect())
+    }
+
+    async fn get_meter_points(&self) -> anyhow::Result<Vec<MeterPoint>> {
+        let properties = self.meter_manager.get_properties(&self.account_id).await?;
+        let mut meter_points = Vec::new();
+
+        for property in &properties.properties.account_.properties_ {
+            for point in &property.electricity_meter_points_ {
+                for meter in &point.meters_ {
+                    meter_points.push(MeterPoint {
+                        id: meter.node_id_.clone(),
+                        site_id: property.id_.to_string(),
+                        name: point.mpan_.clone(),
+                        serial_number: meter.serial_number_.clone(),
+                        commodity: Commodity::Electricity,
+                        // An export meter is linked to the import meter it is paired with
+                        meter_type: if meter.import_meter_.is_some() { MeterType::Export } else { MeterType::Consumption },
+                    });
+                }
+            }
+            for point in &property.gas_meter_points_ {
+                for meter in &point.meters_ {
+                    meter_points.push(MeterPoint {
+                        id: meter.node_id_.clone(),
+                        site_id: property.id_.to_string(),
+                        name: point.mprn_.clone(),
+                        serial_number: meter.serial_number_.clone(),
+                        commodity: Commodity::Gas,
+                        meter_type: MeterType::Consumption,
+                    });
+                }
+            }
+        }
+        Ok(meter_points)
+    }
+
+    async fn get_tariffs(&self, meter_point_id: &str) -> anyhow::Result<Vec<Tariff>> {
+        let agreements = self.meter_manager.get_meter_agreements(&self.account_id).await?;
+        let mut tariffs = Vec::new();
+
+        let electricity_agreements = agreements.import_electricity_map.get(meter_point_id)
+            .or(agreements.export_electricity_map.get(meter_point_id));
+
+        if let Some(agreement_vec) = electricity_agreements {
+            for agreement in agreement_vec {
+                let tariff = meter::Tariff::Electricity(agreement.tariff_.clone());
+                tariffs.push(Tariff {
+                    meter_point_id: meter_point_id.to_string(),
+                    code: tariff.code().to_string(),
+                    name: tariff.full_name().to_string(),
+                    valid_from: agreement.valid_from_.clone(),
+                    valid_to: agreement.valid_to_.clone(),
+                    standing_charge: tariff.gross_standing_charge(),
+                    unit_rate: tariff.unit_rate(),
+                });
+            }
+        }
+
+        if let Some(agreement_vec) = agreements.gas_map.get(meter_point_id) {
+            for agreement in agreement_vec {
+                let tariff = meter::Tariff::Gas(agreement.tariff_.clone());
+                tariffs.push(Tariff {
+                    meter_point_id: meter_point_id.to_string(),
+                    code: tariff.code().to_string(),
+                    name: tariff.full_name().to_string(),
+                    valid_from: agreement.valid_from_.clone(),
+                    valid_to: agreement.valid_to_.clone(),
+                    standing_charge: tariff.gross_standing_charge(),
+                    unit_rate: tariff.unit_rate(),
+                });
+            }
+        }
+        Ok(tariffs)
+    }
+
+    async fn get_consumption(&self, meter_point_id: &str, date_range: &DateRange) -> anyhow::Result<Vec<Consumption>> {
+        let consumption = self.meter_manager.get_consumption(&self.account_id, &meter_point_id.to_string(), date_range, self.billing_timezone).await?;
+
+        Ok(consumption.iter()
+            .map(|item| Consumption {
+                start: item.start_at_.clone(),
+                end: item.end_at_.clone(),
+                value: *item.value_,
+            })
+            .collect())
+    }
+
+    async fn get_bills(&self) -> anyhow::Result<Vec<Bill>> {
+        let bills = self.bill_manager.fetch_bills(self.account_id.clone()).await?;
+
+        Ok(bills.bills.values()
+            .map(|(_, bill)| {
+                let abstract_bill = bill.as_bill_interface();
+                let (charges, credits, opening_balance, closing_balance) = match bill {
+                    BillInterface::StatementType(statement) => (
+                        Some(pounds(statement.total_charges_.gross_total_)),
+                        Some(pounds(statement.total_credits_.gross_total_)),
+                        Some(pounds(statement.opening_balance_)),
+                        Some(pounds(statement.closing_balance_)),
+                    ),
+                    BillInterface::PeriodBasedDocumentType(document) => (
+                        Some(pounds(document.total_charges_.gross_total_)),
+                        Some(pounds(document.total_credits_.gross_total_)),
+                        None,
+                        None,
+                    ),
+                    BillInterface::InvoiceType(invoice) => (Some(pounds(invoice.gross_amount_)), None, None, None),
+                    _ => (None, None, None, None),
+                };
+
+                Bill {
+                    id: abstract_bill.id_.clone(),
+                    bill_type: abstract_bill.bill_type_.to_string(),
+                    issued_date: abstract_bill.issued_date_.clone(),
+                    from_date: abstract_bill.from_date_.clone(),
+                    to_date: abstract_bill.to_date_.clone(),
+                    charges,
+                    credits,
+                    opening_balance,
+                    closing_balance,
+                }
+            })
+            .collect())
+    }
+
+    async fn sync(&self) -> anyhow::Result<()> {
+        self.bill_manager.refresh_bills(self.account_id.clone()).await?;
+        // the agreements are otherwise only fetched once, so this is how a new tariff is seen
+        self.meter_manager.refresh_meter_agreements(&self.account_id).await?;
+
+        // Reading this month fetches whatever is missing from its buckets of consumption and line items, whatever their size, and earlier ones are complete
+        let this_month = util::parse_date_range(Some("this-month"), None)?;
+        let properties = self.meter_manager.get_properties(&self.account_id).await?;
+        let mut failures = Vec::new();
+
+        // one meter which can't be read shouldn't stop the others from being synced
+        for meter_node_id in &properties.meter_node_ids {
+            if let Err(error) = self.meter_manager.get_consumption(&self.account_id, meter_node_id, &this_month, self.billing_timezone).await {
+                warn!("Unable to sync consumption of meter {}: {}", meter_node_id, error);
+                failures.push(format!("meter {}: {}", meter_node_id, error));
+            }
+        }
+
+        for (meter_type, is_export) in [(meter::MeterType::Electricity, false), (meter::MeterType::Electricity, true), (meter::MeterType::Gas, false)] {
+            self.meter_manager.get_line_items(&self.account_id, &meter_type, is_export, &this_month.start, &this_month.end, self.billing_timezone).await?;
+        }
+
+        if !failures.is_empty() {
+            return Err(anyhow!("Unable to sync {}", failures.join(", ")))
+        }
+        Ok(())
+    }
+
+    async fn refresh_tariffs(&self) -> anyhow::Result<()> {
+        self.meter_manager.refresh_meter_agreements_if_due(&self.account_id).await
+    }
+
+    async fn get_current_demand(&self) -> anyhow::Result<Vec<Demand>> {
+        Ok(self.meter_manager.get_current_demand(&self.account_id).await?.into_iter()
+            .map(|(device_id, read_at, demand)| Demand {
+                device_id,
+                read_at,
+                demand,
+            })
+            .collect())
+    }
+}
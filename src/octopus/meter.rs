@@ -1,17 +1,22 @@
 use std::collections::HashMap;
 use std::fmt::Display;
-use std::sync::Arc;
-use std::time::Duration;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 
 use anyhow::anyhow;
 
+use clap::Parser;
 use dioxus::prelude::*;
 use indexmap::IndexMap;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
 use sparko_graphql::types::{Date, DateRange, DateTime, EdgeOf, PageInfo};
 use sparko_graphql::AuthenticatedRequestManager;
 use tokio::time::sleep;
 
+use crate::output::{Document, OutputFormat, Table, TableStream, Value};
 use crate::CacheManager;
+use crate::cache_manager::{Bucket, Freshness, Granularity, TimeSeries};
 
 use super::graphql::meter;
 use super::RequestManager;
@@ -31,10 +36,28 @@ impl Display for MeterType {
     }
 }
 
+/// Arguments of the consumption command
+#[derive(Parser, Debug)]
+pub struct ConsumptionArgs {
+    /// Only show consumption for the given meter
+    #[arg(long, value_name = "METER_NODE_ID")]
+    meter: Option<String>,
+    /// Start of the period, either a date (YYYY-MM-DD) or a keyword such as last-month
+    #[arg(value_name = "DATE")]
+    from: Option<String>,
+    /// End of the period (YYYY-MM-DD), defaults to today
+    #[arg(value_name = "DATE")]
+    to: Option<String>,
+}
+
+/// The least time between refetches of the meter agreements by refresh_meter_agreements_if_due(), which is called on every alert check
+const AGREEMENTS_REFRESH_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
 pub struct MeterManager {
     // pub account_number: String,
     pub cache_manager: Arc<CacheManager>,
     pub request_manager: Arc<RequestManager>,
+    agreements_refreshed_at: Mutex<Option<Instant>>,
     // pub properties: HashMap<String, Arc<PropertyList>>,
     // pub agreements: IndexMap<String,MeterAgreementList>,
 }
@@ -45,15 +68,15 @@ impl MeterManager {
        Self {
             cache_manager: cache_manager.clone(),
             request_manager: request_manager.clone(),
+            agreements_refreshed_at: Mutex::new(None),
         }
     }
 
-    fn get_date_range(_args: std::str::SplitWhitespace<'_>) -> anyhow::Result<DateRange> {
-        // default - current month
-        Ok(DateRange::get_current_month_inclusive()?)
+    fn get_date_range(args: &ConsumptionArgs) -> anyhow::Result<DateRange> {
+        crate::util::parse_date_range(args.from.as_deref(), args.to.as_deref())
     }
 
-    pub async fn consumption_handler(&self, args: std::str::SplitWhitespace<'_>, account_number: &String, billing_timezone: &time_tz::Tz) ->  anyhow::Result<()> {
+    pub async fn consumption_handler(&self, args: ConsumptionArgs, account_number: &String, billing_timezone: &time_tz::Tz) ->  anyhow::Result<Document> {
         let properties = self.get_properties(account_number).await?;
         // if let std::collections::hash_map::Entry::Vacant(entry) = self.properties.entry(account_number.clone()) {
         //     entry.insert(PropertyList::new(&self.cache_manager, &self.request_manager, account_number.clone()).await?);
@@ -61,22 +84,34 @@ impl MeterManager {
         
         // let properties =self.properties.get(account_number).unwrap();
 
-        let date_range = Self::get_date_range(args)?;
+        let date_range = Self::get_date_range(&args)?;
+        let mut document = Document::new();
+
+        if let Some(meter) = &args.meter {
+            if !properties.meter_node_ids.contains(meter) {
+                return Err(anyhow!("Unknown meter '{}'", meter))
+            }
+        }
 
         for meter_node_id in &properties.meter_node_ids {
+            if args.meter.as_ref().is_some_and(|meter| meter != meter_node_id) {
+                continue;
+            }
             //println!("meter_node_id {}",meter_node_id);
 
             let consumption = self.get_consumption(account_number, meter_node_id, &date_range, billing_timezone).await?;
 
             //println!("print {} items",consumption.len());
-            ConsumptionList::print_consumption(&consumption)?;
+            document.table(ConsumptionList::consumption_table(&consumption).with_title(format!("Meter {}", meter_node_id)));
         }
 
-        Ok(())
+        Ok(document)
     }
 
-    pub async fn demand_handler(&self, _args: std::str::SplitWhitespace<'_>, account_number: &String) ->  anyhow::Result<()> {
+    pub async fn demand_handler(&self, account_number: &String, format: Option<OutputFormat>) ->  anyhow::Result<Document> {
         let properties = self.get_properties(account_number).await?;
+        // readings are printed as they are taken, rather than after all of them
+        let mut table = TableStream::new(Table::new(&["Read At", "Demand"]), format);
         for property in &properties.properties.account_.properties_ {
             for network in &property.smart_device_networks_ {
                 for device in &network.smart_devices_ {
@@ -92,23 +127,8 @@ impl MeterManager {
                         while cnt>0 {
                             cnt -= 1;
 
-                            let start = DateTime::from_unix_timestamp(end_timestamp - 60)?;
-                            let end = DateTime::from_unix_timestamp(end_timestamp)?;
-
-                            let query = meter::get_current_demand::Query::builder()
-                                .with_meter_device_id(device.device_id_.clone())
-                                .with_start(start)
-                                .with_end(end)
-                                .with_grouping(crate::octopus::graphql::TelemetryGrouping::TenSeconds)
-                                .build()?;
-                            let demand = &self.request_manager.call(&query).await?;
-                
-                            if demand.smart_meter_telemetry_.is_empty() {
-                                println!("NO RESULT");
-                            }
-                            else {
-                                let result = demand.smart_meter_telemetry_.get(demand.smart_meter_telemetry_.len() - 1).unwrap();
-                                println!("{} at {}", result.demand_, result.read_at_);
+                            if let Some((read_at, demand)) = self.get_demand_reading(&device.device_id_, end_timestamp).await? {
+                                table.push(vec!(Value::from(read_at.to_string()), Value::number(demand)));
                             }
                             end_timestamp += 10;
                             sleep(ten_seconds).await;
@@ -118,7 +138,42 @@ impl MeterManager {
             }
         }
 
-        Ok(())
+        Ok(table.finish())
+    }
+
+    /// The latest demand, in watts, of each smart electricity meter of the account, as (device id, read at, demand)
+    pub async fn get_current_demand(&self, account_number: &String) -> anyhow::Result<Vec<(String, DateTime, rust_decimal::Decimal)>> {
+        let properties = self.get_properties(account_number).await?;
+        let mut result = Vec::new();
+
+        for property in &properties.properties.account_.properties_ {
+            for network in &property.smart_device_networks_ {
+                for device in &network.smart_devices_ {
+                    if let super::graphql::DeviceType::Esme =  device.type_ {
+                        if let Some((read_at, demand)) = self.get_demand_reading(&device.device_id_, DateTime::now_utc().unix_timestamp()).await? {
+                            result.push((device.device_id_.clone(), read_at, demand));
+                        }
+                    }
+                }
+            }
+        }
+        Ok(result)
+    }
+
+    /// The last telemetry reading of the given device in the minute before end_timestamp
+    async fn get_demand_reading(&self, device_id: &String, end_timestamp: i64) -> anyhow::Result<Option<(DateTime, rust_decimal::Decimal)>> {
+        let start = DateTime::from_unix_timestamp(end_timestamp - 60)?;
+        let end = DateTime::from_unix_timestamp(end_timestamp)?;
+
+        let query = meter::get_current_demand::Query::builder()
+            .with_meter_device_id(device_id.clone())
+            .with_start(start)
+            .with_end(end)
+            .with_grouping(crate::octopus::graphql::TelemetryGrouping::TenSeconds)
+            .build()?;
+        let demand = crate::metrics::count_api_call(crate::octopus::MODULE_ID, self.request_manager.call(&query)).await?;
+
+        Ok(demand.smart_meter_telemetry_.last().map(|result| (result.read_at_.clone(), *result.demand_)))
     }
 
     pub async fn get_properties(&self, account_number: &String) -> anyhow::Result<PropertyList>{
@@ -131,6 +186,32 @@ impl MeterManager {
         PropertyList::new(&self.cache_manager, &self.request_manager, account_number.clone()).await
     }
 
+    pub async fn get_meter_agreements(&self, account_number: &String) -> anyhow::Result<MeterAgreementList> {
+        let properties = self.get_properties(account_number).await?;
+
+        MeterAgreementList::new(&self.cache_manager, &self.request_manager, account_number.clone(), &properties.meter_node_ids, false).await
+    }
+
+    /// Fetch the agreements of each meter again, rather than using the cached copy, so that new agreements (i.e. tariff changes) are seen
+    pub async fn refresh_meter_agreements(&self, account_number: &String) -> anyhow::Result<MeterAgreementList> {
+        let properties = self.get_properties(account_number).await?;
+
+        let agreements = MeterAgreementList::new(&self.cache_manager, &self.request_manager, account_number.clone(), &properties.meter_node_ids, true).await?;
+        *self.agreements_refreshed_at.lock().unwrap() = Some(Instant::now());
+        Ok(agreements)
+    }
+
+    /// Refetch the meter agreements unless that was done within the last AGREEMENTS_REFRESH_INTERVAL
+    pub async fn refresh_meter_agreements_if_due(&self, account_number: &String) -> anyhow::Result<()> {
+        let due = self.agreements_refreshed_at.lock().unwrap()
+            .map_or(true, |refreshed_at| refreshed_at.elapsed() >= AGREEMENTS_REFRESH_INTERVAL);
+
+        if due {
+            self.refresh_meter_agreements(account_number).await?;
+        }
+        Ok(())
+    }
+
     pub async fn get_line_items(&self, account_number: &String, meter_type: &MeterType, is_export: bool, start_date: &Date, end_date: &Date, billing_timezone: &time_tz::Tz) -> anyhow::Result<IndexMap<String, (Tariff, Vec<meter::electricity_agreement_line_items::LineItemType>)>>{
         let properties = self.get_properties(account_number).await?;
         // if let std::collections::hash_map::Entry::Vacant(entry) = self.properties.entry(account_number.clone()) {
@@ -138,7 +219,7 @@ impl MeterManager {
         // }
         
         // let properties = self.properties.get(account_number).unwrap();
-        let meter_agreements = MeterAgreementList::new(&self.cache_manager, &self.request_manager, account_number.clone(), &properties.meter_node_ids).await?;
+        let meter_agreements = MeterAgreementList::new(&self.cache_manager, &self.request_manager, account_number.clone(), &properties.meter_node_ids, false).await?;
 
         // println!("Meter Agreements");
         // for (cursor, agreement_vec) in &meter_agreements.electricity_map {
@@ -161,11 +242,32 @@ impl MeterManager {
             start_date_time: &DateTime, end_date_time: &DateTime, billing_timezone: &time_tz::Tz) -> anyhow::Result<Vec<meter::electricity_agreement_line_items::LineItemType>> {
                 let mut in_scope_items = Vec::new();
                 let mut bucket_date = start_date_time.to_date();
+
+                // complete buckets are read in one go, including any made from monthly buckets cached before the size was changed, only the others
+                // need AgreementLineItems to fetch what they are missing
+                let hash_key = AgreementLineItems::hash_key(account_number, agreement_id, meter_type);
+                let mut cached = cache_manager.read_vec_range(&LINE_ITEMS_SERIES, &bucket_date, &end_date_time.to_date(), &hash_key).await?;
+                cached.extend(buckets_from_months(cache_manager, &LINE_ITEMS_SERIES, &bucket_date, &end_date_time.to_date(), &hash_key, billing_timezone,
+                    |item: &meter::electricity_agreement_line_items::LineItemType| &item.start_at_, |item| &item.end_at_).await?);
                 loop {
                     //println!("Get bucket {:?}", bucket_date);
-                    let line_items = AgreementLineItems::new(cache_manager, request_manager, account_number.clone(), meter_type, agreement_id.clone(), &bucket_date, billing_timezone).await?;
+                    let bucket = cache_manager.bucket_for_date(&LINE_ITEMS_SERIES, &bucket_date)?;
+                    let bucket_end_date_time = bucket.end()?.at_midnight(billing_timezone);
+                    let complete = cached.iter().position(|(cached_bucket, items)| *cached_bucket == bucket && items.last().is_some_and(|(_, item)| item.end_at_ >= bucket_end_date_time));
+
+                    let items = match complete {
+                        Some(index) => {
+                            bucket_date = bucket.end()?;
+                            cached.swap_remove(index).1
+                        },
+                        None => {
+                            let line_items = AgreementLineItems::new(cache_manager, request_manager, account_number.clone(), meter_type, agreement_id.clone(), &bucket_date, billing_timezone).await?;
+                            bucket_date = line_items.end_date;
+                            line_items.line_items
+                        },
+                    };
 
-                    for (_cursor, item) in line_items.line_items {
+                    for (_cursor, item) in items {
                         //println!("Candidate line item {:?}-{:?}", item.start_at_, item.end_at_);
                         if &item.start_at_ >= end_date_time {
                             // thats it
@@ -178,8 +280,6 @@ impl MeterManager {
                         }
                     }
 
-                    bucket_date = line_items.end_date;
-
                     //println!("New bucket date {:?}", bucket_date);
 
                     if *bucket_date > end_date_time.date() {
@@ -233,11 +333,32 @@ impl MeterManager {
             account_number: &String, meter_node_id: &String, start_date_time: &DateTime, end_date_time: &DateTime, billing_timezone: &time_tz::Tz) -> anyhow::Result<Vec<meter::meter_consumption::ConsumptionType>> {
                 let mut in_scope_items = Vec::new();
                 let mut bucket_date = start_date_time.to_date();
+
+                // complete buckets are read in one go, including any made from monthly buckets cached before the size was changed, only the others
+                // need ConsumptionList to fetch what they are missing
+                let hash_key = ConsumptionList::hash_key(account_number, meter_node_id);
+                let mut cached = cache_manager.read_vec_range(&CONSUMPTION_SERIES, &bucket_date, &end_date_time.to_date(), &hash_key).await?;
+                cached.extend(buckets_from_months(cache_manager, &CONSUMPTION_SERIES, &bucket_date, &end_date_time.to_date(), &hash_key, billing_timezone,
+                    |item: &meter::meter_consumption::ConsumptionType| &item.start_at_, |item| &item.end_at_).await?);
                 loop {
                     //println!("Get bucket {:?}", bucket_date);
-                    let line_items = ConsumptionList::new(cache_manager, request_manager, account_number.clone(), meter_node_id.clone(), &bucket_date, billing_timezone).await?;
+                    let bucket = cache_manager.bucket_for_date(&CONSUMPTION_SERIES, &bucket_date)?;
+                    let bucket_end_date_time = bucket.end()?.at_midnight(billing_timezone);
+                    let complete = cached.iter().position(|(cached_bucket, items)| *cached_bucket == bucket && items.last().is_some_and(|(_, item)| item.end_at_ >= bucket_end_date_time));
+
+                    let items = match complete {
+                        Some(index) => {
+                            bucket_date = bucket.end()?;
+                            cached.swap_remove(index).1
+                        },
+                        None => {
+                            let consumption = ConsumptionList::new(cache_manager, request_manager, account_number.clone(), meter_node_id.clone(), &bucket_date, billing_timezone).await?;
+                            bucket_date = consumption.end_date;
+                            consumption.consumption
+                        },
+                    };
 
-                    for (_cursor, item) in line_items.consumption{
+                    for (_cursor, item) in items {
                         //println!("Candidate line item {:?}-{:?}", item.start_at_, item.end_at_);
                         if &item.start_at_ >= end_date_time {
                             // thats it
@@ -250,8 +371,6 @@ impl MeterManager {
                         }
                     }
 
-                    bucket_date = line_items.end_date;
-
                     //println!("New bucket date {:?}", bucket_date);
 
                     if *bucket_date > end_date_time.date() {
@@ -270,26 +389,24 @@ pub struct PropertyList {
     pub meter_node_ids: Vec<String>,
 }
 
+/// Properties and their meters change when meters are exchanged or the customer moves
+pub const PROPERTIES_FRESHNESS: Freshness = Freshness {
+    data_set: "properties",
+    ttl: Duration::from_secs(24 * 60 * 60),
+};
+
 impl PropertyList {
     
-   async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>, account_number: String) -> anyhow::Result<Self> {
-    let hash_key = format!("{}#Properties", account_number);
-
-        let opt_properties: Option<meter::account_properties_meters::Response> = cache_manager.read_one(&hash_key)?;
+   async fn new(cache_manager: &Arc<CacheManager>, request_manager: &Arc<RequestManager>, account_number: String) -> anyhow::Result<Self> {
+        let hash_key = format!("{}#Properties", account_number);
+        let request_manager = request_manager.clone();
 
-        let properties = if let Some(properties) = opt_properties {
-            properties
-        }
-        else {
+        let properties: meter::account_properties_meters::Response = cache_manager.read_one_or_fetch(&hash_key, &PROPERTIES_FRESHNESS, move || async move {
             let query = meter::account_properties_meters::Query::builder()
-                .with_account_number(account_number.clone())
+                .with_account_number(account_number)
                 .build()?;
-            let properties = request_manager.call(&query).await?;
-
-            cache_manager.write_one(&hash_key, &properties)?;
-
-            properties
-        };
+            crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await
+        }).await?;
 
         let mut meter_node_ids: Vec<String> = Vec::new();
 
@@ -319,6 +436,66 @@ pub enum Tariff {
 }
 
 impl Tariff {
+    pub fn code(&self) -> &str {
+        match self {
+            Tariff::Electricity(electricity_tariff_type) => {
+                match electricity_tariff_type {
+                    meter::meter_agreements::ElectricityTariffType::StandardTariff(tariff) => &tariff.tariff_code_,
+                    meter::meter_agreements::ElectricityTariffType::DayNightTariff(tariff) => &tariff.tariff_code_,
+                    meter::meter_agreements::ElectricityTariffType::ThreeRateTariff(tariff) => &tariff.tariff_code_,
+                    meter::meter_agreements::ElectricityTariffType::HalfHourlyTariff(tariff) => &tariff.tariff_code_,
+                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(tariff) => &tariff.tariff_code_,
+                }
+            },
+            Tariff::Gas(tariff) => &tariff.tariff_code_,
+        }
+    }
+
+    pub fn full_name(&self) -> &str {
+        match self {
+            Tariff::Electricity(electricity_tariff_type) => {
+                match electricity_tariff_type {
+                    meter::meter_agreements::ElectricityTariffType::StandardTariff(tariff) => &tariff.full_name_,
+                    meter::meter_agreements::ElectricityTariffType::DayNightTariff(tariff) => &tariff.full_name_,
+                    meter::meter_agreements::ElectricityTariffType::ThreeRateTariff(tariff) => &tariff.full_name_,
+                    meter::meter_agreements::ElectricityTariffType::HalfHourlyTariff(tariff) => &tariff.full_name_,
+                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(tariff) => &tariff.full_name_,
+                }
+            },
+            Tariff::Gas(tariff) => &tariff.full_name_,
+        }
+    }
+
+    /// The standing charge including VAT
+    pub fn gross_standing_charge(&self) -> Option<f64> {
+        match self {
+            Tariff::Electricity(electricity_tariff_type) => {
+                match electricity_tariff_type {
+                    meter::meter_agreements::ElectricityTariffType::StandardTariff(tariff) => tariff.standing_charge_,
+                    meter::meter_agreements::ElectricityTariffType::DayNightTariff(tariff) => tariff.standing_charge_,
+                    meter::meter_agreements::ElectricityTariffType::ThreeRateTariff(tariff) => tariff.standing_charge_,
+                    meter::meter_agreements::ElectricityTariffType::HalfHourlyTariff(tariff) => tariff.standing_charge_,
+                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(tariff) => tariff.standing_charge_,
+                }
+            },
+            Tariff::Gas(tariff) => tariff.standing_charge_,
+        }
+    }
+
+    /// The unit rate including VAT, for tariffs which have a single fixed rate
+    pub fn unit_rate(&self) -> Option<f64> {
+        match self {
+            Tariff::Electricity(electricity_tariff_type) => {
+                match electricity_tariff_type {
+                    meter::meter_agreements::ElectricityTariffType::StandardTariff(tariff) => Some(tariff.unit_rate_),
+                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(tariff) => Some(tariff.unit_rate_),
+                    _ => None,
+                }
+            },
+            Tariff::Gas(tariff) => Some(tariff.unit_rate_),
+        }
+    }
+
     pub fn standing_charge(&self) -> f64 {
         match self {
             Tariff::Electricity(electricity_tariff_type) => {
@@ -437,46 +614,85 @@ impl Tariff {
         }
     }
     
-    pub fn print(&self) {
+    pub fn fields(&self) -> Vec<(String, Value)> {
+        fn field<T: Into<Value>>(name: &str, value: T) -> (String, Value) {
+            (name.to_string(), value.into())
+        }
+
         match self {
             Tariff::Electricity(electricity_tariff_type) => {
                 match electricity_tariff_type {
                     meter::meter_agreements::ElectricityTariffType::StandardTariff(tariff) => {
-
-                        println!("Electricity Tariff {}", tariff.display_name_);
-                        println!("Full Name          {}", tariff.full_name_);
-                        println!("Code               {}", tariff.tariff_code_);
-                        // println!("Product Code       {}", tariff.product_code_);
-                        // println!("Description        {}", half_hourly_tariff.description_);
-                        println!("Pre-VAT Standing   {:7.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0));
-                        println!("Standing Charge    {:7.4}", tariff.standing_charge_.unwrap_or(0.0));
-                        println!("Pre-VAT Unit Rate  {:7.4}", tariff.pre_vat_unit_rate_);
-                        println!("Unit Rate          {:7.4}", tariff.unit_rate_);
+                        vec!(
+                            field("Electricity Tariff", tariff.display_name_.as_str()),
+                            field("Full Name", tariff.full_name_.as_str()),
+                            field("Code", tariff.tariff_code_.as_str()),
+                            field("Pre-VAT Standing", Value::number(format!("{:.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0)))),
+                            field("Standing Charge", Value::number(format!("{:.4}", tariff.standing_charge_.unwrap_or(0.0)))),
+                            field("Pre-VAT Unit Rate", Value::number(format!("{:.4}", tariff.pre_vat_unit_rate_))),
+                            field("Unit Rate", Value::number(format!("{:.4}", tariff.unit_rate_))),
+                        )
+                    },
+                    meter::meter_agreements::ElectricityTariffType::DayNightTariff(tariff) => {
+                        vec!(
+                            field("Electricity Tariff", tariff.display_name_.as_str()),
+                            field("Full Name", tariff.full_name_.as_str()),
+                            field("Code", tariff.tariff_code_.as_str()),
+                            field("Pre-VAT Standing", Value::number(format!("{:.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0)))),
+                            field("Standing Charge", Value::number(format!("{:.4}", tariff.standing_charge_.unwrap_or(0.0)))),
+                            field("Pre-VAT Day Rate", Value::number(format!("{:.4}", tariff.pre_vat_day_rate_))),
+                            field("Day Rate", Value::number(format!("{:.4}", tariff.day_rate_))),
+                            field("Pre-VAT Night Rate", Value::number(format!("{:.4}", tariff.pre_vat_night_rate_))),
+                            field("Night Rate", Value::number(format!("{:.4}", tariff.night_rate_))),
+                        )
+                    },
+                    meter::meter_agreements::ElectricityTariffType::ThreeRateTariff(tariff) => {
+                        vec!(
+                            field("Electricity Tariff", tariff.display_name_.as_str()),
+                            field("Full Name", tariff.full_name_.as_str()),
+                            field("Code", tariff.tariff_code_.as_str()),
+                            field("Pre-VAT Standing", Value::number(format!("{:.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0)))),
+                            field("Standing Charge", Value::number(format!("{:.4}", tariff.standing_charge_.unwrap_or(0.0)))),
+                            field("Pre-VAT Day Rate", Value::number(format!("{:.4}", tariff.pre_vat_day_rate_))),
+                            field("Day Rate", Value::number(format!("{:.4}", tariff.day_rate_))),
+                            field("Pre-VAT Night Rate", Value::number(format!("{:.4}", tariff.pre_vat_night_rate_))),
+                            field("Night Rate", Value::number(format!("{:.4}", tariff.night_rate_))),
+                            field("Pre-VAT Off Peak Rate", Value::number(format!("{:.4}", tariff.pre_vat_off_peak_rate_))),
+                            field("Off Peak Rate", Value::number(format!("{:.4}", tariff.off_peak_rate_))),
+                        )
                     },
-                    meter::meter_agreements::ElectricityTariffType::DayNightTariff(_tariff) => todo!(),
-                    meter::meter_agreements::ElectricityTariffType::ThreeRateTariff(_tariff) => todo!(),
                     meter::meter_agreements::ElectricityTariffType::HalfHourlyTariff(tariff) => {
-                        println!("Electricity Tariff {}", tariff.display_name_);
-                        println!("Full Name          {}", tariff.full_name_);
-                        println!("Code               {}", tariff.tariff_code_);
-                        println!("Product Code       {}", tariff.product_code_);
-                        // println!("Description        {}", half_hourly_tariff.description_);
-                        println!("Pre-VAT Standing   {:7.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0));
-                        println!("Standing Charge    {:7.4}", tariff.standing_charge_.unwrap_or(0.0));
-                        // println!("Unit Rates");
-                        // for rate in &half_hourly_tariff.unit_rates_ {
-                        //     println!("{:?} {:?} {:10.4} {:10.4}", rate.valid_from_, rate.valid_to_, rate.pre_vat_value_, rate.value_);
-                        // }
+                        vec!(
+                            field("Electricity Tariff", tariff.display_name_.as_str()),
+                            field("Full Name", tariff.full_name_.as_str()),
+                            field("Code", tariff.tariff_code_.as_str()),
+                            field("Product Code", tariff.product_code_.as_str()),
+                            field("Pre-VAT Standing", Value::number(format!("{:.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0)))),
+                            field("Standing Charge", Value::number(format!("{:.4}", tariff.standing_charge_.unwrap_or(0.0)))),
+                        )
+                    },
+                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(tariff) => {
+                        vec!(
+                            field("Electricity Tariff", tariff.display_name_.as_str()),
+                            field("Full Name", tariff.full_name_.as_str()),
+                            field("Code", tariff.tariff_code_.as_str()),
+                            field("Product Code", tariff.product_code_.as_str()),
+                            field("Pre-VAT Standing", Value::number(format!("{:.4}", tariff.pre_vat_standing_charge_.unwrap_or(0.0)))),
+                            field("Standing Charge", Value::number(format!("{:.4}", tariff.standing_charge_.unwrap_or(0.0)))),
+                            field("Pre-VAT Unit Rate", Value::number(format!("{:.4}", tariff.pre_vat_unit_rate_))),
+                            field("Unit Rate", Value::number(format!("{:.4}", tariff.unit_rate_))),
+                        )
                     },
-                    meter::meter_agreements::ElectricityTariffType::PrepayTariff(_tariff) => todo!(),
                 }
             },
             Tariff::Gas(gas_tariff_type) => {
-                println!("Gas Tariff         {}", gas_tariff_type.full_name_);
-                println!("Code               {}", gas_tariff_type.tariff_code_);
-                println!("Standing Charge    {:7.4}", gas_tariff_type.standing_charge_.unwrap_or(0.0));
-                println!("Pre-VAT Unit Rate  {:7.4}", gas_tariff_type.pre_vat_unit_rate_);
-                println!("Unit Rate          {:7.4}", gas_tariff_type.unit_rate_);
+                vec!(
+                    field("Gas Tariff", gas_tariff_type.full_name_.as_str()),
+                    field("Code", gas_tariff_type.tariff_code_.as_str()),
+                    field("Standing Charge", Value::number(format!("{:.4}", gas_tariff_type.standing_charge_.unwrap_or(0.0)))),
+                    field("Pre-VAT Unit Rate", Value::number(format!("{:.4}", gas_tariff_type.pre_vat_unit_rate_))),
+                    field("Unit Rate", Value::number(format!("{:.4}", gas_tariff_type.unit_rate_))),
+                )
             },
         }
     }
@@ -490,27 +706,34 @@ pub struct MeterAgreementList {
     _hash_key: String,
 }
 
+/// The name by which the meter agreements are fetched again with --refresh, they are otherwise only fetched again by sync
+pub const METER_AGREEMENTS_DATA_SET: &str = "meterAgreements";
+
 impl MeterAgreementList {
-    async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>, account_number: String, meter_node_ids: &Vec<String>) -> anyhow::Result<Self> {
+    /// The agreements of each meter, from the cache unless there are none there, refresh is set or --refresh names them
+    async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>, account_number: String, meter_node_ids: &Vec<String>, refresh: bool) -> anyhow::Result<Self> {
         let hash_key = format!("{}#MeterAgreements", account_number);
             let the_beginning: DateTime = DateTime::from_calendar_date(2000, time::Month::January, 1)?;
             let mut agreements = Vec::new();
     
-            cache_manager.read_vec(&hash_key, &mut agreements)?;
+            cache_manager.read_vec(&hash_key, &mut agreements).await?;
     
-            let cached_cnt = agreements.len();
-    
-            if agreements.is_empty() {
+            if agreements.is_empty() || refresh || cache_manager.must_refresh(&hash_key, METER_AGREEMENTS_DATA_SET) {
+                let mut fetched = Vec::new();
                 for meter_node_id in meter_node_ids {
                     let query = meter::meter_agreements::Query::builder()
                             .with_meter_node_id(meter_node_id.clone())
                             .with_valid_after(the_beginning.clone())
                             .build()?;
-                    let response = request_manager.call(&query).await?;
+                    let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
-                    agreements.push((meter_node_id.clone(), response));
+                    fetched.push((meter_node_id.clone(), response));
                 }
-                cache_manager.write_vec(&hash_key, &agreements, cached_cnt)?;
+                // the records are keyed by meter, so the new agreements replace the cached ones of the same meter
+                cache_manager.write_vec(&hash_key, &fetched, 0).await?;
+
+                agreements.retain(|(meter_node_id, _)| !meter_node_ids.contains(meter_node_id));
+                agreements.extend(fetched);
             }
 
             let mut export_electricity_map = HashMap::new();
@@ -642,28 +865,135 @@ impl Into<meter::electricity_agreement_line_items::LineItemType> for meter::gas_
     }
 }
 
+/// What is kept about a bucket of a time series alongside its records
+#[derive(Serialize, Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct BucketInfo {
+    /// The start time of the query which issued the cursors of the bucket's records, which is the start of an earlier bucket if they were carried over from it
+    query_start_at: DateTime,
+}
+
+/// The info to save with a bucket whose records were fetched by a query which started at query_start_date_time, None if that is the start of the bucket
+fn bucket_info(query_start_date_time: &DateTime, bucket_start_date_time: &DateTime) -> Option<BucketInfo> {
+    (query_start_date_time != bucket_start_date_time).then(|| BucketInfo { query_start_at: query_start_date_time.clone() })
+}
+
+/// Where to carry on fetching a bucket of a time series from its cached records: the start time of the query which issued their cursors and the cursor of
+/// the last of them, None if there are none
+async fn resume_point<T>(cache_manager: &CacheManager, series: &TimeSeries, bucket_start_date: &Date, hash_key: &str, records: &[(String, T)],
+    billing_timezone: &time_tz::Tz) -> anyhow::Result<(DateTime, Option<String>)> {
+    // Cursors are only valid for a query with the same start time, which is the start of the bucket unless its first records were carried over from the one before
+    let mut query_start_date_time = bucket_start_date.at_midnight(billing_timezone);
+    let end_cursor = records.last().map(|(cursor, _)| cursor.clone());
+
+    if end_cursor.is_some() {
+        if let Some(bucket_info) = cache_manager.read_bucket_info_for_date::<BucketInfo>(series, bucket_start_date, hash_key).await? {
+            query_start_date_time = bucket_info.query_start_at;
+        }
+    }
+    Ok((query_start_date_time, end_cursor))
+}
+
+/// Buckets of a time series from the one containing from up to to, made from the monthly buckets cached before the profile gave the series buckets of
+/// another size, so that their history isn't fetched again. A day or a week is the slice of the months it overlaps and a year is its months one after
+/// another, provided that all those months are complete. Empty if the buckets of the series are months.
+async fn buckets_from_months<T: DeserializeOwned>(cache_manager: &CacheManager, series: &TimeSeries, from: &Date, to: &Date, hash_key: &str,
+    billing_timezone: &time_tz::Tz, start_at: impl Fn(&T) -> &DateTime, end_at: impl Fn(&T) -> &DateTime) -> anyhow::Result<Vec<(Bucket, Vec<(String, T)>)>> {
+    let mut complete_months: Vec<Bucket> = Vec::new();
+    let mut buckets: Vec<(Bucket, Vec<(String, T)>)> = Vec::new();
+
+    for (month, records) in cache_manager.read_monthly_vec_range::<T>(series, from, to, hash_key).await? {
+        let month_end_date_time = month.end()?.at_midnight(billing_timezone);
+        if !records.last().is_some_and(|(_, item)| *end_at(item) >= month_end_date_time) {
+            continue;
+        }
+        complete_months.push(month.clone());
+
+        let mut bucket = cache_manager.bucket_for_date(series, &month.start)?;
+        let mut bucket_end_date_time = bucket.end()?.at_midnight(billing_timezone);
+        for (cursor, item) in records {
+            while *start_at(&item) >= bucket_end_date_time {
+                bucket = cache_manager.bucket_for_date(series, &bucket.end()?)?;
+                bucket_end_date_time = bucket.end()?.at_midnight(billing_timezone);
+            }
+            match buckets.last_mut() {
+                Some((last, items)) if *last == bucket => items.push((cursor, item)),
+                _ => buckets.push((bucket.clone(), vec!((cursor, item)))),
+            }
+        }
+    }
+
+    // a bucket which reaches into a month which isn't complete is left to be fetched
+    let mut result = Vec::new();
+    for (bucket, items) in buckets {
+        let bucket_end_date = bucket.end()?;
+        let mut month = Bucket::containing(Granularity::Month, &bucket.start)?;
+        let mut complete = true;
+        while *month.start < *bucket_end_date {
+            complete &= complete_months.contains(&month);
+            month = Bucket::containing(Granularity::Month, &month.end()?)?;
+        }
+        if complete {
+            result.push((bucket, items));
+        }
+    }
+    Ok(result)
+}
+
+/// Save records fetched beyond the end of a bucket as the start of the next bucket (which starts on next_date), so that it can carry on from the last of them
+/// rather than fetching them again. If the next bucket already has records from a query with another start, whichever copy is longer is kept.
+async fn carry_overflow<T: Serialize + DeserializeOwned>(cache_manager: &CacheManager, series: &TimeSeries, hash_key: &str, next_date: &Date,
+    query_start_date_time: &DateTime, overflow: Vec<(String, T)>, billing_timezone: &time_tz::Tz, start_at: impl Fn(&T) -> &DateTime) -> anyhow::Result<()> {
+    if overflow.is_empty() {
+        return Ok(())
+    }
+
+    // a page can reach beyond the next bucket too, but only the next one can continue from its last record
+    let next_bucket = cache_manager.bucket_for_date(series, next_date)?;
+    let next_end_date_time = next_bucket.end()?.at_midnight(billing_timezone);
+    let carried: Vec<(String, T)> = overflow.into_iter().take_while(|(_, item)| *start_at(item) < next_end_date_time).collect();
+
+    // the check of what the next bucket already has and the write are one step in the store, so that another process can't write it in between
+    let info = bucket_info(query_start_date_time, &next_bucket.start.at_midnight(billing_timezone));
+    cache_manager.merge_vec_for_date(series, &next_bucket.start, hash_key, &carried, info.as_ref()).await?;
+    Ok(())
+}
+
+/// Line items (of either meter type), bucketed by month unless the profile says otherwise
+pub const LINE_ITEMS_SERIES: TimeSeries = TimeSeries {
+    data_set: "lineItems",
+    granularity: Granularity::Month,
+};
+
 pub struct AgreementLineItems {
     pub _account_number: String,
     pub agreement_id: String,
     pub end_cursor: Option<String>,
     pub has_next_page: bool,
     pub line_items: Vec<(String, meter::electricity_agreement_line_items::LineItemType)>,
+    /// Records fetched beyond the end of this bucket, which belong to the next one
+    overflow: Vec<(String, meter::electricity_agreement_line_items::LineItemType)>,
     hash_key: String,
     start_date: Date,
     end_date: Date,
     _start_date_time: DateTime,
     end_date_time: DateTime,
+    /// The start time of the query which issued the cursors of this bucket
+    query_start_date_time: DateTime,
 }
 
 impl AgreementLineItems {
+    fn hash_key(account_number: &str, agreement_id: &str, meter_type: &MeterType) -> String {
+        format!("{}#{}#{}AgreementTransactions", account_number, agreement_id, meter_type)
+    }
+
     async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>, account_number: String, meter_type: &MeterType, agreement_id: String, date: &Date, billing_timezone: &time_tz::Tz) -> anyhow::Result<Self> {
-        let hash_key = format!("{}#{}#{}AgreementTransactions", account_number, agreement_id, meter_type);
+        let hash_key = Self::hash_key(&account_number, &agreement_id, meter_type);
         let mut has_next_page = true;
-        let mut end_cursor: Option<String> = None;
         let mut transactions: Vec<(String, meter::electricity_agreement_line_items::LineItemType)> = Vec::new();
 
 
-        let (bucket_start_date, bucket_end_date) = cache_manager.read_vec_for_date(date, &hash_key, &mut transactions)?;
+        let (bucket_start_date, bucket_end_date) = cache_manager.read_vec_for_date(&LINE_ITEMS_SERIES, date, &hash_key, &mut transactions).await?;
         let bucket_start_date_time = bucket_start_date.at_midnight(billing_timezone);
         let bucket_end_date_time = bucket_end_date.at_midnight(billing_timezone);
 
@@ -671,21 +1001,19 @@ impl AgreementLineItems {
 
         //println!("Loaded {} rows for AgreementLineItems[{}..{}]", cached_cnt, bucket_start_date, bucket_end_date);
 
-        if !transactions.is_empty() {
-            let (cursor, final_txn) = transactions.get(transactions.len()-1).unwrap();
-            if final_txn.end_at_ >= bucket_end_date_time {
-                // this bucket is full
-                has_next_page = false;
-            }
-            end_cursor = Some(cursor.clone());
+        let (query_start_date_time, mut end_cursor) = resume_point(cache_manager, &LINE_ITEMS_SERIES, &bucket_start_date, &hash_key, &transactions, billing_timezone).await?;
+        if transactions.last().is_some_and(|(_, final_txn)| final_txn.end_at_ >= bucket_end_date_time) {
+            // this bucket is full
+            has_next_page = false;
         }
+        let mut overflow = Vec::new();
 
         while has_next_page {
             match meter_type {
                 MeterType::Gas => {
                     let mut builder = meter::gas_agreement_line_items::Query::builder()
                         .with_agreement_id(agreement_id.clone())
-                        .with_start_at(bucket_start_date_time.clone())
+                        .with_start_at(query_start_date_time.clone())
                         .with_timezone(String::from("Europe/London"))
                         .with_item_type(super::graphql::LineItemTypeOptions::ConsumptionCharge)
                         .with_line_item_grouping(super::graphql::LineItemGroupingOptions::None)
@@ -697,21 +1025,20 @@ impl AgreementLineItems {
                     
                     let query = builder.build()?;
 
-                    let response = request_manager.call(&query).await?;
+                    let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
                     let response_has_next_page = *&response.gas_agreement_.get_page_info().has_next_page;
 
                     for edge in response.gas_agreement_.get_line_items() {
                         //println!("Record for {:?} - {:?}", edge.node.start_at_, edge.node.end_at_);
 
-                        if has_next_page {
+                        if !has_next_page || edge.node.start_at_ >= bucket_end_date_time {
+                            // this bucket is full, the rest of the page belongs to the next one
+                            has_next_page = false;
+                            overflow.push((edge.cursor, edge.node.into()));
+                        }
+                        else {
                             // we are still filling the bucket we need to return
-                            if edge.node.start_at_ >= bucket_end_date_time {
-                                //println!("Beyond the end of this bucket, break");
-                                // this bucket is full
-                                has_next_page = false;
-                                break; // TODO: save this in the next bucket
-                            }
                             transactions.push((edge.cursor.clone(), edge.node.into()));
                             end_cursor = Some(edge.cursor);
                         }
@@ -729,7 +1056,7 @@ impl AgreementLineItems {
                 MeterType::Electricity =>{
                     let mut builder = meter::electricity_agreement_line_items::Query::builder()
                         .with_agreement_id(agreement_id.clone())
-                        .with_start_at(bucket_start_date_time.clone())
+                        .with_start_at(query_start_date_time.clone())
                         .with_timezone(String::from("Europe/London"))
                         .with_item_type(super::graphql::LineItemTypeOptions::ConsumptionCharge)
                         .with_line_item_grouping(super::graphql::LineItemGroupingOptions::None)
@@ -741,7 +1068,7 @@ impl AgreementLineItems {
                     
                     let query = builder.build()?;
 
-                    let response = request_manager.call(&query).await?;
+                    let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
                     // writeln!(out, "{}", serde_json::to_string(&response)?)?;
 
@@ -753,14 +1080,13 @@ impl AgreementLineItems {
                     for edge in response.electricity_agreement_.get_line_items() {
                         //println!("Record for {:?} - {:?}", edge.node.start_at_, edge.node.end_at_);
 
-                        if has_next_page {
+                        if !has_next_page || edge.node.start_at_ >= bucket_end_date_time {
+                            // this bucket is full, the rest of the page belongs to the next one
+                            has_next_page = false;
+                            overflow.push((edge.cursor, edge.node));
+                        }
+                        else {
                             // we are still filling the bucket we need to return
-                            if edge.node.start_at_ >= bucket_end_date_time {
-                                //println!("Beyond the end of this bucket, break");
-                                // this bucket is full
-                                has_next_page = false;
-                                break; // TODO: save this in the next bucket
-                            }
                             transactions.push((edge.cursor.clone(), edge.node));
                             end_cursor = Some(edge.cursor);
                         }
@@ -785,34 +1111,46 @@ impl AgreementLineItems {
             end_cursor,
             has_next_page,
             line_items: transactions,
+            overflow,
             hash_key,
             start_date: bucket_start_date,
             end_date: bucket_end_date,
             _start_date_time: bucket_start_date_time.clone(),
             end_date_time: bucket_end_date_time,
+            query_start_date_time,
         };
 
         if has_next_page {
             // bucket is not yet full
-            result.fetch_all(request_manager, &bucket_start_date_time).await?;
+            result.fetch_all(request_manager).await?;
         }
 
         
 
         if result.line_items.len() > cached_cnt {
-            cache_manager.write_vec_for_date(&result.start_date, &result.hash_key, &result.line_items, cached_cnt)?;
+            // merged with whatever another process has written to the bucket meanwhile, unless that was fetched by a query with another start
+            let info = bucket_info(&result.query_start_date_time, &result.start_date.at_midnight(billing_timezone));
+            cache_manager.merge_vec_for_date(&LINE_ITEMS_SERIES, &result.start_date, &result.hash_key, &result.line_items, info.as_ref()).await?;
+        }
+
+        if result.line_items.last().is_some_and(|(_, item)| item.end_at_ >= result.end_date_time) {
+            // this bucket is full, so it can be compressed
+            cache_manager.close_bucket_for_date(&LINE_ITEMS_SERIES, &result.start_date, &result.hash_key).await?;
         }
+
+        let overflow = std::mem::take(&mut result.overflow);
+        carry_overflow(cache_manager, &LINE_ITEMS_SERIES, &result.hash_key, &result.end_date, &result.query_start_date_time, overflow, billing_timezone, |item| &item.start_at_).await?;
         
         Ok(result)
     }
 
-    pub async fn fetch_all(&mut self, request_manager: &RequestManager, start_date_time: &DateTime)  -> anyhow::Result<()> {
+    pub async fn fetch_all(&mut self, request_manager: &RequestManager)  -> anyhow::Result<()> {
         let mut has_next_page = self.has_next_page;
 
         while has_next_page {
             let mut builder = meter::electricity_agreement_line_items::Query::builder()
             .with_agreement_id(self.agreement_id.clone())
-                .with_start_at(start_date_time.clone())
+                .with_start_at(self.query_start_date_time.clone())
                 .with_first(100)
                 .with_timezone(String::from("Europe/London"))
                 .with_item_type(super::graphql::LineItemTypeOptions::ConsumptionCharge)
@@ -823,22 +1161,21 @@ impl AgreementLineItems {
                 builder = builder.with_after(end_cursor.clone());
             }
             let query = builder.build()?;
-            let response = request_manager.call(&query).await?;
+            let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
             let response_has_next_page = *&response.electricity_agreement_.get_page_info().has_next_page;
 
             for edge in response.electricity_agreement_.get_line_items() {
-                if edge.node.end_at_ >= self.end_date_time { // have to test here before we move edge.node and break later
+                if !has_next_page {
+                    // this bucket is full, the rest of the page belongs to the next one
+                    self.overflow.push((edge.cursor, edge.node));
+                    continue;
+                }
+                if edge.node.end_at_ >= self.end_date_time { // have to test here before we move edge.node
                     // this bucket is full
                     has_next_page = false;
                 }
                 self.line_items.push((edge.cursor.clone(), edge.node));
                 self.end_cursor = Some(edge.cursor);
-
-
-                if !has_next_page {
-                    // this bucket is full
-                    break; // TODO: save this in the next bucket
-                }
             }
 
             // perhaps there were no additional rows for the next bucket but no more rows for this one either
@@ -851,28 +1188,41 @@ impl AgreementLineItems {
     }
 }
 
+/// Consumption readings, bucketed by month unless the profile says otherwise
+pub const CONSUMPTION_SERIES: TimeSeries = TimeSeries {
+    data_set: "consumption",
+    granularity: Granularity::Month,
+};
+
 pub struct ConsumptionList {
     pub account_number: String,
     pub meter_node_id: String,
     pub end_cursor: Option<String>,
     pub has_next_page: bool,
     pub consumption: Vec<(String, meter::meter_consumption::ConsumptionType)>,
+    /// Records fetched beyond the end of this bucket, which belong to the next one
+    overflow: Vec<(String, meter::meter_consumption::ConsumptionType)>,
     hash_key: String,
     start_date: Date,
     end_date: Date,
     start_date_time: DateTime,
     end_date_time: DateTime,
+    /// The start time of the query which issued the cursors of this bucket
+    query_start_date_time: DateTime,
 }
 
 impl ConsumptionList {
+    fn hash_key(account_number: &str, meter_node_id: &str) -> String {
+        format!("{}#{}#ConsumptionRecords", account_number, meter_node_id)
+    }
+
     async fn new(cache_manager: &CacheManager, request_manager: &AuthenticatedRequestManager<OctopusTokenManager>, account_number: String, meter_node_id: String, date: &Date, billing_timezone: &time_tz::Tz) -> anyhow::Result<Self> {
-        let hash_key = format!("{}#{}#ConsumptionRecords", account_number, meter_node_id);
+        let hash_key = Self::hash_key(&account_number, &meter_node_id);
         let mut has_next_page = true;
-        let mut end_cursor: Option<String> = None;
         let mut transactions: Vec<(String, meter::meter_consumption::ConsumptionType)> = Vec::new();
 
 
-        let (bucket_start_date, bucket_end_date) = cache_manager.read_vec_for_date(date, &hash_key, &mut transactions)?;
+        let (bucket_start_date, bucket_end_date) = cache_manager.read_vec_for_date(&CONSUMPTION_SERIES, date, &hash_key, &mut transactions).await?;
         let bucket_start_date_time = bucket_start_date.at_midnight(billing_timezone);
         let bucket_end_date_time = bucket_end_date.at_midnight(billing_timezone);
 
@@ -880,21 +1230,19 @@ impl ConsumptionList {
 
         //println!("Loaded {} rows for ConsumptionList[{}..{}]", cached_cnt, bucket_start_date, bucket_end_date);
 
-        if !transactions.is_empty() {
-            let (cursor, final_txn) = transactions.get(transactions.len()-1).unwrap();
-            if final_txn.end_at_ >= bucket_end_date_time {
-                // this bucket is full
-                has_next_page = false;
-            }
-            end_cursor = Some(cursor.clone());
+        let (query_start_date_time, mut end_cursor) = resume_point(cache_manager, &CONSUMPTION_SERIES, &bucket_start_date, &hash_key, &transactions, billing_timezone).await?;
+        if transactions.last().is_some_and(|(_, final_txn)| final_txn.end_at_ >= bucket_end_date_time) {
+            // this bucket is full
+            has_next_page = false;
         }
+        let mut overflow = Vec::new();
 
         while has_next_page {
             
                     let mut builder = meter::meter_consumption::Query::builder()
                         .with_meter_id(meter_node_id.clone())
                         .with_grouping(super::graphql::ConsumptionGroupings::HalfHour)
-                        .with_start_at(bucket_start_date_time.clone())
+                        .with_start_at(query_start_date_time.clone())
                         .with_timezone(String::from("Europe/London"))
                         .with_first(50)
                         ;
@@ -904,7 +1252,7 @@ impl ConsumptionList {
                     
                     let query = builder.build()?;
 
-                    let response = request_manager.call(&query).await?;
+                    let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
                     // writeln!(out, "{}", serde_json::to_string(&response)?)?;
 
@@ -927,14 +1275,13 @@ impl ConsumptionList {
                     for edge in page.edges {
                         //println!("Record for {:?} - {:?}", edge.node.start_at_, edge.node.end_at_);
 
-                        if has_next_page {
+                        if !has_next_page || edge.node.start_at_ >= bucket_end_date_time {
+                            // this bucket is full, the rest of the page belongs to the next one
+                            has_next_page = false;
+                            overflow.push((edge.cursor, edge.node));
+                        }
+                        else {
                             // we are still filling the bucket we need to return
-                            if edge.node.start_at_ >= bucket_end_date_time {
-                                //println!("Beyond the end of this bucket, break");
-                                // this bucket is full
-                                has_next_page = false;
-                                break; // TODO: save this in the next bucket
-                            }
                             transactions.push((edge.cursor.clone(), edge.node));
                             end_cursor = Some(edge.cursor);
                         }
@@ -955,37 +1302,52 @@ impl ConsumptionList {
             end_cursor,
             has_next_page,
             consumption: transactions,
+            overflow,
             hash_key,
             start_date: bucket_start_date,
             end_date: bucket_end_date,
             start_date_time: bucket_start_date_time.clone(),
             end_date_time: bucket_end_date_time,
+            query_start_date_time,
         };
 
         if has_next_page {
             // bucket is not yet full
-            result.fetch_all(request_manager, &bucket_start_date_time).await?;
+            result.fetch_all(request_manager).await?;
         }       
 
         if result.consumption.len() > cached_cnt {
-            cache_manager.write_vec_for_date(&result.start_date, &result.hash_key, &result.consumption, cached_cnt)?;
+            // merged with whatever another process has written to the bucket meanwhile, unless that was fetched by a query with another start
+            let info = bucket_info(&result.query_start_date_time, &result.start_date.at_midnight(billing_timezone));
+            cache_manager.merge_vec_for_date(&CONSUMPTION_SERIES, &result.start_date, &result.hash_key, &result.consumption, info.as_ref()).await?;
         }
+
+        if result.consumption.last().is_some_and(|(_, item)| item.end_at_ >= result.end_date_time) {
+            // this bucket is full, so it can be compressed
+            cache_manager.close_bucket_for_date(&CONSUMPTION_SERIES, &result.start_date, &result.hash_key).await?;
+        }
+
+        let overflow = std::mem::take(&mut result.overflow);
+        carry_overflow(cache_manager, &CONSUMPTION_SERIES, &result.hash_key, &result.end_date, &result.query_start_date_time, overflow, billing_timezone, |item| &item.start_at_).await?;
         
         Ok(result)
     }
 
-    pub fn print_consumption(consumption: &Vec<meter::meter_consumption::ConsumptionType>) -> anyhow::Result<()> {
+    pub fn consumption_table(consumption: &Vec<meter::meter_consumption::ConsumptionType>) -> Table {
         let format = time::format_description::parse("[year]-[month]-[day] [hour]:[minute]:[second]").unwrap();
 
-        println!();
-        println!("{:-^20} {:-^20} {:-^10} ", "From", "To", "Amount");
+        let mut table = Table::new(&["From", "To", "Amount"]);
         for item in consumption {
-            println!("{:20} {:20} {:10.3}", item.start_at_.format(&format).unwrap(), item.end_at_.format(&format).unwrap(), item.value_);
+            table.push(vec!(
+                Value::from(item.start_at_.format(&format).unwrap()),
+                Value::from(item.end_at_.format(&format).unwrap()),
+                Value::number(format!("{:.3}", item.value_)),
+            ));
         }
-        Ok(())
+        table
     }
 
-    pub async fn fetch_all(&mut self, request_manager: &RequestManager, start_date_time: &DateTime)  -> anyhow::Result<()> {
+    pub async fn fetch_all(&mut self, request_manager: &RequestManager)  -> anyhow::Result<()> {
         let mut has_next_page = self.has_next_page;
 
         //println!("fetch_all statement transactions {} in buffer", self.line_items.len());
@@ -996,7 +1358,7 @@ impl ConsumptionList {
             let mut builder = meter::meter_consumption::Query::builder()
                 .with_meter_id(self.meter_node_id.clone())
                 .with_grouping(super::graphql::ConsumptionGroupings::HalfHour)
-                .with_start_at(start_date_time.clone())
+                .with_start_at(self.query_start_date_time.clone())
                 .with_timezone(String::from("Europe/London"))
                 .with_first(50)
                 ;
@@ -1006,7 +1368,7 @@ impl ConsumptionList {
             
             let query = builder.build()?;
 
-            let response = request_manager.call(&query).await?;
+            let response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, request_manager.call(&query)).await?;
 
             let page = match response.node_ {
                 meter::meter_consumption::Node::ElectricityMeterType(electricity_meter) => {
@@ -1023,18 +1385,17 @@ impl ConsumptionList {
             let response_has_next_page = page.page_info.has_next_page;
             
             for edge in page.edges {
-                if edge.node.end_at_ >= self.end_date_time { // have to test here before we move edge.node and break later
+                if !has_next_page {
+                    // this bucket is full, the rest of the page belongs to the next one
+                    self.overflow.push((edge.cursor, edge.node));
+                    continue;
+                }
+                if edge.node.end_at_ >= self.end_date_time { // have to test here before we move edge.node
                     // this bucket is full
                     has_next_page = false;
                 }
                 self.consumption.push((edge.cursor.clone(), edge.node));
                 self.end_cursor = Some(edge.cursor);
-
-
-                if !has_next_page {
-                    // this bucket is full
-                    break; // TODO: save this in the next bucket
-                }
             }
 
             // perhaps there were no additional rows for the next bucket but no more rows for this one either
@@ -1045,4 +1406,78 @@ impl ConsumptionList {
         self.has_next_page = has_next_page;
         Ok(())
     }
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use time::Month;
+    use crate::cache_manager::{CachePolicy, FsStore};
+
+    #[derive(Serialize, Deserialize)]
+    struct Reading {
+        start_at: DateTime,
+        end_at: DateTime,
+    }
+
+    #[tokio::test]
+    async fn test_carry_overflow() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let cache_manager = CacheManager::new(Box::new(FsStore::new(dir.path().to_path_buf()).unwrap()), CachePolicy::default(), None);
+        let timezone = time_tz::timezones::db::europe::LONDON;
+        let date = |month, day| Date::from_calendar_date(2025, month, day).unwrap();
+        let reading = |cursor: &str, month, day| (cursor.to_string(), Reading { start_at: date(month, day).at_midnight(timezone), end_at: date(month, day).at_next_midnight(timezone) });
+        let march_start = date(Month::March, 1).at_midnight(timezone);
+
+        // a page of March's query reaches into April and May, only April's records are carried
+        let overflow = vec!(reading("c1", Month::April, 1), reading("c2", Month::April, 2), reading("c3", Month::May, 1));
+        carry_overflow(&cache_manager, &CONSUMPTION_SERIES, "A#Consumption", &date(Month::April, 1), &march_start, overflow, timezone, |item| &item.start_at).await.unwrap();
+
+        let mut april: Vec<(String, Reading)> = Vec::new();
+        cache_manager.read_vec_for_date(&CONSUMPTION_SERIES, &date(Month::April, 1), "A#Consumption", &mut april).await.unwrap();
+        assert_eq!(april.iter().map(|(cursor, _)| cursor.as_str()).collect::<Vec<&str>>(), vec!("c1", "c2"));
+
+        // April carries on from the last carried cursor of March's query
+        let (query_start_date_time, end_cursor) = resume_point(&cache_manager, &CONSUMPTION_SERIES, &date(Month::April, 1), "A#Consumption", &april, timezone).await.unwrap();
+        assert!(query_start_date_time == march_start);
+        assert_eq!(end_cursor.as_deref(), Some("c2"));
+
+        // a shorter carry from a query with another start leaves April as it is
+        let february_start = date(Month::February, 1).at_midnight(timezone);
+        carry_overflow(&cache_manager, &CONSUMPTION_SERIES, "A#Consumption", &date(Month::April, 1), &february_start, vec!(reading("d1", Month::April, 1)), timezone, |item| &item.start_at).await.unwrap();
+
+        let mut april: Vec<(String, Reading)> = Vec::new();
+        cache_manager.read_vec_for_date(&CONSUMPTION_SERIES, &date(Month::April, 1), "A#Consumption", &mut april).await.unwrap();
+        let (query_start_date_time, end_cursor) = resume_point(&cache_manager, &CONSUMPTION_SERIES, &date(Month::April, 1), "A#Consumption", &april, timezone).await.unwrap();
+        assert!(query_start_date_time == march_start);
+        assert_eq!(end_cursor.as_deref(), Some("c2"));
+    }
+
+    #[tokio::test]
+    async fn test_buckets_from_months() {
+        let dir = tempfile::TempDir::new().unwrap();
+        let timezone = time_tz::timezones::db::europe::LONDON;
+        let date = |month, day| Date::from_calendar_date(2025, month, day).unwrap();
+        let days = |month, last_day| (1..=last_day)
+            .map(|day| (format!("{:?}{}", month, day), Reading { start_at: date(month, day).at_midnight(timezone), end_at: date(month, day).at_next_midnight(timezone) }))
+            .collect::<Vec<(String, Reading)>>();
+
+        // all of March and the start of April were cached in monthly buckets
+        let monthly = CacheManager::new(Box::new(FsStore::new(dir.path().to_path_buf()).unwrap()), CachePolicy::default(), None);
+        monthly.write_vec_for_date(&CONSUMPTION_SERIES, &date(Month::March, 1), "A#Consumption", &days(Month::March, 31), 0).await.unwrap();
+        monthly.write_vec_for_date(&CONSUMPTION_SERIES, &date(Month::April, 1), "A#Consumption", &days(Month::April, 5), 0).await.unwrap();
+
+        // only the weeks which lie within complete months can be made from them
+        let policy = CachePolicy { buckets: [(CONSUMPTION_SERIES.data_set.to_string(), Granularity::Week)].into_iter().collect(), ..Default::default() };
+        let weekly = CacheManager::new(Box::new(FsStore::new(dir.path().to_path_buf()).unwrap()), policy, None);
+        let buckets = buckets_from_months(&weekly, &CONSUMPTION_SERIES, &date(Month::February, 20), &date(Month::April, 30), "A#Consumption", timezone,
+            |item: &Reading| &item.start_at, |item| &item.end_at).await.unwrap();
+
+        assert_eq!(buckets.iter().map(|(bucket, _)| bucket.start.day()).collect::<Vec<u8>>(), vec!(3, 10, 17, 24));
+        assert!(buckets.iter().all(|(bucket, items)| bucket.granularity == Granularity::Week && items.len() == 7));
+        assert_eq!(buckets[0].1[0].0, "March3");
+
+        // buckets of months are left as they are
+        assert!(buckets_from_months(&monthly, &CONSUMPTION_SERIES, &date(Month::March, 1), &date(Month::April, 30), "A#Consumption", timezone,
+            |item: &Reading| &item.start_at, |item| &item.end_at).await.unwrap().is_empty());
+    }
+}
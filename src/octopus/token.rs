@@ -2,6 +2,7 @@ use std::sync::Arc;
 use std::time::{SystemTime, UNIX_EPOCH};
 use serde::{Deserialize, Serialize};
 use tokio::sync::Mutex;
+use tracing::debug;
 use crate::MarcoSparkoContext;
 
 use super::graphql::ObtainJsonWebTokenInput;
@@ -147,11 +148,11 @@ impl OctopusTokenManager {
             None
         };
 
-        if let Some(token) = &token {
-            println!("Loaded token from cache: {:?}", token.token);
+        if token.is_some() {
+            debug!("Loaded token from cache");
         }
         else {
-            println!("No cached token found");
+            debug!("No cached token found");
         }
 
         OctopusTokenManager {
@@ -197,18 +198,18 @@ impl TokenManager for OctopusTokenManager {
         }
         else {
             if let Some(refresh_token) = refresh_token {
-                println!("Refreshing Octopus token using refresh token...");
+                debug!("Refreshing Octopus token using refresh token");
 
                 let input = ObtainJsonWebTokenInput::builder()
                     .with_refresh_token(refresh_token.as_ref().clone())
                     .build()?;
 
                 let mutation = super::graphql::login::obtain_kraken_token::Mutation::new(input);
-                let response: crate::octopus::graphql::login::obtain_kraken_token::Response = self.request_manager.call(&mutation, None).await?;
+                let response: crate::octopus::graphql::login::obtain_kraken_token::Response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, self.request_manager.call(&mutation, None)).await?;
         
                 let token = OctopusToken::from(response.obtain_kraken_token_);
 
-                println!("Obtained new Octopus token via refresh: {:?}", token.token);
+                debug!("Obtained new Octopus token via refresh");
         
                 if let Err(error) = self.context.update_cache(crate::octopus::MODULE_ID, &StoredToken::from(&token)) {
                     return Err(sparko_graphql::Error::InternalError(format!("Failed to update cache {}", error)))
@@ -226,10 +227,10 @@ impl TokenManager for OctopusTokenManager {
                     
                     let input = authenticator.to_obtain_json_web_token_input()?;
 
-                    println!("Obtaining new Octopus token...{:?}", input);
+                    debug!("Obtaining new Octopus token");
 
                     let mutation = super::graphql::login::obtain_kraken_token::Mutation::new(input);
-                    let response: crate::octopus::graphql::login::obtain_kraken_token::Response = self.request_manager.call(&mutation, None).await?;
+                    let response: crate::octopus::graphql::login::obtain_kraken_token::Response = crate::metrics::count_api_call(crate::octopus::MODULE_ID, self.request_manager.call(&mutation, None)).await?;
             
                     let token = OctopusToken::from(response.obtain_kraken_token_);
             
@@ -0,0 +1,469 @@
+use std::fmt::Display;
+use std::io::Write;
+
+use clap::ValueEnum;
+use indexmap::IndexMap;
+use serde::{Serialize, Serializer};
+use serde_json::value::RawValue;
+
+/* ***************************************************************************************************************************************************************
+ * Structured command output.
+ *
+ * Commands return a Document rather than printing directly, so that the same result can be rendered as aligned text (for people), or as JSON, CSV or
+ * Markdown (for other programs). A Document is a sequence of Blocks, each of which is a heading, a list of named fields, a table or a paragraph of text.
+ *************************************************************************************************************************************************************** */
+
+/// The formats in which command output can be rendered
+#[derive(ValueEnum, Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum OutputFormat {
+    #[default]
+    Text,
+    Json,
+    Csv,
+    Markdown,
+}
+
+/// A single value in a table or field list.
+///
+/// Numbers are held as preformatted text so that the producer controls precision, they are right aligned in text output and emitted unquoted in JSON.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Value {
+    Empty,
+    Text(String),
+    Number(String),
+}
+
+impl Value {
+    pub fn number<T: Display>(value: T) -> Value {
+        Value::Number(value.to_string())
+    }
+
+    pub fn as_str(&self) -> &str {
+        match self {
+            Value::Empty => "",
+            Value::Text(text) => text,
+            Value::Number(number) => number,
+        }
+    }
+
+}
+
+/// Numbers are written to JSON exactly as formatted, so that 1.500 isn't rounded through f64 to 1.5, or as strings if they aren't valid JSON numbers
+impl Serialize for Value {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            Value::Empty => serializer.serialize_none(),
+            Value::Text(text) => serializer.serialize_str(text),
+            Value::Number(number) => {
+                let number = number.trim();
+                match number.parse::<f64>().is_ok_and(f64::is_finite).then(|| RawValue::from_string(number.to_string()).ok()).flatten() {
+                    Some(raw) => raw.serialize(serializer),
+                    None => serializer.serialize_str(number),
+                }
+            },
+        }
+    }
+}
+
+/// A Block as written to JSON
+#[derive(Serialize)]
+#[serde(untagged)]
+enum JsonBlock<'a> {
+    Heading {
+        heading: &'a str,
+    },
+    Fields {
+        fields: IndexMap<&'a str, &'a Value>,
+    },
+    Table {
+        #[serde(skip_serializing_if = "Option::is_none")]
+        title: Option<&'a str>,
+        rows: Vec<IndexMap<&'a str, &'a Value>>,
+    },
+    Text {
+        text: &'a str,
+    },
+}
+
+impl From<String> for Value {
+    fn from(value: String) -> Self {
+        Value::Text(value)
+    }
+}
+
+impl From<&str> for Value {
+    fn from(value: &str) -> Self {
+        Value::Text(value.to_string())
+    }
+}
+
+impl<T: Into<Value>> From<Option<T>> for Value {
+    fn from(value: Option<T>) -> Self {
+        match value {
+            Some(value) => value.into(),
+            None => Value::Empty,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Table {
+    pub title: Option<String>,
+    pub columns: Vec<String>,
+    pub rows: Vec<Vec<Value>>,
+}
+
+impl Table {
+    pub fn new(columns: &[&str]) -> Table {
+        Table {
+            title: None,
+            columns: columns.iter().map(|c| c.to_string()).collect(),
+            rows: Vec::new(),
+        }
+    }
+
+    pub fn with_title(mut self, title: String) -> Table {
+        self.title = Some(title);
+        self
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        self.rows.push(row);
+    }
+}
+
+/// A table whose rows are printed as they are produced, for commands such as demand which take readings over a period.
+///
+/// Rows are printed in the given format if it can be written a row at a time, otherwise (JSON, or no format when the output is collected, as by --serve) the
+/// table is returned by finish() in the usual way.
+pub struct TableStream {
+    table: Table,
+    format: Option<OutputFormat>,
+    printed: usize,
+}
+
+impl TableStream {
+    pub fn new(table: Table, format: Option<OutputFormat>) -> TableStream {
+        TableStream {
+            table,
+            format: format.filter(|format| *format != OutputFormat::Json),
+            printed: 0,
+        }
+    }
+
+    pub fn push(&mut self, row: Vec<Value>) {
+        if let Some(format) = self.format {
+            print!("{}", self.render_row(format, &row));
+            let _ = std::io::stdout().flush();
+            self.printed += 1;
+        }
+        self.table.push(row);
+    }
+
+    /// The given row rendered as a table of its own, without the title and header after the first row
+    fn render_row(&self, format: OutputFormat, row: &[Value]) -> String {
+        let table = Table {
+            title: if self.printed == 0 { self.table.title.clone() } else { None },
+            columns: self.table.columns.clone(),
+            rows: vec!(row.to_vec()),
+        };
+        let rendered = Document::from(table).render(format);
+        let header_lines = match (self.printed, format) {
+            (0, _) => 0,
+            (_, OutputFormat::Markdown) => 2,
+            _ => 1,
+        };
+
+        let mut out = String::new();
+        for line in rendered.lines().skip(header_lines) {
+            // the blank line which ends a Markdown table would split it
+            if format == OutputFormat::Markdown && line.is_empty() && !out.is_empty() {
+                continue;
+            }
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+
+    /// The rest of the output, which is the table unless its rows have been printed
+    pub fn finish(self) -> Document {
+        if self.printed > 0 {
+            Document::new()
+        }
+        else {
+            Document::from(self.table)
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Block {
+    Heading(String),
+    Fields(Vec<(String, Value)>),
+    Table(Table),
+    Text(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Document {
+    pub blocks: Vec<Block>,
+}
+
+impl From<Table> for Document {
+    fn from(table: Table) -> Self {
+        Document {
+            blocks: vec!(Block::Table(table)),
+        }
+    }
+}
+
+impl Document {
+    pub fn new() -> Document {
+        Document {
+            blocks: Vec::new(),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.blocks.is_empty()
+    }
+
+    pub fn heading(&mut self, heading: &str) {
+        self.blocks.push(Block::Heading(heading.to_string()));
+    }
+
+    pub fn fields(&mut self, fields: Vec<(String, Value)>) {
+        self.blocks.push(Block::Fields(fields));
+    }
+
+    pub fn table(&mut self, table: Table) {
+        self.blocks.push(Block::Table(table));
+    }
+
+    pub fn text(&mut self, text: &str) {
+        self.blocks.push(Block::Text(text.to_string()));
+    }
+
+    pub fn append(&mut self, mut other: Document) {
+        self.blocks.append(&mut other.blocks);
+    }
+
+    pub fn render(&self, format: OutputFormat) -> String {
+        match format {
+            OutputFormat::Text => self.render_text(),
+            OutputFormat::Json => self.render_json(),
+            OutputFormat::Csv => self.render_csv(),
+            OutputFormat::Markdown => self.render_markdown(),
+        }
+    }
+
+    fn render_text(&self) -> String {
+        let mut out = String::new();
+
+        for block in &self.blocks {
+            match block {
+                Block::Heading(heading) => {
+                    out.push_str(&format!("\n{}\n{}\n", heading, "=".repeat(heading.len())));
+                },
+                Block::Fields(fields) => {
+                    let width = fields.iter().map(|(name, _)| name.len()).max().unwrap_or(0);
+                    for (name, value) in fields {
+                        out.push_str(&format!("{:width$} {}\n", name, value.as_str(), width = width));
+                    }
+                },
+                Block::Table(table) => {
+                    if let Some(title) = &table.title {
+                        out.push_str(&format!("\n{}\n", title));
+                    }
+                    let mut widths: Vec<usize> = table.columns.iter().map(|c| c.len()).collect();
+                    for row in &table.rows {
+                        for (i, value) in row.iter().enumerate() {
+                            if i < widths.len() && value.as_str().len() > widths[i] {
+                                widths[i] = value.as_str().len();
+                            }
+                        }
+                    }
+
+                    let header: Vec<String> = table.columns.iter().zip(&widths)
+                        .map(|(column, width)| format!("{:-^width$}", column, width = width))
+                        .collect();
+                    out.push_str(&header.join(" "));
+                    out.push('\n');
+
+                    for row in &table.rows {
+                        let cells: Vec<String> = row.iter().enumerate()
+                            .map(|(i, value)| {
+                                let width = widths.get(i).copied().unwrap_or(0);
+                                match value {
+                                    Value::Number(number) => format!("{:>width$}", number, width = width),
+                                    _ => format!("{:width$}", value.as_str(), width = width),
+                                }
+                            })
+                            .collect();
+                        out.push_str(cells.join(" ").trim_end());
+                        out.push('\n');
+                    }
+                },
+                Block::Text(text) => {
+                    out.push_str(text);
+                    out.push('\n');
+                },
+            }
+        }
+        out
+    }
+
+    fn render_json(&self) -> String {
+        let blocks: Vec<JsonBlock> = self.blocks.iter().map(|block| match block {
+            Block::Heading(heading) => JsonBlock::Heading { heading },
+            Block::Fields(fields) => JsonBlock::Fields {
+                fields: fields.iter().map(|(name, value)| (name.as_str(), value)).collect(),
+            },
+            Block::Table(table) => JsonBlock::Table {
+                title: table.title.as_deref(),
+                rows: table.rows.iter()
+                    .map(|row| table.columns.iter().map(String::as_str).zip(row).collect())
+                    .collect(),
+            },
+            Block::Text(text) => JsonBlock::Text { text },
+        }).collect();
+
+        // Serializing these types can't fail
+        serde_json::to_string_pretty(&blocks).unwrap_or_default()
+    }
+
+    fn render_csv(&self) -> String {
+        fn csv_field(value: &str) -> String {
+            if value.contains(',') || value.contains('"') || value.contains('\n') {
+                format!("\"{}\"", value.replace('"', "\"\""))
+            }
+            else {
+                value.to_string()
+            }
+        }
+
+        let mut sections = Vec::new();
+
+        for block in &self.blocks {
+            let mut out = String::new();
+            match block {
+                Block::Fields(fields) => {
+                    for (name, value) in fields {
+                        out.push_str(&format!("{},{}\n", csv_field(name), csv_field(value.as_str())));
+                    }
+                },
+                Block::Table(table) => {
+                    let header: Vec<String> = table.columns.iter().map(|c| csv_field(c)).collect();
+                    out.push_str(&header.join(","));
+                    out.push('\n');
+                    for row in &table.rows {
+                        let cells: Vec<String> = row.iter().map(|v| csv_field(v.as_str())).collect();
+                        out.push_str(&cells.join(","));
+                        out.push('\n');
+                    }
+                },
+                // CSV has no way to represent free text
+                Block::Heading(_) | Block::Text(_) => continue,
+            }
+            sections.push(out);
+        }
+        sections.join("\n")
+    }
+
+    fn render_markdown(&self) -> String {
+        fn md_cell(value: &str) -> String {
+            value.trim().replace('|', "\\|")
+        }
+
+        let mut out = String::new();
+
+        for block in &self.blocks {
+            match block {
+                Block::Heading(heading) => {
+                    out.push_str(&format!("## {}\n\n", heading));
+                },
+                Block::Fields(fields) => {
+                    out.push_str("| | |\n|---|---|\n");
+                    for (name, value) in fields {
+                        out.push_str(&format!("| {} | {} |\n", md_cell(name), md_cell(value.as_str())));
+                    }
+                    out.push('\n');
+                },
+                Block::Table(table) => {
+                    if let Some(title) = &table.title {
+                        out.push_str(&format!("### {}\n\n", title));
+                    }
+                    let header: Vec<String> = table.columns.iter().map(|c| md_cell(c)).collect();
+                    out.push_str(&format!("| {} |\n", header.join(" | ")));
+                    let align: Vec<&str> = table.columns.iter().enumerate()
+                        .map(|(i, _)| {
+                            if table.rows.iter().any(|row| matches!(row.get(i), Some(Value::Number(_)))) { "---:" } else { "---" }
+                        })
+                        .collect();
+                    out.push_str(&format!("|{}|\n", align.join("|")));
+                    for row in &table.rows {
+                        let cells: Vec<String> = row.iter().map(|v| md_cell(v.as_str())).collect();
+                        out.push_str(&format!("| {} |\n", cells.join(" | ")));
+                    }
+                    out.push('\n');
+                },
+                Block::Text(text) => {
+                    out.push_str(text);
+                    out.push_str("\n\n");
+                },
+            }
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample() -> Document {
+        let mut table = Table::new(&["From", "Amount"]);
+        table.push(vec!(Value::from("2025-01-01"), Value::number("1.500")));
+        table.push(vec!(Value::from("a, \"b\""), Value::Empty));
+        Document::from(table)
+    }
+
+    #[test]
+    fn test_text() {
+        assert_eq!(sample().render(OutputFormat::Text), "---From--- Amount\n2025-01-01  1.500\na, \"b\"\n");
+    }
+
+    #[test]
+    fn test_json() {
+        let rendered = sample().render(OutputFormat::Json);
+        let json: serde_json::Value = serde_json::from_str(&rendered).unwrap();
+        assert_eq!(json[0]["rows"][0]["Amount"], serde_json::json!(1.5));
+        assert_eq!(json[0]["rows"][1]["Amount"], serde_json::Value::Null);
+        assert!(rendered.contains("\"Amount\": 1.500"));
+        // columns keep their order
+        assert!(rendered.find("\"From\"").unwrap() < rendered.find("\"Amount\"").unwrap());
+    }
+
+    #[test]
+    fn test_csv() {
+        assert_eq!(sample().render(OutputFormat::Csv), "From,Amount\n2025-01-01,1.500\n\"a, \"\"b\"\"\",\n");
+    }
+
+    #[test]
+    fn test_markdown() {
+        assert_eq!(sample().render(OutputFormat::Markdown), "| From | Amount |\n|---|---:|\n| 2025-01-01 | 1.500 |\n| a, \"b\" |  |\n\n");
+    }
+
+    #[test]
+    fn test_stream_rows() {
+        let mut stream = TableStream::new(Table::new(&["From", "Amount"]), Some(OutputFormat::Csv));
+        assert_eq!(stream.render_row(OutputFormat::Csv, &[Value::from("2025-01-01"), Value::number("1.5")]), "From,Amount\n2025-01-01,1.5\n");
+        stream.printed = 1;
+        assert_eq!(stream.render_row(OutputFormat::Csv, &[Value::from("2025-01-02"), Value::number("2")]), "2025-01-02,2\n");
+        assert_eq!(stream.render_row(OutputFormat::Markdown, &[Value::from("2025-01-02"), Value::number("2")]), "| 2025-01-02 | 2 |\n");
+
+        let stream = TableStream::new(Table::new(&["From", "Amount"]), Some(OutputFormat::Json));
+        assert!(!stream.finish().is_empty());
+    }
+}
@@ -1,16 +1,18 @@
-use std::collections::HashSet;
+use std::collections::{BTreeMap, HashSet};
 use std::{collections::HashMap};
 use std::fs;
 use std::sync::Mutex;
 use indexmap::IndexMap;
 use serde::{Deserialize, Serialize};
 use anyhow::anyhow;
+use tracing::debug;
 
 use crate::{Cli};
+use crate::cache_manager::Granularity;
 
 const DEFAULT_PROFILE: &str = "default";
 
-#[derive(PartialEq)]
+#[derive(PartialEq, Clone)]
 pub struct ActiveProfile {
     pub all_profiles:   Vec<String>,
     pub active_profile: Profile,
@@ -18,7 +20,7 @@ pub struct ActiveProfile {
 
 impl ActiveProfile {
     // Create empty default profile
-    fn new() -> ActiveProfile {
+    pub(crate) fn new() -> ActiveProfile {
         ActiveProfile {
             all_profiles: vec!(String::from(DEFAULT_PROFILE)),
             active_profile: Profile::new(),
@@ -124,30 +126,38 @@ pub fn set_active_profile(profile_name: &String) -> anyhow::Result<ActiveProfile
 pub fn update_profile<T>(profile_name: &String, module_id: &str, module_profile: &T) -> anyhow::Result<()>
     where
     T: Serialize
+{
+    let value = serde_json::to_value(module_profile)?;
+
+    modify_profile(profile_name, |profile| {
+        profile.modules.insert(module_id.to_string(), value);
+    })
+}
+
+/// Remove a module's entry from the given profile, so that it is no longer initialized when the profile is used
+pub fn remove_module_profile(profile_name: &String, module_id: &str) -> anyhow::Result<()> {
+    modify_profile(profile_name, |profile| {
+        profile.modules.remove(module_id);
+    })
+}
+
+fn modify_profile<F>(profile_name: &String, modifier: F) -> anyhow::Result<()>
+    where
+    F: FnOnce(&mut Profile)
 {
     if let Ok(file)= fs::File::open(&Cli::get_file_path()?) {
         let mut profile_file: ProfileFile = serde_json::from_reader(file)?;
 
-        println!("existing profile_file <{:?}>", &profile_file);
-
-        let mut found = false;
         // let mut profiles = Vec::new();
 
-        for profile in profile_file.iter_mut() {
-            if &profile.name == profile_name {
-                println!("existing profile <{:?}>", &profile);
-                profile.modules.insert(module_id.to_string(), serde_json::to_value(module_profile)?);
-                found = true;
-
-                println!("updated profile <{:?}>", &profile);
-            }
-            // profiles.push(profile);
+        if let Some(profile) = profile_file.iter_mut().find(|profile| &profile.name == profile_name) {
+            modifier(profile);
         }
-        if ! found{
+        else {
             return Err(anyhow!("No such profile \"{}\"", profile_name)); 
         };
 
-         println!("saving profiles <{:?}>", &profile_file);
+        debug!("Saving updated profile \"{}\"", profile_name);
 
             serde_json::to_writer_pretty(fs::File::create(&Cli::get_file_path()?)?, &profile_file)?;
 
@@ -165,7 +175,121 @@ pub type ProfileFile = Vec<Profile>;
 #[serde(rename_all = "camelCase")]
 pub struct Profile {
     pub name: String,
-    pub modules: ModuleProfiles
+    pub modules: ModuleProfiles,
+    /// The log filter to use when none is given on the command line or in the environment, e.g. "info"
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub log_level: Option<String>,
+    /// The MQTT broker to which --mqtt publishes
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub mqtt: Option<MqttSettings>,
+    /// The schedule of --sync
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub sync: Option<SyncSettings>,
+    /// The rules checked against the data of the active modules, and where alerts are sent
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub alerts: Option<crate::alerts::AlertSettings>,
+    /// Where the modules' cached data is stored, in files if not given
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cache: Option<CacheSettings>,
+    /// How long cached data sets such as "viewer" are used before they are fetched again, in seconds, where the default doesn't suit
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cache_ttl: BTreeMap<String, u64>,
+    /// The size of the buckets of time series such as "consumption", where the default of a month doesn't suit
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub cache_buckets: BTreeMap<String, Granularity>,
+    /// Encrypt the cached tokens and data, which are then unlocked with a passphrase or key file at startup
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub encryption: Option<EncryptionSettings>,
+}
+
+/// How the key which encrypts the cache is found
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq, Default)]
+#[serde(rename_all = "camelCase")]
+pub struct EncryptionSettings {
+    /// A file whose contents are the secret, instead of asking for a passphrase
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key_file: Option<String>,
+}
+
+/// The store for cached data
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(tag = "type", rename_all = "camelCase")]
+pub enum CacheSettings {
+    /// A directory of files for each module in ~/.marco-sparko-cache
+    Files,
+    /// An SQLite database for each module, by default alongside the directory which the files store would use
+    Sqlite {
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        path: Option<String>,
+    },
+    /// A DynamoDB table, which can be shared by several machines
+    Dynamodb {
+        table: String,
+        /// The AWS region, from the usual AWS configuration if not given
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        region: Option<String>,
+        /// The endpoint to use instead of AWS, e.g. http://localhost:8000 for DynamoDB Local
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        endpoint: Option<String>,
+    },
+}
+
+/// Schedule of the background sync, in seconds
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase", default)]
+pub struct SyncSettings {
+    pub interval: u64,
+    /// Up to this much is added to each interval at random, so that many clients don't all call the provider at once
+    pub jitter: u64,
+}
+
+impl Default for SyncSettings {
+    fn default() -> SyncSettings {
+        SyncSettings {
+            interval: 6 * 60 * 60,
+            jitter: 15 * 60,
+        }
+    }
+}
+
+/// Connection and publishing settings for the MQTT publisher
+#[derive(Clone, Serialize, Deserialize, Debug, PartialEq)]
+#[serde(rename_all = "camelCase")]
+pub struct MqttSettings {
+    pub host: String,
+    #[serde(default = "MqttSettings::default_port")]
+    pub port: u16,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub username: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub password: Option<String>,
+    /// The state of each sensor is published to TOPIC_PREFIX/MODULE/SENSOR/state
+    #[serde(default = "MqttSettings::default_topic_prefix")]
+    pub topic_prefix: String,
+    /// The prefix to which Home Assistant discovery configuration is published
+    #[serde(default = "MqttSettings::default_discovery_prefix")]
+    pub discovery_prefix: String,
+    /// Seconds between updates
+    #[serde(default = "MqttSettings::default_interval")]
+    pub interval: u64,
+}
+
+impl MqttSettings {
+    fn default_port() -> u16 {
+        1883
+    }
+
+    fn default_topic_prefix() -> String {
+        "marco-sparko".to_string()
+    }
+
+    fn default_discovery_prefix() -> String {
+        "homeassistant".to_string()
+    }
+
+    fn default_interval() -> u64 {
+        300
+    }
 }
 
 impl Profile {
@@ -173,6 +297,14 @@ impl Profile {
         Profile {
             name: DEFAULT_PROFILE.to_string(),
             modules: ModuleProfiles::new(),
+            log_level: None,
+            mqtt: None,
+            sync: None,
+            alerts: None,
+            cache: None,
+            cache_ttl: BTreeMap::new(),
+            cache_buckets: BTreeMap::new(),
+            encryption: None,
         }
     }
 }
@@ -0,0 +1,285 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use anyhow::anyhow;
+use futures::stream::{FuturesUnordered, StreamExt};
+use serde::Serialize;
+use serde_json::json;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info, warn};
+
+use crate::output::OutputFormat;
+use crate::system::EnergyDataSource;
+use crate::{metrics, util, Cli, CliError};
+
+/* ***************************************************************************************************************************************************************
+ * A JSON API over HTTP, so that other tools on the local network can query the data of the active modules.
+ *
+ *  GET /api/modules                                                The ids of the active modules
+ *  GET /api/{module}/sites                                         The module's EnergyDataSource data
+ *  GET /api/{module}/meter-points
+ *  GET /api/{module}/meter-points/{meter_point_id}/tariffs
+ *  GET /api/{module}/meter-points/{meter_point_id}/consumption?from=DATE&to=DATE
+ *  GET /api/{module}/bills
+ *  GET /api/{module}/commands/{command}/{arg}...                  The output of one of the module's commands, e.g. /api/octopus/commands/bill/12345
+ *  GET /metrics                                                    Metrics for Prometheus, see crate::metrics
+ *
+ * Everything is fetched through the modules, and so from the local cache where possible. The modules aren't Send, so rather than a spawned task each
+ * connection is a future polled alongside the others on the serving task: a slow request, such as the demand command which takes readings for most of a
+ * minute, doesn't hold up the others. Only GET is supported, one request per connection.
+ *************************************************************************************************************************************************************** */
+
+pub const DEFAULT_BIND_ADDRESS: &str = "127.0.0.1:8080";
+
+const JSON_CONTENT_TYPE: &str = "application/json";
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+const MAX_HEADERS: usize = 100;
+
+struct Request {
+    method: String,
+    path: Vec<String>,
+    query: HashMap<String, String>,
+    authorization: Option<String>,
+}
+
+struct Response {
+    status: u16,
+    content_type: &'static str,
+    body: String,
+}
+
+impl Response {
+    fn json<T: Serialize>(value: &T) -> anyhow::Result<Response> {
+        Ok(Response {
+            status: 200,
+            content_type: JSON_CONTENT_TYPE,
+            body: serde_json::to_string_pretty(value)?,
+        })
+    }
+
+    fn error(status: u16, message: &str) -> Response {
+        Response {
+            status,
+            content_type: JSON_CONTENT_TYPE,
+            body: json!({ "error": message }).to_string(),
+        }
+    }
+
+    fn reason(&self) -> &'static str {
+        match self.status {
+            200 => "OK",
+            400 => "Bad Request",
+            401 => "Unauthorized",
+            404 => "Not Found",
+            405 => "Method Not Allowed",
+            _ => "Internal Server Error",
+        }
+    }
+}
+
+/// Serve the API until the process is killed
+pub async fn serve(cli: &Cli, bind: &str, api_token: Option<&str>) -> anyhow::Result<()> {
+    let listener = TcpListener::bind(bind).await?;
+    let address = listener.local_addr()?;
+
+    info!("Serving API on {}", address);
+    println!("Serving API on http://{}/api/", address);
+    if api_token.is_none() && !address.ip().is_loopback() {
+        println!("WARNING: no API token is set, anyone who can reach this address can read your data");
+    }
+
+    let mut connections = FuturesUnordered::new();
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, peer) = accepted?;
+                connections.push(async move {
+                    if let Err(error) = handle_connection(cli, stream, api_token).await {
+                        warn!("Request from {} failed: {}", peer, error);
+                    }
+                });
+            },
+            Some(()) = connections.next(), if !connections.is_empty() => {},
+        }
+    }
+}
+
+async fn handle_connection(cli: &Cli, mut stream: TcpStream, api_token: Option<&str>) -> anyhow::Result<()> {
+    let request = tokio::time::timeout(REQUEST_TIMEOUT, read_request(&mut stream)).await
+        .map_err(|_| anyhow!("Timed out reading request"))?;
+
+    let response = match request {
+        Ok(request) => {
+            debug!("{} /{}", request.method, request.path.join("/"));
+            respond(cli, &request, api_token).await
+        },
+        Err(error) => Response::error(400, &error.to_string()),
+    };
+
+    let head = format!("HTTP/1.1 {} {}\r\nContent-Type: {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+        response.status, response.reason(), response.content_type, response.body.len());
+
+    stream.write_all(head.as_bytes()).await?;
+    stream.write_all(response.body.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+async fn read_request(stream: &mut TcpStream) -> anyhow::Result<Request> {
+    let mut reader = BufReader::new(stream);
+    let mut line = String::new();
+
+    reader.read_line(&mut line).await?;
+    let mut words = line.split_whitespace();
+    let method = words.next().ok_or(anyhow!("Empty request"))?.to_string();
+    let target = words.next().ok_or(anyhow!("No request target"))?.to_string();
+
+    let mut authorization = None;
+    for _ in 0..MAX_HEADERS {
+        line.clear();
+        if reader.read_line(&mut line).await? == 0 || line.trim().is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            if name.trim().eq_ignore_ascii_case("authorization") {
+                authorization = Some(value.trim().to_string());
+            }
+        }
+    }
+
+    let (path, query) = target.split_once('?').unwrap_or((&target, ""));
+
+    Ok(Request {
+        method,
+        path: path.split('/').filter(|segment| !segment.is_empty()).map(percent_decode).collect(),
+        query: query.split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .map(|(name, value)| (percent_decode(name), percent_decode(&value.replace('+', " "))))
+            .collect(),
+        authorization,
+    })
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+
+    while i < bytes.len() {
+        if bytes[i] == b'%' && i + 2 < bytes.len() {
+            if let Some(byte) = std::str::from_utf8(&bytes[i + 1..i + 3]).ok().and_then(|hex| u8::from_str_radix(hex, 16).ok()) {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+        }
+        decoded.push(bytes[i]);
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).to_string()
+}
+
+async fn respond(cli: &Cli, request: &Request, api_token: Option<&str>) -> Response {
+    if let Some(api_token) = api_token {
+        let expected = format!("Bearer {}", api_token);
+        if !request.authorization.as_deref().is_some_and(|authorization| constant_time_eq(authorization.as_bytes(), expected.as_bytes())) {
+            return Response::error(401, "A valid bearer token is required")
+        }
+    }
+
+    if request.method != "GET" {
+        return Response::error(405, "Only GET is supported")
+    }
+
+    match route(cli, request).await {
+        Ok(Some(response)) => response,
+        Ok(None) => Response::error(404, "Not found"),
+        Err(error) => {
+            if let Some(CliError::Usage(message)) = error.downcast_ref::<CliError>() {
+                Response::error(400, message)
+            }
+            else {
+                warn!("Request for /{} failed: {}", request.path.join("/"), error);
+                Response::error(500, &error.to_string())
+            }
+        },
+    }
+}
+
+/// Whether a and b are equal, taking the same time whichever bytes differ so that a token can't be guessed a byte at a time
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |difference, (x, y)| difference | (x ^ y)) == 0
+}
+
+async fn route(cli: &Cli, request: &Request) -> anyhow::Result<Option<Response>> {
+    let path: Vec<&str> = request.path.iter().map(String::as_str).collect();
+
+    let (module_id, resource) = match path.as_slice() {
+        ["api", "modules"] => {
+            let mut module_ids: Vec<&String> = cli.modules.keys().collect();
+            module_ids.sort();
+            return Response::json(&module_ids).map(Some)
+        },
+        ["metrics"] => {
+            return Ok(Some(Response {
+                status: 200,
+                content_type: metrics::CONTENT_TYPE,
+                body: metrics::render(&cli.data_sources(), Duration::from_secs(cli.context.args.metrics_interval)).await,
+            }))
+        },
+        ["api", module_id, resource @ ..] if cli.modules.contains_key(*module_id) => (*module_id, resource),
+        _ => return Ok(None),
+    };
+
+    if let ["commands", command, args @ ..] = resource {
+        let args: Vec<String> = args.iter().map(|arg| arg.to_string()).collect();
+        let document = cli.exec_module_command(module_id, command, &args, None).await?;
+        return Ok(Some(Response {
+            status: 200,
+            content_type: JSON_CONTENT_TYPE,
+            body: document.render(OutputFormat::Json),
+        }))
+    }
+
+    let source: &dyn EnergyDataSource = match cli.modules.get(module_id).and_then(|module| module.energy_data_source()) {
+        Some(source) => source,
+        None => return Ok(None),
+    };
+
+    let response = match resource {
+        ["sites"] => Response::json(&source.get_sites().await?)?,
+        ["meter-points"] => Response::json(&source.get_meter_points().await?)?,
+        ["meter-points", meter_point_id, "tariffs"] => Response::json(&source.get_tariffs(meter_point_id).await?)?,
+        ["meter-points", meter_point_id, "consumption"] => {
+            let date_range = util::parse_date_range(
+                    request.query.get("from").map(String::as_str),
+                    request.query.get("to").map(String::as_str))
+                .map_err(|error| CliError::Usage(error.to_string()))?;
+            Response::json(&source.get_consumption(meter_point_id, &date_range).await?)?
+        },
+        ["bills"] => Response::json(&source.get_bills().await?)?,
+        _ => return Ok(None),
+    };
+    Ok(Some(response))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_percent_decode() {
+        assert_eq!(percent_decode("last-month"), "last-month");
+        assert_eq!(percent_decode("a%20b%2Fc"), "a b/c");
+        assert_eq!(percent_decode("100%"), "100%");
+        assert_eq!(percent_decode("%zz"), "%zz");
+    }
+
+    #[test]
+    fn test_constant_time_eq() {
+        assert!(constant_time_eq(b"Bearer abc", b"Bearer abc"));
+        assert!(!constant_time_eq(b"Bearer abd", b"Bearer abc"));
+        assert!(!constant_time_eq(b"Bearer ab", b"Bearer abc"));
+    }
+}
@@ -0,0 +1,124 @@
+use std::fs;
+use std::io::Write;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Serialize};
+use sparko_graphql::types::DateTime;
+use tracing::{info, warn};
+
+use crate::output::{Document, Table, Value};
+use crate::system::EnergyDataSource;
+use crate::{alerts, Cli, MarcoSparkoContext};
+
+/* ***************************************************************************************************************************************************************
+ * Background sync, which keeps the local cache of each active module up to date so that commands don't have to wait for data to be fetched.
+ *
+ * The outcome of the last sync of each module is saved in the cache directory, so that it can be shown by the REPL and the GUI of any process.
+ *************************************************************************************************************************************************************** */
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct SyncStatus {
+    pub last_attempt: DateTime,
+    pub last_success: Option<DateTime>,
+    /// Why the last attempt failed, None if it succeeded
+    pub error: Option<String>,
+}
+
+impl SyncStatus {
+    pub fn read(context: &MarcoSparkoContext, module_id: &str) -> Option<SyncStatus> {
+        let path = context.get_sync_status_file_path(module_id).ok()?;
+        let file = fs::File::open(path).ok()?;
+        serde_json::from_reader(file).ok()
+    }
+
+    /// Save the status, writing it to a temporary file which is renamed over the old one so that another process never reads it half written
+    fn write(&self, context: &MarcoSparkoContext, module_id: &str) -> anyhow::Result<()> {
+        let path = context.get_sync_status_file_path(module_id)?;
+        let mut temp_path = path.as_os_str().to_os_string();
+        temp_path.push(".tmp");
+
+        let mut out = fs::File::create(&temp_path)?;
+        serde_json::to_writer_pretty(&mut out, self)?;
+        out.flush()?;
+        out.sync_all()?;
+        drop(out);
+
+        fs::rename(&temp_path, &path)?;
+        Ok(())
+    }
+
+    /// A one line description, e.g. for the list command
+    pub fn summary(&self) -> String {
+        match (&self.error, &self.last_success) {
+            (None, _) => format!("OK at {}", self.last_attempt),
+            (Some(error), Some(last_success)) => format!("Failed at {} ({}), last OK at {}", self.last_attempt, error, last_success),
+            (Some(error), None) => format!("Failed at {} ({})", self.last_attempt, error),
+        }
+    }
+}
+
+/// Sync one module and save the outcome
+pub async fn sync_module(context: &MarcoSparkoContext, module_id: &str, source: &dyn EnergyDataSource) -> SyncStatus {
+    let previous = SyncStatus::read(context, module_id);
+    let last_attempt = DateTime::now_utc();
+
+    let status = match source.sync().await {
+        Ok(()) => {
+            info!("Synced module {}", module_id);
+            SyncStatus {
+                last_attempt: last_attempt.clone(),
+                last_success: Some(last_attempt),
+                error: None,
+            }
+        },
+        Err(error) => {
+            warn!("Sync of module {} failed: {}", module_id, error);
+            SyncStatus {
+                last_attempt,
+                last_success: previous.and_then(|status| status.last_success),
+                error: Some(error.to_string()),
+            }
+        },
+    };
+
+    if let Err(error) = status.write(context, module_id) {
+        warn!("Unable to save sync status of module {}: {}", module_id, error);
+    }
+    status
+}
+
+/// Sync all the active modules once, returning a table of the outcomes
+pub async fn sync_all(cli: &Cli) -> Document {
+    let mut table = Table::new(&["Module", "Status"]);
+
+    for (module_id, source) in cli.data_sources() {
+        let status = sync_module(&cli.context, module_id, source).await;
+        table.push(vec!(Value::from(module_id), Value::from(status.summary())));
+    }
+    alerts::check(cli).await;
+    table.into()
+}
+
+/// Sync all the active modules on the schedule in the profile until the process is killed
+pub async fn run(cli: &Cli) -> anyhow::Result<()> {
+    let settings = cli.context.profile.active_profile.sync.clone().unwrap_or_default();
+
+    info!("Syncing every {}s with up to {}s jitter", settings.interval, settings.jitter);
+    println!("Syncing every {}s with up to {}s jitter", settings.interval, settings.jitter);
+
+    loop {
+        cli.print_output(&sync_all(cli).await);
+
+        tokio::time::sleep(Duration::from_secs(settings.interval + jitter(settings.jitter))).await;
+    }
+}
+
+/// A pseudo random number of seconds up to max, which only needs to differ between processes and runs
+fn jitter(max: u64) -> u64 {
+    if max == 0 {
+        return 0
+    }
+    let nanos = SystemTime::now().duration_since(UNIX_EPOCH).map(|duration| duration.subsec_nanos()).unwrap_or(0);
+    (nanos as u64 ^ std::process::id() as u64) % (max + 1)
+}
@@ -1,28 +1,130 @@
+use async_trait::async_trait;
 use display_json::DisplayAsJsonPretty;
+use rust_decimal::Decimal;
 use serde::{Deserialize, Serialize};
+use sparko_graphql::types::{Date, DateRange, DateTime};
 
 /// Represents a physical site, or address
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct Site {
-    name: String,
+    pub id: String,
+    pub name: String,
+    pub postcode: Option<String>,
 }
 
 /// Represents a point of metering, which could be a utility meter (one which is used to bill for usage)
 /// or a consumption meter (one which records usage within a Site such as a zwave controller or EV charger)
-#[derive(Serialize, Deserialize, Debug, DisplayAsJsonPretty)]
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
 #[serde(rename_all = "camelCase")]
 pub struct MeterPoint {
-    name: String,
+    /// The provider's identifier for the meter, which is passed to the other EnergyDataSource methods
+    pub id: String,
+    pub site_id: String,
+    /// The industry identifier of the meter point, e.g. an MPAN or MPRN
+    pub name: String,
+    pub serial_number: String,
+    pub commodity: Commodity,
+    pub meter_type: MeterType,
 }
 
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum Commodity {
     Electricity,
     Gas,
     Water
 }
 
+impl Commodity {
+    /// The unit in which consumption of the commodity is given
+    pub fn unit(&self) -> &'static str {
+        match self {
+            Commodity::Electricity | Commodity::Gas => "kWh",
+            Commodity::Water => "m³",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
 pub enum MeterType {
     Consumption,
     Export
-}
\ No newline at end of file
+}
+
+/// A tariff which applies to a meter point for some period
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct Tariff {
+    pub meter_point_id: String,
+    pub code: String,
+    pub name: String,
+    pub valid_from: DateTime,
+    pub valid_to: Option<DateTime>,
+    /// Pence per day, including VAT
+    pub standing_charge: Option<f64>,
+    /// Pence per unit, including VAT, for tariffs with a single fixed unit rate
+    pub unit_rate: Option<f64>,
+}
+
+/// The amount consumed (or exported) by a meter over one interval, usually half an hour
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct Consumption {
+    pub start: DateTime,
+    pub end: DateTime,
+    pub value: Decimal,
+}
+
+/// An instantaneous reading of the power being drawn through a smart meter
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct Demand {
+    pub device_id: String,
+    pub read_at: DateTime,
+    /// Watts
+    pub demand: Decimal,
+}
+
+/// A bill or statement, with amounts in pounds including tax
+#[derive(Serialize, Deserialize, Debug, Clone, DisplayAsJsonPretty)]
+#[serde(rename_all = "camelCase")]
+pub struct Bill {
+    pub id: String,
+    pub bill_type: String,
+    pub issued_date: Date,
+    pub from_date: Date,
+    pub to_date: Date,
+    pub charges: Option<Decimal>,
+    pub credits: Option<Decimal>,
+    pub opening_balance: Option<Decimal>,
+    pub closing_balance: Option<Decimal>,
+}
+
+/// Energy data in types which don't depend on the provider, so that features which work with data from any module
+/// (or from several at once) only need to be written once. Modules which provide such data return an implementation
+/// from Module::energy_data_source()
+#[async_trait]
+pub trait EnergyDataSource: Send + Sync {
+    async fn get_sites(&self) -> anyhow::Result<Vec<Site>>;
+    async fn get_meter_points(&self) -> anyhow::Result<Vec<MeterPoint>>;
+    async fn get_tariffs(&self, meter_point_id: &str) -> anyhow::Result<Vec<Tariff>>;
+    async fn get_consumption(&self, meter_point_id: &str, date_range: &DateRange) -> anyhow::Result<Vec<Consumption>>;
+    async fn get_bills(&self) -> anyhow::Result<Vec<Bill>>;
+
+    /// Fetch anything new from the provider into the local cache, so that later requests don't have to wait for it.
+    /// The default does nothing, for providers which don't cache
+    async fn sync(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// Refetch the tariffs from the provider, so that a change is seen before the cache would next fetch them. The default does nothing, for
+    /// providers which don't cache
+    async fn refresh_tariffs(&self) -> anyhow::Result<()> {
+        Ok(())
+    }
+
+    /// The latest reading of each device which reports live demand, for providers which have such devices
+    async fn get_current_demand(&self) -> anyhow::Result<Vec<Demand>> {
+        Ok(Vec::new())
+    }
+}
@@ -1,3 +1,6 @@
+use sparko_graphql::types::{Date, DateRange};
+use time::{Duration, Month};
+
 
 
 pub fn as_decimal(value: i32, decimals: usize) -> String{
@@ -22,11 +25,82 @@ pub fn as_decimal(value: i32, decimals: usize) -> String{
     s
 }
 
+/// Keywords accepted by commands which take a DATE argument, in addition to explicit dates in the form YYYY-MM-DD.
+pub const DATE_KEYWORDS: [&str; 8] = ["today", "yesterday", "this-week", "last-week", "this-month", "last-month", "this-year", "last-year"];
+
+/// Parse the optional FROM and TO arguments of a command into an inclusive date range.
+///
+/// FROM can be one of the DATE_KEYWORDS, which gives the whole of that period, or an explicit date in which case the range
+/// ends on TO, or today if that is not given. With no arguments the range is the current month.
+pub fn parse_date_range(from: Option<&str>, to: Option<&str>) -> anyhow::Result<DateRange> {
+    let (start, end) = parse_dates(time::OffsetDateTime::now_utc().date(), from, to)?;
+
+    Ok(DateRange {
+        start: Date::from_calendar_date(start.year(), start.month(), start.day())?,
+        end: Date::from_calendar_date(end.year(), end.month(), end.day())?,
+    })
+}
+
+fn parse_dates(today: time::Date, from: Option<&str>, to: Option<&str>) -> anyhow::Result<(time::Date, time::Date)> {
+    let from = from.unwrap_or("this-month");
+    let start_of_week = today - Duration::days(today.weekday().number_days_from_monday() as i64);
+    let start_of_month = today.replace_day(1)?;
+
+    let period = match from {
+        "today" => Some((today, today)),
+        "yesterday" => Some((today - Duration::days(1), today - Duration::days(1))),
+        "this-week" => Some((start_of_week, today)),
+        "last-week" => Some((start_of_week - Duration::days(7), start_of_week - Duration::days(1))),
+        "this-month" => Some((start_of_month, today)),
+        "last-month" => {
+            let end = start_of_month - Duration::days(1);
+            Some((end.replace_day(1)?, end))
+        },
+        "this-year" => Some((time::Date::from_calendar_date(today.year(), Month::January, 1)?, today)),
+        "last-year" => Some((
+            time::Date::from_calendar_date(today.year() - 1, Month::January, 1)?,
+            time::Date::from_calendar_date(today.year() - 1, Month::December, 31)?
+        )),
+        _ => None,
+    };
+
+    if let Some(period) = period {
+        if let Some(to) = to {
+            return Err(anyhow::anyhow!("Unexpected end date '{}' after '{}'", to, from))
+        }
+        return Ok(period)
+    }
+
+    let format = time::format_description::parse("[year]-[month]-[day]")?;
+    let parse = |date: &str| time::Date::parse(date, &format)
+        .map_err(|_| anyhow::anyhow!("Invalid date '{}', expected YYYY-MM-DD or one of {}", date, DATE_KEYWORDS.join(", ")));
+
+    let start = parse(from)?;
+    let end = if let Some(to) = to { parse(to)? } else { today };
+
+    if end < start {
+        return Err(anyhow::anyhow!("End date {} is before start date {}", end, start))
+    }
+    Ok((start, end))
+}
 
 #[cfg(test)]
 mod tests {
     use super::*;
 
+    #[test]
+    fn test_parse_dates() {
+        let today = time::Date::from_calendar_date(2025, Month::March, 12).unwrap();
+        let date = |month, day| time::Date::from_calendar_date(2025, month, day).unwrap();
+
+        assert_eq!(parse_dates(today, None, None).unwrap(), (date(Month::March, 1), today));
+        assert_eq!(parse_dates(today, Some("last-month"), None).unwrap(), (date(Month::February, 1), date(Month::February, 28)));
+        assert_eq!(parse_dates(today, Some("last-week"), None).unwrap(), (date(Month::March, 3), date(Month::March, 9)));
+        assert_eq!(parse_dates(today, Some("2025-01-15"), Some("2025-02-01")).unwrap(), (date(Month::January, 15), date(Month::February, 1)));
+        assert!(parse_dates(today, Some("last-month"), Some("2025-02-01")).is_err());
+        assert!(parse_dates(today, Some("2025-02-01"), Some("2025-01-01")).is_err());
+    }
+
     #[test]
     fn test_zero_int() {
         
@@ -1,7 +1,8 @@
 use std::{collections::HashMap, sync::Arc};
 
-use crate::{MarcoSparkoContext, ModuleRegistrations, components::app::Route};
+use crate::{Cli, MarcoSparkoContext, ModuleRegistrations, components::app::Route};
 use dioxus::prelude::*;
+use tracing::{trace, warn};
 
 /// The Home page component that will be rendered when the current route is `[Route::Home]`
 #[component]
@@ -10,19 +11,17 @@ pub fn Home(
     // xid: i32,
     // modules_signal: ModuleRegistrations
 ) -> Element {
-    println!("TRace Home 1");
-    let context_signal = use_context::<Signal<Option<Arc<MarcoSparkoContext>>>>();
+    trace!("Rendering Home component");
+    let mut context_signal = use_context::<Signal<Option<Arc<MarcoSparkoContext>>>>();
     let opt_context = &*context_signal.read();
     let context = opt_context.as_ref().unwrap();
     let module_registrations = use_context::<ModuleRegistrations>();
     let mut modules = HashMap::new();
-
-    println!("TRace Home 2");
+    let mut alerts = use_signal(|| crate::alerts::AlertState::read(context).recent);
 
     // println!("ZZ2 start id={} ", xid);
     // println!("ZZ2 start i={} {:?}", xid, modules_signal);
     for module_id in module_registrations.0.keys() {
-        println!("ZZ2 module {}", module_id);
         let active = if context.profile.active_profile.modules.contains_key(module_id) {
             "Active"
         }
@@ -30,7 +29,11 @@ pub fn Home(
             "inactive"
         };
 
-        modules.insert(module_id.clone(),active);
+        let last_sync = crate::sync::SyncStatus::read(context, module_id)
+            .map(|sync_status| sync_status.summary())
+            .unwrap_or_default();
+
+        modules.insert(module_id.clone(),(active, context.clone(), last_sync));
     }
     //  for (module_id, active) in &modules {
     //         println!("ZZ3 module {}", module_id);
@@ -38,8 +41,34 @@ pub fn Home(
     rsx! {
         div {
             // h1 { "This is Home #{xid}!" }
+            if !alerts.read().is_empty() {
+                div {
+                    class: "alerts",
+                    h2 { "Alerts" }
+                    for alert in alerts.read().iter().rev() {
+                        p { "{alert.raised_at} {alert.module_id}: {alert.message}" }
+                    }
+                    button {
+                        onclick: {
+                            let context = context.clone();
+                            move |_| {
+                                let context = context.clone();
+                                async move {
+                                    // another process may hold the lock on the alert state for a while
+                                    match tokio::task::spawn_blocking(move || crate::alerts::dismiss_alerts(&context)).await {
+                                        Ok(Ok(())) => alerts.set(Vec::new()),
+                                        Ok(Err(error)) => warn!("Unable to dismiss alerts: {}", error),
+                                        Err(error) => warn!("Unable to dismiss alerts: {}", error),
+                                    }
+                                }
+                            }
+                        },
+                        "Dismiss"
+                    }
+                }
+            }
             h1 { "Modules" }
-            for (module_id , active) in modules {
+            for (module_id , (active, current_context, last_sync)) in modules {
                 Link {
                     class: "nav-item",
                     to: Route::Module {
@@ -48,6 +77,32 @@ pub fn Home(
                     "{module_id}"
                 }
                 " [{active}]"
+                if !last_sync.is_empty() {
+                    " Last sync: {last_sync} "
+                }
+                if active == "Active" {
+                    button {
+                        // Module pages build a new module instance from the profile whenever they are opened
+                        onclick: {
+                            let current_context = current_context.clone();
+                            move |_| {
+                                context_signal.set(Some(current_context.reload_profile()?));
+                                Ok(())
+                            }
+                        },
+                        "Reload"
+                    }
+                    button {
+                        onclick: {
+                            let module_id = module_id.clone();
+                            move |_| {
+                                context_signal.set(Some(Cli::logout_module(&current_context, &module_id, false)?));
+                                Ok(())
+                            }
+                        },
+                        "Log Out"
+                    }
+                }
             }
         }
     }
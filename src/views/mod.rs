@@ -15,4 +15,7 @@ mod module;
 pub use module::Module;
 
 mod navbar;
-pub use navbar::Navbar;
\ No newline at end of file
+pub use navbar::Navbar;
+
+mod unlock;
+pub use unlock::Unlock;
@@ -2,10 +2,10 @@
 
 
 use std::sync::Arc;
-use clap::Parser;
 
 use crate::{ MarcoSparkoContext, components::app::Route};
 use dioxus::prelude::*;
+use tracing::trace;
 
 // use crate::PROFILE_MANAGER;
 
@@ -37,13 +37,13 @@ pub fn Navbar() -> Element {
 
     let mut all = Vec::new();
     for name in &context.profile.all_profiles {
-        all.push(name.clone());
+        all.push((name.clone(), context.clone()));
     }
     
     // get the current route so we can mark the active nav item
     let current_route = use_route::<Route>();
 
-    println!("Current route in Navbar: {:?}", current_route);
+    trace!("Current route in Navbar: {:?}", current_route);
 
     rsx! {
         document::Link { rel: "stylesheet", href: NAVBAR_CSS }
@@ -107,16 +107,13 @@ pub fn Navbar() -> Element {
                     // Dropdown menu
                     div { class: if *menu_open.read() { "menu open" } else { "menu" },
 
-                        for name in all {
+                        for (name, current_context) in all {
                             div {
                                 class: "menu-item",
                                 onclick: move |_| {
 
                                     menu_open.set(false);
-                                    let new_context = Arc::new(MarcoSparkoContext {
-                                        args: crate::Args::parse(),
-                                        profile: crate::profile::set_active_profile(&name)?,
-                                    });
+                                    let new_context = current_context.with_profile(&name)?;
 
                                     context_signal.set(Some(new_context));
                                     Ok(())
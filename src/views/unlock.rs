@@ -0,0 +1,80 @@
+use std::sync::Arc;
+
+use crate::MarcoSparkoContext;
+use dioxus::prelude::*;
+use tracing::trace;
+
+/// Asks for the passphrase of a profile whose cache is encrypted, which is shown instead of the routes until the cache is unlocked
+#[component]
+pub fn Unlock() -> Element {
+    trace!("Rendering Unlock component");
+    let mut context_signal = use_context::<Signal<Option<Arc<MarcoSparkoContext>>>>();
+    let context = context_signal.read().as_ref().unwrap().clone();
+    let mut passphrase = use_signal(|| String::new());
+    let mut confirmation = use_signal(|| String::new());
+    let mut error = use_signal(|| Option::<String>::None);
+    let is_new_key = context.is_new_key();
+    let profile_name = context.profile.active_profile.name.clone();
+
+    rsx! {
+        if let Some(error) = error.read().as_ref() {
+            div { class: "error", "{error}" }
+        }
+        div {
+            h1 { "Unlock {profile_name}" }
+            if is_new_key {
+                p { "The cached data of this profile will be encrypted. Choose a passphrase, it will be needed each time Marco Sparko starts." }
+            }
+            form {
+                onsubmit: move |evt: FormEvent| {
+                    evt.prevent_default();
+                    if is_new_key && *passphrase.read() != *confirmation.read() {
+                        error.set(Some("The passphrases do not match".to_string()));
+                        return;
+                    }
+                    match context.unlock(Some(passphrase.read().as_str())) {
+                        Ok(unlocked) => context_signal.set(Some(unlocked)),
+                        Err(unlock_error) => error.set(Some(unlock_error.to_string())),
+                    }
+                },
+                table {
+                    tr {
+                        td {
+                            label { "Passphrase:" }
+                        }
+                        td {
+                            input {
+                                r#type: "password",
+                                id: "passphrase",
+                                name: "passphrase",
+                                value: "{passphrase}",
+                                oninput: move |e| passphrase.set(e.value().clone()),
+                            }
+                        }
+                    }
+                    if is_new_key {
+                        tr {
+                            td {
+                                label { "Confirm:" }
+                            }
+                            td {
+                                input {
+                                    r#type: "password",
+                                    id: "confirmation",
+                                    name: "confirmation",
+                                    value: "{confirmation}",
+                                    oninput: move |e| confirmation.set(e.value().clone()),
+                                }
+                            }
+                        }
+                    }
+                    tr {
+                        td {
+                            button { r#type: "submit", "Unlock" }
+                        }
+                    }
+                }
+            }
+        }
+    }
+}